@@ -0,0 +1,135 @@
+//! Allow and deny rules for target addresses
+//!
+//! A rule is `CIDR[:PORT]`: `10.0.0.0/8`, `192.168.1.5:22`, `[fd00::/8]:443`,
+//! or `*:25` for a port on any address. A bare address matches only that host.
+//! Rules are checked against the address a target resolves to, just before
+//! connecting, so domains pointing at internal hosts are caught too. A target
+//! matching any deny rule is refused; when allow rules are given, a target
+//! must also match one of them.
+
+use anyhow::{bail, Context, Result};
+use std::net::{IpAddr, SocketAddr};
+use tracing::info;
+
+/// Allow and deny rules in command-line order
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    /// Network address and prefix length; `None` for any address
+    network: Option<(IpAddr, u8)>,
+    port: Option<u16>,
+}
+
+impl AccessList {
+    /// Parse `--allow` and `--deny` rules
+    pub fn parse(allow: &[String], deny: &[String]) -> Result<Self> {
+        let parse_all = |specs: &[String], verb: &str| {
+            specs
+                .iter()
+                .map(|spec| {
+                    let rule = Rule::parse(spec).with_context(|| format!("Invalid --{} rule {}", verb, spec))?;
+                    info!("{} {}", if verb == "allow" { "Allowing" } else { "Denying" }, spec);
+                    Ok(rule)
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Self {
+            allow: parse_all(allow, "allow")?,
+            deny: parse_all(deny, "deny")?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Whether connections to `target` are permitted
+    pub fn permits(&self, target: SocketAddr) -> bool {
+        // An IPv4-mapped IPv6 address is matched as the IPv4 host it carries
+        let target = SocketAddr::new(target.ip().to_canonical(), target.port());
+        !self.deny.iter().any(|rule| rule.matches(target))
+            && (self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(target)))
+    }
+}
+
+impl Rule {
+    fn parse(spec: &str) -> Result<Self> {
+        // Split off the port: IPv6 networks need brackets to carry one
+        let (network, port) = if let Some(rest) = spec.strip_prefix('[') {
+            let Some((network, rest)) = rest.split_once(']') else {
+                bail!("Missing ]");
+            };
+            match rest {
+                "" => (network, None),
+                _ => match rest.strip_prefix(':') {
+                    Some(port) => (network, Some(port)),
+                    None => bail!("Expected :PORT after ]"),
+                },
+            }
+        } else if let Some((network, port)) = spec.split_once(':').filter(|(_, port)| !port.contains(':')) {
+            (network, Some(port))
+        } else {
+            (spec, None)
+        };
+
+        let port = port
+            .map(|port| port.parse::<u16>().ok().filter(|&p| p != 0).context("Invalid port"))
+            .transpose()?;
+
+        let network = match network {
+            "*" if port.is_some() => None,
+            "*" => bail!("* needs a port, e.g. *:25"),
+            _ => {
+                let (address, prefix) = match network.split_once('/') {
+                    Some((address, prefix)) => (address, Some(prefix)),
+                    None => (network, None),
+                };
+                let address: IpAddr = address.parse().context("Expected an IP address or CIDR network")?;
+                if address.to_canonical() != address {
+                    bail!("Use the IPv4 form of IPv4-mapped addresses");
+                }
+                let max = if address.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(prefix) => match prefix.parse::<u8>() {
+                        Ok(n) if n <= max => n,
+                        _ => bail!("Prefix length must be between 0 and {}", max),
+                    },
+                    None => max,
+                };
+                Some((address, prefix))
+            }
+        };
+
+        Ok(Self { network, port })
+    }
+
+    fn matches(&self, target: SocketAddr) -> bool {
+        if self.port.is_some_and(|port| port != target.port()) {
+            return false;
+        }
+        match self.network {
+            None => true,
+            Some((network, prefix)) => in_network(target.ip(), network, prefix),
+        }
+    }
+}
+
+/// Whether the first `prefix` bits of `ip` and `network` are the same
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,317 @@
+//! HTTP API for changing the balancer set at runtime
+//!
+//! `GET /balancers` lists the balancers in use with their stats as JSON.
+//! `POST /balancers` adds the balancer specified in the request body, written
+//! as on the command line (`192.168.1.3@2@rate=5mb`). `DELETE /balancers/N`
+//! removes the Nth balancer of the listing. `POST /balancers/N/drain` takes
+//! it out of rotation while its connections finish, and its `active` count
+//! shows when the link is free; `POST /balancers/N/undrain` puts it back.
+//! Each change builds a new pool and swaps it in, so connections already
+//! relaying keep the pool they started with and are not interrupted.
+
+use crate::load_balancer::{LoadBalancer, LoadBalancerPool, SharedPool, Strategy};
+use anyhow::{bail, Result};
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+/// Largest request, head and body, read before answering
+const MAX_REQUEST: usize = 16 * 1024;
+
+/// Time a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parse and validate a balancer specification, logged as the balancer at the index
+pub type ParseBalancer = dyn Fn(usize, &str) -> Result<LoadBalancer> + Send + Sync;
+
+/// Build a pool from balancers and the strategy in use, with the pool options
+/// given at startup
+pub type BuildPool = dyn Fn(Vec<LoadBalancer>, Strategy) -> LoadBalancerPool + Send + Sync;
+
+/// How the API turns requests into a new balancer set
+pub struct Editor {
+    pub parse: Box<ParseBalancer>,
+    pub build: Box<BuildPool>,
+    /// Set when mirrors, routes or quotas refer to balancers by position,
+    /// which changing the set would silently break
+    pub positional: bool,
+}
+
+/// Serve the admin API for the pool in use on the listener
+pub fn spawn(listener: TcpListener, shared: Arc<SharedPool>, editor: Editor) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving admin API on http://{}/balancers", addr);
+        if !addr.ip().is_loopback() {
+            warn!("The admin API on {} has no authentication and can change the load balancers", addr);
+        }
+    }
+
+    let editor = Arc::new(editor);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let shared = Arc::clone(&shared);
+                    let editor = Arc::clone(&editor);
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, &shared, &editor).await {
+                            debug!("Admin request from {} failed: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Could not accept admin connection: {}", e),
+            }
+        }
+    });
+}
+
+async fn serve(mut stream: TcpStream, shared: &SharedPool, editor: &Editor) -> std::io::Result<()> {
+    let mut request = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    let read_request = async {
+        loop {
+            // Read the head, then as much body as it announces
+            if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                if request.len() >= end + 4 + content_length(&request[..end]) {
+                    break;
+                }
+            }
+            if request.len() >= MAX_REQUEST {
+                break;
+            }
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    if tokio::time::timeout(REQUEST_TIMEOUT, read_request).await.is_err() {
+        return Ok(());
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let (head, body) = request.split_once("\r\n\r\n").unwrap_or((&request, ""));
+    let mut request_line = head.split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or("/");
+    let path = path.split('?').next().unwrap_or(path);
+
+    let (status, body) = handle(method, path, body.trim(), shared, editor);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Status line and JSON body answering one request
+fn handle(method: &str, path: &str, body: &str, shared: &SharedPool, editor: &Editor) -> (&'static str, String) {
+    let Some(rest) = path.strip_prefix("/balancers").filter(|rest| rest.is_empty() || rest.starts_with('/')) else {
+        return ("404 Not Found", error("Not found"));
+    };
+    let mut segments = rest.split('/').filter(|segment| !segment.is_empty());
+    let index = match segments.next().map(str::parse::<usize>) {
+        None => None,
+        Some(Ok(n)) => Some(n),
+        Some(Err(_)) => return ("404 Not Found", error("Not found")),
+    };
+    let action = segments.next();
+    if segments.next().is_some() {
+        return ("404 Not Found", error("Not found"));
+    }
+
+    match (method, index, action) {
+        ("GET", None, None) => ("200 OK", render(&shared.current())),
+        ("GET", Some(n), None) => {
+            let pool = shared.current();
+            match n.checked_sub(1).filter(|&idx| idx < pool.len()) {
+                Some(idx) => ("200 OK", render_balancer(&pool, idx)),
+                None => ("404 Not Found", error(&format!("No load balancer {}", n))),
+            }
+        }
+        ("POST", None, None) | ("DELETE", Some(_), None) if editor.positional => (
+            "409 Conflict",
+            error("Load balancers cannot be changed while --mirror, --route or quota= refer to them"),
+        ),
+        ("POST", None, None) => {
+            if body.is_empty() {
+                return ("400 Bad Request", error("Expected a load balancer specification in the request body"));
+            }
+            let mut status = "400 Bad Request";
+            let result = shared.update(|current| {
+                let lb = (editor.parse)(current.len(), body)?;
+                if lb.options.quota.is_some() {
+                    bail!("quota= balancers need a restart");
+                }
+                let mut balancers = balancers(current);
+                if lb.identity().is_some() && balancers.iter().any(|existing| existing.identity() == lb.identity()) {
+                    status = "409 Conflict";
+                    bail!("Load balancer {} is already in use", body);
+                }
+                balancers.push(lb);
+                Ok((editor.build)(balancers, current.strategy()).with_counters_from(current))
+            });
+            match result {
+                Ok(pool) => {
+                    let idx = pool.len() - 1;
+                    info!("Added load balancer {} through the admin API", idx + 1);
+                    ("201 Created", render_balancer(&pool, idx))
+                }
+                Err(e) => (status, error(&format!("{:#}", e))),
+            }
+        }
+        ("DELETE", Some(n), None) => {
+            let mut removed = None;
+            let mut status = "409 Conflict";
+            let result = shared.update(|current| {
+                let Some(idx) = n.checked_sub(1).filter(|&idx| idx < current.len()) else {
+                    status = "404 Not Found";
+                    bail!("No load balancer {}", n);
+                };
+                if current.len() == 1 {
+                    bail!("Cannot remove the last load balancer");
+                }
+                let mut balancers = balancers(current);
+                removed = Some(balancers.remove(idx));
+                Ok((editor.build)(balancers, current.strategy()).with_counters_from(current))
+            });
+            match (result, removed) {
+                (Ok(_), Some(lb)) => {
+                    info!("Removed load balancer {} ({}) through the admin API", n, lb.address);
+                    ("200 OK", "{}".to_string())
+                }
+                (Err(e), _) => (status, error(&format!("{:#}", e))),
+                (Ok(_), None) => unreachable!("a successful removal always records the balancer"),
+            }
+        }
+        ("POST", Some(n), Some(action @ ("drain" | "undrain"))) => {
+            let draining = action == "drain";
+            let mut status = "200 OK";
+            let result = shared.update(|current| {
+                let Some(idx) = n.checked_sub(1).filter(|&idx| idx < current.len()) else {
+                    status = "404 Not Found";
+                    bail!("No load balancer {}", n);
+                };
+                Ok((editor.build)(balancers(current), current.strategy()).with_counters_from(current).with_draining(idx, draining))
+            });
+            match result {
+                Ok(pool) => {
+                    let idx = n - 1;
+                    if draining {
+                        let active = pool.stats().get(idx).map_or(0, |stats| stats.active);
+                        info!("Draining load balancer {} through the admin API, {} connections active", n, active);
+                    } else {
+                        info!("Load balancer {} back in rotation through the admin API", n);
+                    }
+                    (status, render_balancer(&pool, idx))
+                }
+                Err(e) => (status, error(&format!("{:#}", e))),
+            }
+        }
+        (_, Some(_), Some("drain" | "undrain")) => ("405 Method Not Allowed", error("Method not allowed")),
+        (_, _, Some(_)) => ("404 Not Found", error("Not found")),
+        _ => ("405 Method Not Allowed", error("Method not allowed")),
+    }
+}
+
+/// Copies of the pool's balancers, for building the next pool
+pub fn balancers(pool: &LoadBalancerPool) -> Vec<LoadBalancer> {
+    (0..pool.len()).filter_map(|idx| pool.balancer(idx).map(|lb| LoadBalancer::clone(lb))).collect()
+}
+
+/// The pool's balancers and stats as a JSON array
+fn render(pool: &LoadBalancerPool) -> String {
+    let entries: Vec<String> = (0..pool.len()).map(|idx| render_balancer(pool, idx)).collect();
+    format!("[{}]", entries.join(","))
+}
+
+/// One balancer and its stats as a JSON object
+fn render_balancer(pool: &LoadBalancerPool, idx: usize) -> String {
+    let Some(lb) = pool.balancer(idx) else {
+        return "{}".to_string();
+    };
+    let stats = pool.stats().get(idx).copied().unwrap_or_default();
+    let kind = match (&lb.upstream, lb.blackhole, &lb.iface) {
+        (Some(_), _, _) => "upstream",
+        (_, Some(_), _) => "blackhole",
+        (_, _, Some(_)) => "interface",
+        _ => "tunnel",
+    };
+    let address = lb.source_ip().map_or_else(|| lb.address.clone(), |ip| ip.to_string());
+
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "{{\"index\":{},\"kind\":\"{}\",\"address\":{},\"ratio\":{}",
+        idx + 1,
+        kind,
+        json_string(&address),
+        lb.contention_ratio
+    );
+    if let Some(iface) = &lb.iface {
+        let _ = write!(out, ",\"interface\":{}", json_string(iface));
+    }
+    if let Some(label) = &lb.options.label {
+        let _ = write!(out, ",\"label\":{}", json_string(label));
+    }
+    let _ = write!(
+        out,
+        ",\"connections\":{},\"active\":{},\"errors\":{},\"bytes_out\":{},\"bytes_in\":{},\"rate_out\":{:.1},\"rate_in\":{:.1},\"circuit_open\":{},\"healthy\":{},\"draining\":{}}}",
+        stats.connections,
+        stats.active,
+        stats.errors,
+        stats.bytes_out,
+        stats.bytes_in,
+        stats.rate_out,
+        stats.rate_in,
+        stats.circuit_open,
+        !stats.unhealthy,
+        stats.draining
+    );
+    out.pop();
+    if let (Some(rtt), Some(jitter)) = (stats.rtt_ms, stats.jitter_ms) {
+        let _ = write!(out, ",\"rtt_ms\":{:.1},\"jitter_ms\":{:.1}", rtt, jitter);
+    }
+    out.push('}');
+    out
+}
+
+fn error(message: &str) -> String {
+    format!("{{\"error\":{}}}", json_string(message))
+}
+
+/// A JSON string literal
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if u32::from(c) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", u32::from(c));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Value of the Content-Length header, or zero without one
+fn content_length(head: &[u8]) -> usize {
+    String::from_utf8_lossy(head)
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0)
+}
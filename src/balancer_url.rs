@@ -0,0 +1,81 @@
+//! Balancer list fetched from an HTTP endpoint at startup
+//!
+//! The endpoint returns the same specifications accepted on the command line
+//! (`host:port@ratio`, `IP@ratio@ttl=64`, ...), either one per line or as a
+//! JSON array of strings, so a control plane can manage the upstreams.
+
+use crate::http::{self, HttpUrl};
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Time allowed for connecting to the endpoint and reading the list
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest balancer list accepted
+const MAX_RESPONSE: u64 = 1024 * 1024;
+
+/// Fetch the balancer specifications served at `url`
+pub async fn fetch(url: &str) -> Result<Vec<String>> {
+    let parsed = HttpUrl::parse(url)?;
+    let request = async {
+        let mut stream = TcpStream::connect(&parsed.authority).await?;
+        http::get(&mut stream, &parsed, MAX_RESPONSE).await
+    };
+    let body = tokio::time::timeout(FETCH_TIMEOUT, request)
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out after {:?}", FETCH_TIMEOUT))
+        .and_then(|body| body)
+        .with_context(|| format!("Could not fetch balancers from {}", url))?;
+
+    match body.strip_prefix('[') {
+        Some(array) => parse_json_array(array).with_context(|| format!("Invalid balancer list from {}", url)),
+        None => Ok(body
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect()),
+    }
+}
+
+/// Parse the rest of a JSON array of strings after its opening bracket
+fn parse_json_array(array: &str) -> Result<Vec<String>> {
+    let mut specs = Vec::new();
+    let mut chars = array.trim_start().chars().peekable();
+
+    if chars.peek() == Some(&']') {
+        chars.next();
+    } else {
+        loop {
+            if chars.next() != Some('"') {
+                bail!("Expected a string");
+            }
+            let mut spec = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c @ ('"' | '\\' | '/')) => spec.push(c),
+                        _ => bail!("Unsupported escape in {:?}", spec),
+                    },
+                    Some(c) => spec.push(c),
+                    None => bail!("Unterminated string"),
+                }
+            }
+            specs.push(spec);
+
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            match chars.next() {
+                Some(',') => while chars.next_if(|c| c.is_whitespace()).is_some() {},
+                Some(']') => break,
+                _ => bail!("Expected , or ]"),
+            }
+        }
+    }
+
+    if chars.any(|c| !c.is_whitespace()) {
+        bail!("Unexpected data after the array");
+    }
+    Ok(specs)
+}
@@ -0,0 +1,223 @@
+//! Load balancer definitions read from a config file
+//!
+//! The file is a small subset of TOML: one `[[balancer]]` table per balancer
+//! with string, integer and boolean values. Each table is turned into the
+//! same specification accepted on the command line, so every per-balancer
+//! option is available and parsed the same way:
+//!
+//! ```toml
+//! [[balancer]]
+//! address = "eth0"
+//! ratio = 3
+//!
+//! [[balancer]]
+//! address = "192.168.8.100"
+//! rate = "5mb"
+//! metered = true
+//! label = "lte"
+//! ```
+//!
+//! A `strategy` key before the first table picks the `--strategy` to use
+//! instead of the one on the command line, so a reload can switch it.
+
+use crate::load_balancer::Strategy;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+
+/// Keys holding the address and contention ratio; every other key is a
+/// per-balancer option
+const ADDRESS: &str = "address";
+const RATIO: &str = "ratio";
+
+/// Top-level key selecting the strategy
+const STRATEGY: &str = "strategy";
+
+/// Contents of a config file
+pub struct Config {
+    /// Strategy overriding `--strategy`
+    pub strategy: Option<Strategy>,
+    /// Specification of each balancer
+    pub balancers: Vec<String>,
+}
+
+#[derive(Debug)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Bool(bool),
+}
+
+/// Read the balancer specifications and strategy defined in the file at `path`
+pub fn load(path: &str) -> Result<Config> {
+    let contents = std::fs::read_to_string(path).with_context(|| format!("Could not read config {}", path))?;
+    parse(&contents).with_context(|| format!("Invalid config {}", path))
+}
+
+fn parse(contents: &str) -> Result<Config> {
+    // Each entry with the line its table starts on, for error messages
+    let mut entries: Vec<(usize, Vec<(String, Value)>)> = Vec::new();
+    let mut strategy = None;
+
+    for (number, line) in contents.lines().enumerate() {
+        let number = number + 1;
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            if line != "[[balancer]]" {
+                bail!("Line {}: unknown table {}, expected [[balancer]]", number, line);
+            }
+            entries.push((number, Vec::new()));
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("Line {}: expected key = value", number);
+        };
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            bail!("Line {}: invalid key {:?}", number, key);
+        }
+        let value = parse_value(value.trim()).with_context(|| format!("Line {}: invalid value for {}", number, key))?;
+
+        let Some((_, entry)) = entries.last_mut() else {
+            match value {
+                Value::String(s) if key == STRATEGY && strategy.is_none() => {
+                    let parsed = Strategy::from_str(&s, false)
+                        .map_err(|_| anyhow::anyhow!("Line {}: unknown strategy {:?}", number, s))?;
+                    strategy = Some(parsed);
+                    continue;
+                }
+                _ if key == STRATEGY => bail!("Line {}: invalid {}", number, key),
+                _ => bail!("Line {}: {} is outside a [[balancer]] table", number, key),
+            }
+        };
+        if entry.iter().any(|(k, _)| k == key) {
+            bail!("Line {}: {} is set twice", number, key);
+        }
+        entry.push((key.to_string(), value));
+    }
+
+    let balancers = entries
+        .iter()
+        .enumerate()
+        .map(|(idx, (line, entry))| {
+            to_spec(entry).with_context(|| format!("Balancer {} (line {})", idx + 1, line))
+        })
+        .collect::<Result<_>>()?;
+    Ok(Config { strategy, balancers })
+}
+
+/// Command-line specification (`address@ratio@key=value...`) for one entry
+fn to_spec(entry: &[(String, Value)]) -> Result<String> {
+    let mut address = None;
+    let mut ratio = 1;
+    let mut options = Vec::new();
+
+    for (key, value) in entry {
+        match (key.as_str(), value) {
+            (ADDRESS, Value::String(s)) => address = Some(s.as_str()),
+            (RATIO, Value::Integer(n)) if *n >= 1 && *n <= i64::from(u32::MAX) => ratio = *n,
+            (ADDRESS | RATIO, _) => bail!("Invalid {}", key),
+            (_, Value::Bool(true)) => options.push(key.clone()),
+            (_, Value::Bool(false)) => {}
+            (_, Value::String(s)) => options.push(format!("{}={}", key, s)),
+            (_, Value::Integer(n)) => options.push(format!("{}={}", key, n)),
+        }
+    }
+
+    let Some(address) = address else {
+        bail!("Missing {}", ADDRESS);
+    };
+    if address.is_empty() || address.contains('@') {
+        bail!("Invalid {} {:?}", ADDRESS, address);
+    }
+    if let Some(option) = options.iter().find(|o| o.contains('@')) {
+        bail!("Invalid value in {}", option);
+    }
+
+    let mut spec = format!("{}@{}", address, ratio);
+    for option in options {
+        spec.push('@');
+        spec.push_str(&option);
+    }
+    Ok(spec)
+}
+
+fn parse_value(value: &str) -> Result<Value> {
+    if let Some(quoted) = value.strip_prefix('"') {
+        let mut string = String::new();
+        let mut chars = quoted.chars();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => match chars.next() {
+                    Some(c @ ('"' | '\\')) => string.push(c),
+                    _ => bail!("Unsupported escape"),
+                },
+                Some(c) => string.push(c),
+                None => bail!("Unterminated string"),
+            }
+        }
+        if !chars.as_str().trim().is_empty() {
+            bail!("Unexpected text after the string");
+        }
+        return Ok(Value::String(string));
+    }
+
+    match value {
+        "true" => Ok(Value::Bool(true)),
+        "false" => Ok(Value::Bool(false)),
+        _ => value
+            .replace('_', "")
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| anyhow::anyhow!("Expected a string, integer or boolean")),
+    }
+}
+
+/// The line up to a `#` that is not inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_strategy_before_the_tables() {
+        let config = parse("strategy = \"least-conn\"\n\n[[balancer]]\naddress = \"eth0\"\nratio = 2\n").unwrap();
+        assert_eq!(config.strategy, Some(Strategy::LeastConn));
+        assert_eq!(config.balancers, ["eth0@2"]);
+
+        let config = parse("[[balancer]]\naddress = \"eth0\"\n").unwrap();
+        assert_eq!(config.strategy, None);
+    }
+
+    #[test]
+    fn passes_schedules_on_as_options() {
+        let config = parse("[[balancer]]\naddress = \"eth0\"\nschedule = \"22:00-06:00=5,06:00-09:00=2\" # cheap at night\n").unwrap();
+        assert_eq!(config.balancers, ["eth0@1@schedule=22:00-06:00=5,06:00-09:00=2"]);
+    }
+
+    #[test]
+    fn rejects_invalid_strategies() {
+        assert!(parse("strategy = \"fastest\"\n").is_err());
+        assert!(parse("strategy = 1\n").is_err());
+        assert!(parse("strategy = \"random\"\nstrategy = \"random\"\n").is_err());
+    }
+}
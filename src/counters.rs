@@ -0,0 +1,383 @@
+//! Per-balancer traffic counters, their rolling throughput rates, their
+//! periodic dump to a file, the periodic throughput report in the log, the
+//! table printed on SIGUSR1 and the reset on SIGUSR2
+//!
+//! The dump is a flat `key=value` file rewritten in place, simple enough to
+//! bridge into SNMP or any other monitoring system with a small script.
+
+use crate::load_balancer::{LoadBalancerPool, SharedPool};
+use crate::metrics::{Histogram, CONNECT_TIME_BOUNDS_US, TRANSFER_SIZE_BOUNDS};
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// How often the rolling rates are updated from the byte totals
+const RATE_TICK: Duration = Duration::from_secs(1);
+
+/// Time constant the rolling rates decay over: a change in throughput shows
+/// up about two-thirds of the way after this long
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Running totals for one balancer since startup
+pub struct BalancerCounters {
+    pub connections: AtomicU64,
+    pub errors: AtomicU64,
+    /// Bytes sent to targets
+    pub octets_out: AtomicU64,
+    /// Bytes received from targets
+    pub octets_in: AtomicU64,
+    /// Microseconds each successful connect took
+    pub connect_time: Histogram,
+    /// Bytes each relay moved in both directions
+    pub transfer_size: Histogram,
+    /// `octets_out` and `octets_in` at the previous rate tick
+    ticked: [AtomicU64; 2],
+    /// Rolling bytes per second out and in, as f64 bits
+    rates: [AtomicU64; 2],
+}
+
+impl Default for BalancerCounters {
+    fn default() -> Self {
+        Self {
+            connections: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            octets_out: AtomicU64::new(0),
+            octets_in: AtomicU64::new(0),
+            connect_time: Histogram::new(CONNECT_TIME_BOUNDS_US),
+            transfer_size: Histogram::new(TRANSFER_SIZE_BOUNDS),
+            ticked: Default::default(),
+            rates: Default::default(),
+        }
+    }
+}
+
+/// Point-in-time view of one balancer's counters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BalancerStats {
+    /// Successful connects since startup
+    pub connections: u64,
+    /// Connections currently selected or relaying
+    pub active: u32,
+    /// Failed connects since startup
+    pub errors: u64,
+    /// Bytes sent to targets
+    pub bytes_out: u64,
+    /// Bytes received from targets
+    pub bytes_in: u64,
+    /// Skipped by the circuit breaker after consecutive failures
+    pub circuit_open: bool,
+    /// Skipped after failing its latest health check
+    pub unhealthy: bool,
+    /// Taken out of rotation through the admin API
+    pub draining: bool,
+    /// Average probed round-trip time, once measured
+    pub rtt_ms: Option<f64>,
+    /// Average deviation of probes from `rtt_ms`, once measured
+    pub jitter_ms: Option<f64>,
+    /// Rolling bytes per second sent to targets
+    pub rate_out: f64,
+    /// Rolling bytes per second received from targets
+    pub rate_in: f64,
+}
+
+impl BalancerCounters {
+    pub fn snapshot(&self, active: u32) -> BalancerStats {
+        BalancerStats {
+            connections: self.connections.load(Ordering::Relaxed),
+            active,
+            errors: self.errors.load(Ordering::Relaxed),
+            bytes_out: self.octets_out.load(Ordering::Relaxed),
+            bytes_in: self.octets_in.load(Ordering::Relaxed),
+            circuit_open: false,
+            unhealthy: false,
+            draining: false,
+            rtt_ms: None,
+            jitter_ms: None,
+            rate_out: f64::from_bits(self.rates[0].load(Ordering::Relaxed)),
+            rate_in: f64::from_bits(self.rates[1].load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Fold the bytes counted in the `elapsed` seconds since the previous tick
+    /// into the rolling rates. Unlike per-connection totals, the rates do not
+    /// depend on how long the connections carrying the bytes lived.
+    pub fn tick(&self, elapsed: f64) {
+        let weight = 1.0 - (-elapsed / RATE_WINDOW.as_secs_f64()).exp();
+        for ((total, ticked), rate) in [&self.octets_out, &self.octets_in].into_iter().zip(&self.ticked).zip(&self.rates) {
+            let total = total.load(Ordering::Relaxed);
+            // A reset in between zeroes the previous total as well
+            let bytes = total.saturating_sub(ticked.swap(total, Ordering::Relaxed));
+            let previous = f64::from_bits(rate.load(Ordering::Relaxed));
+            let current = previous + weight * (bytes as f64 / elapsed - previous);
+            rate.store(current.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// Zero the counters and histograms, returning the totals they had
+    pub fn reset(&self, active: u32) -> BalancerStats {
+        self.connect_time.reset();
+        self.transfer_size.reset();
+        for ticked in &self.ticked {
+            ticked.store(0, Ordering::Relaxed);
+        }
+        BalancerStats {
+            connections: self.connections.swap(0, Ordering::Relaxed),
+            errors: self.errors.swap(0, Ordering::Relaxed),
+            bytes_out: self.octets_out.swap(0, Ordering::Relaxed),
+            bytes_in: self.octets_in.swap(0, Ordering::Relaxed),
+            ..self.snapshot(active)
+        }
+    }
+}
+
+/// Address a balancer is reported under: its source IP, or its tunnel,
+/// upstream or blackhole address
+fn display_address(pool: &LoadBalancerPool, idx: usize) -> String {
+    pool.balancer(idx)
+        .map(|lb| lb.source_ip().map_or_else(|| lb.address.clone(), |ip| ip.to_string()))
+        .unwrap_or_default()
+}
+
+/// Write every balancer's counters to `path`
+pub fn dump(pool: &LoadBalancerPool, path: &Path) -> Result<()> {
+    let mut contents = String::new();
+    for (idx, stats) in pool.stats().iter().enumerate() {
+        if pool.balancer(idx).is_none() {
+            continue;
+        }
+        let n = idx + 1;
+        let address = display_address(pool, idx);
+        let _ = writeln!(contents, "lb.{}.address={}", n, address);
+        let _ = writeln!(contents, "lb.{}.connections={}", n, stats.connections);
+        let _ = writeln!(contents, "lb.{}.active={}", n, stats.active);
+        let _ = writeln!(contents, "lb.{}.errors={}", n, stats.errors);
+        let _ = writeln!(contents, "lb.{}.octets_out={}", n, stats.bytes_out);
+        let _ = writeln!(contents, "lb.{}.octets_in={}", n, stats.bytes_in);
+        let _ = writeln!(contents, "lb.{}.rate_out={:.0}", n, stats.rate_out);
+        let _ = writeln!(contents, "lb.{}.rate_in={:.0}", n, stats.rate_in);
+        let _ = writeln!(contents, "lb.{}.circuit={}", n, if stats.circuit_open { "open" } else { "closed" });
+        let _ = writeln!(contents, "lb.{}.health={}", n, if stats.unhealthy { "failing" } else { "ok" });
+    }
+
+    // Write then rename so readers never see a partial file
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents)
+        .and_then(|_| std::fs::rename(&tmp, path))
+        .with_context(|| format!("Could not write counters to {}", path.display()))
+}
+
+/// Rewrite the counters file every `interval` with the pool in use at the time
+pub fn spawn_dump(shared: Arc<SharedPool>, path: String, interval: Duration) {
+    let path = PathBuf::from(path);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = dump(&shared.current(), &path) {
+                warn!("{:#}", e);
+            }
+        }
+    });
+}
+
+/// Update the rolling rates of the pool in use every second. Pools before a
+/// reload share the counters of the balancers they kept, so those carry on.
+pub fn spawn_rates(shared: Arc<SharedPool>) {
+    tokio::spawn(async move {
+        let mut last = Instant::now();
+        let mut interval = tokio::time::interval_at(last + RATE_TICK, RATE_TICK);
+        loop {
+            interval.tick().await;
+            let elapsed = last.elapsed().as_secs_f64().max(0.001);
+            last = Instant::now();
+            let pool = shared.current();
+            for idx in 0..pool.len() {
+                if let Some(counters) = pool.counters(idx) {
+                    counters.tick(elapsed);
+                }
+            }
+        }
+    });
+}
+
+/// Log a line with each balancer's active connections, throughput since the
+/// previous report and total bytes every `interval`. Bytes are counted when a
+/// relay ends, so a long transfer shows up in the report after it finishes.
+pub fn spawn_report(shared: Arc<SharedPool>, interval: Duration) {
+    tokio::spawn(async move {
+        let snapshot = |pool: &LoadBalancerPool| -> Vec<(String, BalancerStats)> {
+            pool.stats()
+                .into_iter()
+                .enumerate()
+                .map(|(idx, stats)| (display_address(pool, idx), stats))
+                .collect()
+        };
+        let mut previous = snapshot(&shared.current());
+        let mut last = Instant::now();
+        let mut interval = tokio::time::interval_at(last + interval, interval);
+        loop {
+            interval.tick().await;
+            let current = snapshot(&shared.current());
+            let elapsed = last.elapsed().as_secs_f64().max(0.001);
+            last = Instant::now();
+
+            // Balancers are matched by address so a reload that reorders them
+            // still compares each with its own previous totals
+            let parts: Vec<String> = current
+                .iter()
+                .enumerate()
+                .map(|(idx, (address, stats))| {
+                    let before = previous.iter().find(|(a, _)| a == address).map(|(_, s)| *s).unwrap_or_default();
+                    let rate = |now: u64, then: u64| now.saturating_sub(then) as f64 / 1000.0 / elapsed;
+                    format!(
+                        "{} {}: {} active, {:.1}/{:.1} KB/s up/down, {} total",
+                        idx + 1,
+                        address,
+                        stats.active,
+                        rate(stats.bytes_out, before.bytes_out),
+                        rate(stats.bytes_in, before.bytes_in),
+                        format_bytes(stats.bytes_out + stats.bytes_in)
+                    )
+                })
+                .collect();
+            info!("Throughput: {}", parts.join(" | "));
+            previous = current;
+        }
+    });
+}
+
+/// The pool's balancers and counters as a table, one row per balancer
+pub fn table(pool: &LoadBalancerPool) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{:>3}  {:<28} {:<10} {:>5} {:>6} {:>11} {:>7} {:>10} {:>10}  state",
+        "#", "balancer", "interface", "ratio", "active", "connections", "errors", "out", "in"
+    );
+    for (idx, stats) in pool.stats().iter().enumerate() {
+        let Some(lb) = pool.balancer(idx) else {
+            continue;
+        };
+        let state = match (stats.draining, stats.circuit_open, stats.unhealthy) {
+            (true, _, _) => "draining",
+            (_, true, _) => "circuit open",
+            (_, _, true) => "failing health",
+            _ => "ok",
+        };
+        let _ = writeln!(
+            out,
+            "{:>3}  {:<28} {:<10} {:>5} {:>6} {:>11} {:>7} {:>10} {:>10}  {}",
+            idx + 1,
+            display_address(pool, idx),
+            lb.iface.as_deref().unwrap_or("-"),
+            lb.contention_ratio,
+            stats.active,
+            stats.connections,
+            stats.errors,
+            format_bytes(stats.bytes_out),
+            format_bytes(stats.bytes_in),
+            state
+        );
+    }
+    out
+}
+
+/// Zero the cumulative counters of the pool's balancers, and of the pools
+/// before a reload that share them, logging each one's totals first. Active
+/// connection counts are not touched.
+pub fn reset(pool: &LoadBalancerPool) {
+    for (idx, totals) in pool.reset_counters().iter().enumerate() {
+        info!(
+            "Reset counters of load balancer {} {}: {} connections, {} errors, {} out, {} in",
+            idx + 1,
+            display_address(pool, idx),
+            totals.connections,
+            totals.errors,
+            format_bytes(totals.bytes_out),
+            format_bytes(totals.bytes_in)
+        );
+    }
+}
+
+/// Reset the counters of the pool in use on every SIGUSR2
+#[cfg(unix)]
+pub fn spawn_reset_signal(shared: Arc<SharedPool>) -> Result<()> {
+    let mut user2 = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2())?;
+    tokio::spawn(async move {
+        while user2.recv().await.is_some() {
+            reset(&shared.current());
+        }
+    });
+    Ok(())
+}
+
+/// Print the table of the pool in use to stderr on every SIGUSR1. It goes
+/// to stderr whatever --quiet says, since it was asked for.
+#[cfg(unix)]
+pub fn spawn_stats_signal(shared: Arc<SharedPool>) -> Result<()> {
+    use std::io::Write as _;
+
+    let mut user1 = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while user1.recv().await.is_some() {
+            let table = table(&shared.current());
+            // Writing a few hundred bytes to stderr is quick enough to do
+            // from the task; the accept loops run on their own tasks
+            let _ = std::io::stderr().lock().write_all(table.as_bytes());
+        }
+    });
+    Ok(())
+}
+
+/// Byte count in decimal units, e.g. `12.3 MB`
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while value >= 1000.0 && unit + 1 < UNITS.len() {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_follow_the_byte_totals() {
+        let counters = BalancerCounters::default();
+        for _ in 0..60 {
+            counters.octets_out.fetch_add(1000, Ordering::Relaxed);
+            counters.octets_in.fetch_add(4000, Ordering::Relaxed);
+            counters.tick(1.0);
+        }
+        let stats = counters.snapshot(0);
+        assert!((stats.rate_out - 1000.0).abs() < 10.0, "{}", stats.rate_out);
+        assert!((stats.rate_in - 4000.0).abs() < 40.0, "{}", stats.rate_in);
+
+        // Bytes all counted at once, as when a long relay ends, are spread
+        // over the window rather than showing up as one spike
+        counters.octets_in.fetch_add(40_000, Ordering::Relaxed);
+        counters.tick(1.0);
+        let rate_in = counters.snapshot(0).rate_in;
+        assert!(rate_in > 4000.0 && rate_in < 10_000.0, "{}", rate_in);
+
+        // Without traffic the rates decay, and a reset does not count as any
+        counters.reset(0);
+        for _ in 0..60 {
+            counters.tick(1.0);
+        }
+        let stats = counters.snapshot(0);
+        assert!(stats.rate_out < 10.0 && stats.rate_in < 50.0, "{} {}", stats.rate_out, stats.rate_in);
+    }
+}
@@ -0,0 +1,155 @@
+//! One-shot diagnostics that exercise the balancers and exit
+
+use crate::dns;
+use crate::http::{self, HttpUrl};
+use crate::load_balancer::LoadBalancer;
+use crate::platform;
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// Timeout for each diagnostic request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest echo response accepted
+const MAX_RESPONSE: u64 = 64 * 1024;
+
+/// Fetch the URL through the balancer and return the response body
+async fn http_get(url: &HttpUrl, lb: &LoadBalancer) -> Result<String> {
+    let mut stream = platform::connect(&url.authority, lb, &Default::default()).await?;
+    http::get(&mut stream, url, MAX_RESPONSE).await
+}
+
+/// Ask an IP echo service which source address each balancer egresses from
+/// and compare it against the balancer's configured source IP.
+/// Returns an error if any balancer failed or egressed from the wrong address.
+pub async fn verify_egress(balancers: &[LoadBalancer], echo_url: &str) -> Result<()> {
+    let url = HttpUrl::parse(echo_url)?;
+    let mut failures = 0;
+    let mut seen: HashMap<IpAddr, usize> = HashMap::new();
+
+    println!("--- Verifying egress via {}", echo_url);
+
+    // Numbered from 1 as in the startup listing
+    for (idx, lb) in (1..).zip(balancers) {
+        let label = match &lb.iface {
+            Some(iface) => format!("{} ({})", lb.address, iface),
+            None => lb.address.clone(),
+        };
+
+        let body = match tokio::time::timeout(REQUEST_TIMEOUT, http_get(&url, lb)).await {
+            Ok(Ok(body)) => body,
+            Ok(Err(e)) => {
+                println!("[!] LB {}: {}: request failed: {}", idx, label, e);
+                failures += 1;
+                continue;
+            }
+            Err(_) => {
+                println!("[!] LB {}: {}: request timed out", idx, label);
+                failures += 1;
+                continue;
+            }
+        };
+
+        let Ok(observed) = body.parse::<IpAddr>() else {
+            println!("[!] LB {}: {}: echo service returned no IP: {:?}", idx, label, body);
+            failures += 1;
+            continue;
+        };
+
+        if let Some(previous) = seen.insert(observed, idx) {
+            println!(
+                "[!] LB {}: {}: egressed from {}, same as LB {} (binding may not have taken effect)",
+                idx, label, observed, previous
+            );
+            failures += 1;
+            continue;
+        }
+
+        match lb.source_ip() {
+            Some(source) if platform::is_global(&source) && source != observed => {
+                println!(
+                    "[!] LB {}: {}: egressed from {}, expected {}",
+                    idx, label, observed, source
+                );
+                failures += 1;
+            }
+            Some(source) if !platform::is_global(&source) => {
+                println!("[+] LB {}: {}: egressed from {} (behind NAT)", idx, label, observed);
+            }
+            _ => println!("[+] LB {}: {}: egressed from {}", idx, label, observed),
+        }
+    }
+
+    if failures > 0 {
+        bail!("{} of {} load balancers failed egress verification", failures, balancers.len());
+    }
+    Ok(())
+}
+
+/// Ask the resolver-echo name through each balancer's egress and report which
+/// recursive resolver answered, next to the one the proxy's own lookups use
+/// (those resolve SOCKS domain targets). Queries go over TCP to `server`, or to
+/// the first non-loopback nameserver in /etc/resolv.conf.
+/// Returns an error if any balancer's query failed.
+pub async fn dns_leak_test(balancers: &[LoadBalancer], query_name: &str, server: Option<IpAddr>) -> Result<()> {
+    let server = match server.or_else(dns::system_nameserver) {
+        Some(server) => server,
+        None => bail!("No usable nameserver in /etc/resolv.conf, use --dns-leak-server"),
+    };
+    let mut failures = 0;
+    let mut seen_via_balancers = Vec::new();
+
+    println!("--- Checking DNS egress for {} via {}", query_name, server);
+
+    let system = match tokio::time::timeout(REQUEST_TIMEOUT, tokio::net::lookup_host((query_name, 0))).await {
+        Ok(Ok(mut addrs)) => addrs.next().map(|a| a.ip()),
+        _ => None,
+    };
+    match system {
+        Some(resolver) => println!("[+] System lookups: answered by resolver {}", resolver),
+        None => println!("[!] System lookups: no answer for {}", query_name),
+    }
+
+    // Numbered from 1 as in the startup listing
+    for (idx, lb) in (1..).zip(balancers) {
+        let label = match &lb.iface {
+            Some(iface) => format!("{} ({})", lb.address, iface),
+            None => lb.address.clone(),
+        };
+
+        let query = dns::query_tcp(SocketAddr::new(server, 53), query_name, dns::TYPE_A, lb);
+        match tokio::time::timeout(REQUEST_TIMEOUT, query).await {
+            Ok(Ok(answer)) if answer.is_empty() => {
+                println!("[!] LB {}: {}: no address in the answer", idx, label);
+                failures += 1;
+            }
+            Ok(Ok(answer)) => {
+                let resolver = answer[0];
+                println!("[+] LB {}: {}: answered by resolver {}", idx, label, resolver);
+                seen_via_balancers.push(resolver);
+            }
+            Ok(Err(e)) => {
+                println!("[!] LB {}: {}: query failed: {}", idx, label, e);
+                failures += 1;
+            }
+            Err(_) => {
+                println!("[!] LB {}: {}: query timed out", idx, label);
+                failures += 1;
+            }
+        }
+    }
+
+    if let Some(resolver) = system.filter(|r| !seen_via_balancers.is_empty() && !seen_via_balancers.contains(r)) {
+        println!(
+            "[!] System lookups use resolver {}, which no balancer reached (DNS may leave over another uplink)",
+            resolver
+        );
+    }
+
+    if failures > 0 {
+        bail!("{} of {} load balancers failed the DNS check", failures, balancers.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,196 @@
+//! DNS lookups sent through a load balancer
+//!
+//! The system resolver sends its queries out of the default route whatever
+//! balancer the connection then leaves through, so they can go over another
+//! uplink and be answered for that uplink's address (geo-split records, CDN
+//! steering). With `--balancer-dns`, domain targets are instead resolved with
+//! queries sent from the selected balancer's source address and interface:
+//! over UDP, falling back to TCP for truncated answers, to the `--dns` server
+//! or the first non-loopback nameserver in /etc/resolv.conf.
+
+use crate::load_balancer::{IpFamily, LoadBalancer};
+use crate::platform;
+use anyhow::{bail, Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
+
+/// Time allowed for each query and its answer
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Largest UDP answer read; without EDNS servers send at most 512 bytes
+const MAX_UDP_RESPONSE: usize = 4096;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+
+/// First usable nameserver from /etc/resolv.conf. Loopback stub resolvers are
+/// skipped since they cannot be reached through a balancer.
+pub fn system_nameserver() -> Option<IpAddr> {
+    let resolv = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    resolv
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|ns| ns.trim().parse::<IpAddr>().ok())
+        .find(|ns| !ns.is_loopback())
+}
+
+/// Resolve `host` to its A and AAAA addresses, or only those of `family`, by
+/// asking `server` through the balancer. Fails only when no address was found.
+pub async fn resolve(host: &str, port: u16, lb: &LoadBalancer, server: SocketAddr, family: Option<IpFamily>) -> Result<Vec<SocketAddr>> {
+    let lookup = |qtype: u16, ipv6: bool| async move {
+        if family.is_none_or(|f| f.matches(ipv6)) {
+            Some(query(server, host, qtype, lb).await)
+        } else {
+            None
+        }
+    };
+    let (v4, v6) = tokio::join!(lookup(TYPE_A, false), lookup(TYPE_AAAA, true));
+
+    let mut addresses = Vec::new();
+    let mut last_error = None;
+    for result in [v4, v6].into_iter().flatten() {
+        match result {
+            Ok(ips) => addresses.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, port))),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    if addresses.is_empty() {
+        let e = last_error.unwrap_or_else(|| anyhow::anyhow!("No address found"));
+        return Err(e.context(format!("Could not resolve {} via {} through {}", host, server, lb.address)));
+    }
+    debug!("Resolved {} via {} through {}: {} addresses", host, server, lb.address, addresses.len());
+    Ok(addresses)
+}
+
+/// Ask for the records of `qtype`, over UDP when the balancer has a socket of
+/// the server's family and over TCP otherwise or when the answer is truncated
+async fn query(server: SocketAddr, name: &str, qtype: u16, lb: &LoadBalancer) -> Result<Vec<IpAddr>> {
+    if server.is_ipv6() == lb.is_ipv6 {
+        let id: u16 = rand::random();
+        let request = build_query(id, name, qtype)?;
+        let response = tokio::time::timeout(QUERY_TIMEOUT, exchange_udp(server, &request, lb))
+            .await
+            .map_err(|_| anyhow::anyhow!("DNS query to {} timed out", server))??;
+        // TC flag: the answer did not fit in the datagram
+        if response[2] & 0x02 == 0 {
+            return parse_answers(&response, id, qtype);
+        }
+        debug!("Truncated answer for {} from {}, asking over TCP", name, server);
+    }
+    query_tcp(server, name, qtype, lb).await
+}
+
+/// Ask for the records of `qtype` over DNS-over-TCP through the balancer
+pub async fn query_tcp(server: SocketAddr, name: &str, qtype: u16, lb: &LoadBalancer) -> Result<Vec<IpAddr>> {
+    let id: u16 = rand::random();
+    let request = build_query(id, name, qtype)?;
+    let response = tokio::time::timeout(QUERY_TIMEOUT, exchange_tcp(server, &request, lb))
+        .await
+        .map_err(|_| anyhow::anyhow!("DNS query to {} timed out", server))??;
+    parse_answers(&response, id, qtype)
+}
+
+async fn exchange_udp(server: SocketAddr, request: &[u8], lb: &LoadBalancer) -> Result<Vec<u8>> {
+    let socket = platform::bind_udp(lb, lb.is_ipv6)?;
+    socket.connect(server).await?;
+    socket.send(request).await?;
+
+    let mut response = vec![0u8; MAX_UDP_RESPONSE];
+    loop {
+        let n = socket.recv(&mut response).await?;
+        // Anything else arriving on the port is not our answer
+        if n >= 12 && response[..2] == request[..2] {
+            response.truncate(n);
+            return Ok(response);
+        }
+    }
+}
+
+async fn exchange_tcp(server: SocketAddr, request: &[u8], lb: &LoadBalancer) -> Result<Vec<u8>> {
+    let mut stream = platform::connect(&server.to_string(), lb, &Default::default()).await?;
+    stream.write_all(&(request.len() as u16).to_be_bytes()).await?;
+    stream.write_all(request).await?;
+
+    let len = stream.read_u16().await? as usize;
+    let mut response = vec![0u8; len];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+/// A recursive query for the records of `qtype` of `name`
+fn build_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    // Header: id, flags (recursion desired), 1 question
+    let mut query = Vec::with_capacity(64);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("Invalid query name {}", name);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    // Root label, QTYPE, QCLASS IN
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&[0, 1]);
+    Ok(query)
+}
+
+/// Addresses in the answer records of `qtype`, skipping CNAMEs and the like.
+/// A name without such records gives an empty list.
+fn parse_answers(response: &[u8], id: u16, qtype: u16) -> Result<Vec<IpAddr>> {
+    let truncated = || anyhow::anyhow!("Truncated DNS response");
+
+    if response.len() < 12 || response[..2] != id.to_be_bytes() {
+        bail!("Malformed DNS response");
+    }
+    match response[3] & 0x0f {
+        0 => {}
+        3 => bail!("No such domain"),
+        rcode => bail!("DNS error code {}", rcode),
+    }
+    let questions = u16::from_be_bytes([response[4], response[5]]);
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(response, pos).ok_or_else(truncated)? + 4;
+    }
+
+    let mut addresses = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(response, pos).ok_or_else(truncated)?;
+        let record = response.get(pos..pos + 10).ok_or_else(truncated)?;
+        let rtype = u16::from_be_bytes([record[0], record[1]]);
+        let rdlen = u16::from_be_bytes([record[8], record[9]]) as usize;
+        let rdata = response.get(pos + 10..pos + 10 + rdlen).ok_or_else(truncated)?;
+        pos += 10 + rdlen;
+
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) if qtype == TYPE_A => {
+                addresses.push(IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])));
+            }
+            (TYPE_AAAA, 16) if qtype == TYPE_AAAA => {
+                let octets: [u8; 16] = rdata.try_into().context("AAAA record")?;
+                addresses.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            _ => {}
+        }
+    }
+    Ok(addresses)
+}
+
+/// Offset just past the (possibly compressed) name starting at `pos`
+fn skip_name(data: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *data.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            l if l & 0xc0 == 0xc0 => return Some(pos + 2),
+            l => pos += l + 1,
+        }
+    }
+}
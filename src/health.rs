@@ -0,0 +1,178 @@
+//! Periodic health checks of interface balancers
+//!
+//! Every interval, each interface balancer is tested the same way `--auto`
+//! tests interfaces at startup: a TCP connect from its source address to the
+//! probe targets. A balancer that fails is skipped by selection until a later
+//! check passes, so a link that drops mid-session stops taking connections
+//! without a reload and rejoins on its own once it is back.
+//!
+//! With `--on-health-change`, a command is run for every balancer whose state
+//! changed and then held for the debounce time, so a link flapping between
+//! checks does not run it on every round.
+
+use crate::latency;
+use crate::load_balancer::SharedPool;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+/// Command run when a balancer goes down or comes back up
+pub struct Hook {
+    command: String,
+    debounce: Duration,
+    /// Per source address, as balancer indices change on reload
+    states: HashMap<IpAddr, HookState>,
+}
+
+struct HookState {
+    /// State the command was last run for
+    reported: bool,
+    /// State of the latest check, and since when it has held
+    healthy: bool,
+    since: Instant,
+}
+
+impl Hook {
+    pub fn new(command: String, debounce: Duration) -> Self {
+        Hook { command, debounce, states: HashMap::new() }
+    }
+
+    /// Record a check of the balancer and run the command once a change has
+    /// held for the debounce time. Balancers start out healthy.
+    fn record(&mut self, idx: usize, ip: IpAddr, iface: Option<&str>, healthy: bool) {
+        let now = Instant::now();
+        let state = self.states.entry(ip).or_insert(HookState { reported: true, healthy: true, since: now });
+        if healthy != state.healthy {
+            state.healthy = healthy;
+            state.since = now;
+        }
+        if state.healthy == state.reported || now.duration_since(state.since) < self.debounce {
+            return;
+        }
+        state.reported = state.healthy;
+        self.run(idx, ip, iface, healthy);
+    }
+
+    /// Start the command with the address and `up` or `down` appended as
+    /// arguments, without waiting for it
+    fn run(&self, idx: usize, ip: IpAddr, iface: Option<&str>, healthy: bool) {
+        let state = if healthy { "up" } else { "down" };
+        let mut command = shell(&self.command);
+        command.arg(ip.to_string()).arg(state).env("DISPATCH_BALANCER", (idx + 1).to_string());
+        if let Some(iface) = iface {
+            command.env("DISPATCH_INTERFACE", iface);
+        }
+        info!("Running --on-health-change for load balancer {} ({}): {}", idx + 1, ip, state);
+        let hook = self.command.clone();
+        match command.spawn() {
+            Ok(mut child) => {
+                tokio::spawn(async move {
+                    match child.wait().await {
+                        Ok(status) if status.success() => debug!("{} exited successfully", hook),
+                        Ok(status) => warn!("{} exited with {}", hook, status),
+                        Err(e) => warn!("Could not wait for {}: {}", hook, e),
+                    }
+                });
+            }
+            Err(e) => warn!("Could not run {}: {}", hook, e),
+        }
+    }
+}
+
+/// The command line run by the shell, with the arguments added to it
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(format!("{} \"$@\"", command)).arg("sh");
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Whether connections from `ip` get through: any of the targets of its
+/// family accepts one, or Cloudflare DNS does when none is of its family
+pub async fn check(ip: IpAddr, targets: &[SocketAddr]) -> bool {
+    let targets: Vec<SocketAddr> = targets.iter().copied().filter(|t| t.is_ipv6() == ip.is_ipv6()).collect();
+    if targets.is_empty() {
+        return latency::probe(ip).await.is_some();
+    }
+
+    let mut probes = JoinSet::new();
+    for target in targets {
+        probes.spawn(latency::probe_target(ip, target));
+    }
+    while let Some(result) = probes.join_next().await {
+        if matches!(result, Ok(Some(_))) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Check every interface balancer in the pool every `interval`. Tunnel,
+/// upstream and blackhole balancers have no source address to check from and
+/// always count as healthy. A reloaded balancer set starts out healthy and is
+/// checked from the next round. At most `concurrency` balancers are probed at
+/// once, so checking many of them does not load the links all together. With
+/// `min_healthy`, a round leaving fewer healthy balancers than that is logged.
+pub fn spawn(
+    shared: Arc<SharedPool>,
+    interval: Duration,
+    targets: Vec<SocketAddr>,
+    concurrency: usize,
+    min_healthy: Option<usize>,
+    mut hook: Option<Hook>,
+) {
+    let targets = Arc::new(targets);
+    let probes = Arc::new(Semaphore::new(concurrency));
+    tokio::spawn(async move {
+        let mut ready = true;
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let pool = shared.current();
+            let mut checks = JoinSet::new();
+            for idx in 0..pool.len() {
+                if let Some(ip) = pool.balancer(idx).and_then(|lb| lb.source_ip()) {
+                    let targets = Arc::clone(&targets);
+                    let probes = Arc::clone(&probes);
+                    checks.spawn(async move {
+                        // The semaphore is never closed
+                        let _permit = probes.acquire_owned().await.ok();
+                        (idx, check(ip, &targets).await)
+                    });
+                }
+            }
+            while let Some(result) = checks.join_next().await {
+                if let Ok((idx, healthy)) = result {
+                    pool.record_health(idx, healthy);
+                    if let (Some(hook), Some(lb)) = (hook.as_mut(), pool.balancer(idx)) {
+                        if let Some(ip) = lb.source_ip() {
+                            hook.record(idx, ip, lb.iface.as_deref(), healthy);
+                        }
+                    }
+                }
+            }
+            if let Some(min) = min_healthy {
+                let healthy = pool.healthy_count();
+                match (ready, healthy >= min) {
+                    (true, false) => warn!("Only {} of the {} required load balancers are healthy", healthy, min),
+                    (false, true) => info!("{} load balancers are healthy again, at least the {} required", healthy, min),
+                    _ => {}
+                }
+                ready = healthy >= min;
+            }
+        }
+    });
+}
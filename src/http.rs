@@ -0,0 +1,63 @@
+//! Minimal HTTP/1.0 GET client for plain `http://` URLs
+
+use anyhow::{bail, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// A plain `http://host[:port]/path` URL
+pub struct HttpUrl {
+    pub host: String,
+    /// `host:port`, with the port defaulting to 80
+    pub authority: String,
+    pub path: String,
+}
+
+impl HttpUrl {
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow::anyhow!("Only http:// URLs are supported: {}", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            bail!("Invalid URL {}", url);
+        }
+
+        // Default to port 80 unless the authority ends in `:port` (outside any IPv6 brackets)
+        let (host, authority) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host.to_string(), authority.to_string()),
+            _ => (authority.to_string(), format!("{}:80", authority)),
+        };
+
+        Ok(Self {
+            host,
+            authority,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Send a GET for the URL over an established connection and return the
+/// trimmed body of a 200 response, reading at most `max_response` bytes
+pub async fn get<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, url: &HttpUrl, max_response: u64) -> Result<String> {
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: dispatch-proxy\r\nConnection: close\r\n\r\n",
+        url.path, url.host
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.take(max_response).read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow::anyhow!("Malformed HTTP response"))?;
+    let status_line = head.lines().next().unwrap_or_default();
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        bail!("Unexpected response: {}", status_line);
+    }
+
+    Ok(body.trim().to_string())
+}
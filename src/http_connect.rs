@@ -0,0 +1,246 @@
+//! HTTP CONNECT proxy handshake, the HTTP counterpart of the SOCKS5 handshake
+//!
+//! Only `CONNECT host:port` is served: the request yields the same target
+//! string and type as a SOCKS request so the connect path is shared, and the
+//! outcome is reported with an HTTP status line instead of a SOCKS reply.
+//! Other methods get `405 Method Not Allowed`.
+
+use anyhow::{bail, Result};
+use std::net::IpAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::relay::ClientStream;
+use crate::socks::{Credentials, Failure, TargetAddressType};
+use crate::lockout::Lockout;
+use crate::tarpit::Tarpit;
+
+/// Read a CONNECT request, checking `Proxy-Authorization` when credentials are
+/// configured, and return the target address with the username the client
+/// authenticated as. Heads (request line and headers) over `max_head` bytes
+/// are refused. Failed authentication from `client_ip` counts towards its
+/// lockout.
+pub async fn handle_connect_request<S: ClientStream>(
+    conn: &mut S,
+    max_head: usize,
+    credentials: &[Credentials],
+    tarpit: Option<&Tarpit>,
+    lockout: Option<&Lockout>,
+    client_ip: IpAddr,
+) -> Result<(String, TargetAddressType, Option<String>)> {
+    let head = read_head(conn, max_head).await?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(authority), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+        send_status(conn, "400 Bad Request").await?;
+        bail!("Malformed HTTP request line {:?}", request_line);
+    };
+    if !version.starts_with("HTTP/1.") {
+        send_status(conn, "400 Bad Request").await?;
+        bail!("Unsupported HTTP version {}", version);
+    }
+    if method != "CONNECT" {
+        conn.write_all(b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            .await?;
+        bail!("Unsupported HTTP method {}", method);
+    }
+
+    let mut username = None;
+    if !credentials.is_empty() {
+        username = lines
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("proxy-authorization"))
+            .filter_map(|(_, value)| basic_credentials(value.trim()))
+            .find(|(username, password)| credentials.iter().any(|c| c.matches(username, password)))
+            .map(|(username, _)| String::from_utf8_lossy(&username).into_owned());
+        let authorized = username.is_some();
+        if let Some(lockout) = lockout {
+            if authorized {
+                lockout.record_success(client_ip);
+            } else {
+                lockout.record_failure(client_ip);
+            }
+        }
+        if !authorized {
+            if let Some(tarpit) = tarpit {
+                tarpit.hold().await;
+            }
+            conn.write_all(
+                b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"dispatch-proxy\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            )
+            .await?;
+            bail!("HTTP client failed authentication");
+        }
+    }
+
+    match parse_authority(authority) {
+        Some((target, target_type)) => Ok((target, target_type, username)),
+        None => {
+            send_status(conn, "400 Bad Request").await?;
+            bail!("Invalid CONNECT target {:?}", authority);
+        }
+    }
+}
+
+/// Tell the client the tunnel is open
+pub async fn send_established<S: ClientStream>(conn: &mut S) -> Result<()> {
+    conn.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+    Ok(())
+}
+
+/// Report a failed CONNECT with the closest HTTP status
+pub async fn send_failure<S: ClientStream>(conn: &mut S, failure: Failure) -> Result<()> {
+    let status = match failure {
+        Failure::NoBalancer => "503 Service Unavailable",
+        Failure::Refused | Failure::HostUnreachable | Failure::NetworkUnreachable | Failure::ConnectFailed => "502 Bad Gateway",
+        Failure::TimedOut | Failure::Deadline => "504 Gateway Timeout",
+        Failure::NotAllowed | Failure::AddressType => "403 Forbidden",
+    };
+    send_status(conn, status).await
+}
+
+async fn send_status<S: ClientStream>(conn: &mut S, status: &str) -> Result<()> {
+    let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+    conn.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Read up to and including the blank line ending the request head. Reads a
+/// byte at a time so nothing the client sends after the head is consumed.
+async fn read_head<S: ClientStream>(conn: &mut S, max_head: usize) -> Result<String> {
+    let mut head = Vec::with_capacity(256);
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= max_head {
+            send_status(conn, "431 Request Header Fields Too Large").await?;
+            bail!("HTTP request head exceeds {} bytes", max_head);
+        }
+        head.push(conn.read_u8().await?);
+    }
+    head.truncate(head.len() - 4);
+    match String::from_utf8(head) {
+        Ok(head) => Ok(head),
+        Err(_) => {
+            send_status(conn, "400 Bad Request").await?;
+            bail!("HTTP request head is not UTF-8");
+        }
+    }
+}
+
+/// Split a CONNECT authority (`host:port`, `[v6]:port`) into the target string
+/// the connect path expects and its address type
+fn parse_authority(authority: &str) -> Option<(String, TargetAddressType)> {
+    let (host, port) = authority.rsplit_once(':')?;
+    let port: u16 = port.parse().ok()?;
+    if port == 0 {
+        return None;
+    }
+
+    if let Some(v6) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        let ip: std::net::Ipv6Addr = v6.parse().ok()?;
+        return Some(match ip.to_ipv4_mapped() {
+            Some(ip) => (format!("{}:{}", ip, port), TargetAddressType::IPv4),
+            None => (format!("[{}]:{}", ip, port), TargetAddressType::IPv6),
+        });
+    }
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => Some((format!("{}:{}", ip, port), TargetAddressType::IPv4)),
+        // An IPv6 address needs brackets to be told apart from the port
+        Ok(IpAddr::V6(_)) => None,
+        Err(_) if !host.is_empty() && host.len() <= 255 && !host.contains(['/', '@', ' ']) => {
+            Some((format!("{}:{}", host, port), TargetAddressType::Domain))
+        }
+        Err(_) => None,
+    }
+}
+
+/// Username and password from a `Basic` authorization value
+fn basic_credentials(value: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = decode_base64(encoded.trim())?;
+    let colon = decoded.iter().position(|&b| b == b':')?;
+    Some((decoded[..colon].to_vec(), decoded[colon + 1..].to_vec()))
+}
+
+/// Standard base64 with optional padding
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in encoded.trim_end_matches('=').bytes() {
+        bits = (bits << 6) | u32::from(value(c)?);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 9));
+
+    /// The --http-max-head default
+    const MAX_HEAD: usize = 8192;
+
+    /// Feed `request` to the CONNECT handshake as the client, with heads up to
+    /// `max_head` bytes accepted, and return the result with the response
+    async fn connect_request(request: &[u8], max_head: usize) -> (Result<(String, TargetAddressType, Option<String>)>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(request.len() + 1024);
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+        let result = handle_connect_request(&mut server, max_head, &[], None, None, CLIENT).await;
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        (result, response)
+    }
+
+    /// A CONNECT request with a header padded so the head is `len` bytes
+    fn padded_request(len: usize) -> Vec<u8> {
+        let start = "CONNECT example.com:443 HTTP/1.1\r\nX-Padding: ";
+        let padding = len - start.len() - 4;
+        format!("{}{}\r\n\r\n", start, "a".repeat(padding)).into_bytes()
+    }
+
+    #[tokio::test]
+    async fn parses_connect_requests() {
+        let (result, response) = connect_request(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n", MAX_HEAD).await;
+        let (target, target_type, username) = result.unwrap();
+        assert_eq!((target.as_str(), target_type, username), ("example.com:443", TargetAddressType::Domain, None));
+        assert!(response.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_request_heads() {
+        for max_head in [MAX_HEAD, 1024] {
+            let (result, response) = connect_request(&padded_request(max_head), max_head).await;
+            assert!(result.is_ok());
+            assert!(response.is_empty());
+
+            let (result, response) = connect_request(&padded_request(max_head + 1), max_head).await;
+            assert_eq!(result.unwrap_err().to_string(), format!("HTTP request head exceeds {} bytes", max_head));
+            assert!(response.starts_with(b"HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+            // A head that never ends is cut off at the limit too
+            let (result, response) = connect_request(&vec![b'a'; max_head * 2], max_head).await;
+            assert!(result.is_err());
+            assert!(response.starts_with(b"HTTP/1.1 431 "));
+        }
+    }
+}
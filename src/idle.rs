@@ -0,0 +1,95 @@
+//! Idle timeout for relays
+//!
+//! Both sides of a relay are wrapped so that every read marks it active. A
+//! watcher sleeps until the deadline set by the latest read and ends the relay
+//! once a whole timeout passes without one, which reaps abandoned sessions and
+//! streams left half-open by a NAT rebind.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+
+/// Reads on both sides of one relay
+pub struct Activity {
+    epoch: Instant,
+    /// Milliseconds since `epoch` of the latest read on either side
+    last_ms: AtomicU64,
+    /// Bytes read from the client, to be sent to the remote
+    pub up: AtomicU64,
+    /// Bytes read from the remote, to be sent to the client
+    pub down: AtomicU64,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            last_ms: AtomicU64::new(0),
+            up: AtomicU64::new(0),
+            down: AtomicU64::new(0),
+        }
+    }
+
+    /// Bytes read so far from the client and from the remote
+    pub fn bytes(&self) -> (u64, u64) {
+        (self.up.load(Ordering::Relaxed), self.down.load(Ordering::Relaxed))
+    }
+
+    /// Resolve once `timeout` passes without a read on either side
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let deadline = self.epoch + Duration::from_millis(self.last_ms.load(Ordering::Relaxed)) + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+/// Stream wrapper that notes each read in the relay's activity
+pub struct Watched<'a, S> {
+    inner: &'a mut S,
+    activity: &'a Activity,
+    /// The activity's byte count for this side
+    bytes: &'a AtomicU64,
+}
+
+impl<'a, S> Watched<'a, S> {
+    pub fn new(inner: &'a mut S, activity: &'a Activity, bytes: &'a AtomicU64) -> Self {
+        Self { inner, activity, bytes }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Watched<'_, S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = ready!(Pin::new(&mut *self.inner).poll_read(cx, buf));
+        let read = buf.filled().len() - before;
+        // End of stream is not activity, so a half-closed relay still times out
+        if read > 0 {
+            self.bytes.fetch_add(read as u64, Ordering::Relaxed);
+            let now = self.activity.epoch.elapsed().as_millis() as u64;
+            self.activity.last_ms.store(now, Ordering::Relaxed);
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Watched<'_, S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
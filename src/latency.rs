@@ -0,0 +1,94 @@
+//! Round-trip time probing for the latency and lowest-jitter strategies
+//!
+//! Each interface balancer periodically opens a TCP connection from its
+//! source address to a well-known anycast resolver; the time to complete the
+//! handshake is one RTT sample. A probe that fails or times out counts as a
+//! sample of the full timeout, so a dead link drifts to the back of the pool
+//! without ever being dropped from it.
+
+use crate::load_balancer::SharedPool;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+/// Time allowed for one probe connection
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Connect from `ip` to Cloudflare DNS (1.1.1.1:53 for IPv4,
+/// [2606:4700:4700::1111]:53 for IPv6) and return the handshake time, or None
+/// if the connection failed or timed out
+pub async fn probe(ip: IpAddr) -> Option<Duration> {
+    let test_addr: SocketAddr = match ip {
+        IpAddr::V4(_) => "1.1.1.1:53".parse().unwrap(),
+        IpAddr::V6(_) => "[2606:4700:4700::1111]:53".parse().unwrap(),
+    };
+    probe_target(ip, test_addr).await
+}
+
+/// Connect from `ip` to `test_addr`, which must be of the same family, and
+/// return the handshake time, or None if the connection failed or timed out
+pub async fn probe_target(ip: IpAddr, test_addr: SocketAddr) -> Option<Duration> {
+    let domain = if ip.is_ipv6() { Domain::IPV6 } else { Domain::IPV4 };
+    let local_addr = SocketAddr::new(ip, 0);
+
+    let result = tokio::time::timeout(PROBE_TIMEOUT, async {
+        let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP)).ok()?;
+        socket.set_reuse_address(true).ok()?;
+        socket.bind(&local_addr.into()).ok()?;
+        socket.set_nonblocking(true).ok()?;
+
+        let started = Instant::now();
+        match socket.connect(&test_addr.into()) {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(_) => return None,
+        }
+
+        let std_stream: std::net::TcpStream = socket.into();
+        let stream = tokio::net::TcpStream::from_std(std_stream).ok()?;
+        stream.writable().await.ok()?;
+
+        if stream.take_error().ok()?.is_some() {
+            return None;
+        }
+
+        Some(started.elapsed())
+    })
+    .await;
+
+    result.ok().flatten()
+}
+
+/// Probe every interface balancer in the pool every `interval`. Tunnel,
+/// upstream and blackhole balancers have no source address to probe from and
+/// keep no measurement. Each round probes the pool in use at the time, so a
+/// reloaded balancer set is measured from the next round, and is skipped
+/// while no strategy of that pool uses the measurements.
+pub fn spawn_probes(shared: Arc<SharedPool>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        // A round can outlast a short interval; don't fire the missed ones in a burst
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let pool = shared.current();
+            if !pool.measures_latency() {
+                continue;
+            }
+            let mut probes = JoinSet::new();
+            for idx in 0..pool.len() {
+                if let Some(ip) = pool.balancer(idx).and_then(|lb| lb.source_ip()) {
+                    probes.spawn(async move { (idx, probe(ip).await) });
+                }
+            }
+            while let Some(result) = probes.join_next().await {
+                if let Ok((idx, rtt)) = result {
+                    pool.record_latency(idx, rtt.unwrap_or(PROBE_TIMEOUT));
+                }
+            }
+        }
+    });
+}
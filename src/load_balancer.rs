@@ -1,4 +1,17 @@
-use std::sync::Mutex;
+use crate::counters::{BalancerCounters, BalancerStats};
+use crate::quota::QuotaTracker;
+use crate::ratelimit::RateLimiter;
+use crate::schedule::RatioSchedule;
+use crate::sticky::StickyMap;
+use crate::strategy::{self, LoadBalancerStrategy, Selection};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
 
 /// Target address type from SOCKS5 request
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -8,6 +21,176 @@ pub enum TargetAddressType {
     Domain,
 }
 
+/// Address family a proxy can be restricted to
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub fn matches(self, is_ipv6: bool) -> bool {
+        is_ipv6 == (self == IpFamily::V6)
+    }
+
+    /// Whether a SOCKS target of this type may be connected to (domains are
+    /// narrowed at resolution instead)
+    pub fn allows(self, target_type: TargetAddressType) -> bool {
+        match target_type {
+            TargetAddressType::IPv4 => self == IpFamily::V4,
+            TargetAddressType::IPv6 => self == IpFamily::V6,
+            TargetAddressType::Domain => true,
+        }
+    }
+}
+
+impl std::fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            IpFamily::V4 => "IPv4",
+            IpFamily::V6 => "IPv6",
+        })
+    }
+}
+
+/// How the pool picks a balancer for each connection
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum Strategy {
+    /// Weighted round-robin by contention ratio
+    #[default]
+    RoundRobin,
+    /// Keep each target host on the same balancer (weighted rendezvous hashing)
+    TargetHash,
+    /// Weighted round-robin with each weight scaled by (1 - recent connect error rate)
+    ErrorAware,
+    /// The balancer with the fewest live connections, ties going to the higher contention ratio
+    LeastConn,
+    /// Weighted random pick with each weight divided by the measured round-trip time
+    #[value(alias = "lowest-latency")]
+    Latency,
+    /// The balancer whose measured round-trip time varies least
+    LowestJitter,
+    /// Weighted random pick by contention ratio
+    Random,
+}
+
+impl std::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The name as written on the command line
+        match clap::ValueEnum::to_possible_value(self) {
+            Some(value) => f.write_str(value.get_name()),
+            None => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// Weight given to each new connect outcome in the error-rate average
+const ERROR_RATE_ALPHA: f64 = 0.1;
+
+/// Lowest weight multiplier from the error rate, so a failing balancer still
+/// gets the occasional connection that lets it recover
+const MIN_ERROR_FACTOR: f64 = 0.05;
+
+/// Weight given to each new probe in the round-trip time and jitter averages
+const LATENCY_ALPHA: f64 = 0.3;
+
+/// Cooldown of a circuit opened by the threshold failure, doubled by each
+/// further consecutive failure
+const CIRCUIT_BASE_COOLDOWN: Duration = Duration::from_secs(1);
+
+/// Longest cooldown of an open circuit
+const CIRCUIT_MAX_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// What to do when the same balancer is specified more than once
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum DuplicatePolicy {
+    /// Refuse to start
+    Error,
+    /// Combine them into one balancer with the summed contention ratio, with a warning
+    #[default]
+    Merge,
+    /// Keep each as a separate balancer
+    Allow,
+}
+
+/// Optional per-balancer settings given as `key=value` suffixes (e.g. `IP@ratio@ttl=64`)
+#[derive(Debug, Clone, Default)]
+pub struct BalancerOptions {
+    /// IP TTL (hop limit for IPv6) applied to outbound connections
+    pub ttl: Option<u32>,
+    /// Connect timeout overriding the global one
+    pub connect_timeout: Option<Duration>,
+    /// Data quota in bytes per billing period
+    pub quota: Option<u64>,
+    /// IPv6 flow label set on outbound connections
+    pub flow_label: Option<FlowLabel>,
+    /// Only used when no unmetered balancer is available
+    pub metered: bool,
+    /// Linux VRF (l3mdev master device) whose routing table outbound connections use
+    pub vrf: Option<String>,
+    /// Linux firewall mark (SO_MARK) set on outbound sockets instead of binding them to the interface
+    pub mark: Option<u32>,
+    /// Rest the balancer once it has made this many connections within a window
+    pub rest: Option<RestLimit>,
+    /// Most connections relaying through the balancer at once
+    pub max_connections: Option<u32>,
+    /// Cap in bytes per second on traffic relayed through the balancer, both directions together
+    pub rate: Option<u64>,
+    /// Name shown in --check-balancers and metrics
+    pub label: Option<String>,
+    /// Contention ratios replacing the balancer's own during time windows
+    pub schedule: Option<RatioSchedule>,
+}
+
+/// Connection budget per window: a balancer that uses it up is skipped until
+/// the window ends
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RestLimit {
+    pub connections: u32,
+    pub window: Duration,
+}
+
+/// IPv6 flow label for a balancer's connections
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlowLabel {
+    /// The same 20-bit label on every connection
+    Fixed(u32),
+    /// A new random label per connection
+    Random,
+}
+
+/// Protocol spoken by an upstream proxy balancer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UpstreamKind {
+    Http,
+    Socks5,
+}
+
+impl UpstreamKind {
+    pub fn scheme(&self) -> &'static str {
+        match self {
+            UpstreamKind::Http => "http",
+            UpstreamKind::Socks5 => "socks5",
+        }
+    }
+}
+
+/// How a `blackhole` test balancer fails its connections
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlackholeMode {
+    /// Fail immediately with connection refused
+    Refuse,
+    /// Never complete, so the connect timeout applies
+    Timeout,
+}
+
+/// An upstream proxy that connections are chained through instead of a local interface
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub kind: UpstreamKind,
+    pub credentials: Option<(String, String)>,
+}
+
 /// A single load balancer endpoint
 #[derive(Debug, Clone)]
 pub struct LoadBalancer {
@@ -15,6 +198,15 @@ pub struct LoadBalancer {
     pub iface: Option<String>,
     pub contention_ratio: u32,
     pub is_ipv6: bool,
+    pub options: BalancerOptions,
+    pub upstream: Option<Upstream>,
+    /// Pseudo balancer that always fails, for exercising failover
+    pub blackhole: Option<BlackholeMode>,
+    /// Address of the same interface in the other IP family, for balancers
+    /// given by interface name, so domain targets can be raced over both
+    pub other_family: Option<IpAddr>,
+    /// Taken out of rotation: no new connections, existing ones run on
+    pub draining: bool,
 }
 
 impl LoadBalancer {
@@ -24,103 +216,940 @@ impl LoadBalancer {
             iface,
             contention_ratio,
             is_ipv6,
+            options: BalancerOptions::default(),
+            upstream: None,
+            blackhole: None,
+            other_family: None,
+            draining: false,
+        }
+    }
+
+    /// Identity used to detect the same balancer specified twice: source IP and
+    /// interface, or the tunnel/upstream address. None for blackholes.
+    pub fn identity(&self) -> Option<String> {
+        if self.blackhole.is_some() {
+            return None;
         }
+        let identity = match (&self.upstream, &self.iface) {
+            (Some(upstream), _) => format!("{}://{}", upstream.kind.scheme(), self.address),
+            (None, Some(iface)) => format!("{}%{}", self.address, iface),
+            (None, None) => self.address.clone(),
+        };
+        // The same address in another routing domain is a different path
+        Some(match &self.options.vrf {
+            Some(vrf) => format!("{} vrf {}", identity, vrf),
+            None => identity,
+        })
+    }
+
+    /// Local source IP of an interface balancer (None for tunnel and upstream balancers)
+    pub fn source_ip(&self) -> Option<IpAddr> {
+        if self.iface.is_none() || self.upstream.is_some() {
+            return None;
+        }
+        self.address.parse::<SocketAddr>().ok().map(|a| a.ip())
+    }
+
+    /// Local address to connect from to targets of the given family, with an
+    /// ephemeral port. None when the balancer has no address of that family.
+    pub fn source_addr(&self, ipv6: bool) -> Option<SocketAddr> {
+        if ipv6 == self.is_ipv6 {
+            return self.address.parse().ok();
+        }
+        self.other_family.map(|ip| SocketAddr::new(ip, 0))
+    }
+
+    /// Whether the balancer can connect to targets of the given family
+    pub fn has_family(&self, ipv6: bool) -> bool {
+        ipv6 == self.is_ipv6 || self.other_family.is_some()
+    }
+
+    /// Whether the balancer can connect to a target of this type. Upstream
+    /// proxies resolve the target themselves and blackholes never connect, so
+    /// they serve any target, as every balancer does a domain.
+    pub fn serves(&self, target_type: Option<TargetAddressType>) -> bool {
+        if self.upstream.is_some() || self.blackhole.is_some() {
+            return true;
+        }
+        match target_type {
+            Some(TargetAddressType::IPv4) => self.has_family(false),
+            Some(TargetAddressType::IPv6) => self.has_family(true),
+            Some(TargetAddressType::Domain) | None => true,
+        }
+    }
+
+    pub fn with_upstream(mut self, upstream: Upstream) -> Self {
+        self.upstream = Some(upstream);
+        self
+    }
+
+    pub fn with_other_family(mut self, ip: IpAddr) -> Self {
+        self.other_family = Some(ip);
+        self
+    }
+
+    pub fn with_blackhole(mut self, mode: BlackholeMode) -> Self {
+        self.blackhole = Some(mode);
+        self
+    }
+
+    pub fn with_options(mut self, options: BalancerOptions) -> Self {
+        self.options = options;
+        self
     }
 }
 
 /// Thread-safe pool of load balancers with weighted round-robin selection
 pub struct LoadBalancerPool {
-    balancers: Vec<LoadBalancer>,
-    state: Mutex<PoolState>,
+    /// Shared with the connections using them, so selecting one is a
+    /// reference count increment rather than a copy
+    balancers: Vec<Arc<LoadBalancer>>,
+    jitter: bool,
+    start_index: usize,
+    strategy: Strategy,
+    /// Strategy per target port instead of `strategy`, see `with_port_strategies`
+    port_strategies: Vec<(u16, Strategy)>,
+    sticky: bool,
+    /// Sticky assignments kept across reloads and restarts, see `with_sticky_map`
+    sticky_map: Option<Arc<StickyMap>>,
+    selector: Box<dyn LoadBalancerStrategy + Send + Sync>,
+    quota: Option<Arc<QuotaTracker>>,
+    /// Window over which a connect failure lowers a balancer's weight
+    failure_decay: Option<Duration>,
+    failures: Vec<RecentFailures>,
+    /// Consecutive connect failures that open a balancer's circuit
+    circuit_threshold: Option<u32>,
+    circuits: Vec<Mutex<Circuit>>,
+    /// Whether health checks run, see `with_health_checks`
+    health_checks: bool,
+    /// Set while a balancer's latest health check failed
+    unhealthy: Vec<AtomicBool>,
+    /// Close a balancer's relays when it fails a health check, see `with_drain_on_unhealthy`
+    drain_on_unhealthy: bool,
+    /// Signalled when a balancer fails a health check, shared like the counters
+    /// so relays started on a previous pool are closed too
+    health_drops: Vec<Arc<watch::Sender<()>>>,
+    /// Moving average of connect failures per attempt (f64 bits), error-aware strategy only
+    error_rates: Vec<AtomicU64>,
+    /// Shared with the pools before and after a reload, see `with_counters_from`
+    counters: Vec<Arc<BalancerCounters>>,
+    /// Bias new connections away from balancers holding more than their share of connection age
+    rebalance_nudge: bool,
+    /// Only select balancers of an IPv4 or IPv6 target's family
+    family_filter: bool,
+    active: Vec<ActiveRelays>,
+    rest_windows: Vec<Mutex<RestWindow>>,
+    /// Connections from selection until their relay ends, per balancer;
+    /// shared like the counters so connections on a previous pool still count
+    live: Vec<Arc<AtomicU32>>,
+    /// Moving average of probed round-trip time in milliseconds (f64 bits, 0 until
+    /// first measured), latency strategy only
+    latencies: Vec<AtomicU64>,
+    /// Moving average of each probe's deviation from the round-trip time
+    /// average in milliseconds (f64 bits), updated along with `latencies`
+    jitters: Vec<AtomicU64>,
+    /// Token bucket per balancer with a rate= option, shared by its relays
+    rate_limits: Vec<Option<RateLimiter>>,
+    epoch: Instant,
+}
+
+/// A live connection through a balancer, counted until dropped
+pub struct Lease<'a>(Option<&'a AtomicU32>);
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        if let Some(live) = self.0 {
+            live.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Connect failures of one balancer within the current decay window
+#[derive(Default)]
+struct RecentFailures {
+    /// Milliseconds since the pool's epoch of the latest failure
+    last_ms: AtomicU64,
+    count: AtomicU32,
+}
+
+/// Circuit breaker state of one balancer
+#[derive(Default)]
+struct Circuit {
+    consecutive_failures: u32,
+    /// Milliseconds since the pool's epoch until which the balancer is skipped
+    open_until_ms: u64,
+}
+
+/// Connections of one balancer in its current rest window
+#[derive(Default)]
+struct RestWindow {
+    /// Milliseconds since the pool's epoch at which the window started
+    start_ms: u64,
+    count: u32,
 }
 
-struct PoolState {
-    current_index: usize,
-    current_connections: u32,
+/// Relays currently running through one balancer
+#[derive(Default)]
+struct ActiveRelays {
+    count: AtomicU64,
+    /// Sum of the relays' start times in milliseconds since the pool's epoch
+    start_sum_ms: AtomicU64,
 }
 
+/// Combined age of active relays below which the rebalance nudge stays off
+const NUDGE_MIN_AGE_MS: u64 = 60_000;
+
+/// Lowest weight multiplier the rebalance nudge applies
+const MIN_NUDGE_FACTOR: f64 = 0.25;
+
 impl LoadBalancerPool {
     pub fn new(balancers: Vec<LoadBalancer>) -> Self {
         Self {
-            balancers,
-            state: Mutex::new(PoolState {
-                current_index: 0,
-                current_connections: 0,
-            }),
+            failures: balancers.iter().map(|_| RecentFailures::default()).collect(),
+            circuit_threshold: None,
+            circuits: balancers.iter().map(|_| Mutex::default()).collect(),
+            health_checks: false,
+            unhealthy: balancers.iter().map(|_| AtomicBool::new(false)).collect(),
+            drain_on_unhealthy: false,
+            health_drops: balancers.iter().map(|_| Arc::new(watch::channel(()).0)).collect(),
+            error_rates: balancers.iter().map(|_| AtomicU64::new(0f64.to_bits())).collect(),
+            counters: balancers.iter().map(|_| Arc::default()).collect(),
+            rebalance_nudge: false,
+            family_filter: true,
+            active: balancers.iter().map(|_| ActiveRelays::default()).collect(),
+            rest_windows: balancers.iter().map(|_| Mutex::default()).collect(),
+            live: balancers.iter().map(|_| Arc::default()).collect(),
+            latencies: balancers.iter().map(|_| AtomicU64::new(0f64.to_bits())).collect(),
+            jitters: balancers.iter().map(|_| AtomicU64::new(0f64.to_bits())).collect(),
+            rate_limits: balancers.iter().map(|lb| lb.options.rate.map(RateLimiter::new)).collect(),
+            balancers: balancers.into_iter().map(Arc::new).collect(),
+            jitter: false,
+            start_index: 0,
+            strategy: Strategy::default(),
+            port_strategies: Vec::new(),
+            sticky: false,
+            sticky_map: None,
+            selector: strategy::build(Strategy::default(), &[], false, 0, false, None),
+            quota: None,
+            failure_decay: None,
+            epoch: Instant::now(),
+        }
+    }
+
+    /// Randomize the order of selections within each round-robin cycle so that
+    /// simultaneous connections spread across balancers instead of bursting on one.
+    /// Each balancer still receives exactly `contention_ratio` selections per cycle.
+    pub fn with_jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self.rebuild_selector()
+    }
+
+    /// Start the round-robin cycle at a random balancer so the first connections
+    /// after a restart don't always land on the first one. A seed makes the
+    /// starting balancer reproducible.
+    pub fn with_shuffled_start(mut self, shuffle: bool, seed: Option<u64>) -> Self {
+        if shuffle && !self.balancers.is_empty() {
+            let start = match seed {
+                Some(seed) => StdRng::seed_from_u64(seed).gen_range(0..self.balancers.len()),
+                None => rand::thread_rng().gen_range(0..self.balancers.len()),
+            };
+            self.start_index = start;
+            debug!("Round-robin starts at load balancer {}", start + 1);
         }
+        self.rebuild_selector()
+    }
+
+    pub fn with_strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self.rebuild_selector()
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Select balancers for connections to each of the target ports with its
+    /// own strategy; other ports use the pool's strategy
+    pub fn with_port_strategies(mut self, ports: Vec<(u16, Strategy)>) -> Self {
+        self.port_strategies = ports;
+        self.rebuild_selector()
+    }
+
+    /// Whether a strategy in use selects by probed round-trip times
+    pub fn measures_latency(&self) -> bool {
+        self.uses_strategy(Strategy::Latency) || self.uses_strategy(Strategy::LowestJitter)
+    }
+
+    /// Whether the strategy, or that of any target port, is `strategy`
+    pub fn uses_strategy(&self, strategy: Strategy) -> bool {
+        self.strategy == strategy || self.port_strategies.iter().any(|&(_, s)| s == strategy)
+    }
+
+    /// Strategy selecting the balancer for a connection to `target`
+    fn strategy_for(&self, target: Option<&str>) -> Strategy {
+        let port = target.and_then(strategy::target_port);
+        self.port_strategies.iter().find(|&&(p, _)| Some(p) == port).map_or(self.strategy, |&(_, s)| s)
+    }
+
+    /// Send every connection from the same client source address through the
+    /// same balancer, moving it only while that balancer is skipped
+    pub fn with_sticky(mut self, sticky: bool) -> Self {
+        self.sticky = sticky;
+        self.rebuild_selector()
+    }
+
+    /// Remember the balancer each client is given with `with_sticky`, keeping
+    /// it there while it is eligible
+    pub fn with_sticky_map(mut self, map: Option<Arc<StickyMap>>) -> Self {
+        self.sticky_map = map;
+        self.rebuild_selector()
+    }
+
+    fn rebuild_selector(mut self) -> Self {
+        let remembered = self.sticky_map.clone();
+        self.selector =
+            strategy::build(self.strategy, &self.port_strategies, self.jitter, self.start_index, self.sticky, remembered);
+        self
+    }
+
+    /// Shrink the selection weight of balancers nearing their data quota and
+    /// skip those that have exceeded it
+    pub fn with_quota(mut self, quota: Arc<QuotaTracker>) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Lower the weight of balancers that failed to connect recently. The penalty
+    /// grows with repeated failures and fades out over `decay` after the last one.
+    pub fn with_failure_decay(mut self, decay: Option<Duration>) -> Self {
+        self.failure_decay = decay;
+        self
+    }
+
+    /// Skip a balancer for a cooldown once `threshold` connects in a row have
+    /// failed through it. The cooldown starts at one second and doubles with
+    /// each further failure up to a minute; after it, connections try the
+    /// balancer again and the first success closes the circuit.
+    pub fn with_circuit_breaker(mut self, threshold: Option<u32>) -> Self {
+        self.circuit_threshold = threshold;
+        self
+    }
+
+    /// Skip balancers whose latest health check failed, as reported through
+    /// `record_health`, until one passes again
+    pub fn with_health_checks(mut self, enabled: bool) -> Self {
+        self.health_checks = enabled;
+        self
+    }
+
+    /// Close the relays through a balancer as soon as it fails a health check,
+    /// so their clients reconnect through a healthy one
+    pub fn with_drain_on_unhealthy(mut self, enabled: bool) -> Self {
+        self.drain_on_unhealthy = enabled;
+        self
+    }
+
+    /// Weigh new selections against balancers whose long-lived connections hold
+    /// more than their contention ratio's share of the pool's total connection
+    /// age. Existing connections are not touched.
+    pub fn with_rebalance_nudge(mut self, nudge: bool) -> Self {
+        self.rebalance_nudge = nudge;
+        self
+    }
+
+    /// Take the balancer at the index out of rotation, or put it back
+    pub fn with_draining(mut self, idx: usize, draining: bool) -> Self {
+        if let Some(lb) = self.balancers.get_mut(idx) {
+            Arc::make_mut(lb).draining = draining;
+        }
+        self
+    }
+
+    /// Select balancers for IPv4 and IPv6 targets by contention ratio alone,
+    /// whatever their family, for egress through a NAT64 or similar transit
+    pub fn with_family_filter(mut self, enabled: bool) -> Self {
+        self.family_filter = enabled;
+        self
+    }
+
+    /// Carry on the counters, live connections and drain state of balancers
+    /// that were also in `previous`, matched by identity, so they survive a
+    /// reload. Connections still running on the previous pool keep adding to
+    /// the same counters. Remembered sticky assignments and the strategies per
+    /// target port carry on as well, and so does the data quota tracker: a
+    /// pool with quotas is only rebuilt with the same balancers in the same
+    /// positions, to drain one or follow a ratio schedule.
+    pub fn with_counters_from(mut self, previous: &LoadBalancerPool) -> Self {
+        for (((lb, counters), live), drops) in
+            self.balancers.iter_mut().zip(&mut self.counters).zip(&mut self.live).zip(&mut self.health_drops)
+        {
+            let Some(identity) = lb.identity() else {
+                continue;
+            };
+            if let Some(idx) = previous.balancers.iter().position(|old| old.identity().as_ref() == Some(&identity)) {
+                *counters = Arc::clone(&previous.counters[idx]);
+                *live = Arc::clone(&previous.live[idx]);
+                *drops = Arc::clone(&previous.health_drops[idx]);
+                if previous.balancers[idx].draining {
+                    Arc::make_mut(lb).draining = true;
+                }
+            }
+        }
+        self.quota = self.quota.or_else(|| previous.quota.clone());
+        let carried = (self.sticky_map.is_none() && previous.sticky_map.is_some())
+            || (self.port_strategies.is_empty() && !previous.port_strategies.is_empty());
+        if !carried {
+            return self;
+        }
+        self.sticky_map = self.sticky_map.or_else(|| previous.sticky_map.clone());
+        if self.port_strategies.is_empty() {
+            self.port_strategies = previous.port_strategies.clone();
+        }
+        self.rebuild_selector()
+    }
+
+    /// Note a failed connect through a balancer
+    pub fn record_failure(&self, idx: usize) {
+        self.update_error_rate(idx, 1.0);
+        self.count_towards_rest(idx);
+        if let Some(counters) = self.counters.get(idx) {
+            counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.trip_circuit(idx);
+        if let (Some(decay), Some(failures)) = (self.failure_decay, self.failures.get(idx)) {
+            let now = self.epoch.elapsed().as_millis() as u64;
+            let last = failures.last_ms.swap(now, Ordering::Relaxed);
+            // Failures older than the window no longer count towards the penalty
+            if now.saturating_sub(last) > decay.as_millis() as u64 {
+                failures.count.store(1, Ordering::Relaxed);
+            } else {
+                failures.count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Note a successful connect through a balancer
+    pub fn record_success(&self, idx: usize, connect_time: Duration) {
+        self.update_error_rate(idx, 0.0);
+        self.close_circuit(idx);
+        self.count_towards_rest(idx);
+        if let Some(counters) = self.counters.get(idx) {
+            counters.connections.fetch_add(1, Ordering::Relaxed);
+            counters.connect_time.observe(connect_time.as_micros() as u64);
+        }
+    }
+
+    /// Note the bytes a finished relay moved in both directions
+    pub fn record_relay_size(&self, idx: usize, bytes: u64) {
+        if let Some(counters) = self.counters.get(idx) {
+            counters.transfer_size.observe(bytes);
+        }
+    }
+
+    /// Counters of a balancer, for the histograms that are not in `stats`
+    pub fn counters(&self, idx: usize) -> Option<&BalancerCounters> {
+        self.counters.get(idx).map(Arc::as_ref)
+    }
+
+    /// Count a failure towards opening the balancer's circuit, and open it (or
+    /// extend a half-open one) once the threshold is reached
+    fn trip_circuit(&self, idx: usize) {
+        let (Some(threshold), Some(circuit)) = (self.circuit_threshold, self.circuits.get(idx)) else {
+            return;
+        };
+        let mut circuit = circuit.lock().unwrap();
+        circuit.consecutive_failures += 1;
+        let Some(beyond) = circuit.consecutive_failures.checked_sub(threshold) else {
+            return;
+        };
+        let cooldown = CIRCUIT_BASE_COOLDOWN
+            .saturating_mul(1 << beyond.min(16))
+            .min(CIRCUIT_MAX_COOLDOWN);
+        circuit.open_until_ms = self.epoch.elapsed().as_millis() as u64 + cooldown.as_millis() as u64;
+        warn!(
+            "Load balancer {} circuit open for {}s after {} consecutive failures",
+            idx + 1,
+            cooldown.as_secs(),
+            circuit.consecutive_failures
+        );
+    }
+
+    fn close_circuit(&self, idx: usize) {
+        let (Some(threshold), Some(circuit)) = (self.circuit_threshold, self.circuits.get(idx)) else {
+            return;
+        };
+        let mut circuit = circuit.lock().unwrap();
+        if circuit.consecutive_failures >= threshold {
+            info!("Load balancer {} circuit closed", idx + 1);
+        }
+        *circuit = Circuit::default();
+    }
+
+    /// Whether the balancer's circuit is open and it is still cooling down
+    fn circuit_open(&self, idx: usize) -> bool {
+        if self.circuit_threshold.is_none() {
+            return false;
+        }
+        let Some(circuit) = self.circuits.get(idx) else {
+            return false;
+        };
+        let now = self.epoch.elapsed().as_millis() as u64;
+        now < circuit.lock().unwrap().open_until_ms
+    }
+
+    /// Note the outcome of a balancer's health check
+    pub fn record_health(&self, idx: usize, healthy: bool) {
+        let Some(unhealthy) = self.unhealthy.get(idx) else {
+            return;
+        };
+        let was_unhealthy = unhealthy.swap(!healthy, Ordering::Relaxed);
+        match (was_unhealthy, healthy) {
+            (false, false) => {
+                warn!("Load balancer {} failed its health check, skipping it until it passes", idx + 1);
+                if let Some(drops) = self.health_drops.get(idx).filter(|_| self.drain_on_unhealthy) {
+                    let relays = drops.receiver_count();
+                    if relays > 0 {
+                        warn!("Closing {} relays through unhealthy load balancer {}", relays, idx + 1);
+                    }
+                    drops.send_replace(());
+                }
+            }
+            (true, true) => info!("Load balancer {} passed its health check again", idx + 1),
+            _ => {}
+        }
+    }
+
+    /// Receiver that changes when the balancer next fails a health check, for
+    /// its relays to close on; None unless `with_drain_on_unhealthy` is set
+    pub fn health_drop(&self, idx: usize) -> Option<watch::Receiver<()>> {
+        self.health_drops.get(idx).filter(|_| self.drain_on_unhealthy).map(|drops| drops.subscribe())
+    }
+
+    /// Balancers not failing their health check; all of them without health checks
+    pub fn healthy_count(&self) -> usize {
+        (0..self.len()).filter(|&idx| !self.is_unhealthy(idx)).count()
+    }
+
+    fn is_unhealthy(&self, idx: usize) -> bool {
+        self.health_checks && self.unhealthy.get(idx).is_some_and(|u| u.load(Ordering::Relaxed))
+    }
+
+    /// Count a connect attempt against the balancer's rest limit, starting a
+    /// new window once the previous one has ended
+    fn count_towards_rest(&self, idx: usize) {
+        let (Some(limit), Some(window)) = (
+            self.balancers.get(idx).and_then(|lb| lb.options.rest),
+            self.rest_windows.get(idx),
+        ) else {
+            return;
+        };
+        let now = self.epoch.elapsed().as_millis() as u64;
+        let mut window = window.lock().unwrap();
+        if now.saturating_sub(window.start_ms) >= limit.window.as_millis() as u64 {
+            *window = RestWindow { start_ms: now, count: 0 };
+        }
+        window.count += 1;
+        if window.count == limit.connections {
+            let left = Duration::from_millis((window.start_ms + limit.window.as_millis() as u64).saturating_sub(now));
+            info!(
+                "Load balancer {} resting for {:.1}s after {} connections",
+                idx + 1,
+                left.as_secs_f64(),
+                limit.connections
+            );
+        }
+    }
+
+    /// Whether the balancer has used up its connections for the current rest window
+    fn resting(&self, idx: usize) -> bool {
+        let (Some(limit), Some(window)) = (
+            self.balancers.get(idx).and_then(|lb| lb.options.rest),
+            self.rest_windows.get(idx),
+        ) else {
+            return false;
+        };
+        let now = self.epoch.elapsed().as_millis() as u64;
+        let window = window.lock().unwrap();
+        window.count >= limit.connections && now.saturating_sub(window.start_ms) < limit.window.as_millis() as u64
+    }
+
+    /// Whether the balancer has as many live connections as its cap allows
+    fn saturated(&self, idx: usize) -> bool {
+        let (Some(max), Some(live)) = (
+            self.balancers.get(idx).and_then(|lb| lb.options.max_connections),
+            self.live.get(idx),
+        ) else {
+            return false;
+        };
+        live.load(Ordering::Relaxed) >= max
+    }
+
+    fn update_error_rate(&self, idx: usize, outcome: f64) {
+        if !self.uses_strategy(Strategy::ErrorAware) {
+            return;
+        }
+        let Some(rate) = self.error_rates.get(idx) else {
+            return;
+        };
+        let average = |old: f64| old + ERROR_RATE_ALPHA * (outcome - old);
+        let (Ok(old) | Err(old)) = rate.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some(average(f64::from_bits(bits)).to_bits())
+        });
+        let (old, new) = (f64::from_bits(old), average(f64::from_bits(old)));
+        if (old * 100.0).round() != (new * 100.0).round() {
+            debug!("Load balancer {} connect error rate {:.2}", idx + 1, new);
+        }
+    }
+
+    /// Fold a probed round-trip time into a balancer's latency average, and
+    /// its deviation from that average into the jitter average
+    pub fn record_latency(&self, idx: usize, rtt: Duration) {
+        let (Some(latency), Some(jitter)) = (self.latencies.get(idx), self.jitters.get(idx)) else {
+            return;
+        };
+        let sample = rtt.as_secs_f64() * 1000.0;
+        let average = |old: f64| if old == 0.0 { sample } else { old + LATENCY_ALPHA * (sample - old) };
+        let (Ok(old) | Err(old)) = latency.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some(average(f64::from_bits(bits)).to_bits())
+        });
+        let old = f64::from_bits(old);
+        // The first probe has no average to deviate from
+        let deviation = if old == 0.0 { 0.0 } else { (sample - old).abs() };
+        let (Ok(old_jitter) | Err(old_jitter)) = jitter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            let old = f64::from_bits(bits);
+            Some((old + LATENCY_ALPHA * (deviation - old)).to_bits())
+        });
+        let jitter = f64::from_bits(old_jitter) + LATENCY_ALPHA * (deviation - f64::from_bits(old_jitter));
+        debug!(
+            "Load balancer {} round-trip time {:.1}ms, jitter {:.1}ms (probe {:.1}ms)",
+            idx + 1,
+            average(old),
+            jitter,
+            sample
+        );
+    }
+
+    /// Selection weight multiplier from the error rate (error-aware strategy only)
+    fn error_factor(&self, idx: usize, error_aware: bool) -> f64 {
+        if !error_aware {
+            return 1.0;
+        }
+        let rate = f64::from_bits(self.error_rates[idx].load(Ordering::Relaxed));
+        (1.0 - rate).max(MIN_ERROR_FACTOR)
+    }
+
+    /// Selection weight multiplier from recent failures: 1 / (1 + failures)
+    /// right after a failure, recovering linearly to 1 over the decay window
+    fn failure_factor(&self, idx: usize) -> f64 {
+        let Some(decay) = self.failure_decay else {
+            return 1.0;
+        };
+        let failures = &self.failures[idx];
+        let count = failures.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return 1.0;
+        }
+
+        let now = self.epoch.elapsed().as_millis() as u64;
+        let elapsed = now.saturating_sub(failures.last_ms.load(Ordering::Relaxed));
+        let recovered = (elapsed as f64 / decay.as_millis().max(1) as f64).min(1.0);
+        let penalized = 1.0 / (1.0 + f64::from(count));
+        penalized + (1.0 - penalized) * recovered
+    }
+
+    /// Account bytes relayed through a balancer: `up` sent to the target, `down` received
+    pub fn record_transfer(&self, idx: usize, up: u64, down: u64) {
+        if let Some(counters) = self.counters.get(idx) {
+            counters.octets_out.fetch_add(up, Ordering::Relaxed);
+            counters.octets_in.fetch_add(down, Ordering::Relaxed);
+        }
+        if let Some(quota) = &self.quota {
+            quota.record(idx, up + down);
+        }
+    }
+
+    /// Note that a relay through a balancer started; returns the start time to
+    /// pass to `relay_finished`
+    pub fn relay_started(&self, idx: usize) -> u64 {
+        let now = self.epoch.elapsed().as_millis() as u64;
+        if let Some(active) = self.active.get(idx) {
+            active.count.fetch_add(1, Ordering::Relaxed);
+            active.start_sum_ms.fetch_add(now, Ordering::Relaxed);
+        }
+        now
+    }
+
+    pub fn relay_finished(&self, idx: usize, started_ms: u64) {
+        if let Some(active) = self.active.get(idx) {
+            active.count.fetch_sub(1, Ordering::Relaxed);
+            active.start_sum_ms.fetch_sub(started_ms, Ordering::Relaxed);
+        }
+    }
+
+    /// Selection weight multiplier from the rebalance nudge: the balancer's
+    /// target share over its share of active connection age, when above target
+    fn nudge_factor(&self, idx: usize) -> f64 {
+        if !self.rebalance_nudge {
+            return 1.0;
+        }
+        let now = self.epoch.elapsed().as_millis() as u64;
+        let age = |active: &ActiveRelays| {
+            let count = active.count.load(Ordering::Relaxed);
+            (count * now).saturating_sub(active.start_sum_ms.load(Ordering::Relaxed))
+        };
+
+        let total_age: u64 = self.active.iter().map(age).sum();
+        if total_age < NUDGE_MIN_AGE_MS {
+            return 1.0;
+        }
+        let total_ratio: u32 = self.balancers.iter().map(|lb| lb.contention_ratio).sum();
+        let target = f64::from(self.balancers[idx].contention_ratio) / f64::from(total_ratio.max(1));
+        let share = age(&self.active[idx]) as f64 / total_age as f64;
+
+        if share <= target {
+            1.0
+        } else {
+            (target / share).max(MIN_NUDGE_FACTOR)
+        }
+    }
+
+    /// Merge the caller's skip set with balancers held back by their quota,
+    /// recent failures, error rate or the rebalance nudge, and those resting,
+    /// at their connection cap, with an open circuit or failing their health
+    /// check.
+    /// A balancer with a reduced weight factor is held back with probability
+    /// `1 - factor`. The error rate only counts with `error_aware`.
+    fn soft_skip(&self, skip: Option<&[bool]>, error_aware: bool) -> Option<Vec<bool>> {
+        if self.quota.is_none()
+            && self.failure_decay.is_none()
+            && !error_aware
+            && !self.rebalance_nudge
+            && self.circuit_threshold.is_none()
+            && !self.health_checks
+            && self.balancers.iter().all(|lb| lb.options.rest.is_none() && lb.options.max_connections.is_none())
+        {
+            return None;
+        }
+        let mut rng = rand::thread_rng();
+
+        Some(
+            (0..self.balancers.len())
+                .map(|i| {
+                    let skipped = skip.is_some_and(|s| s.get(i).copied().unwrap_or(false));
+                    let quota = self.quota.as_ref().map_or(1.0, |q| q.factor(i));
+                    let factor = quota * self.failure_factor(i) * self.error_factor(i, error_aware) * self.nudge_factor(i);
+                    skipped || self.resting(i) || self.saturated(i) || self.circuit_open(i) || self.is_unhealthy(i) || (factor < 1.0 && rng.gen::<f64>() >= factor)
+                })
+                .collect(),
+        )
+    }
+
+    /// Whether the balancer can take a connection it is pinned to: it is not
+    /// draining, resting, at its connection cap, out of quota, behind an open
+    /// circuit or failing its health check. Weight reductions that only make
+    /// selection less likely do not count.
+    pub fn eligible(&self, idx: usize) -> bool {
+        let Some(lb) = self.balancers.get(idx) else {
+            return false;
+        };
+        let quota_left = self.quota.as_ref().is_none_or(|q| q.factor(idx) > 0.0);
+        !lb.draining && quota_left && !self.resting(idx) && !self.saturated(idx) && !self.circuit_open(idx) && !self.is_unhealthy(idx)
     }
 
     pub fn len(&self) -> usize {
         self.balancers.len()
     }
 
+    pub fn balancer(&self, idx: usize) -> Option<&Arc<LoadBalancer>> {
+        self.balancers.get(idx)
+    }
+
+    /// Rate limit shared by the relays through a balancer, if it has one
+    pub fn rate_limit(&self, idx: usize) -> Option<&RateLimiter> {
+        self.rate_limits.get(idx).and_then(Option::as_ref)
+    }
+
+    /// Snapshot of every balancer's connection and byte counters, in pool order
+    pub fn stats(&self) -> Vec<BalancerStats> {
+        self.counters
+            .iter()
+            .zip(&self.live)
+            .enumerate()
+            .map(|(idx, (counters, live))| {
+                // Both averages are unset until the first probe
+                let rtt = f64::from_bits(self.latencies[idx].load(Ordering::Relaxed));
+                let measured = rtt > 0.0;
+                BalancerStats {
+                    circuit_open: self.circuit_open(idx),
+                    unhealthy: self.is_unhealthy(idx),
+                    draining: self.balancers[idx].draining,
+                    rtt_ms: measured.then_some(rtt),
+                    jitter_ms: measured.then(|| f64::from_bits(self.jitters[idx].load(Ordering::Relaxed))),
+                    ..counters.snapshot(live.load(Ordering::Relaxed))
+                }
+            })
+            .collect()
+    }
+
+    /// Zero every balancer's cumulative counters and return the totals they had
+    pub fn reset_counters(&self) -> Vec<BalancerStats> {
+        self.counters
+            .iter()
+            .zip(&self.live)
+            .map(|(counters, live)| counters.reset(live.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Count a connection through the selected balancer as live until the
+    /// returned lease is dropped; used by the least-connections strategy
+    pub fn lease(&self, idx: usize) -> Lease<'_> {
+        let live = self.live.get(idx).map(Arc::as_ref);
+        if let Some(live) = live {
+            live.fetch_add(1, Ordering::Relaxed);
+        }
+        Lease(live)
+    }
+
     /// Get the next load balancer according to contention ratio.
     /// If `skip` is provided, skip balancers marked as true in the slice.
     /// If `target_type` is provided, only select balancers matching the address family.
-    pub fn get_load_balancer(&self, skip: Option<&[bool]>, target_type: Option<TargetAddressType>) -> (LoadBalancer, usize) {
-        let mut state = self.state.lock().unwrap();
-
-        // For address family matching:
-        // - IPv4 target -> prefer IPv4 interfaces
-        // - IPv6 target -> prefer IPv6 interfaces
-        // - Domain -> use any interface (DNS will determine)
-        let family_filter = |lb: &LoadBalancer| -> bool {
-            match target_type {
-                Some(TargetAddressType::IPv4) => !lb.is_ipv6,
-                Some(TargetAddressType::IPv6) => lb.is_ipv6,
-                Some(TargetAddressType::Domain) | None => true,
+    /// `target` (host or host:port) is used by the target-hash strategy and
+    /// `client` (the client's source address) by sticky selection.
+    /// Draining balancers are never selected.
+    /// Returns `None` if the pool has no balancers, or none of the target's
+    /// family that is neither skipped by the caller nor draining.
+    pub fn get_load_balancer(
+        &self,
+        skip: Option<&[bool]>,
+        target_type: Option<TargetAddressType>,
+        target: Option<&str>,
+        client: Option<IpAddr>,
+    ) -> Option<(Arc<LoadBalancer>, usize)> {
+        if self.balancers.is_empty() {
+            return None;
+        }
+
+        let skipped = |set: Option<&[bool]>, i: usize| set.is_some_and(|s| s.get(i).copied().unwrap_or(false));
+        // Draining balancers take no new connections, as if the caller skipped them
+        let drained: Option<Vec<bool>> = self
+            .balancers
+            .iter()
+            .any(|lb| lb.draining)
+            .then(|| self.balancers.iter().enumerate().map(|(i, lb)| lb.draining || skipped(skip, i)).collect());
+        let skip = drained.as_deref().or(skip);
+
+        let soft_skip = self.soft_skip(skip, self.strategy_for(target) == Strategy::ErrorAware);
+        let serves = |i: usize| !self.family_filter || self.balancers[i].serves(target_type);
+
+        // Prefer balancers of the target's family that neither the caller nor
+        // the pool skips. When the pool's soft skips rule them all out, fall
+        // back to those of the family the caller has not skipped. A balancer
+        // of the other family could only fail to connect to an IPv4 or IPv6
+        // target, so without any the target gets none.
+        let merged = soft_skip.as_deref().or(skip);
+        let skip = if !(0..self.balancers.len()).any(|i| serves(i) && !skipped(merged, i)) {
+            if !(0..self.balancers.len()).any(|i| serves(i) && !skipped(skip, i)) {
+                return None;
             }
+            skip
+        } else {
+            merged
         };
+        let available = |i: usize| serves(i) && !skipped(skip, i);
 
-        // Count available balancers (not skipped and matching family)
-        let available_count = self.balancers.iter().enumerate().filter(|(i, lb)| {
-            let not_skipped = skip.map_or(true, |s| !s.get(*i).copied().unwrap_or(false));
-            not_skipped && family_filter(lb)
-        }).count();
+        // Metered balancers are a second tier, only used when no unmetered one is available
+        let unmetered_available = (0..self.balancers.len()).any(|i| available(i) && !self.balancers[i].options.metered);
+        let eligible = |i: usize| available(i) && !(unmetered_available && self.balancers[i].options.metered);
 
-        // If no balancers match the family, fall back to any available (for Domain or mixed scenarios)
-        let use_family_filter = available_count > 0;
+        let idx = self.selector.select(&Selection {
+            balancers: &self.balancers,
+            eligible: &eligible,
+            skip,
+            target,
+            client,
+            live: &self.live,
+            latencies: &self.latencies,
+            jitters: &self.jitters,
+        });
+        Some((Arc::clone(&self.balancers[idx]), idx))
+    }
+}
 
-        // Find next valid balancer
-        let start_index = state.current_index;
-        let mut iterations = 0;
+/// The pool new connections select from, replaced whole when the balancer set
+/// is reloaded. A connection keeps the pool it started with, so its balancer
+/// indices, lease and counters stay valid however the set changes.
+pub struct SharedPool {
+    current: RwLock<Arc<LoadBalancerPool>>,
+}
 
-        loop {
-            let idx = state.current_index;
-            let lb = &self.balancers[idx];
+impl SharedPool {
+    pub fn new(pool: LoadBalancerPool) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(pool)),
+        }
+    }
 
-            let is_skipped = skip.map_or(false, |s| s.get(idx).copied().unwrap_or(false));
-            let matches_family = !use_family_filter || family_filter(lb);
+    pub fn current(&self) -> Arc<LoadBalancerPool> {
+        Arc::clone(&self.current.read().unwrap())
+    }
 
-            if !is_skipped && matches_family {
-                // Found a valid balancer
-                state.current_connections += 1;
+    /// Replace the pool with one derived from the current pool. Concurrent
+    /// updates are serialized, so none is built from a pool another replaced;
+    /// selections wait for the lock only while `f` runs.
+    pub fn update(&self, f: impl FnOnce(&LoadBalancerPool) -> anyhow::Result<LoadBalancerPool>) -> anyhow::Result<Arc<LoadBalancerPool>> {
+        let mut current = self.current.write().unwrap();
+        let pool = Arc::new(f(&current)?);
+        *current = Arc::clone(&pool);
+        Ok(pool)
+    }
+}
 
-                if state.current_connections >= lb.contention_ratio {
-                    state.current_connections = 0;
-                    state.current_index = (state.current_index + 1) % self.balancers.len();
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                return (lb.clone(), idx);
-            }
+    /// IPv4 balancers with the given contention ratios
+    fn pool(ratios: &[u32]) -> LoadBalancerPool {
+        LoadBalancerPool::new(
+            (1..)
+                .zip(ratios)
+                .map(|(i, &ratio)| LoadBalancer::new(format!("192.0.2.{}", i), None, ratio, false))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn empty_pool_selects_nothing() {
+        let pool = pool(&[]);
+        assert!(pool.get_load_balancer(None, None, None, None).is_none());
+        assert!(pool.get_load_balancer(Some(&[]), Some(TargetAddressType::IPv4), Some("192.0.2.9:80"), None).is_none());
+    }
 
-            // Move to next
-            state.current_connections = 0;
-            state.current_index = (state.current_index + 1) % self.balancers.len();
-            iterations += 1;
-
-            // If we've checked all balancers and found none, return current anyway
-            if iterations >= self.balancers.len() {
-                // Fall back to first non-skipped balancer
-                for (i, lb) in self.balancers.iter().enumerate() {
-                    let is_skipped = skip.map_or(false, |s| s.get(i).copied().unwrap_or(false));
-                    if !is_skipped {
-                        return (lb.clone(), i);
+    #[test]
+    fn selects_nothing_without_a_usable_balancer() {
+        let pool = pool(&[1, 1]);
+        assert!(pool.get_load_balancer(Some(&[true, true]), None, None, None).is_none());
+        assert!(pool.get_load_balancer(None, Some(TargetAddressType::IPv6), None, None).is_none());
+        let (lb, idx) = pool.get_load_balancer(Some(&[true, false]), None, None, None).unwrap();
+        assert_eq!((lb.address.as_str(), idx), ("192.0.2.2", 1));
+    }
+
+    #[test]
+    fn concurrent_selections_respect_the_ratios() {
+        let pool = pool(&[3, 1, 2]);
+        let counts: Vec<AtomicU64> = (0..3).map(|_| AtomicU64::new(0)).collect();
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..6000 {
+                        let (_, idx) = pool.get_load_balancer(None, None, None, None).unwrap();
+                        counts[idx].fetch_add(1, Ordering::Relaxed);
                     }
-                }
-                // If all are skipped, return current index anyway
-                return (self.balancers[start_index].clone(), start_index);
+                });
             }
-        }
+        });
+        // Every cycle hands out each balancer's full share, whatever the interleaving
+        let counts: Vec<u64> = counts.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+        assert_eq!(counts, [24000, 8000, 16000]);
     }
 }
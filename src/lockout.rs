@@ -0,0 +1,124 @@
+//! Temporary refusal of clients that keep failing authentication
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Failed logins counted for one source address
+struct Failures {
+    /// Start of the window the failures are counted in
+    since: Instant,
+    count: u32,
+    /// Refuse the address until then once the threshold was reached
+    locked_until: Option<Instant>,
+}
+
+/// Locks out a source address for `cooldown` after `threshold` failed logins
+/// within `window`. Entries whose window and cooldown have passed are dropped
+/// as failures are recorded, so the map only holds recent offenders.
+pub struct Lockout {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    clients: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+impl Lockout {
+    pub fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            cooldown,
+            clients: Mutex::default(),
+        }
+    }
+
+    /// Whether connections from `ip` are refused
+    pub fn is_locked(&self, ip: IpAddr) -> bool {
+        self.locked_at(ip, Instant::now())
+    }
+
+    /// Count a failed login from `ip`
+    pub fn record_failure(&self, ip: IpAddr) {
+        self.failure_at(ip, Instant::now());
+    }
+
+    /// Forget the failures of `ip` after it logged in
+    pub fn record_success(&self, ip: IpAddr) {
+        self.clients.lock().unwrap().remove(&ip);
+    }
+
+    fn locked_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let clients = self.clients.lock().unwrap();
+        clients.get(&ip).and_then(|f| f.locked_until).is_some_and(|until| now < until)
+    }
+
+    fn failure_at(&self, ip: IpAddr, now: Instant) {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|_, f| match f.locked_until {
+            Some(until) => now < until,
+            None => now.duration_since(f.since) < self.window,
+        });
+        let failures = clients.entry(ip).or_insert(Failures {
+            since: now,
+            count: 0,
+            locked_until: None,
+        });
+        if failures.locked_until.is_some() {
+            return;
+        }
+        failures.count += 1;
+        if failures.count >= self.threshold {
+            failures.locked_until = Some(now + self.cooldown);
+            warn!("Locking out {} for {:?} after {} failed logins", ip, self.cooldown, failures.count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 9));
+
+    fn lockout() -> Lockout {
+        Lockout::new(3, Duration::from_secs(60), Duration::from_secs(300))
+    }
+
+    #[test]
+    fn locks_out_after_threshold_within_window() {
+        let lockout = lockout();
+        let start = Instant::now();
+        lockout.failure_at(CLIENT, start);
+        lockout.failure_at(CLIENT, start + Duration::from_secs(10));
+        assert!(!lockout.locked_at(CLIENT, start + Duration::from_secs(10)));
+        lockout.failure_at(CLIENT, start + Duration::from_secs(20));
+        assert!(lockout.locked_at(CLIENT, start + Duration::from_secs(20)));
+        assert!(lockout.locked_at(CLIENT, start + Duration::from_secs(319)));
+        assert!(!lockout.locked_at(CLIENT, start + Duration::from_secs(320)));
+        assert!(!lockout.locked_at(IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), start));
+    }
+
+    #[test]
+    fn failures_outside_the_window_start_over() {
+        let lockout = lockout();
+        let start = Instant::now();
+        lockout.failure_at(CLIENT, start);
+        lockout.failure_at(CLIENT, start + Duration::from_secs(30));
+        lockout.failure_at(CLIENT, start + Duration::from_secs(61));
+        assert!(!lockout.locked_at(CLIENT, start + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn success_clears_failures() {
+        let lockout = lockout();
+        let start = Instant::now();
+        lockout.failure_at(CLIENT, start);
+        lockout.failure_at(CLIENT, start);
+        lockout.record_success(CLIENT);
+        lockout.failure_at(CLIENT, start);
+        assert!(!lockout.locked_at(CLIENT, start));
+    }
+}
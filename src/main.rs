@@ -1,20 +1,63 @@
+mod acl;
+mod admin;
+mod balancer_url;
+mod config;
+mod health;
+mod counters;
+mod diagnostics;
+mod dns;
+mod http;
+mod http_connect;
+mod idle;
+mod latency;
 mod load_balancer;
+mod lockout;
+mod metrics;
+mod mirror;
+mod pac;
+#[cfg(feature = "pcap")]
+mod pcap;
 mod platform;
+mod proxy_protocol;
+mod quota;
+mod ratelimit;
+mod relay;
+mod routes;
+mod schedule;
+mod selftest;
+mod settings;
+mod shedding;
+mod shutdown;
+mod sni;
+mod sticky;
 mod socks;
+mod strategy;
+mod tarpit;
+#[cfg(feature = "tls")]
+mod tls;
+mod udp;
+mod upstream;
 
-use anyhow::{bail, Result};
-use clap::Parser;
-use load_balancer::{LoadBalancer, LoadBalancerPool};
-use socket2::{Domain, Protocol, Socket, Type};
-use std::net::{IpAddr, SocketAddr};
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use load_balancer::{BalancerOptions, BlackholeMode, DuplicatePolicy, FlowLabel, IpFamily, LoadBalancer, LoadBalancerPool, RestLimit, SharedPool, Strategy, TargetAddressType, Upstream, UpstreamKind};
+use platform::ReusePolicy;
+use proxy_protocol::{HeaderVersion, ProxyProtocol};
+use ratelimit::RateLimiter;
+use relay::{BufferMemory, ClientStream};
+use settings::Settings;
+use shedding::{ActiveConnections, ConnectionLimit, Shedder};
+use lockout::Lockout;
+use tarpit::Tarpit;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::TcpListener;
-use tracing::{info, warn, Level};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
-#[derive(Parser, Debug)]
-#[command(name = "dispatch-proxy")]
+#[derive(Parser, Debug, Clone)]
+#[command(name = "dispatch-proxy", disable_help_subcommand = true)]
 #[command(about = "A SOCKS5 load balancing proxy that combines multiple internet connections")]
 struct Args {
     /// The host to listen for SOCKS connections
@@ -25,7 +68,19 @@ struct Args {
     #[arg(long, default_value = "8080")]
     lport: u16,
 
-    /// Shows the available addresses for dispatching (non-tunnelling mode only)
+    /// Listen on ADDR:PORT instead of --lhost/--lport, optionally speaking :socks or :http there, also written socks5://ADDR:PORT or http://ADDR:PORT (repeatable)
+    #[arg(long, value_name = "ADDR:PORT[:MODE]")]
+    listen: Vec<String>,
+
+    /// Accept IPv4 and IPv6 clients: an unspecified listen address becomes [::] with IPV6_V6ONLY off, a loopback one listens on both 127.0.0.1 and ::1
+    #[arg(long, conflicts_with = "transparent")]
+    dual_stack: bool,
+
+    /// Port on the listen host serving a proxy.pac file that points clients at this proxy
+    #[arg(long, value_name = "PORT")]
+    pac_port: Option<u16>,
+
+    /// Shows the available addresses for dispatching (non-tunnelling mode only); with --auto, tests each for connectivity
     #[arg(short, long)]
     list: bool,
 
@@ -33,94 +88,734 @@ struct Args {
     #[arg(short, long)]
     tunnel: bool,
 
+    /// Speak HTTP CONNECT instead of SOCKS5 on the listener
+    #[arg(long, conflicts_with = "tunnel")]
+    http: bool,
+
+    /// Largest HTTP CONNECT request head, the request line and headers, accepted in bytes
+    #[arg(long, value_name = "BYTES", default_value = "8192", value_parser = clap::value_parser!(u32).range(1..))]
+    http_max_head: u32,
+
+    /// Relay connections diverted by an iptables TPROXY or REDIRECT rule to their original destination (Linux only)
+    #[arg(long, conflicts_with_all = ["tunnel", "http"])]
+    transparent: bool,
+
+    /// Read the original client and destination from a PROXY protocol header on tunnel connections
+    #[arg(long, value_enum, value_name = "VERSION", num_args = 0..=1, require_equals = true, default_missing_value = "any", requires = "tunnel")]
+    accept_proxy_protocol: Option<ProxyProtocol>,
+
+    /// Send a PROXY protocol header with the client's address to tunnel upstreams
+    #[arg(long, alias = "proxy-protocol", value_enum, value_name = "VERSION", num_args = 0..=1, require_equals = true, default_missing_value = "v1", requires = "tunnel")]
+    send_proxy_protocol: Option<HeaderVersion>,
+
     /// Disable logs
     #[arg(short, long)]
     quiet: bool,
 
-    /// Auto-detect interfaces with working internet connectivity
+    /// Auto-detect interfaces with working internet connectivity (with --tunnel, use the reachable tunnel addresses)
     #[arg(short, long)]
     auto: bool,
 
-    /// Load balancer addresses (IP@ratio or host:port@ratio for tunnel mode)
+    /// Family --auto connects from first on an interface where both work; the other is used for targets of its family
+    #[arg(long, value_enum, value_name = "FAMILY", default_value = "v4")]
+    auto_prefer: IpFamily,
+
+    /// Make --auto create a balancer per working address instead of one per interface
+    #[arg(long, requires = "auto")]
+    auto_per_address: bool,
+
+    /// Address --auto and --health-interval connect to from each interface instead of Cloudflare DNS; an interface works if any target accepts (repeatable)
+    #[arg(long, value_name = "HOST:PORT")]
+    probe_target: Vec<String>,
+
+    /// Fetch additional load balancers from an http:// URL at startup (one per line or a JSON array)
+    #[arg(long, value_name = "URL", conflicts_with = "auto")]
+    balancer_url: Option<String>,
+
+    /// Read load balancers from a config file of [[balancer]] tables instead of the command line
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["auto", "addresses"])]
+    config: Option<String>,
+
+    /// Randomize selection order within each round-robin cycle to spread simultaneous connections
+    #[arg(long)]
+    jitter: bool,
+
+    /// Start the round-robin cycle at a random balancer instead of the first
+    #[arg(long)]
+    shuffle_start: bool,
+
+    /// Seed for --shuffle-start, for a reproducible starting balancer
+    #[arg(long, value_name = "N", requires = "shuffle_start")]
+    seed: Option<u64>,
+
+    /// Seconds over which a balancer's weight recovers after a failed connect
+    #[arg(long, value_name = "SECS")]
+    failure_decay: Option<u64>,
+
+    /// Skip a balancer for a growing cooldown (up to 60s) after this many consecutive failed connects
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    circuit_breaker: Option<u32>,
+
+    /// Steer new connections away from balancers carrying more than their share of long-lived connections
+    #[arg(long)]
+    rebalance_nudge: bool,
+
+    /// How connections are assigned to load balancers
+    #[arg(long, value_enum, default_value_t = Strategy::RoundRobin)]
+    strategy: Strategy,
+
+    /// Use another strategy for connections to some target ports, e.g. 443=latency,default=least-conn
+    #[arg(long, value_name = "PORT=STRATEGY,...")]
+    strategy_for_port: Option<String>,
+
+    /// Seconds between round-trip time probes of each balancer (latency strategy)
+    #[arg(long, value_name = "SECS", default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    latency_interval: u64,
+
+    /// Check each interface balancer's connectivity every SECS seconds and skip those failing until they pass again
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    health_interval: Option<u64>,
+
+    /// Most balancers health-checked at the same time
+    #[arg(long, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..), requires = "health_interval")]
+    health_probe_concurrency: u32,
+
+    /// Report unready on /healthz while fewer than K load balancers pass their health checks
+    #[arg(long, value_name = "K", value_parser = clap::value_parser!(u32).range(1..), requires = "health_interval")]
+    min_healthy: Option<u32>,
+
+    /// Also close new connections while fewer than --min-healthy load balancers are healthy
+    #[arg(long, requires = "min_healthy")]
+    refuse_unready: bool,
+
+    /// Close the open connections through a load balancer when it fails its health check
+    #[arg(long, requires = "health_interval")]
+    drain_on_unhealthy: bool,
+
+    /// Shell command run with a balancer's address and `up` or `down` appended when a health check changes its state
+    #[arg(long, value_name = "CMD", requires = "health_interval")]
+    on_health_change: Option<String>,
+
+    /// Seconds a new health state must hold before --on-health-change runs, so flapping links run it once
+    #[arg(long, value_name = "SECS", default_value_t = 30, requires = "on_health_change")]
+    health_change_debounce: u64,
+
+    /// Send all connections from the same client IP through the same load balancer
+    #[arg(long)]
+    sticky: bool,
+
+    /// File where --sticky remembers each client's load balancer across restarts
+    #[arg(long, value_name = "PATH", requires = "sticky")]
+    sticky_state: Option<String>,
+
+    /// Seconds a remembered --sticky-state assignment lasts after the client's latest connection
+    #[arg(long, value_name = "SECS", default_value = "3600", value_parser = clap::value_parser!(u64).range(1..), requires = "sticky_state")]
+    sticky_ttl: u64,
+
+    /// Send SOCKS requests for a domain through one load balancer (N as listed at startup), e.g. *.example.com=2
+    #[arg(long, value_name = "PATTERN=LB_INDEX")]
+    route: Vec<String>,
+
+    /// Send connections of users matching a pattern through one load balancer (N as listed at startup), e.g. team-*=2
+    #[arg(long, value_name = "PATTERN=LB_INDEX", requires = "auth")]
+    route_user: Vec<String>,
+
+    /// Let clients pick a load balancer with a username of PREFIX, its number and optionally a dash and more, e.g. lb2-session
+    #[arg(long, value_name = "PREFIX", requires = "auth")]
+    user_balancer_prefix: Option<String>,
+
+    /// How to handle a load balancer that is specified more than once
+    #[arg(long, value_enum, default_value_t = DuplicatePolicy::Merge)]
+    on_duplicate: DuplicatePolicy,
+
+    /// Read the numbers after @ as percentages of connections, which must add up to 100
+    #[arg(long, conflicts_with_all = ["auto", "admin_addr"])]
+    weights_as_percent: bool,
+
+    /// Reject SOCKS requests with a non-zero reserved byte
+    #[arg(long)]
+    strict_socks: bool,
+
+    /// Drop SOCKS clients whose greeting does not offer this authentication method
+    #[arg(long, value_enum, value_name = "METHOD")]
+    require_auth_method: Option<socks::AuthMethod>,
+
+    /// Require clients to authenticate with this username and password (repeatable)
+    #[arg(long, value_name = "USER:PASS")]
+    auth: Vec<String>,
+
+    /// Reply with a different SOCKS code for a kind of failure, e.g. connect-failed=host-unreachable
+    #[arg(long, value_name = "FAILURE=CODE")]
+    reply_code: Vec<String>,
+
+    /// Hold connections denied by policy this many seconds before refusing them
+    #[arg(long, value_name = "SECS")]
+    tarpit: Option<u64>,
+
+    /// Most denied connections held by --tarpit at once; beyond this they are dropped immediately
+    #[arg(long, value_name = "N", default_value = "256")]
+    tarpit_max: usize,
+
+    /// Refuse a client address for a while after this many failed logins within --lockout-window
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..), requires = "auth")]
+    lockout_failures: Option<u32>,
+
+    /// Seconds in which --lockout-failures failed logins lock a client out
+    #[arg(long, value_name = "SECS", default_value = "60", requires = "lockout_failures")]
+    lockout_window: u64,
+
+    /// Seconds a locked-out client's connections are refused
+    #[arg(long, value_name = "SECS", default_value = "300", requires = "lockout_failures")]
+    lockout_cooldown: u64,
+
+    /// Time in milliseconds a client has to complete its TLS or SOCKS handshake or PROXY protocol header (0 to wait forever)
+    #[arg(long, value_name = "MS", default_value = "10000")]
+    handshake_timeout: u64,
+
+    /// Connect timeout in milliseconds for balancers without a timeout= option (0 to wait for the OS)
+    #[arg(long, value_name = "MS", default_value = "10000")]
+    connect_timeout: u64,
+
+    /// Overall time budget in milliseconds for DNS resolution and connecting, across all balancers tried
+    #[arg(long, value_name = "MS")]
+    connect_deadline: Option<u64>,
+
+    /// Times a balancer is retried after a transient local connect error (such as no free source port) before the next is tried
+    #[arg(long, value_name = "N", default_value = "0", value_parser = clap::value_parser!(u32).range(0..=10))]
+    max_retries: u32,
+
+    /// Wait in milliseconds before the first retry, doubled for each further one up to 2 seconds
+    #[arg(long, value_name = "MS", default_value = "50", value_parser = clap::value_parser!(u64).range(1..))]
+    retry_backoff: u64,
+
+    /// Close relays on which no data flows in either direction for this many seconds
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout: Option<u64>,
+
+    /// Most resolved addresses of a domain tried per connection before giving up
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_resolve_attempts: Option<u32>,
+
+    /// Resolve domain targets with DNS queries sent from the selected load balancer instead of the system resolver
+    #[arg(long, conflicts_with = "tunnel")]
+    balancer_dns: bool,
+
+    /// Nameserver for --balancer-dns, IP or IP:PORT (implies --balancer-dns) [default: first in /etc/resolv.conf]
+    #[arg(long, value_name = "SERVER", conflicts_with = "tunnel")]
+    dns: Option<String>,
+
+    /// Ignore IPv6 balancers, resolve domains to IPv4 only and reject IPv6 targets
+    #[arg(long, conflicts_with = "ipv6_only")]
+    ipv4_only: bool,
+
+    /// Ignore IPv4 balancers, resolve domains to IPv6 only and reject IPv4 targets
+    #[arg(long)]
+    ipv6_only: bool,
+
+    /// Select balancers by contention ratio alone, ignoring the address family of IP targets
+    #[arg(long, conflicts_with_all = ["ipv4_only", "ipv6_only"])]
+    no_family_filter: bool,
+
+    /// NAT64 prefix (/96) IPv4 targets are embedded in when connected from an IPv6-only balancer
+    #[arg(long, value_name = "PREFIX", default_value = "64:ff9b::", requires = "no_family_filter")]
+    nat64_prefix: Ipv6Addr,
+
+    /// Refuse targets that resolve to private, loopback, link-local or ULA addresses
+    #[arg(long)]
+    block_private: bool,
+
+    /// Only connect to targets in this network, optionally on this port, e.g. 203.0.113.0/24:443 (repeatable)
+    #[arg(long, value_name = "CIDR[:PORT]")]
+    allow: Vec<String>,
+
+    /// Never connect to targets in this network, optionally on this port, e.g. 10.0.0.0/8 or *:25 (repeatable)
+    #[arg(long, value_name = "CIDR[:PORT]")]
+    deny: Vec<String>,
+
+    /// Send --early-data in the SYN of outbound connections with TCP Fast Open where the kernel supports it
+    #[arg(long, requires = "early_data")]
+    tfo: bool,
+
+    /// Forward client bytes sent ahead of the SOCKS reply together with the connect (pairs with --tfo)
+    #[arg(long)]
+    early_data: bool,
+
+    /// Size relay buffers to whole segments of each balancer interface's MTU
+    #[arg(long)]
+    mtu_buffers: bool,
+
+    /// Cap on relay buffer memory across all connections, e.g. 256mb; relays wait while it is used up
+    #[arg(long, value_name = "SIZE")]
+    max_buffer_memory: Option<String>,
+
+    /// Copy client traffic relayed via the primary balancer to the secondary, discarding its responses
+    #[arg(long, value_name = "PRIMARY=SECONDARY")]
+    mirror: Vec<String>,
+
+    /// Connect from the client's source port (for FTP, SIP and other protocols that advertise it)
+    #[arg(long)]
+    preserve_source_port: bool,
+
+    /// Connect from a random port in START-END instead of an ephemeral one, moving on to another port while one is taken
+    #[arg(long, value_name = "START-END", conflicts_with = "preserve_source_port")]
+    source_ports: Option<String>,
+
+    /// Address reuse options set on outbound sockets before binding
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = ReusePolicy::Addr)]
+    socket_reuse: ReusePolicy,
+
+    /// Cap combined throughput of all relays, e.g. 5mb (bytes per second) or 40mbit
+    #[arg(long, value_name = "RATE")]
+    total_rate: Option<String>,
+
+    /// File rewritten periodically with per-balancer counters in key=value form
+    #[arg(long, value_name = "PATH")]
+    counters_file: Option<String>,
+
+    /// Seconds between rewrites of --counters-file
+    #[arg(long, value_name = "SECS", default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    counters_interval: u64,
+
+    /// Log each balancer's throughput and totals every this many seconds
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    report_interval: Option<u64>,
+
+    /// Address serving Prometheus metrics on /metrics, e.g. 127.0.0.1:9090
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Address serving an HTTP API to list, add and remove load balancers at runtime, e.g. 127.0.0.1:9091
+    #[arg(long, value_name = "ADDR")]
+    admin_addr: Option<SocketAddr>,
+
+    /// File where per-balancer quota usage is persisted across restarts
+    #[arg(long, value_name = "PATH")]
+    quota_state: Option<String>,
+
+    /// Day of the month on which quota usage resets
+    #[arg(long, value_name = "DAY", default_value = "1", value_parser = clap::value_parser!(u32).range(1..=28))]
+    quota_reset_day: u32,
+
+    /// Validate the configuration without listening: print each load balancer as parsed and resolved, check that tunnel and upstream endpoints resolve, then exit
+    #[arg(long, alias = "check")]
+    check_balancers: bool,
+
+    /// Check that each load balancer egresses from its own source IP, then exit
+    #[arg(long)]
+    verify_egress: bool,
+
+    /// IP echo service used by --verify-egress (must return the caller's IP as plain text)
+    #[arg(long, value_name = "URL", default_value = "http://api.ipify.org/")]
+    egress_echo_url: String,
+
+    /// Report which DNS resolver answers through each load balancer, then exit
+    #[arg(long)]
+    dns_leak_test: bool,
+
+    /// Name whose answer is the address of the resolver asking for it
+    #[arg(long, value_name = "NAME", default_value = "whoami.akamai.net")]
+    dns_leak_name: String,
+
+    /// Nameserver queried by --dns-leak-test [default: first in /etc/resolv.conf]
+    #[arg(long, value_name = "IP")]
+    dns_leak_server: Option<IpAddr>,
+
+    /// Log (at debug level) relays whose up/down byte ratio reaches this value
+    #[arg(long, value_name = "RATIO")]
+    skew_ratio: Option<f64>,
+
+    /// Minimum bytes transferred before a relay is checked for skew
+    #[arg(long, value_name = "BYTES", default_value = "1048576")]
+    skew_min_bytes: u64,
+
+    /// Log the TLS server name (SNI) requested on each relayed connection
+    #[arg(long)]
+    log_sni: bool,
+
+    /// Send TCP keepalive probes after this many idle seconds on client and outbound sockets
+    #[arg(long, value_name = "SECS", visible_alias = "keepalive")]
+    keepalive_idle: Option<u64>,
+
+    /// Seconds between unanswered keepalive probes
+    #[arg(long, value_name = "SECS", requires = "keepalive_idle")]
+    keepalive_interval: Option<u64>,
+
+    /// Unanswered keepalive probes before the connection is dropped
+    #[arg(long, value_name = "N", requires = "keepalive_idle")]
+    keepalive_count: Option<u32>,
+
+    /// Set TCP_NODELAY on client and outbound sockets so small writes go out at once; --nodelay=false favours bulk transfers
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, require_equals = true, default_value_t = true, default_missing_value = "true", action = clap::ArgAction::Set)]
+    nodelay: bool,
+
+    /// Write relayed traffic to a pcap-ng file for debugging
+    #[cfg(feature = "pcap")]
+    #[arg(long, value_name = "PATH")]
+    trace_pcap: Option<String>,
+
+    /// Only trace connections to this target (host, host:port or :port)
+    #[cfg(feature = "pcap")]
+    #[arg(long, value_name = "FILTER", requires = "trace_pcap")]
+    trace_filter: Option<String>,
+
+    /// Certificate chain (PEM) for accepting clients over TLS
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", requires = "tls_key", conflicts_with = "transparent")]
+    tls_cert: Option<String>,
+
+    /// Private key (PEM) for accepting clients over TLS
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    tls_key: Option<String>,
+
+    /// Most client connections handled at once; further ones wait until one closes
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_connections: Option<u32>,
+
+    /// Number of runtime worker threads [default: number of CPUs]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    worker_threads: Option<u32>,
+
+    /// Warn when the runtime stalls for at least this many milliseconds
+    #[arg(long, value_name = "MS")]
+    stall_warn_ms: Option<u64>,
+
+    /// Seconds to let active connections finish after SIGINT/SIGTERM before exiting
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    drain_timeout: u64,
+
+    /// Load balancer addresses (IP@ratio, INTERFACE@ratio or host:port@ratio for tunnel mode)
     addresses: Vec<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-/// Detect and list available network interfaces
-fn detect_interfaces() {
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Relay a SOCKS5 CONNECT through a loopback balancer to a local echo server and report pass/fail
+    Selftest,
+}
+
+/// Detect and list available network interfaces with their addresses,
+/// state and default routes. With probe targets, also test whether each
+/// address gets through to the internet.
+async fn detect_interfaces(probe_targets: Option<&[SocketAddr]>) {
     println!("--- Listing the available addresses for dispatching");
 
-    if let Ok(interfaces) = get_if_addrs::get_if_addrs() {
-        for iface in interfaces {
-            if !iface.is_loopback() {
-                match iface.ip() {
-                    IpAddr::V4(ipv4) => {
-                        println!("[+] {}, IPv4:{}", iface.name, ipv4);
-                    }
-                    IpAddr::V6(ipv6) => {
-                        println!("[+] {}, IPv6:{}", iface.name, ipv6);
-                    }
-                }
+    let Ok(interfaces) = get_if_addrs::get_if_addrs() else {
+        return;
+    };
+    // Group addresses by interface, sorted by name then IP so the listing is
+    // stable across runs
+    let mut grouped: std::collections::BTreeMap<String, Vec<(IpAddr, u32)>> = std::collections::BTreeMap::new();
+    for iface in interfaces.into_iter().filter(|iface| !iface.is_loopback()) {
+        let (ip, prefix) = match &iface.addr {
+            get_if_addrs::IfAddr::V4(v4) => (IpAddr::V4(v4.ip), u32::from(v4.netmask).count_ones()),
+            get_if_addrs::IfAddr::V6(v6) => (IpAddr::V6(v6.ip), u128::from(v6.netmask).count_ones()),
+        };
+        grouped.entry(iface.name).or_default().push((ip, prefix));
+    }
+
+    // Probe every address at once; link-local addresses cannot reach the internet
+    let mut reachable = std::collections::HashMap::new();
+    if let Some(targets) = probe_targets {
+        let mut probes = tokio::task::JoinSet::new();
+        for &(ip, _) in grouped.values().flatten() {
+            if !is_link_local(ip) {
+                let targets = targets.to_vec();
+                probes.spawn(async move { (ip, test_interface_connectivity(ip, &targets).await) });
+            }
+        }
+        while let Some(result) = probes.join_next().await {
+            if let Ok((ip, works)) = result {
+                reachable.insert(ip, works);
             }
         }
     }
+
+    for (name, mut addresses) in grouped {
+        addresses.sort();
+        let mut header = format!("[+] {}", name);
+        match platform::interface_up(&name) {
+            Some(true) => header.push_str(", up"),
+            Some(false) => header.push_str(", down"),
+            None => {}
+        }
+        let gateways = platform::default_gateways(&name);
+        let via: Vec<String> = gateways.iter().filter(|gw| !gw.is_unspecified()).map(|gw| gw.to_string()).collect();
+        if !via.is_empty() {
+            header.push_str(&format!(", default route via {}", via.join(", ")));
+        } else if !gateways.is_empty() {
+            header.push_str(", default route");
+        }
+        println!("{}", header);
+
+        for (ip, prefix) in addresses {
+            let family = if ip.is_ipv6() { "IPv6" } else { "IPv4" };
+            let status = match reachable.get(&ip) {
+                Some(true) => ", internet reachable",
+                Some(false) => ", internet unreachable",
+                None if probe_targets.is_some() => ", link-local",
+                None => "",
+            };
+            println!("    {}:{}/{}{}", family, ip, prefix, status);
+        }
+    }
 }
 
-/// Get interface name from IP address (supports both IPv4 and IPv6)
-fn get_iface_from_ip(ip: &IpAddr) -> Option<String> {
-    if let Ok(interfaces) = get_if_addrs::get_if_addrs() {
-        for iface in interfaces {
-            if !iface.is_loopback() && &iface.ip() == ip {
-                return Some(iface.name);
+/// Whether an address is IPv4 (169.254/16) or IPv6 (fe80::/10) link-local
+fn is_link_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_link_local(),
+        IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+/// Print each balancer in its fully resolved form, for --check-balancers
+fn print_balancers(load_balancers: &[LoadBalancer]) {
+    for (idx, lb) in load_balancers.iter().enumerate() {
+        let (kind, family) = match (&lb.upstream, lb.blackhole, &lb.iface) {
+            (Some(upstream), _, _) => {
+                let auth = if upstream.credentials.is_some() { " with credentials" } else { "" };
+                (format!("{} upstream {}{}", upstream.kind.scheme(), lb.address, auth), "any")
+            }
+            (_, Some(BlackholeMode::Refuse), _) => ("blackhole refusing connections".to_string(), "any"),
+            (_, Some(BlackholeMode::Timeout), _) => ("blackhole timing out connections".to_string(), "any"),
+            (_, _, Some(iface)) => {
+                let ip = lb.source_ip().map_or_else(|| lb.address.clone(), |ip| ip.to_string());
+                let ip = match lb.other_family {
+                    Some(other) => format!("{} and {}", ip, other),
+                    None => ip,
+                };
+                let family = match (lb.is_ipv6, lb.other_family.is_some()) {
+                    (_, true) => "dual-stack",
+                    (true, false) => "IPv6",
+                    (false, false) => "IPv4",
+                };
+                (format!("source {} on {}", ip, iface), family)
+            }
+            _ => (format!("tunnel to {}", lb.address), if lb.is_ipv6 { "IPv6" } else { "IPv4" }),
+        };
+
+        let mut options = Vec::new();
+        if let Some(ttl) = lb.options.ttl {
+            options.push(format!("ttl={}", ttl));
+        }
+        if let Some(timeout) = lb.options.connect_timeout {
+            options.push(format!("timeout={}ms", timeout.as_millis()));
+        }
+        if let Some(quota) = lb.options.quota {
+            options.push(format!("quota={} bytes", quota));
+        }
+        match lb.options.flow_label {
+            Some(FlowLabel::Fixed(label)) => options.push(format!("flowlabel={:#07x}", label)),
+            Some(FlowLabel::Random) => options.push("flowlabel=random".to_string()),
+            None => {}
+        }
+        if lb.options.metered {
+            options.push("metered".to_string());
+        }
+        if let Some(vrf) = &lb.options.vrf {
+            options.push(format!("vrf={}", vrf));
+        }
+        if let Some(mark) = lb.options.mark {
+            options.push(format!("mark={:#x}", mark));
+        }
+        if let Some(rest) = lb.options.rest {
+            options.push(format!("rest={}/{}s", rest.connections, rest.window.as_secs_f64()));
+        }
+        if let Some(max) = lb.options.max_connections {
+            options.push(format!("max={}", max));
+        }
+        if let Some(rate) = lb.options.rate {
+            options.push(format!("rate={} bytes/s", rate));
+        }
+        if let Some(label) = &lb.options.label {
+            options.push(format!("label={}", label));
+        }
+        if let Some(schedule) = &lb.options.schedule {
+            options.push(format!("schedule={}", schedule));
+        }
+
+        println!(
+            "[{}] {}, {}, contention ratio {}{}{}",
+            idx + 1,
+            kind,
+            family,
+            lb.contention_ratio,
+            if options.is_empty() { "" } else { ", " },
+            options.join(" ")
+        );
+    }
+}
+
+/// Resolve the endpoint of every tunnel and upstream balancer, printing those
+/// that fail, and return how many did
+async fn check_endpoints(load_balancers: &[LoadBalancer]) -> usize {
+    let mut failed = 0;
+    for (idx, lb) in load_balancers.iter().enumerate() {
+        if lb.iface.is_some() || lb.blackhole.is_some() {
+            continue;
+        }
+        // Any port resolves the same as the one a client brings
+        let address = lb.address.replace(PORT_TEMPLATE, "0");
+        match tokio::net::lookup_host(address.as_str()).await.map(|mut addresses| addresses.next()) {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                println!("[{}] {} resolves to no address", idx + 1, lb.address);
+                failed += 1;
+            }
+            Err(e) => {
+                println!("[{}] {} does not resolve: {}", idx + 1, lb.address, e);
+                failed += 1;
             }
         }
     }
-    None
+    failed
 }
 
-/// Test if an interface has working internet connectivity
-async fn test_interface_connectivity(ip: IpAddr) -> bool {
-    // Use Cloudflare DNS (1.1.1.1:53 for IPv4, [2606:4700:4700::1111]:53 for IPv6)
-    let (test_addr, domain): (SocketAddr, Domain) = match ip {
-        IpAddr::V4(_) => ("1.1.1.1:53".parse().unwrap(), Domain::IPV4),
-        IpAddr::V6(_) => ("[2606:4700:4700::1111]:53".parse().unwrap(), Domain::IPV6),
+/// Get interface name from IP address (supports both IPv4 and IPv6)
+fn get_iface_from_ip(ip: &IpAddr) -> Option<String> {
+    let mut names: Vec<String> = get_if_addrs::get_if_addrs()
+        .ok()?
+        .into_iter()
+        .filter(|iface| !iface.is_loopback() && &iface.ip() == ip)
+        .map(|iface| iface.name)
+        .collect();
+    names.dedup();
+    if names.len() > 1 {
+        warn!("IP address {} is on several interfaces ({}), using {}", ip, names.join(", "), names[0]);
+    }
+    names.into_iter().next()
+}
+
+/// Resolve an `iface`, `iface:v4` / `iface:v6` or `mac:<address>` balancer to
+/// the interface's current address. An interface given without a family also
+/// returns its global address in the other family, if it has one.
+fn resolve_iface_family(spec: &str) -> Result<(IpAddr, Option<IpAddr>)> {
+    if let Some(mac) = spec.strip_prefix("mac:") {
+        return resolve_mac(mac);
+    }
+
+    let (name, want_v6) = match spec.rsplit_once(':') {
+        Some((name, "v4")) => (name, Some(false)),
+        Some((name, "v6")) => (name, Some(true)),
+        // A bare interface name takes its preferred address
+        None if interface_exists(spec) => (spec, None),
+        _ => bail!("Invalid address {}", spec),
     };
+    interface_addresses(name, want_v6)
+}
+
+fn interface_exists(name: &str) -> bool {
+    get_if_addrs::get_if_addrs().is_ok_and(|interfaces| interfaces.iter().any(|iface| iface.name == name))
+}
 
-    let local_addr: SocketAddr = match ip {
-        IpAddr::V4(v4) => SocketAddr::new(IpAddr::V4(v4), 0),
-        IpAddr::V6(v6) => SocketAddr::new(IpAddr::V6(v6), 0),
+/// Resolve a MAC address, optionally followed by `:v4` or `:v6`, to the
+/// address of the interface that currently has it
+fn resolve_mac(spec: &str) -> Result<(IpAddr, Option<IpAddr>)> {
+    let (mac, want_v6) = match spec.rsplit_once(':') {
+        Some((mac, "v4")) => (mac, Some(false)),
+        Some((mac, "v6")) => (mac, Some(true)),
+        _ => (spec, None),
     };
+    let mac = mac.to_ascii_lowercase().replace('-', ":");
 
-    // Try to connect with a timeout
-    let result = tokio::time::timeout(Duration::from_secs(3), async {
-        let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP)).ok()?;
-        socket.set_reuse_address(true).ok()?;
-        socket.bind(&local_addr.into()).ok()?;
-        socket.set_nonblocking(true).ok()?;
+    let name = platform::interface_by_mac(&mac)
+        .ok_or_else(|| anyhow::anyhow!("No interface with MAC address {}", mac))?;
+    info!("MAC address {} is interface {}", mac, name);
+    interface_addresses(&name, want_v6)
+}
 
-        match socket.connect(&test_addr.into()) {
-            Ok(()) => {}
-            Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
-            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-            Err(_) => return None,
-        }
+/// The interface's address of the given family, or its preferred address and
+/// its global address in the other family when no family is given
+fn interface_addresses(name: &str, want_v6: Option<bool>) -> Result<(IpAddr, Option<IpAddr>)> {
+    let ip = interface_address(name, want_v6)?;
+    if want_v6.is_some() {
+        return Ok((ip, None));
+    }
+    let other = interface_address(name, Some(!ip.is_ipv6()))
+        .ok()
+        .filter(|other| !matches!(other, IpAddr::V6(v6) if (v6.segments()[0] & 0xffc0) == 0xfe80));
+    Ok((ip, other))
+}
 
-        let std_stream: std::net::TcpStream = socket.into();
-        let stream = tokio::net::TcpStream::from_std(std_stream).ok()?;
-        stream.writable().await.ok()?;
+/// Current address of an interface, of the given family if any. IPv4 is
+/// preferred, then global IPv6 over link-local.
+fn interface_address(name: &str, want_v6: Option<bool>) -> Result<IpAddr> {
+    let mut addresses: Vec<IpAddr> = get_if_addrs::get_if_addrs()?
+        .into_iter()
+        .filter(|iface| iface.name == name && want_v6.is_none_or(|v6| iface.ip().is_ipv6() == v6))
+        .map(|iface| iface.ip())
+        .collect();
+    addresses.sort_by_key(|ip| (ip.is_ipv6(), matches!(ip, IpAddr::V6(v6) if (v6.segments()[0] & 0xffc0) == 0xfe80)));
 
-        if stream.take_error().ok()?.is_some() {
-            return None;
-        }
+    let family = match want_v6 {
+        Some(true) => " IPv6",
+        Some(false) => " IPv4",
+        None => "",
+    };
+    addresses
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Interface {} has no{} address", name, family))
+}
+
+/// Test if an interface has working internet connectivity
+async fn test_interface_connectivity(ip: IpAddr, targets: &[SocketAddr]) -> bool {
+    health::check(ip, targets).await
+}
+
+/// Resolve the `--probe-target` addresses, domains to all their addresses
+async fn resolve_probe_targets(specs: &[String]) -> Result<Vec<SocketAddr>> {
+    let mut targets = Vec::new();
+    for spec in specs {
+        let resolved = tokio::net::lookup_host(spec.as_str())
+            .await
+            .with_context(|| format!("Could not resolve probe target {}", spec))?;
+        targets.extend(resolved);
+    }
+    Ok(targets)
+}
+
+/// Test if a tunnel endpoint accepts connections
+async fn test_tunnel_connectivity(address: &str) -> bool {
+    matches!(
+        tokio::time::timeout(latency::PROBE_TIMEOUT, TcpStream::connect(address)).await,
+        Ok(Ok(_))
+    )
+}
 
-        Some(())
-    })
-    .await;
+/// Keep the tunnel balancers whose endpoint accepts connections. Endpoints
+/// on the `{port}` template have no port to probe and blackholes nothing to
+/// reach, so both are kept as they are.
+async fn probe_tunnels(load_balancers: Vec<LoadBalancer>) -> Vec<LoadBalancer> {
+    let mut handles = Vec::new();
+    for lb in load_balancers {
+        handles.push(tokio::spawn(async move {
+            let works = lb.blackhole.is_some()
+                || lb.address.ends_with(&format!(":{}", PORT_TEMPLATE))
+                || test_tunnel_connectivity(&lb.address).await;
+            (lb, works)
+        }));
+    }
 
-    matches!(result, Ok(Some(())))
+    let mut reachable = Vec::new();
+    for handle in handles {
+        if let Ok((lb, works)) = handle.await {
+            if works {
+                reachable.push(lb);
+            } else {
+                warn!("Dropping tunnel {}, not reachable", lb.address);
+            }
+        }
+    }
+    reachable
 }
 
 /// Auto-detect interfaces with working internet connectivity
-async fn auto_detect_interfaces() -> Vec<(String, IpAddr)> {
+async fn auto_detect_interfaces(targets: &[SocketAddr]) -> Vec<(String, IpAddr)> {
     let mut interfaces = Vec::new();
 
     if let Ok(all_interfaces) = get_if_addrs::get_if_addrs() {
@@ -138,8 +833,9 @@ async fn auto_detect_interfaces() -> Vec<(String, IpAddr)> {
 
     for (name, ip) in interfaces {
         let name_clone = name.clone();
+        let targets = targets.to_vec();
         let handle = tokio::spawn(async move {
-            let works = test_interface_connectivity(ip).await;
+            let works = test_interface_connectivity(ip, &targets).await;
             (name_clone, ip, works)
         });
         handles.push(handle);
@@ -156,128 +852,827 @@ async fn auto_detect_interfaces() -> Vec<(String, IpAddr)> {
     working
 }
 
+/// Group working (interface, address) pairs into one entry per interface: its
+/// first address of the preferred family, plus its first of the other family
+/// when that works too. An address on several interfaces is warned about,
+/// since their balancers would share it.
+fn group_by_interface(mut working: Vec<(String, IpAddr)>, prefer: IpFamily) -> Vec<(String, IpAddr, Option<IpAddr>)> {
+    working.sort();
+    for (idx, (name, ip)) in working.iter().enumerate() {
+        if let Some((other, _)) = working[..idx].iter().find(|(other, addr)| addr == ip && other != name) {
+            warn!("IP address {} is on both {} and {}", ip, other, name);
+        }
+    }
+
+    let mut grouped: Vec<(String, IpAddr, Option<IpAddr>)> = Vec::new();
+    for (name, ip) in working {
+        match grouped.iter_mut().find(|(existing, _, _)| *existing == name) {
+            None => grouped.push((name, ip, None)),
+            Some(entry) => {
+                let (primary, other) = (entry.1, entry.2);
+                if primary.is_ipv6() == ip.is_ipv6() || other.is_some() {
+                    continue;
+                }
+                // Keep the preferred family as the address connections start from
+                *entry = if prefer.matches(ip.is_ipv6()) {
+                    (name, ip, Some(primary))
+                } else {
+                    (name, primary, Some(ip))
+                };
+            }
+        }
+    }
+    grouped
+}
+
 /// Parse an IP address that may be in bracket notation for IPv6
 fn parse_ip_address(s: &str) -> Option<IpAddr> {
+    parse_scoped_ip(s).map(|(ip, _)| ip)
+}
+
+/// Parse an IP address with the zone of an IPv6 address, as in `fe80::1%eth0`
+/// or `[fe80::1%eth0]`, naming the interface the address belongs to
+fn parse_scoped_ip(s: &str) -> Option<(IpAddr, Option<&str>)> {
     // Handle bracketed IPv6 addresses like [::1] or [fe80::1]
-    if s.starts_with('[') && s.ends_with(']') {
-        s[1..s.len() - 1].parse().ok()
-    } else {
-        s.parse().ok()
+    let s = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s);
+    match s.split_once('%') {
+        Some((ip, zone)) if !zone.is_empty() => match ip.parse().ok()? {
+            ip @ IpAddr::V6(_) => Some((ip, Some(zone))),
+            IpAddr::V4(_) => None,
+        },
+        Some(_) => None,
+        None => Some((s.parse().ok()?, None)),
     }
 }
 
-/// Parse load balancer addresses from command line arguments
-fn parse_load_balancers(args: &[String], tunnel: bool) -> Result<Vec<LoadBalancer>> {
-    if args.is_empty() {
-        bail!("Please specify one or more load balancers");
+/// Source address of an interface balancer. IPv6 link-local addresses are
+/// only unique on their link, so they carry the interface's index as scope
+/// id; binding to one without it fails.
+fn balancer_address(ip: IpAddr, iface: &str) -> String {
+    match ip {
+        IpAddr::V4(v4) => format!("{}:0", v4),
+        IpAddr::V6(v6) if is_link_local(ip) => {
+            SocketAddrV6::new(v6, 0, 0, platform::interface_index(iface).unwrap_or(0)).to_string()
+        }
+        IpAddr::V6(v6) => format!("[{}]:0", v6),
     }
+}
 
-    let mut load_balancers = Vec::with_capacity(args.len());
-
-    for (idx, arg) in args.iter().enumerate() {
-        let parts: Vec<&str> = arg.split('@').collect();
-        let address_part = parts[0];
-
-        // Parse contention ratio
-        let contention_ratio: u32 = if parts.len() > 1 {
-            parts[1]
-                .parse()
-                .map_err(|_| anyhow::anyhow!("Invalid contention ratio for {}", address_part))?
-        } else {
-            1
-        };
+/// Parse a duration such as `500ms`, `4s` or `1m` (bare numbers are seconds)
+fn parse_duration(s: &str) -> Option<Duration> {
+    let digits_end = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits_end);
+    let number: f64 = number.parse().ok()?;
 
-        if contention_ratio == 0 {
-            bail!("Invalid contention ratio for {}", address_part);
-        }
-
-        let (address, iface, is_ipv6) = if tunnel {
-            // Tunnel mode: expect host:port format
-            // Handle IPv6 addresses like [::1]:7777
-            let (host, port_str) = if address_part.starts_with('[') {
-                // IPv6 address in brackets
-                let bracket_end = address_part
-                    .find(']')
-                    .ok_or_else(|| anyhow::anyhow!("Invalid IPv6 address {}", address_part))?;
-                let host = &address_part[..=bracket_end];
-                let rest = &address_part[bracket_end + 1..];
-                if !rest.starts_with(':') {
-                    bail!("Invalid address specification {}", address_part);
-                }
-                (host.to_string(), &rest[1..])
-            } else {
-                // IPv4 or hostname
-                let parts: Vec<&str> = address_part.rsplitn(2, ':').collect();
-                if parts.len() != 2 {
-                    bail!("Invalid address specification {}", address_part);
-                }
-                (parts[1].to_string(), parts[0])
-            };
+    let secs = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        _ => return None,
+    };
 
-            let port: u16 = port_str
-                .parse()
-                .map_err(|_| anyhow::anyhow!("Invalid port {}", address_part))?;
+    (secs > 0.0).then(|| Duration::from_secs_f64(secs))
+}
 
-            if port == 0 {
-                bail!("Invalid port {}", address_part);
-            }
+/// Parse a byte size such as `500mb` or `100gb` (decimal units, as used by carriers)
+fn parse_byte_size(s: &str) -> Option<u64> {
+    let s = s.to_ascii_lowercase();
+    let digits_end = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits_end);
+    let number: f64 = number.parse().ok()?;
 
-            let is_ipv6 = host.starts_with('[');
-            (format!("{}:{}", host, port), None, is_ipv6)
-        } else {
-            // Normal mode: expect IP address
-            let ip: IpAddr = parse_ip_address(address_part)
-                .ok_or_else(|| anyhow::anyhow!("Invalid address {}", address_part))?;
+    let multiplier = match unit.trim_end_matches('b') {
+        "" => 1u64,
+        "k" => 1_000,
+        "m" => 1_000_000,
+        "g" => 1_000_000_000,
+        "t" => 1_000_000_000_000,
+        _ => return None,
+    };
 
-            let iface = get_iface_from_ip(&ip)
-                .ok_or_else(|| anyhow::anyhow!("IP address not associated with an interface {}", ip))?;
+    let bytes = (number * multiplier as f64) as u64;
+    (bytes > 0).then_some(bytes)
+}
 
-            let is_ipv6 = ip.is_ipv6();
-            let address = match ip {
-                IpAddr::V4(v4) => format!("{}:0", v4),
-                IpAddr::V6(v6) => format!("[{}]:0", v6),
-            };
+/// Parse a rate in bytes per second such as `5mb`, or in bits with a `bit`
+/// suffix such as `40mbit`; a trailing `/s` is accepted
+fn parse_rate(s: &str) -> Option<u64> {
+    let s = s.to_ascii_lowercase();
+    let s = s.strip_suffix("/s").unwrap_or(&s);
+    match s.strip_suffix("bit") {
+        Some(bits) => parse_byte_size(bits).map(|bits| bits / 8).filter(|&bytes| bytes > 0),
+        None => parse_byte_size(s),
+    }
+}
 
-            (address, Some(iface), is_ipv6)
-        };
+/// Parse the `key=value` option suffixes that follow the contention ratio,
+/// which a `schedule=` returns to outside its windows
+fn parse_balancer_options(opts: &[&str], address_part: &str, contention_ratio: u32) -> Result<BalancerOptions> {
+    let mut options = BalancerOptions::default();
 
-        let port_display = if tunnel {
-            let port = address.rsplit(':').next().unwrap_or("0");
-            format!(":{}", port)
-        } else {
-            String::new()
-        };
+    for opt in opts {
+        if *opt == "metered" {
+            options.metered = true;
+            continue;
+        }
 
-        info!(
-            "Load balancer {}: {}{}, contention ratio: {}",
-            idx + 1,
-            address_part,
-            if tunnel { &port_display } else { "" },
-            contention_ratio
-        );
+        let (key, value) = opt
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Invalid option {} for {}", opt, address_part))?;
 
-        load_balancers.push(LoadBalancer::new(address, iface, contention_ratio, is_ipv6));
+        match key {
+            "ttl" => {
+                let ttl: u32 = value
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid ttl for {}", address_part))?;
+                if ttl == 0 || ttl > 255 {
+                    bail!("Invalid ttl for {}", address_part);
+                }
+                options.ttl = Some(ttl);
+            }
+            "timeout" => {
+                let timeout = parse_duration(value)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid timeout for {}", address_part))?;
+                options.connect_timeout = Some(timeout);
+            }
+            "quota" => {
+                let quota = parse_byte_size(value)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid quota for {}", address_part))?;
+                options.quota = Some(quota);
+            }
+            "flowlabel" => {
+                let label = match value {
+                    "random" => Some(FlowLabel::Random),
+                    _ => match value.strip_prefix("0x") {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                        None => value.parse().ok(),
+                    }
+                    .filter(|&label| label > 0 && label <= 0xf_ffff)
+                    .map(FlowLabel::Fixed),
+                };
+                options.flow_label =
+                    Some(label.ok_or_else(|| anyhow::anyhow!("Invalid flowlabel for {}", address_part))?);
+            }
+            "vrf" => {
+                if !cfg!(target_os = "linux") {
+                    bail!("VRF binding is only supported on Linux ({})", address_part);
+                }
+                // Interface names are limited to IFNAMSIZ - 1 bytes
+                if value.is_empty() || value.len() > 15 || value.contains('/') {
+                    bail!("Invalid vrf for {}", address_part);
+                }
+                if !std::path::Path::new("/sys/class/net").join(value).exists() {
+                    warn!("VRF device {} for {} does not exist yet", value, address_part);
+                }
+                options.vrf = Some(value.to_string());
+            }
+            "mark" => {
+                if !cfg!(target_os = "linux") {
+                    bail!("Firewall marks are only supported on Linux ({})", address_part);
+                }
+                let mark = match value.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => value.parse().ok(),
+                };
+                options.mark = Some(mark.ok_or_else(|| anyhow::anyhow!("Invalid mark for {}", address_part))?);
+            }
+            "rest" => {
+                let rest = value.split_once('/').and_then(|(connections, window)| {
+                    Some(RestLimit {
+                        connections: connections.parse().ok().filter(|&n| n > 0)?,
+                        window: parse_duration(window)?,
+                    })
+                });
+                options.rest = Some(rest.ok_or_else(|| anyhow::anyhow!("Invalid rest for {}", address_part))?);
+            }
+            "max" => {
+                let max: u32 = value
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid max for {}", address_part))?;
+                options.max_connections = Some(max);
+            }
+            "label" => {
+                if value.is_empty() {
+                    bail!("Invalid label for {}", address_part);
+                }
+                options.label = Some(value.to_string());
+            }
+            "rate" => {
+                let rate = parse_rate(value).ok_or_else(|| anyhow::anyhow!("Invalid rate for {}", address_part))?;
+                options.rate = Some(rate);
+            }
+            "schedule" => {
+                let schedule = schedule::RatioSchedule::parse(value, contention_ratio)
+                    .with_context(|| format!("Invalid schedule for {}", address_part))?;
+                options.schedule = Some(schedule);
+            }
+            _ => bail!("Unknown option {} for {}", key, address_part),
+        }
     }
 
-    Ok(load_balancers)
+    Ok(options)
 }
 
-async fn handle_connection(
-    mut client: tokio::net::TcpStream,
-    pool: Arc<LoadBalancerPool>,
-    tunnel: bool,
-) {
-    if tunnel {
-        if let Err(e) = handle_tunnel_connection(client, pool).await {
-            warn!("Tunnel connection error: {}", e);
+/// Placeholder in a tunnel balancer address replaced by the original destination port
+const PORT_TEMPLATE: &str = "{port}";
+
+/// Parse a `host:port` or `[v6]:port` address, keeping the IPv6 brackets on the host
+fn parse_host_port(address_part: &str) -> Result<(String, u16)> {
+    // Handle IPv6 addresses like [::1]:7777
+    let (host, port_str) = if address_part.starts_with('[') {
+        // IPv6 address in brackets
+        let bracket_end = address_part
+            .find(']')
+            .ok_or_else(|| anyhow::anyhow!("Invalid IPv6 address {}", address_part))?;
+        let host = &address_part[..=bracket_end];
+        let rest = &address_part[bracket_end + 1..];
+        if !rest.starts_with(':') {
+            bail!("Invalid address specification {}", address_part);
         }
+        (host.to_string(), &rest[1..])
     } else {
-        match socks::handle_socks_handshake(&mut client).await {
-            Ok((target_addr, target_type)) => {
-                if let Err(e) = platform::connect_and_relay(client, &target_addr, target_type, pool).await {
-                    warn!("Connection error: {}", e);
-                }
-            }
+        // IPv4 or hostname
+        let parts: Vec<&str> = address_part.rsplitn(2, ':').collect();
+        if parts.len() != 2 {
+            bail!("Invalid address specification {}", address_part);
+        }
+        (parts[1].to_string(), parts[0])
+    };
+
+    let port: u16 = port_str
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid port {}", address_part))?;
+
+    if port == 0 {
+        bail!("Invalid port {}", address_part);
+    }
+
+    Ok((host, port))
+}
+
+/// Split an upstream proxy specification (`http://[user:pass@]host:port...`,
+/// or `socks5://` for a SOCKS5 upstream) into its upstream description and the
+/// remaining `host:port@ratio...` part
+fn parse_upstream_prefix(arg: &str) -> Option<(Upstream, &str)> {
+    let (kind, rest) = if let Some(rest) = arg.strip_prefix("http://") {
+        (UpstreamKind::Http, rest)
+    } else if let Some(rest) = arg.strip_prefix("socks5://") {
+        (UpstreamKind::Socks5, rest)
+    } else {
+        return None;
+    };
+
+    // `user:pass@host:port@ratio` - credentials are present when the segment
+    // after the first `@` is itself a host:port rather than a ratio or option
+    let mut credentials = None;
+    let mut spec = rest;
+    if let Some((userinfo, remainder)) = rest.split_once('@') {
+        let next = remainder.split('@').next().unwrap_or_default();
+        if next.contains(':') && parse_host_port(next).is_ok() {
+            let (user, pass) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+            credentials = Some((user.to_string(), pass.to_string()));
+            spec = remainder;
+        }
+    }
+
+    Some((Upstream { kind, credentials }, spec))
+}
+
+/// Parse load balancer addresses from command line arguments
+fn parse_load_balancers(args: &[String], tunnel: bool, on_duplicate: DuplicatePolicy, percent: bool) -> Result<Vec<LoadBalancer>> {
+    if args.is_empty() {
+        bail!("Please specify one or more load balancers");
+    }
+
+    let load_balancers = args
+        .iter()
+        .enumerate()
+        .map(|(idx, arg)| parse_load_balancer(idx, arg, tunnel))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut load_balancers = merge_duplicates(load_balancers, on_duplicate)?;
+    if percent {
+        let percentages: Vec<u32> = load_balancers.iter().map(|lb| lb.contention_ratio).collect();
+        let ratios = percent_ratios(&percentages)?;
+        info!(
+            "Percentages {} give contention ratios {}",
+            join_ratios(&percentages, "/"),
+            join_ratios(&ratios, ":")
+        );
+        for (lb, ratio) in load_balancers.iter_mut().zip(ratios) {
+            lb.contention_ratio = ratio;
+            if let Some(schedule) = &mut lb.options.schedule {
+                schedule.set_base(ratio);
+            }
+        }
+    }
+    Ok(load_balancers)
+}
+
+/// The smallest contention ratios splitting connections by the percentages,
+/// which must add up to 100
+fn percent_ratios(percentages: &[u32]) -> Result<Vec<u32>> {
+    let total: u64 = percentages.iter().map(|&p| u64::from(p)).sum();
+    if total != 100 {
+        bail!(
+            "Percentage weights {} add up to {}%, not 100%",
+            join_ratios(percentages, "/"),
+            total
+        );
+    }
+    let divisor = percentages.iter().fold(0, |a, &b| gcd(a, b)).max(1);
+    Ok(percentages.iter().map(|&p| p / divisor).collect())
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+fn join_ratios(values: &[u32], separator: &str) -> String {
+    values.iter().map(u32::to_string).collect::<Vec<_>>().join(separator)
+}
+
+/// Parse one load balancer specification, logged as the balancer at `idx`
+fn parse_load_balancer(idx: usize, arg: &str, tunnel: bool) -> Result<LoadBalancer> {
+    let (upstream, spec) = match parse_upstream_prefix(arg) {
+        Some((upstream, spec)) => (Some(upstream), spec),
+        None => (None, arg),
+    };
+
+    let parts: Vec<&str> = spec.split('@').collect();
+    let address_part = parts[0];
+
+    // Parse contention ratio
+    let contention_ratio: u32 = if parts.len() > 1 {
+        parts[1]
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid contention ratio for {}", address_part))?
+    } else {
+        1
+    };
+
+    if contention_ratio == 0 {
+        bail!("Invalid contention ratio for {}", address_part);
+    }
+
+    let options = parse_balancer_options(parts.get(2..).unwrap_or_default(), address_part, contention_ratio)?;
+
+    if upstream.is_some() && tunnel {
+        bail!("Upstream proxies are not supported in tunnel mode {}", arg);
+    }
+
+    let blackhole = match address_part {
+        "blackhole" => Some(BlackholeMode::Refuse),
+        "blackhole:timeout" => Some(BlackholeMode::Timeout),
+        _ => None,
+    };
+    if blackhole.is_some() && upstream.is_some() {
+        bail!("Invalid address specification {}", arg);
+    }
+
+    let mut other_family = None;
+    let (address, iface, is_ipv6) = if blackhole.is_some() {
+        (address_part.to_string(), None, false)
+    } else if tunnel || upstream.is_some() {
+        // Tunnel mode and upstream proxies: expect host:port format.
+        // In tunnel mode the port may be the `{port}` template, which is
+        // replaced by the client's original destination port.
+        if let Some(host) = address_part.strip_suffix(&format!(":{}", PORT_TEMPLATE)).filter(|_| tunnel) {
+            if host.is_empty() {
+                bail!("Invalid address specification {}", address_part);
+            }
+            (address_part.to_string(), None, host.starts_with('['))
+        } else {
+            let (host, port) = parse_host_port(address_part)?;
+            let is_ipv6 = host.starts_with('[');
+            (format!("{}:{}", host, port), None, is_ipv6)
+        }
+    } else {
+        // Normal mode: expect an IP address or interface:family
+        let (ip, other, zone) = match parse_scoped_ip(address_part) {
+            Some((ip, zone)) => (ip, None, zone),
+            None => {
+                let (ip, other) = resolve_iface_family(address_part)?;
+                (ip, other, None)
+            }
+        };
+        other_family = other;
+
+        // A zone picks the interface, since a link-local address can be on several.
+        // Interface listings leave link-local addresses out, so trying a bind
+        // to it is what tells whether the address is on the interface.
+        let iface = match zone {
+            Some(zone) => {
+                let scope = platform::interface_index(zone).ok_or_else(|| anyhow::anyhow!("No interface {}", zone))?;
+                if let IpAddr::V6(v6) = ip {
+                    std::net::UdpSocket::bind(SocketAddrV6::new(v6, 0, 0, scope))
+                        .with_context(|| format!("IP address {} is not an address of interface {}", ip, zone))?;
+                }
+                zone.to_string()
+            }
+            None if is_link_local(ip) && ip.is_ipv6() => {
+                bail!("Link-local address {} needs its interface as a zone, as in {}%eth0", ip, ip)
+            }
+            None => get_iface_from_ip(&ip)
+                .ok_or_else(|| anyhow::anyhow!("IP address not associated with an interface {}", ip))?,
+        };
+
+        let is_ipv6 = ip.is_ipv6();
+        let address = balancer_address(ip, &iface);
+
+        (address, Some(iface), is_ipv6)
+    };
+
+    let port_display = if tunnel && blackhole.is_none() {
+        let port = address.rsplit(':').next().unwrap_or("0");
+        format!(":{}", port)
+    } else {
+        String::new()
+    };
+
+    info!(
+        "Load balancer {}: {}{}, contention ratio: {}",
+        idx + 1,
+        match &upstream {
+            Some(upstream) => format!("{}://{}", upstream.kind.scheme(), address_part),
+            None => address_part.to_string(),
+        },
+        if tunnel { &port_display } else { "" },
+        contention_ratio
+    );
+
+    let mut lb = LoadBalancer::new(address, iface, contention_ratio, is_ipv6).with_options(options);
+    if let Some(upstream) = upstream {
+        lb = lb.with_upstream(upstream);
+    }
+    if let Some(mode) = blackhole {
+        lb = lb.with_blackhole(mode);
+    }
+    if let Some(ip) = other_family {
+        info!("Load balancer {} is dual-stack, also connecting from {}", idx + 1, ip);
+        lb = lb.with_other_family(ip);
+    }
+    Ok(lb)
+}
+
+/// Detect balancers specified more than once, which would otherwise silently
+/// double their share of connections
+fn merge_duplicates(load_balancers: Vec<LoadBalancer>, policy: DuplicatePolicy) -> Result<Vec<LoadBalancer>> {
+    if policy == DuplicatePolicy::Allow {
+        return Ok(load_balancers);
+    }
+
+    let mut merged: Vec<LoadBalancer> = Vec::with_capacity(load_balancers.len());
+    for lb in load_balancers {
+        let identity = lb.identity();
+        let existing = identity
+            .as_ref()
+            .and_then(|id| merged.iter_mut().find(|m| m.identity().as_ref() == Some(id)));
+
+        let name = lb.source_ip().map_or_else(|| lb.address.clone(), |ip| ip.to_string());
+
+        match existing {
+            None => merged.push(lb),
+            Some(_) if policy == DuplicatePolicy::Error => {
+                bail!("Load balancer {} is specified more than once", name);
+            }
+            Some(existing) => {
+                existing.contention_ratio += lb.contention_ratio;
+                warn!(
+                    "Load balancer {} is specified more than once, merged into contention ratio {}",
+                    name, existing.contention_ratio
+                );
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// Parse a `primary=secondary` mirror pair into balancer indices. Balancers are
+/// named by their address as given on the command line.
+fn parse_mirror(spec: &str, balancers: &[LoadBalancer]) -> Result<(usize, usize)> {
+    let find = |name: &str| {
+        let ip = parse_ip_address(name);
+        balancers
+            .iter()
+            .position(|lb| ip.is_some() && lb.source_ip() == ip || lb.address == name)
+            .ok_or_else(|| anyhow::anyhow!("Mirror balancer {} is not a configured load balancer", name))
+    };
+
+    let (primary, secondary) = spec
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Invalid mirror {}, expected PRIMARY=SECONDARY", spec))?;
+    let (primary, secondary) = (find(primary)?, find(secondary)?);
+    if primary == secondary {
+        bail!("Invalid mirror {}, a balancer cannot mirror itself", spec);
+    }
+
+    info!("Mirroring load balancer {} to {}", primary + 1, secondary + 1);
+    Ok((primary, secondary))
+}
+
+/// Handshake clients of a SOCKS or HTTP CONNECT listener speak
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ListenMode {
+    Socks,
+    Http,
+}
+
+impl std::fmt::Display for ListenMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ListenMode::Socks => write!(f, "SOCKS"),
+            ListenMode::Http => write!(f, "HTTP CONNECT"),
+        }
+    }
+}
+
+/// Addresses and modes of the listeners from --listen, or --lhost/--lport,
+/// expanded for --dual-stack
+fn listen_addresses(args: &Args) -> Result<Vec<(SocketAddr, ListenMode)>> {
+    let default_mode = if args.http { ListenMode::Http } else { ListenMode::Socks };
+    let listens = if args.listen.is_empty() {
+        let lhost: IpAddr = args.lhost.parse()?;
+        vec![(SocketAddr::new(lhost, args.lport), default_mode)]
+    } else {
+        let listens = args
+            .listen
+            .iter()
+            .map(|spec| parse_listen(spec, default_mode))
+            .collect::<Result<Vec<_>>>()?;
+        if (args.tunnel || args.transparent) && listens.iter().any(|&(_, mode)| mode != default_mode) {
+            bail!("Listener modes cannot be used in tunnel or transparent mode");
+        }
+        listens
+    };
+    if !args.dual_stack {
+        return Ok(listens);
+    }
+    let mut expanded = Vec::new();
+    for (addr, mode) in listens {
+        expanded.extend(dual_stack_addresses(addr)?.into_iter().map(|addr| (addr, mode)));
+    }
+    Ok(expanded)
+}
+
+/// Addresses to listen on for `addr` with --dual-stack: [::] for either
+/// unspecified address, and both loopback addresses for either loopback one
+fn dual_stack_addresses(addr: SocketAddr) -> Result<Vec<SocketAddr>> {
+    let port = addr.port();
+    match addr.ip() {
+        ip if ip.is_unspecified() => Ok(vec![SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port)]),
+        ip if ip.is_loopback() => Ok(vec![
+            SocketAddr::new(std::net::Ipv4Addr::LOCALHOST.into(), port),
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port),
+        ]),
+        ip => bail!("--dual-stack needs an unspecified or loopback listen address, {} has a single family", ip),
+    }
+}
+
+/// Parse a `--listen` entry, `ADDR:PORT` optionally followed by `:socks` or
+/// `:http` or preceded by `socks5://` or `http://`; entries without a mode
+/// speak `default`
+fn parse_listen(spec: &str, default: ListenMode) -> Result<(SocketAddr, ListenMode)> {
+    let (addr, mode) = if let Some(addr) = spec.strip_prefix("socks5://") {
+        (addr, ListenMode::Socks)
+    } else if let Some(addr) = spec.strip_prefix("http://") {
+        (addr, ListenMode::Http)
+    } else {
+        match spec.rsplit_once(':') {
+            Some((addr, "socks")) => (addr, ListenMode::Socks),
+            Some((addr, "http")) => (addr, ListenMode::Http),
+            _ => (spec, default),
+        }
+    };
+    let addr = addr.parse().map_err(|_| {
+        anyhow::anyhow!("Invalid listen address {}, expected ADDR:PORT[:socks|:http], socks5://ADDR:PORT or http://ADDR:PORT", spec)
+    })?;
+    Ok((addr, mode))
+}
+
+/// What the accept loops of all listeners share
+#[derive(Clone)]
+struct Server {
+    shared: Arc<SharedPool>,
+    settings: Arc<Settings>,
+    active: ActiveConnections,
+    limit: Option<ConnectionLimit>,
+    #[cfg(feature = "tls")]
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+}
+
+/// Accept clients on one listener and handle each in its own task, until
+/// the loop is aborted at shutdown
+async fn accept_loop(listener: TcpListener, mode: ListenMode, own_addresses: Vec<SocketAddr>, mut server: Server) {
+    let listen_addr = listener.local_addr().unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let mut shedder = Shedder::new(server.active.clone());
+    loop {
+        let permit = match &mut server.limit {
+            Some(limit) => Some(limit.acquire().await),
+            None => None,
+        };
+        match listener.accept().await {
+            Ok((socket, client_addr)) => {
+                shedder.accepted();
+                let connection = server.active.track();
+                // IPv4 clients of a dual-stack listener arrive as v4-mapped IPv6 addresses
+                let client_addr = SocketAddr::new(client_addr.ip().to_canonical(), client_addr.port());
+                let local_addr = socket.local_addr().unwrap_or(listen_addr);
+                let local_addr = SocketAddr::new(local_addr.ip().to_canonical(), local_addr.port());
+                let pool = server.shared.current();
+                let settings = Arc::clone(&server.settings);
+                if settings.refuse_below_healthy.is_some_and(|min| pool.healthy_count() < min) {
+                    debug!("Refusing {}, fewer than the required load balancers are healthy", client_addr);
+                    continue;
+                }
+                let original_dst = if settings.tunnel {
+                    platform::original_destination(&socket)
+                } else if settings.transparent {
+                    // A connection made to the listener itself was not intercepted,
+                    // and relaying it would connect straight back
+                    platform::transparent_destination(&socket).filter(|dst| !own_addresses.contains(dst))
+                } else {
+                    None
+                };
+                if let Some(keepalive) = &settings.keepalive {
+                    platform::set_keepalive(&socket, keepalive);
+                }
+                platform::set_nodelay(&socket, settings.nodelay);
+
+                #[cfg(feature = "tls")]
+                if let Some(acceptor) = &server.tls_acceptor {
+                    let acceptor = acceptor.clone();
+                    tokio::spawn(async move {
+                        let _connection = (connection, permit);
+                        let accept = acceptor.accept(socket);
+                        let accepted = match settings.handshake_timeout {
+                            Some(limit) => tokio::time::timeout(limit, accept).await.unwrap_or_else(|_| {
+                                Err(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("timed out after {:?}", limit)))
+                            }),
+                            None => accept.await,
+                        };
+                        match accepted {
+                            Ok(stream) => {
+                                handle_connection(stream, client_addr, local_addr, mode, original_dst, pool, settings).await
+                            }
+                            Err(e) => warn!("TLS handshake error from {}: {}", client_addr, e),
+                        }
+                    });
+                    continue;
+                }
+
+                tokio::spawn(async move {
+                    let _connection = (connection, permit);
+                    handle_connection(socket, client_addr, local_addr, mode, original_dst, pool, settings).await;
+                });
+            }
+            Err(e) if shedding::is_fd_exhaustion(&e) => shedder.shed(&e).await,
+            Err(e) => {
+                warn!("Could not accept connection: {}", e);
+            }
+        }
+    }
+}
+
+/// Addresses connections reach the listener on: its own, or every local
+/// address of its family with its port when it listens on all of them
+fn listener_addresses(listener: &TcpListener) -> Result<Vec<SocketAddr>> {
+    let addr = listener.local_addr()?;
+    if !addr.ip().is_unspecified() {
+        return Ok(vec![addr]);
+    }
+    Ok(get_if_addrs::get_if_addrs()?
+        .into_iter()
+        .map(|iface| SocketAddr::new(iface.ip(), addr.port()))
+        .filter(|own| own.is_ipv6() == addr.is_ipv6())
+        .collect())
+}
+
+async fn handle_connection<S: ClientStream>(
+    mut client: S,
+    client_addr: SocketAddr,
+    local_addr: SocketAddr,
+    mode: ListenMode,
+    original_dst: Option<SocketAddr>,
+    pool: Arc<LoadBalancerPool>,
+    settings: Arc<Settings>,
+) {
+    if settings.tunnel {
+        let (client_addr, original_dst) = match settings.proxy_protocol {
+            Some(accepted) => {
+                let header = within_handshake_timeout(&settings, proxy_protocol::read_header(&mut client, accepted)).await;
+                match header {
+                    Ok(Some((source, destination))) => (source, Some(destination)),
+                    Ok(None) => (client_addr, original_dst),
+                    Err(e) => {
+                        warn!("PROXY protocol error from {}: {}", client_addr, e);
+                        return;
+                    }
+                }
+            }
+            None => (client_addr, original_dst),
+        };
+
+        if let Err(e) = handle_tunnel_connection(client, client_addr, original_dst, local_addr, pool, &settings).await {
+            warn!("Tunnel connection error: {}", e);
+        }
+    } else if settings.transparent {
+        let Some(destination) = original_dst else {
+            warn!("No original destination for connection from {}, was it intercepted?", client_addr);
+            return;
+        };
+        let target_type = if destination.is_ipv6() { TargetAddressType::IPv6 } else { TargetAddressType::IPv4 };
+        let protocol = platform::ClientProtocol::Transparent;
+        if let Err(e) = platform::connect_and_relay(
+            client,
+            protocol,
+            client_addr,
+            None,
+            &destination.to_string(),
+            target_type,
+            pool,
+            &settings,
+        )
+        .await
+        {
+            warn!("Transparent connection error: {}", e);
+        }
+    } else if mode == ListenMode::Http {
+        if settings.lockout.as_ref().is_some_and(|l| l.is_locked(client_addr.ip())) {
+            debug!("Refusing locked-out client {}", client_addr);
+            return;
+        }
+        let request = http_connect::handle_connect_request(
+            &mut client,
+            settings.http_max_head,
+            &settings.credentials,
+            settings.tarpit.as_ref(),
+            settings.lockout.as_ref(),
+            client_addr.ip(),
+        );
+        let result = within_handshake_timeout(&settings, request).await;
+
+        match result {
+            Ok((target_addr, target_type, username)) => {
+                let protocol = platform::ClientProtocol::HttpConnect;
+                let username = username.as_deref();
+                if let Err(e) = platform::connect_and_relay(
+                    client,
+                    protocol,
+                    client_addr,
+                    username,
+                    &target_addr,
+                    target_type,
+                    pool,
+                    &settings,
+                )
+                .await
+                {
+                    warn!("Connection error: {}", e);
+                }
+            }
+            Err(e) => {
+                warn!("HTTP CONNECT error: {}", e);
+            }
+        }
+    } else {
+        if settings.lockout.as_ref().is_some_and(|l| l.is_locked(client_addr.ip())) {
+            debug!("Refusing locked-out client {}", client_addr);
+            return;
+        }
+        match socks_handshake(&mut client, client_addr.ip(), &settings).await {
+            Ok((command @ (socks::Command::Connect | socks::Command::Socks4Connect), target_addr, target_type, username)) => {
+                let protocol = match command {
+                    socks::Command::Socks4Connect => platform::ClientProtocol::Socks4,
+                    _ => platform::ClientProtocol::Socks5,
+                };
+                let username = username.as_deref();
+                if let Err(e) = platform::connect_and_relay(
+                    client,
+                    protocol,
+                    client_addr,
+                    username,
+                    &target_addr,
+                    target_type,
+                    pool,
+                    &settings,
+                )
+                .await
+                {
+                    warn!("Connection error: {}", e);
+                }
+            }
+            Ok((socks::Command::UdpAssociate, ..)) => {
+                if let Err(e) = udp::associate(client, client_addr, local_addr.ip(), pool, &settings).await {
+                    warn!("UDP association error: {}", e);
+                }
+            }
             Err(e) => {
                 warn!("SOCKS handshake error: {}", e);
             }
@@ -285,28 +1680,100 @@ async fn handle_connection(
     }
 }
 
-async fn handle_tunnel_connection(
-    client: tokio::net::TcpStream,
+/// Run a step of a client's handshake, failing it once --handshake-timeout passes
+async fn within_handshake_timeout<T>(settings: &Settings, step: impl std::future::Future<Output = Result<T>>) -> Result<T> {
+    match settings.handshake_timeout {
+        Some(limit) => tokio::time::timeout(limit, step)
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out after {:?}", limit))),
+        None => step.await,
+    }
+}
+
+/// Read a client's SOCKS handshake, authentication included, within --handshake-timeout
+async fn socks_handshake<S: ClientStream>(
+    client: &mut S,
+    client_ip: IpAddr,
+    settings: &Settings,
+) -> Result<(socks::Command, String, TargetAddressType, Option<String>)> {
+    let handshake = socks::handle_socks_handshake(
+        client,
+        settings.strict_socks,
+        settings.required_auth_method,
+        &settings.credentials,
+        settings.tarpit.as_ref(),
+        settings.lockout.as_ref(),
+        client_ip,
+    );
+    within_handshake_timeout(settings, handshake).await
+}
+
+async fn handle_tunnel_connection<S: ClientStream>(
+    client: S,
+    client_addr: SocketAddr,
+    original_dst: Option<SocketAddr>,
+    local_addr: SocketAddr,
     pool: Arc<LoadBalancerPool>,
+    settings: &Settings,
 ) -> Result<()> {
-    use tokio::io::copy_bidirectional;
-    use tokio::net::TcpStream;
+    use tokio::io::AsyncWriteExt;
 
     let mut tried = vec![false; pool.len()];
+    let deadline = settings.connect_deadline.map(|budget| tokio::time::Instant::now() + budget);
 
     loop {
         // Tunnel mode doesn't know the target type, use None
-        let (lb, idx) = pool.get_load_balancer(Some(&tried), None);
+        let (lb, idx) = pool
+            .get_load_balancer(Some(&tried), None, None, Some(client_addr.ip()))
+            .ok_or_else(|| anyhow::anyhow!("No load balancers available"))?;
+        let _lease = pool.lease(idx);
+
+        let address = if lb.address.contains(PORT_TEMPLATE) {
+            let original_dst = original_dst
+                .ok_or_else(|| anyhow::anyhow!("Original destination unavailable for {}", lb.address))?;
+            lb.address.replace(PORT_TEMPLATE, &original_dst.port().to_string())
+        } else {
+            lb.address.clone()
+        };
 
-        match TcpStream::connect(&lb.address).await {
+        let started = tokio::time::Instant::now();
+        let timeout = lb.options.connect_timeout.or(settings.connect_timeout);
+        let connecting = async {
+            match lb.blackhole {
+                Some(mode) => platform::connect_blackhole(mode, timeout).await,
+                None => platform::with_retries(settings.retry, &address, idx, || platform::connect_tunnel(&address, timeout)).await,
+            }
+        };
+        let Some(result) = platform::within_deadline(deadline, connecting).await else {
+            anyhow::bail!("Connect deadline exceeded for {}", address);
+        };
+
+        match result {
             Ok(mut remote) => {
+                pool.record_success(idx, started.elapsed());
+                if let Some(version) = settings.send_proxy_protocol {
+                    // Without an original destination the client connected to the listener itself
+                    let header = proxy_protocol::header(version, client_addr, original_dst.unwrap_or(local_addr));
+                    remote
+                        .write_all(&header)
+                        .await
+                        .with_context(|| format!("Could not send PROXY protocol header to {}", address))?;
+                }
                 let mut client = client;
-                info!("Tunnelled to {} LB: {}", lb.address, idx);
-                let _ = copy_bidirectional(&mut client, &mut remote).await;
+                info!("Tunnelled to {} LB: {}", address, idx + 1);
+                let flow = relay::Flow {
+                    client_addr,
+                    target: &address,
+                    lb: &lb,
+                    lb_index: idx,
+                    pool: &pool,
+                };
+                let _ = relay::relay(&mut client, &mut remote, &flow, settings).await;
                 return Ok(());
             }
             Err(e) => {
-                warn!("{} {{{}}} LB: {}", lb.address, e, idx);
+                warn!("{} {{{}}} LB: {}", address, e, idx + 1);
+                pool.record_failure(idx);
                 tried[idx] = true;
 
                 if tried.iter().all(|&t| t) {
@@ -318,54 +1785,71 @@ async fn handle_tunnel_connection(
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     let args = Args::parse();
 
-    // Handle list mode
-    if args.list {
-        detect_interfaces();
-        return Ok(());
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = args.worker_threads {
+        runtime.worker_threads(threads as usize);
     }
+    runtime.build()?.block_on(run(args))
+}
 
-    // Setup logging (do this early for auto-detect feedback)
-    if !args.quiet {
-        let subscriber = FmtSubscriber::builder()
-            .with_max_level(Level::DEBUG)
-            .with_target(false)
-            .with_thread_ids(false)
-            .without_time()
-            .finish();
-        tracing::subscriber::set_global_default(subscriber)?;
-    }
+/// Log whenever the runtime falls behind a periodic tick by `threshold`,
+/// which means some task held a worker thread without yielding
+fn spawn_stall_detector(threshold: Duration) {
+    const TICK: Duration = Duration::from_millis(100);
 
-    // Determine load balancers
-    let load_balancers = if args.auto {
-        if args.tunnel {
-            bail!("Auto-detection is not supported in tunnel mode");
+    tokio::spawn(async move {
+        loop {
+            let start = tokio::time::Instant::now();
+            tokio::time::sleep(TICK).await;
+            let lag = start.elapsed().saturating_sub(TICK);
+            if lag >= threshold {
+                warn!("Runtime stalled for {} ms, a task blocked a worker thread", lag.as_millis());
+            }
         }
+    });
+}
 
+/// Determine the load balancers from auto-detection or the command line and
+/// --balancer-url, keeping only those of the --ipv4-only/--ipv6-only family,
+/// and the strategy, which --config may set instead of --strategy.
+/// Run at startup and again on each reload.
+async fn load_balancers(args: &Args, ip_family: Option<IpFamily>) -> Result<(Vec<LoadBalancer>, Strategy)> {
+    let mut strategy = args.strategy;
+    let mut load_balancers = if args.auto && !args.tunnel {
         info!("Auto-detecting interfaces with internet connectivity...");
-        let working = auto_detect_interfaces().await;
+        let targets = resolve_probe_targets(&args.probe_target).await?;
+        let working = auto_detect_interfaces(&targets).await;
 
         if working.is_empty() {
             bail!("No interfaces with working internet connectivity found");
         }
 
+        let working = if args.auto_per_address {
+            working.into_iter().map(|(name, ip)| (name, ip, None)).collect()
+        } else {
+            group_by_interface(working, args.auto_prefer)
+        };
+
         let mut lbs = Vec::new();
-        for (idx, (name, ip)) in working.iter().enumerate() {
+        for (idx, (name, ip, other)) in working.iter().enumerate() {
             let is_ipv6 = ip.is_ipv6();
-            let address = match ip {
-                IpAddr::V4(v4) => format!("{}:0", v4),
-                IpAddr::V6(v6) => format!("[{}]:0", v6),
-            };
+            let address = balancer_address(*ip, name);
             info!(
                 "Load balancer {}: {} ({}), contention ratio: 1",
                 idx + 1,
                 ip,
                 name
             );
-            lbs.push(LoadBalancer::new(address, Some(name.clone()), 1, is_ipv6));
+            let mut lb = LoadBalancer::new(address, Some(name.clone()), 1, is_ipv6);
+            if let Some(other) = *other {
+                info!("Load balancer {} is dual-stack, also connecting from {}", idx + 1, other);
+                lb = lb.with_other_family(other);
+            }
+            lbs.push(lb);
         }
         lbs
     } else {
@@ -375,28 +1859,589 @@ async fn main() -> Result<()> {
             .parse()
             .map_err(|_| anyhow::anyhow!("Invalid host {}", args.lhost))?;
 
-        parse_load_balancers(&args.addresses, args.tunnel)?
+        let mut addresses = match &args.config {
+            Some(path) => {
+                let config = config::load(path)?;
+                info!("Read {} load balancers from {}", config.balancers.len(), path);
+                strategy = config.strategy.unwrap_or(strategy);
+                config.balancers
+            }
+            None => args.addresses.clone(),
+        };
+        if let Some(url) = &args.balancer_url {
+            let fetched = balancer_url::fetch(url).await?;
+            info!("Fetched {} load balancers from {}", fetched.len(), url);
+            addresses.extend(fetched);
+        }
+        let parsed = parse_load_balancers(&addresses, args.tunnel, args.on_duplicate, args.weights_as_percent);
+        let parsed = match &args.config {
+            Some(path) => parsed.with_context(|| format!("Invalid load balancer in {}", path))?,
+            None => parsed?,
+        };
+        if args.auto {
+            info!("Checking which tunnel endpoints are reachable...");
+            let reachable = probe_tunnels(parsed).await;
+            if reachable.is_empty() {
+                bail!("No reachable tunnel endpoints found");
+            }
+            reachable
+        } else {
+            parsed
+        }
     };
 
-    let pool = Arc::new(LoadBalancerPool::new(load_balancers));
+    if let Some(family) = ip_family {
+        // Upstream proxies and blackholes carry either family, only interface
+        // and tunnel balancers are tied to one
+        load_balancers.retain(|lb| {
+            let keep = lb.upstream.is_some() || lb.blackhole.is_some() || lb.has_family(family == IpFamily::V6);
+            if !keep {
+                info!("Ignoring load balancer {}, not {}", lb.address, family);
+            }
+            keep
+        });
+        if load_balancers.is_empty() {
+            bail!("No {} load balancers", family);
+        }
+    }
 
-    // Start server
-    let bind_addr = format!("{}:{}", args.lhost, args.lport);
-    let listener = TcpListener::bind(&bind_addr).await?;
-    info!("Local server started on {}", bind_addr);
+    Ok((load_balancers, strategy))
+}
 
-    loop {
-        match listener.accept().await {
-            Ok((socket, _)) => {
-                let pool = Arc::clone(&pool);
-                let tunnel = args.tunnel;
-                tokio::spawn(async move {
-                    handle_connection(socket, pool, tunnel).await;
-                });
+/// Pool over the balancers with the strategy and the selection options from
+/// the command line
+fn build_pool(args: &Args, strategy: Strategy, mut load_balancers: Vec<LoadBalancer>) -> LoadBalancerPool {
+    schedule::apply(&mut load_balancers, schedule::local_minute());
+    LoadBalancerPool::new(load_balancers)
+        .with_jitter(args.jitter)
+        .with_shuffled_start(args.shuffle_start, args.seed)
+        .with_strategy(strategy)
+        .with_sticky(args.sticky)
+        .with_failure_decay(args.failure_decay.map(Duration::from_secs))
+        .with_circuit_breaker(args.circuit_breaker)
+        .with_health_checks(args.health_interval.is_some())
+        .with_drain_on_unhealthy(args.drain_on_unhealthy)
+        .with_rebalance_nudge(args.rebalance_nudge)
+        .with_family_filter(!args.no_family_filter)
+}
+
+/// Determine the load balancers and strategy again on SIGHUP and swap them in
+/// for new connections, carrying over the counters of balancers that remain.
+/// `positional` is set when mirrors, routes or quotas refer to balancers by
+/// position, which a new set would silently break.
+#[cfg(unix)]
+fn spawn_reload(args: Args, ip_family: Option<IpFamily>, shared: Arc<SharedPool>, positional: bool) -> Result<()> {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            if positional {
+                warn!("Ignoring SIGHUP, load balancers cannot be reloaded while --mirror, --route or quota= refer to them");
+                continue;
             }
-            Err(e) => {
-                warn!("Could not accept connection: {}", e);
+            info!("Reloading load balancers");
+            match load_balancers(&args, ip_family).await {
+                Ok((load_balancers, _)) if load_balancers.iter().any(|lb| lb.options.quota.is_some()) => {
+                    warn!("Reload adds quota= balancers, which need a restart; keeping the current load balancers");
+                }
+                Ok((load_balancers, strategy)) => {
+                    let pool = shared.update(|current| {
+                        if strategy != current.strategy() {
+                            info!("Switching strategy from {} to {}", current.strategy(), strategy);
+                        }
+                        Ok(build_pool(&args, strategy, load_balancers).with_counters_from(current))
+                    });
+                    if let Ok(pool) = pool {
+                        info!("Reloaded {} load balancers", pool.len());
+                    }
+                }
+                Err(e) => warn!("Reload failed, keeping the current load balancers: {:#}", e),
             }
         }
+    });
+    Ok(())
+}
+
+async fn run(mut args: Args) -> Result<()> {
+    if let Some(Command::Selftest) = args.command {
+        return selftest::run().await;
+    }
+
+    // Handle list mode
+    if args.list {
+        // --auto adds a connectivity test of each address to the listing
+        let targets = if args.auto { Some(resolve_probe_targets(&args.probe_target).await?) } else { None };
+        detect_interfaces(targets.as_deref()).await;
+        return Ok(());
+    }
+
+    // Setup logging (do this early for auto-detect feedback)
+    if !args.quiet {
+        let subscriber = FmtSubscriber::builder()
+            .with_max_level(Level::DEBUG)
+            .with_target(false)
+            .with_thread_ids(false)
+            .without_time()
+            .finish();
+        tracing::subscriber::set_global_default(subscriber)?;
+    }
+
+    if let Some(threshold) = args.stall_warn_ms {
+        spawn_stall_detector(Duration::from_millis(threshold));
+    }
+
+    if !args.probe_target.is_empty() && !args.auto && args.health_interval.is_none() {
+        bail!("--probe-target is only used with --auto or --health-interval");
+    }
+
+    if args.nat64_prefix.segments()[6..] != [0, 0] {
+        bail!("--nat64-prefix must be a /96 prefix such as 64:ff9b::");
+    }
+
+    let port_strategies = match &args.strategy_for_port {
+        Some(spec) => strategy::parse_port_strategies(spec).context("Invalid --strategy-for-port")?,
+        None => strategy::PortStrategies::default(),
+    };
+    if port_strategies.default.is_some() || !port_strategies.ports.is_empty() {
+        if args.tunnel {
+            bail!("Per-port strategies are not supported in tunnel mode");
+        }
+        args.strategy = port_strategies.default.unwrap_or(args.strategy);
+    }
+
+    let ip_family = match (args.ipv4_only, args.ipv6_only) {
+        (true, _) => Some(IpFamily::V4),
+        (_, true) => Some(IpFamily::V6),
+        _ => None,
+    };
+    let (load_balancers, strategy) = load_balancers(&args, ip_family).await?;
+
+    if args.verify_egress {
+        if args.tunnel {
+            bail!("Egress verification is not supported in tunnel mode");
+        }
+        return diagnostics::verify_egress(&load_balancers, &args.egress_echo_url).await;
+    }
+
+    if args.dns_leak_test {
+        if args.tunnel {
+            bail!("The DNS leak test is not supported in tunnel mode");
+        }
+        return diagnostics::dns_leak_test(&load_balancers, &args.dns_leak_name, args.dns_leak_server).await;
+    }
+
+    let mirrors = args
+        .mirror
+        .iter()
+        .map(|spec| parse_mirror(spec, &load_balancers))
+        .collect::<Result<Vec<_>>>()?;
+    if !mirrors.is_empty() && args.tunnel {
+        bail!("Mirroring is not supported in tunnel mode");
+    }
+    let routes = routes::RouteTable::parse(&args.route, load_balancers.len())?;
+    if !routes.is_empty() && args.tunnel {
+        bail!("Domain routing is not supported in tunnel mode");
+    }
+    if let Some(min) = args.min_healthy.filter(|&k| k as usize > load_balancers.len()) {
+        bail!("--min-healthy {} is more than the {} load balancers", min, load_balancers.len());
+    }
+    let user_routes = routes::UserRouteTable::parse(&args.route_user, load_balancers.len())?;
+    if (!user_routes.is_empty() || args.user_balancer_prefix.is_some()) && args.tunnel {
+        bail!("User routing is not supported in tunnel mode");
+    }
+
+    let access = acl::AccessList::parse(&args.allow, &args.deny)?;
+
+    if args.check_balancers {
+        print_balancers(&load_balancers);
+        listen_addresses(&args)?;
+        let failed = check_endpoints(&load_balancers).await;
+        if failed > 0 {
+            bail!("{} of {} load balancers have an endpoint that does not resolve", failed, load_balancers.len());
+        }
+        println!("Configuration OK, load balancers: {}", load_balancers.len());
+        return Ok(());
+    }
+
+    let quota = if load_balancers.iter().any(|lb| lb.options.quota.is_some()) {
+        let tracker = quota::QuotaTracker::new(&load_balancers, args.quota_state.clone(), args.quota_reset_day)?;
+        Some(Arc::new(tracker))
+    } else {
+        None
+    };
+
+    let sticky_map = match &args.sticky_state {
+        Some(path) => {
+            let identities: Vec<String> = load_balancers.iter().filter_map(|lb| lb.identity()).collect();
+            let map = sticky::StickyMap::load(path.clone(), Duration::from_secs(args.sticky_ttl), &identities)?;
+            let map = Arc::new(map);
+            sticky::spawn_persistence(Arc::clone(&map));
+            Some(map)
+        }
+        None => None,
+    };
+
+    let mut pool = build_pool(&args, strategy, load_balancers)
+        .with_sticky_map(sticky_map.clone())
+        .with_port_strategies(port_strategies.ports);
+    if let Some(quota) = &quota {
+        quota::spawn_persistence(Arc::clone(quota));
+        pool = pool.with_quota(Arc::clone(quota));
+    }
+    let shared = Arc::new(SharedPool::new(pool));
+    let positional = !args.mirror.is_empty() || !args.route.is_empty() || !args.route_user.is_empty() || quota.is_some();
+    #[cfg(unix)]
+    spawn_reload(args.clone(), ip_family, Arc::clone(&shared), positional)?;
+    #[cfg(unix)]
+    counters::spawn_stats_signal(Arc::clone(&shared))?;
+    #[cfg(unix)]
+    counters::spawn_reset_signal(Arc::clone(&shared))?;
+    // A config file can switch to a strategy using round-trip times on reload, so probe whenever one is read
+    if shared.current().measures_latency() || args.config.is_some() {
+        latency::spawn_probes(Arc::clone(&shared), Duration::from_secs(args.latency_interval));
+    }
+    if let Some(secs) = args.health_interval {
+        let targets = resolve_probe_targets(&args.probe_target).await?;
+        let hook = args
+            .on_health_change
+            .clone()
+            .map(|command| health::Hook::new(command, Duration::from_secs(args.health_change_debounce)));
+        health::spawn(
+            Arc::clone(&shared),
+            Duration::from_secs(secs),
+            targets,
+            args.health_probe_concurrency as usize,
+            args.min_healthy.map(|k| k as usize),
+            hook,
+        );
+    }
+    counters::spawn_rates(Arc::clone(&shared));
+    if let Some(path) = &args.counters_file {
+        counters::spawn_dump(Arc::clone(&shared), path.clone(), Duration::from_secs(args.counters_interval));
+    }
+    // The report only goes to the log, which --quiet turns off
+    if let Some(secs) = args.report_interval.filter(|_| !args.quiet) {
+        counters::spawn_report(Arc::clone(&shared), Duration::from_secs(secs));
+    }
+    if let Some(addr) = args.metrics_addr {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Could not listen for metrics on {}", addr))?;
+        metrics::spawn(listener, Arc::clone(&shared), args.min_healthy.map_or(1, |k| k as usize));
+    }
+    if let Some(addr) = args.admin_addr {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Could not listen for the admin API on {}", addr))?;
+        let (tunnel, pool_args) = (args.tunnel, args.clone());
+        let editor = admin::Editor {
+            parse: Box::new(move |idx, spec| {
+                let lb = parse_load_balancer(idx, spec, tunnel)?;
+                if let Some(family) = ip_family {
+                    if lb.upstream.is_none() && lb.blackhole.is_none() && !lb.has_family(family == IpFamily::V6) {
+                        bail!("Load balancer {} is not {}", lb.address, family);
+                    }
+                }
+                Ok(lb)
+            }),
+            build: Box::new(move |load_balancers, strategy| build_pool(&pool_args, strategy, load_balancers)),
+            positional,
+        };
+        admin::spawn(listener, Arc::clone(&shared), editor);
+    }
+    // Scheduled balancers can also arrive with a reload or through the admin API
+    let current = shared.current();
+    let scheduled = (0..current.len()).filter_map(|idx| current.balancer(idx)).any(|lb| lb.options.schedule.is_some());
+    if scheduled || args.config.is_some() || args.admin_addr.is_some() {
+        let pool_args = args.clone();
+        schedule::spawn(Arc::clone(&shared), Box::new(move |load_balancers, strategy| build_pool(&pool_args, strategy, load_balancers)));
+    }
+
+    let total_rate = match &args.total_rate {
+        Some(rate) => {
+            let Some(bytes_per_second) = parse_rate(rate) else {
+                bail!("Invalid --total-rate '{}', expected e.g. 5mb or 40mbit", rate);
+            };
+            info!("Capping total relay throughput at {} bytes/s", bytes_per_second);
+            Some(RateLimiter::new(bytes_per_second))
+        }
+        None => None,
+    };
+
+    let buffer_memory = match &args.max_buffer_memory {
+        Some(size) => {
+            let Some(bytes) = parse_byte_size(size) else {
+                bail!("Invalid --max-buffer-memory '{}', expected e.g. 256mb", size);
+            };
+            Some(BufferMemory::new(bytes))
+        }
+        None => None,
+    };
+
+    let mut reply_codes = socks::ReplyCodes::default();
+    for spec in &args.reply_code {
+        reply_codes.set_from(spec)?;
+    }
+
+    let credentials = args
+        .auth
+        .iter()
+        .map(|spec| socks::Credentials::parse(spec))
+        .collect::<Result<Vec<_>>>()?;
+    if !credentials.is_empty() && args.tunnel {
+        bail!("SOCKS authentication is not supported in tunnel mode");
+    }
+    if !credentials.is_empty() && args.transparent {
+        bail!("SOCKS authentication is not supported in transparent mode");
+    }
+    if args.transparent && !cfg!(target_os = "linux") {
+        bail!("Transparent mode is only supported on Linux");
+    }
+
+    let source_ports = args.source_ports.as_deref().map(platform::PortRange::parse).transpose()?;
+    if let Some(range) = source_ports {
+        info!("Connecting from source ports {}", range);
+    }
+
+    let dns_server = match &args.dns {
+        Some(spec) => Some(
+            spec.parse::<SocketAddr>()
+                .or_else(|_| spec.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                .map_err(|_| anyhow::anyhow!("Invalid --dns server '{}', expected an IP or IP:PORT", spec))?,
+        ),
+        None if args.balancer_dns => match dns::system_nameserver() {
+            Some(ip) => Some(SocketAddr::new(ip, 53)),
+            None => bail!("No usable nameserver in /etc/resolv.conf for --balancer-dns, use --dns"),
+        },
+        None => None,
+    };
+    if let Some(server) = dns_server {
+        info!("Resolving domain targets via {} through the selected load balancer", server);
+    }
+
+    let settings = Arc::new(Settings {
+        tunnel: args.tunnel,
+        transparent: args.transparent,
+        proxy_protocol: args.accept_proxy_protocol,
+        send_proxy_protocol: args.send_proxy_protocol,
+        strict_socks: args.strict_socks,
+        required_auth_method: args.require_auth_method,
+        credentials,
+        reply_codes,
+        tarpit: args.tarpit.map(|secs| Tarpit::new(Duration::from_secs(secs), args.tarpit_max)),
+        lockout: args
+            .lockout_failures
+            .map(|n| Lockout::new(n, Duration::from_secs(args.lockout_window), Duration::from_secs(args.lockout_cooldown))),
+        refuse_below_healthy: args.min_healthy.filter(|_| args.refuse_unready).map(|k| k as usize),
+        http_max_head: args.http_max_head as usize,
+        handshake_timeout: (args.handshake_timeout > 0).then(|| Duration::from_millis(args.handshake_timeout)),
+        connect_timeout: (args.connect_timeout > 0).then(|| Duration::from_millis(args.connect_timeout)),
+        connect_deadline: args.connect_deadline.map(Duration::from_millis),
+        retry: (args.max_retries > 0).then(|| platform::RetryPolicy {
+            max_retries: args.max_retries,
+            backoff: Duration::from_millis(args.retry_backoff),
+        }),
+        idle_timeout: args.idle_timeout.map(Duration::from_secs),
+        max_resolve_attempts: args.max_resolve_attempts.map(|n| n as usize),
+        dns_server,
+        ip_family,
+        block_private: args.block_private,
+        nat64_prefix: args.no_family_filter.then_some(args.nat64_prefix),
+        access,
+        fast_open: args.tfo,
+        early_data: args.early_data,
+        mtu_buffers: args.mtu_buffers,
+        buffer_memory,
+        mirrors,
+        routes,
+        user_routes,
+        user_balancer_prefix: args.user_balancer_prefix.clone(),
+        preserve_source_port: args.preserve_source_port,
+        source_ports,
+        socket_reuse: args.socket_reuse,
+        total_rate,
+        skew_ratio: args.skew_ratio,
+        skew_min_bytes: args.skew_min_bytes,
+        log_sni: args.log_sni,
+        keepalive: args.keepalive_idle.map(|idle| {
+            platform::keepalive(idle, args.keepalive_interval, args.keepalive_count)
+        }),
+        nodelay: args.nodelay,
+        #[cfg(feature = "pcap")]
+        tracer: match &args.trace_pcap {
+            Some(path) => {
+                let filter = args.trace_filter.as_deref().map(pcap::TraceFilter::parse).transpose()?;
+                info!("Tracing relayed connections to {}", path);
+                Some(Arc::new(pcap::PcapTracer::create(path, filter)?))
+            }
+            None => None,
+        },
+    });
+
+    #[cfg(feature = "tls")]
+    let tls_acceptor = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::load_acceptor(cert, key)?),
+        _ => None,
+    };
+
+    // Start servers
+    let listens = listen_addresses(&args)?;
+
+    let mut listeners = Vec::with_capacity(listens.len());
+    for &(addr, mode) in &listens {
+        let listener = if args.transparent {
+            platform::bind_transparent(addr)?
+        } else {
+            platform::bind_listener(addr, args.dual_stack).with_context(|| format!("Could not listen on {}", addr))?
+        };
+        let bound = listener.local_addr().unwrap_or(addr);
+        let families = if args.dual_stack && bound.ip().is_unspecified() { ", IPv4 and IPv6" } else { "" };
+        if args.listen.is_empty() {
+            info!("Local server started on {}{}", bound, families);
+        } else {
+            info!("Listening on {} ({}{})", bound, mode, families);
+        }
+        listeners.push((listener, mode));
+    }
+
+    if let Some(pac_port) = args.pac_port {
+        if args.tunnel {
+            bail!("A PAC file cannot point clients at a tunnel mode listener");
+        }
+        if args.transparent {
+            bail!("A PAC file cannot point clients at a transparent mode listener");
+        }
+        let Some(&(socks, _)) = listens.iter().find(|&&(_, mode)| mode == ListenMode::Socks) else {
+            bail!("A PAC file cannot point clients at an HTTP CONNECT listener, which does not proxy plain HTTP requests");
+        };
+        let pac_listener = TcpListener::bind((socks.ip(), pac_port)).await?;
+        pac::spawn(pac_listener, socks.ip(), socks.port());
+    }
+
+    let active = ActiveConnections::default();
+    let server = Server {
+        shared,
+        settings,
+        active: active.clone(),
+        limit: args.max_connections.map(|max| ConnectionLimit::new(max as usize)),
+        #[cfg(feature = "tls")]
+        tls_acceptor,
+    };
+    let mut signals = shutdown::Signals::new()?;
+    let mut accepting = Vec::with_capacity(listeners.len());
+    for (listener, mode) in listeners {
+        let own_addresses = if args.transparent { listener_addresses(&listener)? } else { Vec::new() };
+        accepting.push(tokio::spawn(accept_loop(listener, mode, own_addresses, server.clone())));
+    }
+    signals.recv().await;
+
+    // Stop accepting before waiting for active connections
+    for task in &accepting {
+        task.abort();
+    }
+    for task in accepting {
+        let _ = task.await;
+    }
+    shutdown::drain(&active, Duration::from_secs(args.drain_timeout), &mut signals).await;
+    if let Some(map) = &sticky_map {
+        if let Err(e) = map.save() {
+            warn!("{:#}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("5mb"), Some(5_000_000));
+        assert_eq!(parse_rate("5MB/s"), Some(5_000_000));
+        assert_eq!(parse_rate("40mbit"), Some(5_000_000));
+        assert_eq!(parse_rate("1.5kb"), Some(1_500));
+        assert_eq!(parse_rate("1000"), Some(1_000));
+        for rate in ["", "0", "4bit", "fast", "5xb"] {
+            assert_eq!(parse_rate(rate), None, "{}", rate);
+        }
+    }
+
+    #[test]
+    fn reduces_percentages_to_ratios() {
+        assert_eq!(percent_ratios(&[70, 30]).unwrap(), [7, 3]);
+        assert_eq!(percent_ratios(&[50, 25, 25]).unwrap(), [2, 1, 1]);
+        assert_eq!(percent_ratios(&[33, 33, 34]).unwrap(), [33, 33, 34]);
+        assert_eq!(percent_ratios(&[100]).unwrap(), [1]);
+    }
+
+    #[test]
+    fn rejects_percentages_not_adding_up_to_100() {
+        let error = percent_ratios(&[70, 20]).unwrap_err().to_string();
+        assert_eq!(error, "Percentage weights 70/20 add up to 90%, not 100%");
+        assert!(percent_ratios(&[60, 50]).is_err());
+        assert!(percent_ratios(&[]).is_err());
+        assert!(percent_ratios(&[u32::MAX, 101]).is_err());
+    }
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 9));
+
+    /// Settings with a short --handshake-timeout and the given `user:pass` credentials
+    fn handshake_settings(credentials: &[&str]) -> Settings {
+        Settings {
+            handshake_timeout: Some(Duration::from_millis(100)),
+            credentials: credentials.iter().map(|c| socks::Credentials::parse(c).unwrap()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Run the SOCKS handshake for a client that sends `sent` and then stalls.
+    /// Returns the error and everything the proxy wrote before the connection closed.
+    async fn stalled_handshake(settings: &Settings, sent: &[u8]) -> (String, Vec<u8>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(sent).await.unwrap();
+        let started = std::time::Instant::now();
+        let error = socks_handshake(&mut server, CLIENT, settings).await.unwrap_err().to_string();
+        assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+        drop(server);
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        (error, replies)
+    }
+
+    #[test]
+    fn parses_listen_entries() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        for (spec, expected) in [
+            ("127.0.0.1:1080", (addr("127.0.0.1:1080"), ListenMode::Socks)),
+            ("127.0.0.1:1080:http", (addr("127.0.0.1:1080"), ListenMode::Http)),
+            ("[::1]:8080:socks", (addr("[::1]:8080"), ListenMode::Socks)),
+            ("socks5://0.0.0.0:1080", (addr("0.0.0.0:1080"), ListenMode::Socks)),
+            ("http://[::]:8080", (addr("[::]:8080"), ListenMode::Http)),
+        ] {
+            assert_eq!(parse_listen(spec, ListenMode::Socks).unwrap(), expected, "{}", spec);
+        }
+        assert_eq!(parse_listen("127.0.0.1:1080", ListenMode::Http).unwrap().1, ListenMode::Http);
+        for spec in ["127.0.0.1", "127.0.0.1:1080:ftp", "socks4://127.0.0.1:1080", "http://127.0.0.1:8080:socks", "http://localhost:8080"] {
+            assert!(parse_listen(spec, ListenMode::Socks).is_err(), "accepted {}", spec);
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_request_times_out() {
+        // Greeting offering no authentication and the start of a request, then nothing
+        let (error, replies) = stalled_handshake(&handshake_settings(&[]), &[5, 1, 0, 5, 1, 0, 3, 11, b'e']).await;
+        assert_eq!(error, "Timed out after 100ms");
+        assert_eq!(replies, [5, 0]);
+    }
+
+    #[tokio::test]
+    async fn stalled_auth_request_times_out() {
+        // Greeting offering username/password and the start of the login, then nothing
+        let settings = handshake_settings(&["alice:secret"]);
+        let (error, replies) = stalled_handshake(&settings, &[5, 1, 2, 1, 5, b'a']).await;
+        assert_eq!(error, "Timed out after 100ms");
+        assert_eq!(replies, [5, 2]);
     }
 }
@@ -0,0 +1,291 @@
+//! Prometheus metrics endpoint
+//!
+//! Serves the pool's per-balancer stats in the Prometheus text exposition
+//! format on `/metrics`. Each series is labelled with the balancer's position
+//! in the listing logged at startup (or the latest reload) and its address, so
+//! balancers specified twice still get distinct series. Besides counters and
+//! gauges, the time successful connects took and the bytes each relay moved
+//! are exported as histograms.
+//!
+//! `/healthz` answers `200 OK` while at least the required number of
+//! balancers pass their health checks and `503 Service Unavailable`
+//! otherwise, for load balancers and orchestrators in front of the proxy.
+//! `POST /reset` zeroes the cumulative counters, like SIGUSR2.
+
+use crate::counters;
+use crate::load_balancer::{LoadBalancerPool, SharedPool};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+/// Largest request head read before answering
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// Time a scraper has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bucket bounds of the connect time histogram, in microseconds
+pub const CONNECT_TIME_BOUNDS_US: &[u64] =
+    &[1_000, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000, 5_000_000, 10_000_000];
+
+/// Bucket bounds of the relay size histogram, in bytes
+pub const TRANSFER_SIZE_BOUNDS: &[u64] =
+    &[1_000, 10_000, 100_000, 1_000_000, 10_000_000, 100_000_000, 1_000_000_000, 10_000_000_000];
+
+/// Observations counted into fixed buckets, without locks, and exported in
+/// the Prometheus histogram layout
+pub struct Histogram {
+    /// Inclusive upper bounds of the buckets, ascending
+    bounds: &'static [u64],
+    /// Observations per bucket, not cumulative; the extra last one is `+Inf`
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [u64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let bucket = self.bounds.partition_point(|&bound| bound < value);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.sum.store(0, Ordering::Relaxed);
+        self.count.store(0, Ordering::Relaxed);
+    }
+
+    /// Write the `_bucket`, `_sum` and `_count` series of one balancer, with
+    /// bounds and sum divided by `scale` to get the exported unit
+    fn render(&self, out: &mut String, name: &str, labels: &str, scale: f64) {
+        let mut cumulative = 0;
+        for (bucket, bound) in self.buckets.iter().zip(self.bounds.iter().map(Some).chain([None])) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = bound.map_or_else(|| "+Inf".to_string(), |&b| (b as f64 / scale).to_string());
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, cumulative);
+        }
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum.load(Ordering::Relaxed) as f64 / scale);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count.load(Ordering::Relaxed));
+    }
+}
+
+/// Serve metrics for the pool in use on the listener, reporting ready while
+/// `min_healthy` balancers are healthy
+pub fn spawn(listener: TcpListener, shared: Arc<SharedPool>, min_healthy: usize) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving metrics on http://{}/metrics", addr);
+    }
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let pool = shared.current();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, &pool, min_healthy).await {
+                            debug!("Metrics request from {} failed: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Could not accept metrics connection: {}", e),
+            }
+        }
+    });
+}
+
+async fn serve(mut stream: TcpStream, pool: &LoadBalancerPool, min_healthy: usize) -> std::io::Result<()> {
+    let mut request = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    let read_head = async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    if tokio::time::timeout(REQUEST_TIMEOUT, read_head).await.is_err() {
+        return Ok(());
+    }
+
+    let mut request_line = std::str::from_utf8(&request).ok().into_iter().flat_map(str::split_whitespace);
+    let method = request_line.next().unwrap_or("GET");
+    let path = request_line.next().unwrap_or("/");
+    let path = path.split('?').next().unwrap_or(path);
+
+    let response = if path == "/reset" {
+        if method == "POST" {
+            counters::reset(pool);
+            "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n".to_string()
+        } else {
+            "HTTP/1.1 405 Method Not Allowed\r\nAllow: POST\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+    } else if matches!(path, "/" | "/metrics") {
+        let body = render(pool);
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else if path == "/healthz" {
+        let healthy = pool.healthy_count();
+        let (status, body) = if healthy >= min_healthy {
+            ("200 OK", format!("ok: {} load balancers healthy\n", healthy))
+        } else {
+            ("503 Service Unavailable", format!("unready: {} of {} required load balancers healthy\n", healthy, min_healthy))
+        };
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Current stats in the Prometheus text format
+fn render(pool: &LoadBalancerPool) -> String {
+    let stats = pool.stats();
+    let labels: Vec<String> = (0..stats.len())
+        .map(|idx| {
+            let lb = pool.balancer(idx);
+            let address = lb
+                .map(|lb| lb.source_ip().map_or_else(|| lb.address.clone(), |ip| ip.to_string()))
+                .unwrap_or_default();
+            match lb.and_then(|lb| lb.options.label.as_deref()) {
+                Some(label) => format!("index=\"{}\",lb=\"{}\",label=\"{}\"", idx + 1, escape(&address), escape(label)),
+                None => format!("index=\"{}\",lb=\"{}\"", idx + 1, escape(&address)),
+            }
+        })
+        .collect();
+
+    let mut out = String::new();
+    header(&mut out, "dispatch_connections_total", "counter", "Successful connects through the balancer");
+    for (label, stats) in labels.iter().zip(&stats) {
+        let _ = writeln!(out, "dispatch_connections_total{{{}}} {}", label, stats.connections);
+    }
+    header(&mut out, "dispatch_active_connections", "gauge", "Connections currently using the balancer");
+    for (label, stats) in labels.iter().zip(&stats) {
+        let _ = writeln!(out, "dispatch_active_connections{{{}}} {}", label, stats.active);
+    }
+    header(&mut out, "dispatch_bytes_total", "counter", "Bytes relayed through the balancer, out to targets and in from them");
+    for (label, stats) in labels.iter().zip(&stats) {
+        let _ = writeln!(out, "dispatch_bytes_total{{{},direction=\"out\"}} {}", label, stats.bytes_out);
+        let _ = writeln!(out, "dispatch_bytes_total{{{},direction=\"in\"}} {}", label, stats.bytes_in);
+    }
+    header(&mut out, "dispatch_throughput_bytes_per_second", "gauge", "Rolling rate of bytes relayed through the balancer, out to targets and in from them");
+    for (label, stats) in labels.iter().zip(&stats) {
+        let _ = writeln!(out, "dispatch_throughput_bytes_per_second{{{},direction=\"out\"}} {:.1}", label, stats.rate_out);
+        let _ = writeln!(out, "dispatch_throughput_bytes_per_second{{{},direction=\"in\"}} {:.1}", label, stats.rate_in);
+    }
+    header(&mut out, "dispatch_connect_failures_total", "counter", "Failed connects through the balancer");
+    for (label, stats) in labels.iter().zip(&stats) {
+        let _ = writeln!(out, "dispatch_connect_failures_total{{{}}} {}", label, stats.errors);
+    }
+    header(&mut out, "dispatch_circuit_open", "gauge", "Whether the balancer is skipped by the circuit breaker");
+    for (label, stats) in labels.iter().zip(&stats) {
+        let _ = writeln!(out, "dispatch_circuit_open{{{}}} {}", label, u8::from(stats.circuit_open));
+    }
+    header(&mut out, "dispatch_health_failing", "gauge", "Whether the balancer is skipped after failing its health check");
+    for (label, stats) in labels.iter().zip(&stats) {
+        let _ = writeln!(out, "dispatch_health_failing{{{}}} {}", label, u8::from(stats.unhealthy));
+    }
+    header(&mut out, "dispatch_rtt_seconds", "gauge", "Average probed round-trip time of the balancer, once measured");
+    for (label, stats) in labels.iter().zip(&stats) {
+        if let Some(rtt) = stats.rtt_ms {
+            let _ = writeln!(out, "dispatch_rtt_seconds{{{}}} {}", label, rtt / 1000.0);
+        }
+    }
+    header(&mut out, "dispatch_rtt_jitter_seconds", "gauge", "Average deviation of probes from the balancer's round-trip time");
+    for (label, stats) in labels.iter().zip(&stats) {
+        if let Some(jitter) = stats.jitter_ms {
+            let _ = writeln!(out, "dispatch_rtt_jitter_seconds{{{}}} {}", label, jitter / 1000.0);
+        }
+    }
+    header(&mut out, "dispatch_connect_duration_seconds", "histogram", "Time successful connects through the balancer took");
+    for (idx, label) in labels.iter().enumerate() {
+        if let Some(counters) = pool.counters(idx) {
+            counters.connect_time.render(&mut out, "dispatch_connect_duration_seconds", label, 1e6);
+        }
+    }
+    header(&mut out, "dispatch_relay_bytes", "histogram", "Bytes moved in both directions by each relay through the balancer");
+    for (idx, label) in labels.iter().enumerate() {
+        if let Some(counters) = pool.counters(idx) {
+            counters.transfer_size.render(&mut out, "dispatch_relay_bytes", label, 1.0);
+        }
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_renders_cumulative_buckets() {
+        let histogram = Histogram::new(&[10, 100]);
+        for value in [5, 10, 11, 1000] {
+            histogram.observe(value);
+        }
+        let mut out = String::new();
+        histogram.render(&mut out, "size", "index=\"1\"", 1.0);
+        assert_eq!(
+            out,
+            "size_bucket{index=\"1\",le=\"10\"} 2\n\
+             size_bucket{index=\"1\",le=\"100\"} 3\n\
+             size_bucket{index=\"1\",le=\"+Inf\"} 4\n\
+             size_sum{index=\"1\"} 1026\n\
+             size_count{index=\"1\"} 4\n"
+        );
+
+        histogram.reset();
+        let mut out = String::new();
+        histogram.render(&mut out, "size", "index=\"1\"", 1.0);
+        assert!(out.ends_with("size_count{index=\"1\"} 0\n"));
+    }
+
+    #[test]
+    fn histogram_scales_bounds_and_sum() {
+        let histogram = Histogram::new(CONNECT_TIME_BOUNDS_US);
+        histogram.observe(1_500);
+        let mut out = String::new();
+        histogram.render(&mut out, "t", "i=\"1\"", 1e6);
+        assert!(out.contains("t_bucket{i=\"1\",le=\"0.001\"} 0\n"));
+        assert!(out.contains("t_bucket{i=\"1\",le=\"0.005\"} 1\n"));
+        assert!(out.contains("t_sum{i=\"1\"} 0.0015\n"));
+    }
+}
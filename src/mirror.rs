@@ -0,0 +1,106 @@
+//! Mirroring of client traffic to a secondary balancer
+//!
+//! Used to validate a new uplink: the primary balancer carries the real relay
+//! while the secondary receives a copy of the client's bytes. Its responses
+//! are discarded, and it never slows down or fails the client-facing path.
+
+use crate::load_balancer::LoadBalancer;
+use crate::platform::{self, ConnectOptions};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Client chunks buffered for the mirror before it is considered too slow
+const MIRROR_BACKLOG: usize = 64;
+
+/// Client stream wrapper that copies every byte read from it to a mirror
+pub struct MirroredStream<'a, S> {
+    inner: &'a mut S,
+    tx: Option<mpsc::Sender<Vec<u8>>>,
+}
+
+impl<'a, S> MirroredStream<'a, S> {
+    pub fn new(inner: &'a mut S, tx: mpsc::Sender<Vec<u8>>) -> Self {
+        Self { inner, tx: Some(tx) }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MirroredStream<'_, S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut *self.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = result {
+            let read = &buf.filled()[before..];
+            if !read.is_empty() {
+                // A mirror that falls behind stops receiving rather than holding up the client
+                if let Some(tx) = &self.tx {
+                    if tx.try_send(read.to_vec()).is_err() {
+                        debug!("Mirror fell behind, no longer copying this connection");
+                        self.tx = None;
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MirroredStream<'_, S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Connect to the target through the secondary balancer in the background and
+/// log when the outcome differs from the primary's. When the primary connected,
+/// returns the sender for client bytes to copy to the mirror.
+pub fn spawn(target: &str, secondary: Arc<LoadBalancer>, primary_connected: bool) -> Option<mpsc::Sender<Vec<u8>>> {
+    let target = target.to_string();
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(MIRROR_BACKLOG);
+
+    tokio::spawn(async move {
+        let mut stream = match platform::connect(&target, &secondary, &ConnectOptions::default()).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                if primary_connected {
+                    warn!("Mirror {} -> {} failed where the primary connected: {}", target, secondary.address, e);
+                }
+                return;
+            }
+        };
+
+        if !primary_connected {
+            info!("Mirror {} -> {} connected where the primary failed", target, secondary.address);
+            return;
+        }
+
+        let (mut reader, mut writer) = stream.split();
+        let mut sink = tokio::io::sink();
+        let discard = tokio::io::copy(&mut reader, &mut sink);
+        let forward = async {
+            while let Some(chunk) = rx.recv().await {
+                writer.write_all(&chunk).await?;
+            }
+            writer.shutdown().await
+        };
+
+        if let (_, Err(e)) = tokio::join!(discard, forward) {
+            debug!("Mirror {} -> {} closed: {}", target, secondary.address, e);
+        }
+    });
+
+    primary_connected.then_some(tx)
+}
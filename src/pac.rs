@@ -0,0 +1,93 @@
+//! Proxy auto-configuration (PAC) file server
+//!
+//! Serves a generated `proxy.pac` pointing clients at the SOCKS listener, so
+//! browsers and devices can be configured with a single URL.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+/// Largest request head read before answering
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// Time a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve the PAC file for the SOCKS listener on `socks_host:socks_port`
+pub fn spawn(listener: TcpListener, socks_host: IpAddr, socks_port: u16) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving PAC file on http://{}/proxy.pac", addr);
+    }
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, socks_host, socks_port).await {
+                            debug!("PAC request from {} failed: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Could not accept PAC connection: {}", e),
+            }
+        }
+    });
+}
+
+async fn serve(mut stream: TcpStream, socks_host: IpAddr, socks_port: u16) -> std::io::Result<()> {
+    let mut request = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    let read_head = async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    if tokio::time::timeout(REQUEST_TIMEOUT, read_head).await.is_err() {
+        return Ok(());
+    }
+
+    let path = std::str::from_utf8(&request)
+        .ok()
+        .and_then(|head| head.split_whitespace().nth(1))
+        .unwrap_or("/");
+    let path = path.split('?').next().unwrap_or(path);
+
+    let response = if matches!(path, "/" | "/proxy.pac" | "/wpad.dat") {
+        // A wildcard listener has no single address to hand out, so point the
+        // client at the address it reached us on
+        let host = if socks_host.is_unspecified() {
+            stream.local_addr()?.ip()
+        } else {
+            socks_host
+        };
+        let body = pac_file(SocketAddr::new(host, socks_port));
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-ns-proxy-autoconfig\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// PAC script sending every request through the SOCKS listener, with the
+/// bare `SOCKS` keyword (SOCKS4 to most clients) as the fallback for clients
+/// that don't know `SOCKS5`
+fn pac_file(socks: SocketAddr) -> String {
+    format!(
+        "function FindProxyForURL(url, host) {{\n    return \"SOCKS5 {}; SOCKS {}\";\n}}\n",
+        socks, socks
+    )
+}
@@ -0,0 +1,345 @@
+//! Debug tracing of relayed traffic into a pcap-ng file
+//!
+//! Relayed bytes are written as synthetic TCP segments between the client
+//! and the target, so a single flow can be inspected in Wireshark without
+//! capturing on the interfaces themselves.
+
+use anyhow::Result;
+use pcap_file::pcapng::blocks::enhanced_packet::EnhancedPacketBlock;
+use pcap_file::pcapng::blocks::interface_description::InterfaceDescriptionBlock;
+use pcap_file::pcapng::PcapNgWriter;
+use pcap_file::DataLink;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::warn;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// Largest payload written into a single synthetic segment
+const MAX_SEGMENT: usize = 16384;
+
+/// Connections to trace, matched against the target host and/or port
+#[derive(Debug, Clone)]
+pub struct TraceFilter {
+    host: Option<String>,
+    port: Option<u16>,
+}
+
+impl TraceFilter {
+    /// Parse `host`, `host:port` or `:port`
+    pub fn parse(s: &str) -> Result<Self> {
+        let (host, port) = split_host_port(s);
+        let port = match port {
+            Some(p) => Some(
+                p.parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid trace filter {}", s))?,
+            ),
+            None => None,
+        };
+        let host = (!host.is_empty()).then(|| host.to_ascii_lowercase());
+
+        if host.is_none() && port.is_none() {
+            anyhow::bail!("Invalid trace filter {}", s);
+        }
+
+        Ok(Self { host, port })
+    }
+
+    fn matches(&self, target: &str) -> bool {
+        let (host, port) = split_host_port(target);
+        let host_ok = self
+            .host
+            .as_ref()
+            .is_none_or(|h| h.eq_ignore_ascii_case(host));
+        let port_ok = self
+            .port
+            .is_none_or(|p| port.and_then(|s| s.parse().ok()) == Some(p));
+        host_ok && port_ok
+    }
+}
+
+/// Split `host:port` / `[v6]:port` into its parts, stripping IPv6 brackets
+fn split_host_port(s: &str) -> (&str, Option<&str>) {
+    if let Some(rest) = s.strip_prefix('[') {
+        if let Some((host, tail)) = rest.split_once(']') {
+            return (host, tail.strip_prefix(':'));
+        }
+    }
+
+    match s.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => (host, Some(port)),
+        _ => (s, None),
+    }
+}
+
+/// Shared pcap-ng writer for all traced connections
+pub struct PcapTracer {
+    writer: Mutex<PcapNgWriter<BufWriter<File>>>,
+    filter: Option<TraceFilter>,
+}
+
+impl PcapTracer {
+    pub fn create(path: &str, filter: Option<TraceFilter>) -> Result<Self> {
+        let file = File::create(path)
+            .map_err(|e| anyhow::anyhow!("Could not create trace file {}: {}", path, e))?;
+        let mut writer = PcapNgWriter::new(BufWriter::new(file))?;
+        writer.write_pcapng_block(InterfaceDescriptionBlock::new(DataLink::RAW, 0))?;
+
+        Ok(Self {
+            writer: Mutex::new(writer),
+            filter,
+        })
+    }
+
+    /// Whether connections to `target` should be traced
+    pub fn matches(&self, target: &str) -> bool {
+        self.filter.as_ref().is_none_or(|f| f.matches(target))
+    }
+
+    /// Wrap the client side of a relay so both directions get recorded
+    pub fn trace<'a, S>(
+        &'a self,
+        inner: &'a mut S,
+        client: SocketAddr,
+        server: SocketAddr,
+    ) -> TracedStream<'a, S> {
+        let (client, server) = unify_families(client, server);
+        let mut stream = TracedStream {
+            inner,
+            tracer: self,
+            client,
+            server,
+            client_seq: 1,
+            server_seq: 1,
+        };
+
+        // Synthetic three-way handshake so dissectors can follow the stream
+        stream.emit(true, TCP_SYN, &[]);
+        stream.client_seq += 1;
+        stream.emit(false, TCP_SYN | TCP_ACK, &[]);
+        stream.server_seq += 1;
+        stream.emit(true, TCP_ACK, &[]);
+        stream
+    }
+
+    fn write_packet(&self, packet: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let block = EnhancedPacketBlock {
+            interface_id: 0,
+            timestamp,
+            original_len: packet.len() as u32,
+            data: packet.into(),
+            options: vec![],
+        };
+
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writer.write_pcapng_block(block) {
+            warn!("Could not write trace packet: {}", e);
+        }
+    }
+
+    fn flush(&self) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writer.get_mut().flush() {
+            warn!("Could not flush trace file: {}", e);
+        }
+    }
+}
+
+/// Client stream wrapper that records everything read from and written to it
+pub struct TracedStream<'a, S> {
+    inner: &'a mut S,
+    tracer: &'a PcapTracer,
+    client: SocketAddr,
+    server: SocketAddr,
+    client_seq: u32,
+    server_seq: u32,
+}
+
+impl<S> TracedStream<'_, S> {
+    /// Record a segment travelling client->server (`from_client`) or server->client
+    fn emit(&mut self, from_client: bool, flags: u8, payload: &[u8]) {
+        let (src, dst, seq, ack) = if from_client {
+            (self.client, self.server, self.client_seq, self.server_seq)
+        } else {
+            (self.server, self.client, self.server_seq, self.client_seq)
+        };
+        let ack = if flags & TCP_SYN != 0 && flags & TCP_ACK == 0 { 0 } else { ack };
+
+        self.tracer
+            .write_packet(&build_segment(src, dst, seq, ack, flags, payload));
+    }
+
+    fn record(&mut self, from_client: bool, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT) {
+            self.emit(from_client, TCP_PSH | TCP_ACK, chunk);
+            let seq = if from_client {
+                &mut self.client_seq
+            } else {
+                &mut self.server_seq
+            };
+            *seq = seq.wrapping_add(chunk.len() as u32);
+        }
+    }
+}
+
+impl<S> Drop for TracedStream<'_, S> {
+    fn drop(&mut self) {
+        self.emit(true, TCP_FIN | TCP_ACK, &[]);
+        self.client_seq = self.client_seq.wrapping_add(1);
+        self.emit(false, TCP_FIN | TCP_ACK, &[]);
+        self.tracer.flush();
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TracedStream<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut *this.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = result {
+            let data = buf.filled()[before..].to_vec();
+            this.record(true, &data);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TracedStream<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut *this.inner).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(n)) = result {
+            this.record(false, &buf[..n]);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// A synthetic segment needs both endpoints in one family; map IPv4 into IPv6 if they differ
+fn unify_families(a: SocketAddr, b: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let to_v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(v4) => SocketAddr::new(IpAddr::V6(v4.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+
+    if a.is_ipv4() == b.is_ipv4() {
+        (a, b)
+    } else {
+        (to_v6(a), to_v6(b))
+    }
+}
+
+/// Build a raw IPv4/IPv6 packet carrying a TCP segment
+fn build_segment(
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let tcp_len = 20 + payload.len();
+    let mut tcp = Vec::with_capacity(tcp_len);
+    tcp.extend_from_slice(&src.port().to_be_bytes());
+    tcp.extend_from_slice(&dst.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    tcp.push(5 << 4);
+    tcp.push(flags);
+    tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+    tcp.extend_from_slice(&[0, 0, 0, 0]);
+    tcp.extend_from_slice(payload);
+
+    let mut packet = Vec::with_capacity(40 + tcp_len);
+    let pseudo_sum = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((20 + tcp_len) as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            packet.extend_from_slice(&s.octets());
+            packet.extend_from_slice(&d.octets());
+            let header_sum = checksum(&packet, 0);
+            packet[10..12].copy_from_slice(&header_sum.to_be_bytes());
+
+            let mut pseudo = Vec::with_capacity(12);
+            pseudo.extend_from_slice(&s.octets());
+            pseudo.extend_from_slice(&d.octets());
+            pseudo.extend_from_slice(&[0, 6]);
+            pseudo.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+            sum_words(&pseudo)
+        }
+        (s, d) => {
+            let (s, d) = (to_v6_octets(s), to_v6_octets(d));
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+            packet.extend_from_slice(&[6, 64]);
+            packet.extend_from_slice(&s);
+            packet.extend_from_slice(&d);
+
+            let mut pseudo = Vec::with_capacity(40);
+            pseudo.extend_from_slice(&s);
+            pseudo.extend_from_slice(&d);
+            pseudo.extend_from_slice(&(tcp_len as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, 6]);
+            sum_words(&pseudo)
+        }
+    };
+
+    let tcp_sum = checksum(&tcp, pseudo_sum);
+    tcp[16..18].copy_from_slice(&tcp_sum.to_be_bytes());
+    packet.extend_from_slice(&tcp);
+    packet
+}
+
+fn to_v6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+        IpAddr::V6(v6) => v6.octets(),
+    }
+}
+
+/// One's-complement sum of big-endian 16-bit words
+fn sum_words(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|c| u32::from(u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)])))
+        .sum()
+}
+
+/// Internet checksum over `data`, seeded with a partial sum (e.g. a pseudo-header)
+fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = initial + sum_words(data);
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
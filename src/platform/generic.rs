@@ -1,58 +1,184 @@
 //! Generic (non-Linux) platform implementation
-//! Uses source address binding without SO_BINDTODEVICE
+//! Uses source address binding without SO_BINDTODEVICE, plus IP_BOUND_IF on macOS
 
-use crate::load_balancer::LoadBalancer;
+use super::{ConnectError, ConnectOptions};
+use crate::load_balancer::{FlowLabel, LoadBalancer};
 use anyhow::Result;
 use socket2::{Domain, Protocol, Socket, Type};
-use std::net::{SocketAddr, ToSocketAddrs};
-use tokio::net::TcpStream;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+#[cfg(target_os = "macos")]
+use super::macos::bind_interface;
+
+/// Only macOS can pin a socket to an interface outside Linux
+#[cfg(not(target_os = "macos"))]
+fn bind_interface(_socket: &Socket, _lb: &LoadBalancer, _ipv6: bool) {}
 
 /// Connect to target address with local address binding
 pub async fn connect_with_interface(
-    target_addr: &str,
+    mut target: SocketAddr,
     lb: &LoadBalancer,
+    opts: &ConnectOptions<'_>,
 ) -> Result<TcpStream> {
-    let domain = if lb.is_ipv6 { Domain::IPV6 } else { Domain::IPV4 };
-
-    // Parse local address (the load balancer's IP with port 0)
-    let local_addr: SocketAddr = lb
-        .address
-        .to_socket_addrs()?
-        .find(|a| if lb.is_ipv6 { a.is_ipv6() } else { a.is_ipv4() })
+    // Local address: the balancer's IP of the target's family, or its only
+    // one, with the requested source port (0 for ephemeral)
+    let mut local_addr: SocketAddr = lb
+        .source_addr(target.is_ipv6())
+        .or_else(|| lb.source_addr(lb.is_ipv6))
         .ok_or_else(|| anyhow::anyhow!("Could not resolve local address"))?;
-
-    // Parse target address - prefer matching IP version, fallback to any
-    let target: SocketAddr = target_addr
-        .to_socket_addrs()?
-        .find(|a| if lb.is_ipv6 { a.is_ipv6() } else { a.is_ipv4() })
-        .or_else(|| target_addr.to_socket_addrs().ok()?.next())
-        .ok_or_else(|| anyhow::anyhow!("Could not resolve target address"))?;
+    local_addr.set_port(opts.source_port);
+    super::scope_target(&mut target, &local_addr);
+    super::translate_target(&mut target, &local_addr, opts.nat64_prefix);
+    let domain = if local_addr.is_ipv6() { Domain::IPV6 } else { Domain::IPV4 };
 
     // Create socket and bind to local address
     let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
-    socket.set_reuse_address(true)?;
-    socket.bind(&local_addr.into())?;
+    super::apply_reuse(&socket, opts.reuse)?;
+    super::apply_balancer_options(&socket, lb, local_addr.is_ipv6())?;
+    bind_interface(&socket, lb, local_addr.is_ipv6());
+    socket
+        .bind(&local_addr.into())
+        .map_err(|e| ConnectError::Bind(local_addr, e))?;
     socket.set_nonblocking(true)?;
 
+    // Without a flow label manager the label is only passed in sin6_flowinfo,
+    // which the platform may ignore
+    if let (Some(label), SocketAddr::V6(v6)) = (lb.options.flow_label, &mut target) {
+        let label = match label {
+            FlowLabel::Fixed(label) => label,
+            FlowLabel::Random => rand::random::<u32>() & 0xf_ffff,
+        };
+        v6.set_flowinfo(label.to_be());
+    }
+
     // Connect to target
     match socket.connect(&target.into()) {
         Ok(()) => {}
         Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
         Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-        Err(e) => return Err(e.into()),
+        Err(e) => return Err(ConnectError::Connect(e).into()),
     }
 
     // Convert to tokio TcpStream
     let std_stream: std::net::TcpStream = socket.into();
-    let stream = TcpStream::from_std(std_stream)?;
+    let mut stream = TcpStream::from_std(std_stream)?;
 
-    // Wait for connection to complete
-    stream.writable().await?;
+    // Wait for connection to complete, bounded by the balancer's or the global timeout
+    match lb.options.connect_timeout.or(opts.timeout) {
+        Some(timeout) => tokio::time::timeout(timeout, stream.writable())
+            .await
+            .map_err(|_| ConnectError::Connect(std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out")))?
+            .map_err(ConnectError::Connect)?,
+        None => stream.writable().await.map_err(ConnectError::Connect)?,
+    }
 
     // Check for connection errors
     if let Some(e) = stream.take_error()? {
-        return Err(e.into());
+        return Err(ConnectError::Connect(e).into());
+    }
+
+    // Some platforms report a failed nonblocking connect as writable with
+    // SO_ERROR already cleared; only a connected socket has a peer address
+    stream.peer_addr().map_err(ConnectError::Connect)?;
+
+    // No Fast Open here, the data follows the handshake
+    if !opts.fast_open_data.is_empty() {
+        stream.write_all(opts.fast_open_data).await.map_err(ConnectError::Connect)?;
     }
 
     Ok(stream)
 }
+
+/// Open a UDP socket sending from the balancer's source address of the
+/// family (and interface on macOS)
+pub fn bind_udp(lb: &LoadBalancer, ipv6: bool) -> Result<UdpSocket> {
+    let domain = if ipv6 { Domain::IPV6 } else { Domain::IPV4 };
+    let local_addr: SocketAddr = if ipv6 == lb.is_ipv6 {
+        lb.address
+            .to_socket_addrs()?
+            .find(|a| a.is_ipv6() == ipv6)
+            .ok_or_else(|| anyhow::anyhow!("Could not resolve local address"))?
+    } else {
+        lb.source_addr(ipv6)
+            .ok_or_else(|| anyhow::anyhow!("No {} source address", if ipv6 { "IPv6" } else { "IPv4" }))?
+    };
+
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    bind_interface(&socket, lb, ipv6);
+    socket
+        .bind(&local_addr.into())
+        .map_err(|e| ConnectError::Bind(local_addr, e))?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Interface MTUs are only read from sysfs on Linux
+pub fn interface_mtu(_iface: &str) -> Option<u32> {
+    None
+}
+
+/// Interface states are only read from sysfs on Linux
+pub fn interface_up(_iface: &str) -> Option<bool> {
+    None
+}
+
+/// Routes are only read from procfs on Linux
+pub fn default_gateways(_iface: &str) -> Vec<IpAddr> {
+    Vec::new()
+}
+
+/// MAC addresses are only read from sysfs on Linux
+pub fn interface_by_mac(_mac: &str) -> Option<String> {
+    None
+}
+
+/// Original destination lookup is only available through netfilter on Linux
+pub fn original_destination(_stream: &TcpStream) -> Option<SocketAddr> {
+    None
+}
+
+/// Transparent interception relies on TPROXY and is only available on Linux
+pub fn bind_transparent(_addr: SocketAddr) -> Result<TcpListener> {
+    anyhow::bail!("Transparent mode is only supported on Linux")
+}
+
+/// Transparent interception relies on TPROXY and is only available on Linux
+pub fn transparent_destination(_stream: &TcpStream) -> Option<SocketAddr> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loopback() -> LoadBalancer {
+        LoadBalancer::new("127.0.0.1:0".to_string(), None, 1, false)
+    }
+
+    #[tokio::test]
+    async fn refused_connects_fail_instead_of_returning_a_stream() {
+        // A port that was just released has nothing listening on it
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap();
+        drop(listener);
+
+        let e = connect_with_interface(target, &loopback(), &ConnectOptions::default()).await.unwrap_err();
+        match e.downcast_ref::<ConnectError>() {
+            Some(ConnectError::Connect(e)) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionRefused),
+            other => panic!("expected a refused connect, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn accepted_connects_return_a_connected_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+
+        let stream = connect_with_interface(target, &loopback(), &ConnectOptions::default()).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), target);
+        let (_, client) = listener.accept().await.unwrap();
+        assert_eq!(client, stream.local_addr().unwrap());
+    }
+}
@@ -1,72 +1,369 @@
 //! Linux-specific platform implementation
 //! Uses SO_BINDTODEVICE for true per-interface binding
 
-use crate::load_balancer::LoadBalancer;
-use anyhow::Result;
-use nix::sys::socket::{setsockopt, sockopt::BindToDevice};
-use socket2::{Domain, Protocol, Socket, Type};
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::os::fd::AsFd;
-use tokio::net::TcpStream;
-use tracing::warn;
+use super::{ConnectError, ConnectOptions};
+use crate::load_balancer::{FlowLabel, LoadBalancer};
+use anyhow::{Context, Result};
+use nix::sys::socket::{setsockopt, sockopt::{BindToDevice, Mark}};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs};
+use std::os::fd::{AsFd, AsRawFd};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tracing::{debug, warn};
 
 /// Connect to target address with interface binding using SO_BINDTODEVICE
 pub async fn connect_with_interface(
-    target_addr: &str,
+    mut target: SocketAddr,
     lb: &LoadBalancer,
+    opts: &ConnectOptions<'_>,
 ) -> Result<TcpStream> {
-    let domain = if lb.is_ipv6 { Domain::IPV6 } else { Domain::IPV4 };
-
-    // Parse local address (the load balancer's IP with port 0)
-    let local_addr: SocketAddr = lb
-        .address
-        .to_socket_addrs()?
-        .find(|a| if lb.is_ipv6 { a.is_ipv6() } else { a.is_ipv4() })
+    // Local address: the balancer's IP of the target's family, or its only
+    // one, with the requested source port (0 for ephemeral)
+    let mut local_addr: SocketAddr = lb
+        .source_addr(target.is_ipv6())
+        .or_else(|| lb.source_addr(lb.is_ipv6))
         .ok_or_else(|| anyhow::anyhow!("Could not resolve local address"))?;
-
-    // Parse target address - prefer matching IP version, fallback to any
-    let target: SocketAddr = target_addr
-        .to_socket_addrs()?
-        .find(|a| if lb.is_ipv6 { a.is_ipv6() } else { a.is_ipv4() })
-        .or_else(|| target_addr.to_socket_addrs().ok()?.next())
-        .ok_or_else(|| anyhow::anyhow!("Could not resolve target address"))?;
+    local_addr.set_port(opts.source_port);
+    super::scope_target(&mut target, &local_addr);
+    super::translate_target(&mut target, &local_addr, opts.nat64_prefix);
+    let domain = if local_addr.is_ipv6() { Domain::IPV6 } else { Domain::IPV4 };
 
     // Create socket
     let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
-    socket.set_reuse_address(true)?;
+    super::apply_reuse(&socket, opts.reuse)?;
+    super::apply_balancer_options(&socket, lb, local_addr.is_ipv6())?;
 
-    // Bind to interface using SO_BINDTODEVICE if interface name is provided
-    // NOTE: Requires root or CAP_NET_RAW capability
-    // sudo setcap cap_net_raw=eip ./dispatch-proxy
-    if let Some(ref iface) = lb.iface {
-        if let Err(e) = setsockopt(&socket.as_fd(), BindToDevice, &std::ffi::OsString::from(iface)) {
-            warn!("Couldn't bind to interface {}: {}", iface, e);
-        }
-    }
+    bind_device(&socket, lb)?;
 
     // Bind to local address
-    socket.bind(&local_addr.into())?;
+    socket
+        .bind(&local_addr.into())
+        .map_err(|e| ConnectError::Bind(local_addr, e))?;
     socket.set_nonblocking(true)?;
 
-    // Connect to target
-    match socket.connect(&target.into()) {
+    if let (Some(label), SocketAddr::V6(v6)) = (lb.options.flow_label, &mut target) {
+        let flowinfo = lease_flow_label(&socket, v6, label)?;
+        v6.set_flowinfo(flowinfo);
+    }
+
+    // Connect to target, sending the Fast Open data with the SYN when there is some
+    let sent = if opts.fast_open_data.is_empty() {
+        None
+    } else {
+        send_fast_open(&socket, target, opts.fast_open_data).map_err(ConnectError::Connect)?
+    };
+    let connecting = match sent {
+        Some(_) => Ok(()),
+        None => socket.connect(&target.into()),
+    };
+    match connecting {
         Ok(()) => {}
         Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
         Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-        Err(e) => return Err(e.into()),
+        Err(e) => return Err(ConnectError::Connect(e).into()),
     }
 
     // Convert to tokio TcpStream
     let std_stream: std::net::TcpStream = socket.into();
-    let stream = TcpStream::from_std(std_stream)?;
+    let mut stream = TcpStream::from_std(std_stream)?;
 
-    // Wait for connection to complete
-    stream.writable().await?;
+    // Wait for connection to complete, bounded by the balancer's or the global timeout
+    match lb.options.connect_timeout.or(opts.timeout) {
+        Some(timeout) => tokio::time::timeout(timeout, stream.writable())
+            .await
+            .map_err(|_| ConnectError::Connect(std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out")))?
+            .map_err(ConnectError::Connect)?,
+        None => stream.writable().await.map_err(ConnectError::Connect)?,
+    }
 
     // Check for connection errors
     if let Some(e) = stream.take_error()? {
-        return Err(e.into());
+        return Err(ConnectError::Connect(e).into());
+    }
+
+    // Whatever did not fit in the SYN, or all of it without a cookie
+    let rest = &opts.fast_open_data[sent.unwrap_or(0)..];
+    if !rest.is_empty() {
+        stream.write_all(rest).await.map_err(ConnectError::Connect)?;
     }
 
     Ok(stream)
 }
+
+/// Bind to interface using SO_BINDTODEVICE if interface name is provided
+/// NOTE: Requires root or CAP_NET_RAW capability
+/// sudo setcap cap_net_raw=eip ./dispatch-proxy
+///
+/// A balancer with a firewall mark is steered by `ip rule fwmark` / iptables
+/// rules instead, so its socket is marked with SO_MARK (CAP_NET_ADMIN) and
+/// only bound to a VRF, never to the interface itself. Like a VRF bind, a
+/// failed mark is fatal, since the connection would take the wrong route.
+fn bind_device(socket: &Socket, lb: &LoadBalancer) -> Result<()> {
+    if let Some(mark) = lb.options.mark {
+        setsockopt(&socket.as_fd(), Mark, &mark)
+            .map_err(|e| anyhow::anyhow!("Couldn't set firewall mark {:#x}: {}", mark, e))?;
+    }
+    if let Some(ref vrf) = lb.options.vrf {
+        // Binding to the VRF master (not the enslaved interface) makes the
+        // socket use the VRF's routing table and policy rules. Without it the
+        // connection would silently take the main table, so this is fatal.
+        setsockopt(&socket.as_fd(), BindToDevice, &std::ffi::OsString::from(vrf))
+            .map_err(|e| anyhow::anyhow!("Couldn't bind to VRF {}: {}", vrf, e))?;
+    } else if let (Some(iface), None) = (&lb.iface, lb.options.mark) {
+        if let Err(e) = setsockopt(&socket.as_fd(), BindToDevice, &std::ffi::OsString::from(iface)) {
+            warn!("Couldn't bind to interface {}: {}", iface, e);
+        }
+    }
+    Ok(())
+}
+
+/// Open a UDP socket sending from the balancer's source address of the
+/// family and its interface
+pub fn bind_udp(lb: &LoadBalancer, ipv6: bool) -> Result<UdpSocket> {
+    let domain = if ipv6 { Domain::IPV6 } else { Domain::IPV4 };
+    let local_addr: SocketAddr = if ipv6 == lb.is_ipv6 {
+        lb.address
+            .to_socket_addrs()?
+            .find(|a| a.is_ipv6() == ipv6)
+            .ok_or_else(|| anyhow::anyhow!("Could not resolve local address"))?
+    } else {
+        lb.source_addr(ipv6)
+            .ok_or_else(|| anyhow::anyhow!("No {} source address", if ipv6 { "IPv6" } else { "IPv4" }))?
+    };
+
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    bind_device(&socket, lb)?;
+    socket
+        .bind(&local_addr.into())
+        .map_err(|e| ConnectError::Bind(local_addr, e))?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Start the connect with `sendto(MSG_FASTOPEN)`, which puts the data in the
+/// SYN when a cookie for the target is cached and otherwise sends a plain SYN
+/// asking for one. Returns how much of the data was queued, or None when the
+/// kernel has Fast Open disabled and a normal connect is needed. Either way
+/// the handshake is still in progress: the socket only turns writable once it
+/// completes, so a refused or unreachable target fails the connect as usual.
+fn send_fast_open(socket: &Socket, target: SocketAddr, data: &[u8]) -> std::io::Result<Option<usize>> {
+    let addr = socket2::SockAddr::from(target);
+    // SAFETY: the fd is a valid socket, and data and addr outlive the call
+    let ret = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            data.as_ptr() as *const libc::c_void,
+            data.len(),
+            libc::MSG_FASTOPEN | libc::MSG_NOSIGNAL,
+            addr.as_ptr(),
+            addr.len(),
+        )
+    };
+    if ret >= 0 {
+        return Ok(Some(ret as usize));
+    }
+    let e = std::io::Error::last_os_error();
+    match e.raw_os_error() {
+        // No cookie yet: a plain SYN is on its way and nothing was queued
+        Some(libc::EINPROGRESS) => Ok(Some(0)),
+        Some(libc::EOPNOTSUPP) => {
+            debug!("TCP Fast Open unavailable: {}", e);
+            Ok(None)
+        }
+        _ => Err(e),
+    }
+}
+
+/// Linux `struct in6_flowlabel_req`
+#[repr(C)]
+struct FlowLabelRequest {
+    dst: libc::in6_addr,
+    label: u32,
+    action: u8,
+    share: u8,
+    flags: u16,
+    expires: u16,
+    linger: u16,
+    pad: u32,
+}
+
+const IPV6_FL_A_GET: u8 = 0;
+const IPV6_FL_F_CREATE: u16 = 1;
+const IPV6_FL_S_EXCL: u8 = 1;
+const IPV6_FL_S_ANY: u8 = 255;
+
+/// Lease a flow label for connecting to `dst` from the kernel's flow label
+/// manager and enable sending it. Asking for label 0 makes the kernel pick a
+/// random one. Returns the value for sin6_flowinfo (network byte order).
+fn lease_flow_label(socket: &Socket, dst: &SocketAddrV6, label: FlowLabel) -> std::io::Result<u32> {
+    let (label, share) = match label {
+        FlowLabel::Fixed(label) => (label, IPV6_FL_S_ANY),
+        FlowLabel::Random => (0, IPV6_FL_S_EXCL),
+    };
+    let mut request = FlowLabelRequest {
+        dst: libc::in6_addr { s6_addr: dst.ip().octets() },
+        label: label.to_be(),
+        action: IPV6_FL_A_GET,
+        share,
+        flags: IPV6_FL_F_CREATE,
+        expires: 0,
+        linger: 0,
+        pad: 0,
+    };
+
+    // SAFETY: the fd is a valid socket and the option value is an in6_flowlabel_req,
+    // which the kernel may write the chosen label back into
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_FLOWLABEL_MGR,
+            std::ptr::addr_of_mut!(request) as *const libc::c_void,
+            std::mem::size_of::<FlowLabelRequest>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let enable: libc::c_int = 1;
+    // SAFETY: the fd is a valid socket and the option value is a c_int
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_FLOWINFO_SEND,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    debug!("Using IPv6 flow label {:#07x} for {}", u32::from_be(request.label), dst);
+    Ok(request.label)
+}
+
+/// Current MTU of a network interface
+pub fn interface_mtu(iface: &str) -> Option<u32> {
+    std::fs::read_to_string(format!("/sys/class/net/{}/mtu", iface))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Whether a network interface is up: its operational state, or for drivers
+/// that report none (tun, wireguard) whether it is administratively up
+pub fn interface_up(iface: &str) -> Option<bool> {
+    let sysfs = format!("/sys/class/net/{}", iface);
+    match std::fs::read_to_string(format!("{}/operstate", sysfs)).ok()?.trim() {
+        "up" => Some(true),
+        "unknown" => {
+            let flags = std::fs::read_to_string(format!("{}/flags", sysfs)).ok()?;
+            let flags = u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).ok()?;
+            Some(flags & libc::IFF_UP as u32 != 0)
+        }
+        _ => Some(false),
+    }
+}
+
+/// Gateways of the default routes through a network interface, read from
+/// /proc/net/route and /proc/net/ipv6_route. An unspecified address stands
+/// for a default route without a gateway, as on point-to-point links.
+pub fn default_gateways(iface: &str) -> Vec<IpAddr> {
+    let mut gateways = Vec::new();
+
+    // Iface Destination Gateway Flags RefCnt Use Metric Mask ..., addresses in hex
+    let routes = std::fs::read_to_string("/proc/net/route").unwrap_or_default();
+    for fields in routes.lines().skip(1).map(|line| line.split_whitespace().collect::<Vec<_>>()) {
+        if let [name, "00000000", gateway, flags, _, _, _, "00000000", ..] = fields[..] {
+            if name == iface && route_usable(flags) {
+                if let Ok(gateway) = u32::from_str_radix(gateway, 16) {
+                    // Printed from the address in memory, so in host byte order
+                    gateways.push(IpAddr::V4(Ipv4Addr::from(gateway.to_ne_bytes())));
+                }
+            }
+        }
+    }
+
+    // Destination, prefix, source, prefix, next hop, metric, refcnt, use, flags, iface
+    let routes = std::fs::read_to_string("/proc/net/ipv6_route").unwrap_or_default();
+    for fields in routes.lines().map(|line| line.split_whitespace().collect::<Vec<_>>()) {
+        if let [dest, "00", _, _, next_hop, _, _, _, flags, name] = fields[..] {
+            if name == iface && dest.bytes().all(|b| b == b'0') && route_usable(flags) {
+                if let Ok(next_hop) = u128::from_str_radix(next_hop, 16) {
+                    gateways.push(IpAddr::V6(Ipv6Addr::from(next_hop)));
+                }
+            }
+        }
+    }
+    gateways
+}
+
+/// Whether route flags (hex) mark a route that is up and not a reject route
+fn route_usable(flags: &str) -> bool {
+    u32::from_str_radix(flags, 16)
+        .is_ok_and(|flags| flags & libc::RTF_UP as u32 != 0 && flags & libc::RTF_REJECT as u32 == 0)
+}
+
+/// Name of the interface with the given MAC address (lowercase, colon-separated)
+pub fn interface_by_mac(mac: &str) -> Option<String> {
+    std::fs::read_dir("/sys/class/net").ok()?.flatten().find_map(|entry| {
+        let address = std::fs::read_to_string(entry.path().join("address")).ok()?;
+        (address.trim().eq_ignore_ascii_case(mac)).then(|| entry.file_name().to_string_lossy().into_owned())
+    })
+}
+
+/// Original destination of a connection redirected to us by netfilter
+/// (iptables REDIRECT/DNAT), read via SO_ORIGINAL_DST
+pub fn original_destination(stream: &TcpStream) -> Option<SocketAddr> {
+    let sock = SockRef::from(stream);
+    let addr = if stream.local_addr().ok()?.is_ipv6() {
+        sock.original_dst_ipv6()
+    } else {
+        sock.original_dst()
+    };
+    addr.ok()?.as_socket()
+}
+
+/// Listen on `addr` with IP_TRANSPARENT set, so connections a TPROXY rule
+/// diverts to the socket are accepted whatever address they were sent to
+pub fn bind_transparent(addr: SocketAddr) -> Result<TcpListener> {
+    let domain = if addr.is_ipv6() { Domain::IPV6 } else { Domain::IPV4 };
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        let enable: libc::c_int = 1;
+        // SAFETY: the fd is a valid socket and the option value is a c_int
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IPV6,
+                libc::IPV6_TRANSPARENT,
+                &enable as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error()).context("Could not set IPV6_TRANSPARENT (needs CAP_NET_ADMIN)");
+        }
+    } else {
+        socket
+            .set_ip_transparent(true)
+            .context("Could not set IP_TRANSPARENT (needs CAP_NET_ADMIN)")?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Destination a transparently intercepted connection was sent to: the
+/// netfilter original destination for REDIRECT/DNAT, otherwise the local
+/// address, which TPROXY leaves as the original destination
+pub fn transparent_destination(stream: &TcpStream) -> Option<SocketAddr> {
+    original_destination(stream).or_else(|| stream.local_addr().ok())
+}
@@ -0,0 +1,38 @@
+//! macOS interface binding
+//!
+//! Source address binding alone lets the routing table pick the egress
+//! interface, so with two interfaces on equal default routes traffic can leave
+//! through the wrong one. IP_BOUND_IF / IPV6_BOUND_IF pin the socket to the
+//! interface itself, the counterpart of SO_BINDTODEVICE on Linux.
+
+use crate::load_balancer::LoadBalancer;
+use socket2::Socket;
+use std::ffi::CString;
+use std::num::NonZeroU32;
+use tracing::warn;
+
+/// Bind the socket, of the given family, to the balancer's interface. On
+/// failure the socket keeps its plain source address binding.
+pub fn bind_interface(socket: &Socket, lb: &LoadBalancer, ipv6: bool) {
+    let Some(ref iface) = lb.iface else {
+        return;
+    };
+    let Some(index) = interface_index(iface) else {
+        warn!("Couldn't bind to interface {}: no such interface", iface);
+        return;
+    };
+    let bound = if ipv6 {
+        socket.bind_device_by_index_v6(Some(index))
+    } else {
+        socket.bind_device_by_index_v4(Some(index))
+    };
+    if let Err(e) = bound {
+        warn!("Couldn't bind to interface {}: {}", iface, e);
+    }
+}
+
+fn interface_index(iface: &str) -> Option<NonZeroU32> {
+    let name = CString::new(iface).ok()?;
+    // SAFETY: `name` is a valid NUL-terminated string for the duration of the call
+    NonZeroU32::new(unsafe { libc::if_nametoindex(name.as_ptr()) })
+}
@@ -1,44 +1,920 @@
 #[cfg(target_os = "linux")]
 mod linux;
 
-#[cfg(not(target_os = "linux"))]
+// Also built for tests on Linux so the generic connect path is exercised there
+#[cfg(any(not(target_os = "linux"), test))]
+#[cfg_attr(all(target_os = "linux", test), allow(dead_code))]
 mod generic;
 
-use crate::load_balancer::{LoadBalancerPool, TargetAddressType};
-use crate::socks;
+#[cfg(target_os = "macos")]
+mod macos;
+
+use crate::acl::AccessList;
+use crate::dns;
+use crate::load_balancer::{BlackholeMode, IpFamily, LoadBalancer, LoadBalancerPool, TargetAddressType};
+use crate::http_connect;
+use crate::mirror;
+use crate::relay::{self, ClientStream};
+use crate::routes;
+use crate::settings::Settings;
+use crate::socks::{self, Failure};
+use crate::strategy::target_host;
+use crate::upstream;
 use anyhow::Result;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tracing::{info, warn};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+/// Most client bytes forwarded as early data
+const EARLY_DATA_MAX: usize = 16 * 1024;
+
+/// Time a connection attempt to one of a target's addresses gets before the
+/// next address is raced against it (RFC 8305 Connection Attempt Delay)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Ports of a `--source-ports` range tried before falling back to an ephemeral one
+const SOURCE_PORT_TRIES: usize = 8;
+
+/// Longest wait between two retries of a balancer
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(2);
+
+/// A connection attempt to one resolved address of a target
+type Attempt<'a> = Pin<Box<dyn Future<Output = (SocketAddr, Result<TcpStream>)> + Send + 'a>>;
+
+#[cfg(target_os = "linux")]
+use linux::connect_with_interface as connect_socket;
+
+#[cfg(not(target_os = "linux"))]
+use generic::connect_with_interface as connect_socket;
 
 #[cfg(target_os = "linux")]
-use linux::connect_with_interface;
+pub use linux::{
+    bind_transparent, bind_udp, default_gateways, interface_by_mac, interface_mtu, interface_up,
+    original_destination, transparent_destination,
+};
 
 #[cfg(not(target_os = "linux"))]
-use generic::connect_with_interface;
+pub use generic::{
+    bind_transparent, bind_udp, default_gateways, interface_by_mac, interface_mtu, interface_up,
+    original_destination, transparent_destination,
+};
+
+/// Index of a network interface, the scope id of its link-local addresses
+#[cfg(unix)]
+pub fn interface_index(iface: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(iface).ok()?;
+    // SAFETY: name is a valid NUL-terminated string
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    (index != 0).then_some(index)
+}
+
+/// Give a link-local IPv6 target without a scope id the one of the source
+/// address, so it is reached on the balancer's link
+fn scope_target(target: &mut SocketAddr, local: &SocketAddr) {
+    if let (SocketAddr::V6(target), SocketAddr::V6(local)) = (target, local) {
+        if target.scope_id() == 0 && target.ip().segments()[0] & 0xffc0 == 0xfe80 {
+            target.set_scope_id(local.scope_id());
+        }
+    }
+}
+
+/// Rewrite an IPv4 target connected to from an IPv6 address as its NAT64
+/// address, the IPv4 address in the low 32 bits of the prefix
+fn translate_target(target: &mut SocketAddr, local: &SocketAddr, prefix: Option<Ipv6Addr>) {
+    if let (SocketAddr::V4(v4), true, Some(prefix)) = (*target, local.is_ipv6(), prefix) {
+        let [a, b, c, d] = v4.ip().octets();
+        let [p0, p1, p2, p3, p4, p5, ..] = prefix.segments();
+        let ip = Ipv6Addr::new(p0, p1, p2, p3, p4, p5, u16::from_be_bytes([a, b]), u16::from_be_bytes([c, d]));
+        *target = SocketAddr::new(ip.into(), v4.port());
+    }
+}
+
+/// Interface indices are looked up with if_nametoindex, only on Unix
+#[cfg(not(unix))]
+pub fn interface_index(_iface: &str) -> Option<u32> {
+    None
+}
+
+/// Per-connection parameters for connects made from a balancer's interface
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectOptions<'a> {
+    /// Local port to connect from (0 for an ephemeral port)
+    pub source_port: u16,
+    /// Ports to pick the local port from when `source_port` is 0
+    pub source_ports: Option<PortRange>,
+    /// Connect timeout used when the balancer does not set its own
+    pub timeout: Option<Duration>,
+    /// Refuse targets that resolve to private, loopback or link-local addresses
+    pub block_private: bool,
+    /// Allow and deny rules for target addresses
+    pub access: Option<&'a AccessList>,
+    /// Client bytes to send with TCP Fast Open, in the SYN where the platform
+    /// supports it (Linux only). A returned stream has sent all of them.
+    pub fast_open_data: &'a [u8],
+    /// Only connect to addresses of this family
+    pub family: Option<IpFamily>,
+    /// Address and port reuse options set before binding
+    pub reuse: ReusePolicy,
+    /// Most resolved addresses to try before giving up
+    pub max_attempts: Option<usize>,
+    /// Resolve domain targets by asking this nameserver through the balancer
+    /// instead of using the system resolver
+    pub dns_server: Option<SocketAddr>,
+    /// /96 prefix IPv4 targets are embedded in (RFC 6052) when the balancer
+    /// only has an IPv6 address to connect from
+    pub nat64_prefix: Option<Ipv6Addr>,
+}
+
+impl ConnectOptions<'_> {
+    /// Whether policy lets connections reach `target`
+    pub fn permits(&self, target: SocketAddr) -> bool {
+        (!self.block_private || is_global(&target.ip())) && self.access.is_none_or(|access| access.permits(target))
+    }
+}
+
+/// SO_REUSEADDR / SO_REUSEPORT policy for outbound sockets
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum ReusePolicy {
+    /// Set neither option
+    None,
+    /// Set SO_REUSEADDR
+    #[default]
+    Addr,
+    /// Set SO_REUSEADDR and SO_REUSEPORT (Unix only)
+    AddrPort,
+}
+
+/// Range of local ports outbound connections are bound from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    /// Parse `START-END`, both inclusive
+    pub fn parse(spec: &str) -> Result<Self> {
+        let range = spec
+            .split_once('-')
+            .and_then(|(start, end)| Some(Self { start: start.trim().parse().ok()?, end: end.trim().parse().ok()? }));
+        match range {
+            Some(range) if range.start > 0 && range.start <= range.end => Ok(range),
+            _ => anyhow::bail!("Invalid port range {}, expected START-END, e.g. 40000-40999", spec),
+        }
+    }
+
+    fn len(&self) -> usize {
+        usize::from(self.end - self.start) + 1
+    }
+
+    fn random(&self) -> u16 {
+        rand::thread_rng().gen_range(self.start..=self.end)
+    }
+}
+
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// Phase in which a connection from a balancer's interface failed
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    /// The balancer's source address could not be bound, typically because its
+    /// interface is down or the address was removed
+    #[error("bind to {0} failed: {1}")]
+    Bind(SocketAddr, #[source] std::io::Error),
+    /// The target could not be reached from the bound source address
+    #[error("{0}")]
+    Connect(#[source] std::io::Error),
+    /// The target's name gave no address to connect to
+    #[error("could not resolve {0}: {1:#}")]
+    Resolve(String, anyhow::Error),
+    /// The target resolved to an address refused by `--block-private` or the
+    /// `--allow` / `--deny` rules
+    #[error("target address {0} is not allowed")]
+    NotAllowed(IpAddr),
+}
+
+/// Whether an address is publicly routable, i.e. not expected to be NATed
+pub fn is_global(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            !(v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                // 100.64.0.0/10 carrier-grade NAT
+                || (v4.octets()[0] == 100 && (v4.octets()[1] & 0xc0) == 64))
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_global(&IpAddr::V4(v4)),
+            None => {
+                let first = v6.segments()[0];
+                !(v6.is_loopback()
+                    || v6.is_unspecified()
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
 
-/// Connect to target address through load balancer and relay data
-pub async fn connect_and_relay(
-    mut client: TcpStream,
+/// Apply the per-balancer socket options shared by all platform backends
+fn apply_balancer_options(socket: &Socket, lb: &LoadBalancer, ipv6: bool) -> Result<()> {
+    if let Some(ttl) = lb.options.ttl {
+        if ipv6 {
+            socket.set_unicast_hops_v6(ttl)?;
+        } else {
+            socket.set_ttl(ttl)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Apply the address reuse policy to an outbound socket before it is bound
+fn apply_reuse(socket: &Socket, policy: ReusePolicy) -> std::io::Result<()> {
+    socket.set_reuse_address(policy != ReusePolicy::None)?;
+
+    #[cfg(unix)]
+    if policy == ReusePolicy::AddrPort {
+        socket.set_reuse_port(true)?;
+    }
+    Ok(())
+}
+
+/// Build the keepalive parameters; the probe count is only honoured where
+/// the platform supports setting it
+pub fn keepalive(idle: u64, interval: Option<u64>, count: Option<u32>) -> TcpKeepalive {
+    let mut keepalive = TcpKeepalive::new().with_time(Duration::from_secs(idle));
+    if let Some(interval) = interval {
+        keepalive = keepalive.with_interval(Duration::from_secs(interval));
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(count) = count {
+        keepalive = keepalive.with_retries(count);
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = count;
+
+    keepalive
+}
+
+/// Enable TCP keepalive probes on a connected stream
+pub fn set_keepalive(stream: &TcpStream, keepalive: &TcpKeepalive) {
+    if let Err(e) = SockRef::from(stream).set_tcp_keepalive(keepalive) {
+        debug!("Could not enable keepalive: {}", e);
+    }
+}
+
+/// Turn Nagle's algorithm off (or back on) for a connected stream
+pub fn set_nodelay(stream: &TcpStream, nodelay: bool) {
+    if let Err(e) = stream.set_nodelay(nodelay) {
+        debug!("Could not set TCP_NODELAY: {}", e);
+    }
+}
+
+/// Listen on `addr`. With `dual_stack` an IPv6 socket has IPV6_V6ONLY
+/// cleared, so it also accepts IPv4 clients whatever the OS default.
+pub fn bind_listener(addr: SocketAddr, dual_stack: bool) -> Result<tokio::net::TcpListener> {
+    let domain = if addr.is_ipv6() { Domain::IPV6 } else { Domain::IPV4 };
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    // As tokio does: lets a restarted proxy bind while old connections linger
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() && dual_stack {
+        socket.set_only_v6(false)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(tokio::net::TcpListener::from_std(socket.into())?)
+}
+
+/// Run a connection phase within what is left of the deadline, if any.
+/// Returns `None` once the deadline has passed.
+pub async fn within_deadline<T>(deadline: Option<Instant>, phase: impl Future<Output = T>) -> Option<T> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, phase).await.ok(),
+        None => Some(phase.await),
+    }
+}
+
+/// How often and how soon a balancer is retried after a transient connect error
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for each further one
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Wait before retry `n`, counting from 0
+    fn delay(&self, n: u32) -> Duration {
+        self.backoff.saturating_mul(1 << n.min(16)).min(MAX_RETRY_BACKOFF)
+    }
+}
+
+/// Whether a connect error is a local, usually momentary, shortage rather
+/// than a verdict on the target or the link: no free source port, no buffer
+/// space or an interrupted call. A bind that fails for want of the address
+/// means it is gone, which a retry does not fix.
+fn is_transient(e: &anyhow::Error) -> bool {
+    match e.downcast_ref() {
+        Some(ConnectError::Bind(_, io_error)) => io_error.kind() == std::io::ErrorKind::AddrInUse || out_of_buffers(io_error),
+        Some(ConnectError::Connect(io_error)) => is_shortage(io_error),
+        Some(ConnectError::NotAllowed(_) | ConnectError::Resolve(..)) => false,
+        None => e.downcast_ref::<std::io::Error>().is_some_and(is_shortage),
+    }
+}
+
+fn is_shortage(io_error: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    out_of_buffers(io_error)
+        || matches!(
+            io_error.kind(),
+            ErrorKind::AddrNotAvailable | ErrorKind::AddrInUse | ErrorKind::WouldBlock | ErrorKind::Interrupted
+        )
+}
+
+#[cfg(unix)]
+fn out_of_buffers(io_error: &std::io::Error) -> bool {
+    io_error.raw_os_error() == Some(libc::ENOBUFS)
+}
+
+#[cfg(not(unix))]
+fn out_of_buffers(_: &std::io::Error) -> bool {
+    false
+}
+
+/// Connect with `attempt`, making it again after a capped exponential backoff
+/// while it fails with a transient error and the policy has retries left.
+/// Other errors, and the last transient one, are returned as they are.
+pub async fn with_retries<T, F, Fut>(policy: Option<RetryPolicy>, target: &str, idx: usize, mut attempt: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut retries = 0;
+    loop {
+        let result = attempt().await;
+        let Some(policy) = policy.filter(|policy| retries < policy.max_retries) else {
+            return result;
+        };
+        match result {
+            Err(e) if is_transient(&e) => {
+                let delay = policy.delay(retries);
+                debug!("{} {{{}}} LB: {}, retrying in {:?}", target, e, idx + 1, delay);
+                tokio::time::sleep(delay).await;
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Fail a connection through a `blackhole` test balancer. In timeout mode the
+/// attempt never completes unless `timeout` is given.
+pub async fn connect_blackhole(mode: BlackholeMode, timeout: Option<Duration>) -> Result<TcpStream> {
+    match mode {
+        BlackholeMode::Refuse => Err(ConnectError::Connect(std::io::ErrorKind::ConnectionRefused.into()).into()),
+        BlackholeMode::Timeout => {
+            match timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+            Err(ConnectError::Connect(std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out")).into())
+        }
+    }
+}
+
+/// Connect to a tunnel balancer's endpoint, which needs no interface binding,
+/// giving up after `timeout`
+pub async fn connect_tunnel(address: &str, timeout: Option<Duration>) -> Result<TcpStream> {
+    let connecting = TcpStream::connect(address);
+    let connected = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, connecting)
+            .await
+            .map_err(|_| ConnectError::Connect(std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out")))?,
+        None => connecting.await,
+    };
+    Ok(connected.map_err(ConnectError::Connect)?)
+}
+
+/// Resolve the target to all addresses usable from the balancer, in random
+/// order within each family. Addresses matching the balancer's family are
+/// preferred; others are only returned when no matching address exists. A
+/// dual-stack balancer gets both families interleaved, IPv6 first, so the
+/// connection race alternates between them.
+pub async fn resolve_target(target_addr: &str, lb: &LoadBalancer, family: Option<IpFamily>, dns_server: Option<SocketAddr>) -> Result<Vec<SocketAddr>> {
+    let unresolved = |e: anyhow::Error| ConnectError::Resolve(target_addr.to_string(), e);
+    let resolved: Vec<SocketAddr> = match (dns_server, target_addr.parse::<SocketAddr>()) {
+        (_, Ok(target)) => vec![target],
+        (Some(server), Err(_)) => {
+            let (host, port) = target_addr
+                .rsplit_once(':')
+                .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+                .ok_or_else(|| anyhow::anyhow!("Invalid target address {}", target_addr))?;
+            // Boxed since the TCP fallback of a query connects through here again
+            Box::pin(dns::resolve(host, port, lb, server, family)).await.map_err(unresolved)?
+        }
+        (None, Err(_)) => tokio::net::lookup_host(target_addr).await.map_err(|e| unresolved(e.into()))?.collect(),
+    };
+    let (mut v6, mut v4): (Vec<SocketAddr>, Vec<SocketAddr>) = resolved
+        .into_iter()
+        .filter(|a| family.is_none_or(|f| f.matches(a.is_ipv6())))
+        .partition(|a| a.is_ipv6());
+    if v6.is_empty() && v4.is_empty() {
+        return Err(unresolved(anyhow::anyhow!("no address of the allowed family")).into());
+    }
+    v6.shuffle(&mut rand::thread_rng());
+    v4.shuffle(&mut rand::thread_rng());
+
+    if lb.other_family.is_some() {
+        return Ok(interleave(v6, v4));
+    }
+    let (preferred, other) = if lb.is_ipv6 { (v6, v4) } else { (v4, v6) };
+    Ok(if preferred.is_empty() { other } else { preferred })
+}
+
+/// Alternate between the two lists, starting with the first
+fn interleave(first: Vec<SocketAddr>, second: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let mut out = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return out,
+            (a, b) => out.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connect to the target through the balancer's interface, racing its
+/// resolved addresses: each attempt gets `CONNECTION_ATTEMPT_DELAY` before the
+/// next address is tried alongside it, a failed attempt starts the next one
+/// right away, and the first to connect wins while the rest are dropped.
+/// Fast Open data goes out with the SYN, so with it each attempt waits for the
+/// previous one to fail instead, and only one target ever receives the data.
+async fn connect_with_interface(target_addr: &str, lb: &LoadBalancer, opts: &ConnectOptions<'_>) -> Result<TcpStream> {
+    if let Some(mode) = lb.blackhole {
+        return connect_blackhole(mode, lb.options.connect_timeout.or(opts.timeout)).await;
+    }
+
+    let mut candidates = resolve_target(target_addr, lb, opts.family, opts.dns_server).await?;
+    if opts.block_private || opts.access.is_some() {
+        // Checked after resolution so domains pointing at internal hosts are caught too
+        let blocked = candidates[0].ip();
+        candidates.retain(|&a| opts.permits(a));
+        if candidates.is_empty() {
+            return Err(ConnectError::NotAllowed(blocked).into());
+        }
+    }
+    if let Some(max) = opts.max_attempts {
+        candidates.truncate(max);
+    }
+
+    let mut pending = candidates.into_iter();
+    let mut attempts: Vec<Attempt<'_>> = Vec::new();
+    // Indexed by is_ipv6: a family whose source address can't be bound would
+    // fail the same way for all its addresses, so they are skipped
+    let mut unbindable = [false; 2];
+    let mut last_error = None;
+
+    loop {
+        if let Some(target) = pending.by_ref().find(|t| !unbindable[usize::from(t.is_ipv6())]) {
+            attempts.push(Box::pin(async move { (target, connect_from_port(target, lb, opts).await) }));
+        }
+        if attempts.is_empty() {
+            break;
+        }
+
+        let finished = std::future::poll_fn(|cx| {
+            let ready = attempts.iter_mut().enumerate().find_map(|(idx, attempt)| match attempt.as_mut().poll(cx) {
+                Poll::Ready(outcome) => Some((idx, outcome)),
+                Poll::Pending => None,
+            });
+            match ready {
+                Some((idx, outcome)) => {
+                    drop(attempts.swap_remove(idx));
+                    Poll::Ready(outcome)
+                }
+                None => Poll::Pending,
+            }
+        });
+        let outcome = if pending.as_slice().is_empty() || !opts.fast_open_data.is_empty() {
+            Some(finished.await)
+        } else {
+            tokio::time::timeout(CONNECTION_ATTEMPT_DELAY, finished).await.ok()
+        };
+        let Some((target, result)) = outcome else {
+            continue;
+        };
+
+        match result {
+            // Returning drops the attempts still in progress, closing their sockets
+            Ok(stream) => return Ok(stream),
+            Err(e) if matches!(e.downcast_ref(), Some(ConnectError::Bind(..))) => {
+                warn!("Load balancer {} source unavailable: {}", lb.address, e);
+                unbindable[usize::from(target.is_ipv6())] = true;
+                last_error = Some(e);
+            }
+            Err(e) => {
+                debug!("{} ({}) via {}: {}", target_addr, target.ip(), lb.address, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("Could not resolve target address")))
+}
+
+/// Connect from a fixed source port, falling back to an ephemeral port when
+/// that port is already taken on the balancer's address
+async fn connect_from_port(target: SocketAddr, lb: &LoadBalancer, opts: &ConnectOptions<'_>) -> Result<TcpStream> {
+    if let (0, Some(range)) = (opts.source_port, opts.source_ports) {
+        return connect_from_range(target, lb, opts, range).await;
+    }
+    match connect_socket(target, lb, opts).await {
+        Err(e) if opts.source_port != 0 && is_addr_in_use(&e) => {
+            debug!("Source port {} in use on {}, using an ephemeral port", opts.source_port, lb.address);
+            let opts = ConnectOptions { source_port: 0, ..*opts };
+            connect_socket(target, lb, &opts).await
+        }
+        result => result,
+    }
+}
+
+/// Connect from random ports of the range, moving on to another while a port
+/// is taken, and from an ephemeral port after `SOURCE_PORT_TRIES` taken ones
+async fn connect_from_range(target: SocketAddr, lb: &LoadBalancer, opts: &ConnectOptions<'_>, range: PortRange) -> Result<TcpStream> {
+    for _ in 0..range.len().min(SOURCE_PORT_TRIES) {
+        let opts = ConnectOptions { source_port: range.random(), ..*opts };
+        match connect_socket(target, lb, &opts).await {
+            // Bound but the connection to this target from the port exists already
+            Err(e) if is_addr_in_use(&e) || is_addr_not_available(&e) => {
+                debug!("Source port {} taken on {}: {}", opts.source_port, lb.address, e);
+            }
+            result => return result,
+        }
+    }
+    debug!("No free source port in {} on {}, using an ephemeral port", range, lb.address);
+    let opts = ConnectOptions { source_port: 0, ..*opts };
+    connect_socket(target, lb, &opts).await
+}
+
+fn is_addr_not_available(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref(),
+        Some(ConnectError::Connect(e)) if e.kind() == std::io::ErrorKind::AddrNotAvailable
+    )
+}
+
+fn is_addr_in_use(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref(),
+        Some(ConnectError::Bind(_, e)) if e.kind() == std::io::ErrorKind::AddrInUse
+    )
+}
+
+/// Open a connection to the target through the given balancer, either via its
+/// upstream proxy or directly from its interface
+pub async fn connect(target_addr: &str, lb: &LoadBalancer, opts: &ConnectOptions<'_>) -> Result<TcpStream> {
+    // Upstream proxies resolve domains themselves, so only literal IPs can be checked
+    if let (Some(_), Ok(target)) = (&lb.upstream, target_addr.parse::<SocketAddr>()) {
+        if !opts.permits(target) {
+            return Err(ConnectError::NotAllowed(target.ip()).into());
+        }
+    }
+
+    match &lb.upstream {
+        Some(upstream) => {
+            let mut stream = upstream::connect_via_upstream(target_addr, lb, upstream, lb.options.connect_timeout.or(opts.timeout)).await?;
+            if !opts.fast_open_data.is_empty() {
+                stream.write_all(opts.fast_open_data).await.map_err(ConnectError::Connect)?;
+            }
+            Ok(stream)
+        }
+        None => connect_with_interface(target_addr, lb, opts).await,
+    }
+}
+
+/// Categorize a failed connect for the SOCKS reply
+fn connect_failure(e: &anyhow::Error) -> Failure {
+    let io_error = match e.downcast_ref() {
+        Some(ConnectError::Connect(io_error)) => Some(io_error),
+        Some(ConnectError::Resolve(..)) => return Failure::HostUnreachable,
+        _ => e.downcast_ref::<std::io::Error>(),
+    };
+    let Some(io_error) = io_error else {
+        return Failure::ConnectFailed;
+    };
+    match io_error.kind() {
+        std::io::ErrorKind::ConnectionRefused => Failure::Refused,
+        std::io::ErrorKind::HostUnreachable => Failure::HostUnreachable,
+        std::io::ErrorKind::NetworkUnreachable => Failure::NetworkUnreachable,
+        std::io::ErrorKind::TimedOut => Failure::TimedOut,
+        _ if host_down(io_error) => Failure::HostUnreachable,
+        _ => Failure::ConnectFailed,
+    }
+}
+
+/// EHOSTDOWN, which has no error kind of its own
+#[cfg(unix)]
+fn host_down(e: &std::io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EHOSTDOWN)
+}
+
+#[cfg(not(unix))]
+fn host_down(_: &std::io::Error) -> bool {
+    false
+}
+
+/// Read whatever the client has already sent without waiting for more
+async fn read_buffered<S: ClientStream>(client: &mut S) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; EARLY_DATA_MAX];
+    // A zero timeout still polls the read once, so this never blocks
+    match tokio::time::timeout(Duration::ZERO, client.read(&mut buf)).await {
+        Ok(read) => buf.truncate(read?),
+        Err(_) => buf.clear(),
+    }
+    Ok(buf)
+}
+
+/// Handshake a client arrived with, which decides how the connect outcome is reported
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientProtocol {
+    Socks5,
+    Socks4,
+    HttpConnect,
+    /// Intercepted with `--transparent`; the client expects no reply
+    Transparent,
+}
+
+async fn send_failure<S: ClientStream>(client: &mut S, protocol: ClientProtocol, settings: &Settings, failure: Failure) -> Result<()> {
+    match protocol {
+        ClientProtocol::Socks5 => socks::send_failure(client, &settings.reply_codes, failure).await,
+        ClientProtocol::Socks4 => socks::send_socks4_reply(client, false, None).await,
+        ClientProtocol::HttpConnect => http_connect::send_failure(client, failure).await,
+        ClientProtocol::Transparent => Ok(()),
+    }
+}
+
+/// Connect to target address through load balancer and relay data. `username`
+/// is the name the client authenticated as, if it did.
+#[allow(clippy::too_many_arguments)]
+pub async fn connect_and_relay<S: ClientStream>(
+    mut client: S,
+    protocol: ClientProtocol,
+    client_addr: SocketAddr,
+    username: Option<&str>,
     target_addr: &str,
     target_type: TargetAddressType,
     pool: Arc<LoadBalancerPool>,
+    settings: &Settings,
 ) -> Result<()> {
-    let (lb, idx) = pool.get_load_balancer(None, Some(target_type));
+    if let Some(family) = settings.ip_family.filter(|f| !f.allows(target_type)) {
+        send_failure(&mut client, protocol, settings, Failure::AddressType).await?;
+        anyhow::bail!("{} is not an {} target", target_addr, family);
+    }
 
-    match connect_with_interface(target_addr, &lb).await {
-        Ok(mut remote) => {
-            info!("{} -> {} LB: {}", target_addr, lb.address, idx);
-            socks::send_success_response(&mut client).await?;
+    let deadline = settings.connect_deadline.map(|budget| Instant::now() + budget);
 
-            // Bidirectional relay
-            let _ = tokio::io::copy_bidirectional(&mut client, &mut remote).await;
-            Ok(())
+    // Bytes an optimistic client sent right after its request, so they can go
+    // out with the connect (in the SYN when TCP Fast Open is enabled)
+    let mut early_data = Vec::new();
+    if settings.early_data {
+        early_data = read_buffered(&mut client).await?;
+    }
+
+    let opts = ConnectOptions {
+        // Protocols such as FTP and SIP advertise their source port in the payload
+        source_port: if settings.preserve_source_port { client_addr.port() } else { 0 },
+        source_ports: settings.source_ports,
+        timeout: settings.connect_timeout,
+        block_private: settings.block_private,
+        access: (!settings.access.is_empty()).then_some(&settings.access),
+        fast_open_data: if settings.fast_open { &early_data } else { &[] },
+        family: settings.ip_family,
+        reuse: settings.socket_reuse,
+        max_attempts: settings.max_resolve_attempts,
+        dns_server: settings.dns_server,
+        nat64_prefix: settings.nat64_prefix,
+    };
+
+    // Try balancers until one connects; the client only hears back once one
+    // has, or once every balancer has failed
+    let mut tried = vec![false; pool.len()];
+    let mut last_error = None;
+    // A balancer named in the username, then routing rules for the user and
+    // the domain, pin the request to a balancer until that balancer fails or
+    // selection would not pick it (draining, down, over its caps). The request
+    // then falls back to the next of them and finally normal selection.
+    let routed = [
+        username
+            .zip(settings.user_balancer_prefix.as_deref())
+            .and_then(|(name, prefix)| routes::prefixed_balancer(name, prefix, pool.len())),
+        username.and_then(|name| settings.user_routes.lookup(name)),
+        match target_type {
+            TargetAddressType::Domain => settings.routes.lookup(target_host(target_addr)),
+            _ => None,
+        },
+    ];
+    loop {
+        let pinned = routed.iter().flatten().copied().find(|&idx| !tried[idx] && pool.eligible(idx));
+        let selected = match pinned.and_then(|idx| pool.balancer(idx).map(|lb| (Arc::clone(lb), idx))) {
+            Some(selected) => Some(selected),
+            None => pool.get_load_balancer(Some(&tried), Some(target_type), Some(target_addr), Some(client_addr.ip())),
+        };
+        let Some((lb, idx)) = selected else {
+            // Every balancer of the target's family has failed; report why
+            if last_error.is_some() {
+                break;
+            }
+            if (0..pool.len()).any(|i| pool.balancer(i).is_some_and(|lb| lb.serves(Some(target_type)))) {
+                send_failure(&mut client, protocol, settings, Failure::NoBalancer).await?;
+                anyhow::bail!("No load balancers available for {}", target_addr);
+            }
+            send_failure(&mut client, protocol, settings, Failure::AddressType).await?;
+            anyhow::bail!("No load balancer can connect to {}, none is of its address family", target_addr);
+        };
+        // Selection falls back to a tried balancer once every one is skipped
+        if tried[idx] {
+            break;
         }
-        Err(e) => {
-            warn!("{} -> {} {{{}}} LB: {}", target_addr, lb.address, e, idx);
-            socks::send_network_unreachable(&mut client).await?;
-            Err(e)
+        let _lease = pool.lease(idx);
+
+        let started = Instant::now();
+        let connecting = with_retries(settings.retry, target_addr, idx, || connect(target_addr, &lb, &opts));
+        let Some(result) = within_deadline(deadline, connecting).await else {
+            warn!("{} -> {} {{connect deadline exceeded}} LB: {}", target_addr, lb.address, idx + 1);
+            pool.record_failure(idx);
+            send_failure(&mut client, protocol, settings, Failure::Deadline).await?;
+            anyhow::bail!("Connect deadline exceeded for {}", target_addr);
+        };
+
+        let mirror = settings
+            .mirrors
+            .iter()
+            .find(|(primary, _)| *primary == idx)
+            .and_then(|&(_, secondary)| pool.balancer(secondary))
+            .cloned();
+
+        let mut remote = match result {
+            Ok(remote) => remote,
+            Err(e) if matches!(e.downcast_ref(), Some(ConnectError::NotAllowed(_))) => {
+                warn!("{} from {} blocked: {}", target_addr, client_addr, e);
+                if let Some(tarpit) = &settings.tarpit {
+                    tarpit.hold().await;
+                }
+                send_failure(&mut client, protocol, settings, Failure::NotAllowed).await?;
+                return Err(e);
+            }
+            Err(e) => {
+                warn!("{} -> {} {{{}}} LB: {}", target_addr, lb.address, e, idx + 1);
+                pool.record_failure(idx);
+                if let Some(secondary) = mirror {
+                    mirror::spawn(target_addr, secondary, false);
+                }
+                tried[idx] = true;
+                last_error = Some(e);
+                if tried.iter().all(|&t| t) {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        pool.record_success(idx, started.elapsed());
+        let mirror_tx = mirror.and_then(|secondary| mirror::spawn(target_addr, secondary, true));
+        if let (Some(tx), false) = (&mirror_tx, early_data.is_empty()) {
+            let _ = tx.try_send(early_data.clone());
+        }
+
+        if !early_data.is_empty() {
+            // With TCP Fast Open the connect has sent it already
+            if opts.fast_open_data.is_empty() {
+                remote.write_all(&early_data).await?;
+            }
+            pool.record_transfer(idx, early_data.len() as u64, 0);
+            debug!("Sent {} bytes of early data to {}", early_data.len(), target_addr);
+        }
+
+        // Show which address a domain resolved to, to tell DNS problems from egress ones
+        match remote.peer_addr() {
+            Ok(peer) if target_type == TargetAddressType::Domain && lb.upstream.is_none() => {
+                let family = if peer.is_ipv6() { "IPv6" } else { "IPv4" };
+                info!("{} ({} {}) -> {} LB: {}", target_addr, family, peer.ip(), lb.address, idx + 1);
+            }
+            _ => info!("{} -> {} LB: {}", target_addr, lb.address, idx + 1),
+        }
+        match protocol {
+            // BND.ADDR and BND.PORT carry the outbound socket's local address
+            ClientProtocol::Socks5 => match remote.local_addr() {
+                Ok(bound) => {
+                    // Tie the address the client sees to the balancer that owns it
+                    info!(
+                        "{} BND {} is LB: {} {} on {}",
+                        client_addr,
+                        bound,
+                        idx + 1,
+                        lb.address,
+                        lb.iface.as_deref().unwrap_or("any interface")
+                    );
+                    socks::send_bound_response(&mut client, bound).await?
+                }
+                Err(_) => socks::send_success_response(&mut client).await?,
+            },
+            ClientProtocol::Socks4 => socks::send_socks4_reply(&mut client, true, remote.local_addr().ok()).await?,
+            ClientProtocol::HttpConnect => http_connect::send_established(&mut client).await?,
+            ClientProtocol::Transparent => {}
         }
+
+        // Bidirectional relay
+        let flow = relay::Flow {
+            client_addr,
+            target: target_addr,
+            lb: &lb,
+            lb_index: idx,
+            pool: &pool,
+        };
+        let _ = match mirror_tx {
+            Some(tx) => {
+                let mut mirrored = mirror::MirroredStream::new(&mut client, tx);
+                relay::relay(&mut mirrored, &mut remote, &flow, settings).await
+            }
+            None => relay::relay(&mut client, &mut remote, &flow, settings).await,
+        };
+        return Ok(());
+    }
+
+    let e = last_error.unwrap_or_else(|| anyhow::anyhow!("All load balancers failed"));
+    send_failure(&mut client, protocol, settings, connect_failure(&e)).await?;
+    Err(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loopback() -> LoadBalancer {
+        LoadBalancer::new("127.0.0.1:0".to_string(), None, 1, false)
+    }
+
+    #[tokio::test]
+    async fn refused_connects_fail_instead_of_returning_a_stream() {
+        // A port that was just released has nothing listening on it
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap();
+        drop(listener);
+
+        let e = connect_socket(target, &loopback(), &ConnectOptions::default()).await.unwrap_err();
+        match e.downcast_ref::<ConnectError>() {
+            Some(ConnectError::Connect(e)) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionRefused),
+            other => panic!("expected a refused connect, got {:?}", other),
+        }
+    }
+
+    /// Linux drops SYNs to a listener whose accept queue is full, so a connect
+    /// to it hangs like one to a blackholed address
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn tunnel_connects_to_a_blackholed_endpoint_time_out() {
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        listener.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into()).unwrap();
+        listener.listen(0).unwrap();
+        let target = listener.local_addr().unwrap().as_socket().unwrap().to_string();
+        // Fill the accept queue
+        let _queued = connect_tunnel(&target, None).await.unwrap();
+
+        let started = tokio::time::Instant::now();
+        let e = connect_tunnel(&target, Some(Duration::from_millis(100))).await.unwrap_err();
+        match e.downcast_ref::<ConnectError>() {
+            Some(ConnectError::Connect(e)) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+            other => panic!("expected a timed out connect, got {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_millis(900), "took {:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn accepted_connects_return_a_connected_stream() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+
+        let stream = connect_socket(target, &loopback(), &ConnectOptions::default()).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), target);
+        let (_, client) = listener.accept().await.unwrap();
+        assert_eq!(client, stream.local_addr().unwrap());
     }
 }
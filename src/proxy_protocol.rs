@@ -0,0 +1,287 @@
+//! PROXY protocol (v1 text and v2 binary) headers, parsed on accepted
+//! connections and written to tunnel upstreams
+//!
+//! Used when dispatch-proxy sits behind another proxy: the header carries the
+//! original client and destination addresses that the TCP connection itself
+//! no longer shows. The header is read byte-exact so nothing after it is lost.
+//! In front of a backend that expects one, the same header is sent ahead of
+//! the relayed data so the backend sees the client rather than the balancer.
+
+use crate::relay::ClientStream;
+use anyhow::{bail, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::AsyncReadExt;
+
+/// PROXY protocol versions accepted on incoming connections
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ProxyProtocol {
+    /// Text header (`PROXY TCP4 ...`)
+    V1,
+    /// Binary header
+    V2,
+    /// Either version
+    Any,
+}
+
+/// PROXY protocol version of the header sent to tunnel upstreams
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum HeaderVersion {
+    /// Text header (`PROXY TCP4 ...`)
+    V1,
+    /// Binary header
+    V2,
+}
+
+/// Signature that starts every v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest possible v1 header, including the CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+/// Read the PROXY protocol header and return the original source and
+/// destination, or None when the sender proxied nothing (v1 `UNKNOWN`, v2 `LOCAL`)
+pub async fn read_header<S: ClientStream>(
+    conn: &mut S,
+    accepted: ProxyProtocol,
+) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    let first = conn.read_u8().await?;
+    match (first, accepted) {
+        (b'P', ProxyProtocol::V1 | ProxyProtocol::Any) => read_v1(conn).await,
+        (b'\r', ProxyProtocol::V2 | ProxyProtocol::Any) => read_v2(conn).await,
+        _ => bail!("Missing PROXY protocol header (accepting {:?})", accepted),
+    }
+}
+
+/// A header announcing a connection from `source` to `destination`. Addresses
+/// of different families cannot be described, so they give a v1 `UNKNOWN` or
+/// a v2 header of unspecified family, telling the receiver to use the
+/// connection's own addresses.
+pub fn header(version: HeaderVersion, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let canonical = |addr: SocketAddr| SocketAddr::new(addr.ip().to_canonical(), addr.port());
+    let (source, destination) = (canonical(source), canonical(destination));
+
+    match version {
+        HeaderVersion::V1 => {
+            let line = match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    format!("PROXY TCP4 {} {} {} {}\r\n", src, dst, source.port(), destination.port())
+                }
+                (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                    format!("PROXY TCP6 {} {} {} {}\r\n", src, dst, source.port(), destination.port())
+                }
+                _ => "PROXY UNKNOWN\r\n".to_string(),
+            };
+            line.into_bytes()
+        }
+        HeaderVersion::V2 => {
+            let (family, addresses) = match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => (0x11, [src.octets().as_slice(), &dst.octets()].concat()),
+                (IpAddr::V6(src), IpAddr::V6(dst)) => (0x21, [src.octets().as_slice(), &dst.octets()].concat()),
+                _ => (0x00, Vec::new()),
+            };
+            let mut body = addresses;
+            if family != 0x00 {
+                body.extend_from_slice(&source.port().to_be_bytes());
+                body.extend_from_slice(&destination.port().to_be_bytes());
+            }
+
+            let mut header = V2_SIGNATURE.to_vec();
+            // Version 2, PROXY command
+            header.push(0x21);
+            header.push(family);
+            header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+            header.extend_from_slice(&body);
+            header
+        }
+    }
+}
+
+async fn read_v1<S: ClientStream>(conn: &mut S) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    let mut line = vec![b'P'];
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            bail!("PROXY protocol v1 header too long");
+        }
+        line.push(conn.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, dst, src_port, dst_port] => {
+            let parse = |ip: &str, port: &str| -> Result<SocketAddr> {
+                Ok(SocketAddr::new(ip.parse::<IpAddr>()?, port.parse()?))
+            };
+            Ok(Some((parse(src, src_port)?, parse(dst, dst_port)?)))
+        }
+        _ => bail!("Invalid PROXY protocol v1 header: {}", line),
+    }
+}
+
+async fn read_v2<S: ClientStream>(conn: &mut S) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    let mut header = [0u8; 16];
+    header[0] = b'\r';
+    conn.read_exact(&mut header[1..]).await?;
+    if header[..12] != V2_SIGNATURE {
+        bail!("Invalid PROXY protocol v2 signature");
+    }
+
+    let (version, command, family) = (header[12] >> 4, header[12] & 0x0f, header[13]);
+    if version != 2 {
+        bail!("Unsupported PROXY protocol version {}", version);
+    }
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let mut body = vec![0u8; length];
+    conn.read_exact(&mut body).await?;
+
+    // LOCAL: a health check from the proxy itself, not a proxied client
+    if command == 0 {
+        return Ok(None);
+    }
+    if command != 1 {
+        bail!("Unsupported PROXY protocol v2 command {}", command);
+    }
+
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    match family {
+        // TCP over IPv4
+        0x11 if length >= 12 => {
+            let src = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let dst = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            Ok(Some((SocketAddr::new(src.into(), port(8)), SocketAddr::new(dst.into(), port(10)))))
+        }
+        // TCP over IPv6
+        0x21 if length >= 36 => {
+            let src: [u8; 16] = body[..16].try_into()?;
+            let dst: [u8; 16] = body[16..32].try_into()?;
+            Ok(Some((
+                SocketAddr::new(Ipv6Addr::from(src).into(), port(32)),
+                SocketAddr::new(Ipv6Addr::from(dst).into(), port(34)),
+            )))
+        }
+        // Unspecified family: nothing proxied
+        0x00 => Ok(None),
+        _ => bail!("Unsupported PROXY protocol v2 address family {:#04x}", family),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    const V4_SOURCE: &str = "192.0.2.7:40000";
+    const V4_DESTINATION: &str = "198.51.100.1:443";
+    const V6_SOURCE: &str = "[2001:db8::7]:40000";
+    const V6_DESTINATION: &str = "[2001:db8::1]:443";
+
+    /// Parse `input` as the header, returning the addresses and what the
+    /// connection still holds after it
+    async fn parse(input: &[u8], accepted: ProxyProtocol) -> (Result<Option<(SocketAddr, SocketAddr)>>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(input).await.unwrap();
+        drop(client);
+        let result = read_header(&mut server, accepted).await;
+        let mut rest = Vec::new();
+        server.read_to_end(&mut rest).await.unwrap();
+        (result, rest)
+    }
+
+    fn addrs(source: &str, destination: &str) -> (SocketAddr, SocketAddr) {
+        (source.parse().unwrap(), destination.parse().unwrap())
+    }
+
+    #[test]
+    fn formats_v1_lines() {
+        let (src, dst) = addrs(V4_SOURCE, V4_DESTINATION);
+        assert_eq!(header(HeaderVersion::V1, src, dst), b"PROXY TCP4 192.0.2.7 198.51.100.1 40000 443\r\n");
+        let (src, dst) = addrs(V6_SOURCE, V6_DESTINATION);
+        assert_eq!(header(HeaderVersion::V1, src, dst), b"PROXY TCP6 2001:db8::7 2001:db8::1 40000 443\r\n");
+        // A mapped IPv4 client is described as IPv4
+        let (src, dst) = addrs("[::ffff:192.0.2.7]:40000", V4_DESTINATION);
+        assert_eq!(header(HeaderVersion::V1, src, dst), b"PROXY TCP4 192.0.2.7 198.51.100.1 40000 443\r\n");
+        let (src, dst) = addrs(V4_SOURCE, V6_DESTINATION);
+        assert_eq!(header(HeaderVersion::V1, src, dst), b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn formats_v2_headers() {
+        let (src, dst) = addrs(V4_SOURCE, V4_DESTINATION);
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12, 192, 0, 2, 7, 198, 51, 100, 1, 0x9c, 0x40, 0x01, 0xbb]);
+        assert_eq!(header(HeaderVersion::V2, src, dst), expected);
+
+        let (src, dst) = addrs(V6_SOURCE, V6_DESTINATION);
+        let v6 = header(HeaderVersion::V2, src, dst);
+        assert_eq!(v6[12..16], [0x21, 0x21, 0, 36]);
+        assert_eq!(v6.len(), 16 + 36);
+
+        let (src, dst) = addrs(V6_SOURCE, V4_DESTINATION);
+        assert_eq!(header(HeaderVersion::V2, src, dst)[12..], [0x21, 0x00, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn headers_parse_back() {
+        for version in [HeaderVersion::V1, HeaderVersion::V2] {
+            for (source, destination) in [(V4_SOURCE, V4_DESTINATION), (V6_SOURCE, V6_DESTINATION)] {
+                let (src, dst) = addrs(source, destination);
+                let mut input = header(version, src, dst);
+                input.extend_from_slice(b"GET /");
+                let (result, rest) = parse(&input, ProxyProtocol::Any).await;
+                assert_eq!(result.unwrap(), Some((src, dst)), "{:?} {}", version, source);
+                assert_eq!(rest, b"GET /");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn mixed_families_proxy_nothing() {
+        let (src, dst) = addrs(V4_SOURCE, V6_DESTINATION);
+        for version in [HeaderVersion::V1, HeaderVersion::V2] {
+            let mut input = header(version, src, dst);
+            input.extend_from_slice(b"data");
+            let (result, rest) = parse(&input, ProxyProtocol::Any).await;
+            assert_eq!(result.unwrap(), None);
+            assert_eq!(rest, b"data");
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_local_command_proxies_nothing() {
+        let mut input = V2_SIGNATURE.to_vec();
+        // LOCAL with an IPv4 body, which is skipped
+        input.extend_from_slice(&[0x20, 0x11, 0, 12]);
+        input.extend_from_slice(&[0; 12]);
+        input.extend_from_slice(b"data");
+        let (result, rest) = parse(&input, ProxyProtocol::V2).await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"data");
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_headers() {
+        let (src, dst) = addrs(V4_SOURCE, V4_DESTINATION);
+        // Versions the listener does not accept
+        assert!(parse(&header(HeaderVersion::V1, src, dst), ProxyProtocol::V2).await.0.is_err());
+        assert!(parse(&header(HeaderVersion::V2, src, dst), ProxyProtocol::V1).await.0.is_err());
+        assert!(parse(b"GET / HTTP/1.1\r\n", ProxyProtocol::Any).await.0.is_err());
+
+        assert!(parse(b"PROXY TCP4 192.0.2.7 198.51.100.1 40000\r\n", ProxyProtocol::V1).await.0.is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.7 198.51.100.1 40000 http\r\n", ProxyProtocol::V1).await.0.is_err());
+        assert!(parse(&[b'P'; 200], ProxyProtocol::V1).await.0.is_err());
+
+        let mut bad_command = header(HeaderVersion::V2, src, dst);
+        bad_command[12] = 0x22;
+        assert!(parse(&bad_command, ProxyProtocol::V2).await.0.is_err());
+        let mut bad_version = header(HeaderVersion::V2, src, dst);
+        bad_version[12] = 0x11;
+        assert!(parse(&bad_version, ProxyProtocol::V2).await.0.is_err());
+        let mut short_body = header(HeaderVersion::V2, src, dst);
+        short_body[15] = 4;
+        assert!(parse(&short_body, ProxyProtocol::V2).await.0.is_err());
+        let mut truncated = header(HeaderVersion::V2, src, dst);
+        truncated.truncate(20);
+        assert!(parse(&truncated, ProxyProtocol::V2).await.0.is_err());
+    }
+}
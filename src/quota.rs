@@ -0,0 +1,201 @@
+//! Monthly data quotas for metered balancers
+//!
+//! Usage is tracked per balancer and persisted to a small state file so it
+//! survives restarts. Usage resets at the start of each billing period.
+
+use crate::load_balancer::LoadBalancer;
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Fraction of the quota after which a balancer's weight starts shrinking
+const SOFT_LIMIT: f64 = 0.8;
+
+/// How often usage is written to the state file
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Per-balancer data usage against optional monthly quotas
+pub struct QuotaTracker {
+    /// Stable identity of each balancer in the state file
+    keys: Vec<String>,
+    limits: Vec<Option<u64>>,
+    used: Vec<AtomicU64>,
+    exhausted: Vec<AtomicBool>,
+    state_path: Option<PathBuf>,
+    reset_day: u32,
+    /// Start date (YYYY-MM-DD) of the billing period the counters belong to
+    period: Mutex<String>,
+}
+
+impl QuotaTracker {
+    /// Create a tracker for the balancers, restoring usage from the state file
+    /// if it belongs to the current billing period
+    pub fn new(balancers: &[LoadBalancer], state_path: Option<String>, reset_day: u32) -> Result<Self> {
+        let keys: Vec<String> = balancers
+            .iter()
+            .map(|lb| match &lb.iface {
+                Some(iface) => format!("{}%{}", lb.address, iface),
+                None => lb.address.clone(),
+            })
+            .collect();
+
+        let tracker = Self {
+            limits: balancers.iter().map(|lb| lb.options.quota).collect(),
+            used: keys.iter().map(|_| AtomicU64::new(0)).collect(),
+            exhausted: keys.iter().map(|_| AtomicBool::new(false)).collect(),
+            keys,
+            state_path: state_path.map(PathBuf::from),
+            reset_day,
+            period: Mutex::new(current_period(reset_day)),
+        };
+        tracker.load()?;
+        Ok(tracker)
+    }
+
+    fn load(&self) -> Result<()> {
+        let Some(path) = &self.state_path else {
+            return Ok(());
+        };
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Could not read quota state {}", path.display()))
+            }
+        };
+
+        let mut lines = contents.lines();
+        let period = lines.next().and_then(|l| l.strip_prefix("period "));
+        if period != Some(self.period.lock().unwrap().as_str()) {
+            info!("Quota state {} is from a previous billing period, starting fresh", path.display());
+            return Ok(());
+        }
+
+        for line in lines {
+            let Some((bytes, key)) = line.split_once(' ') else {
+                continue;
+            };
+            let Ok(bytes) = bytes.parse::<u64>() else {
+                continue;
+            };
+            if let Some(idx) = self.keys.iter().position(|k| k == key) {
+                self.used[idx].store(bytes, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write current usage to the state file
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.state_path else {
+            return Ok(());
+        };
+
+        let mut contents = format!("period {}\n", self.period.lock().unwrap());
+        for (key, used) in self.keys.iter().zip(&self.used) {
+            contents.push_str(&format!("{} {}\n", used.load(Ordering::Relaxed), key));
+        }
+
+        // Write then rename so a crash never leaves a truncated state file
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, contents)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .with_context(|| format!("Could not write quota state {}", path.display()))
+    }
+
+    /// Reset usage when a new billing period has started
+    fn roll_period(&self) {
+        let now = current_period(self.reset_day);
+        let mut period = self.period.lock().unwrap();
+        if *period != now {
+            info!("New billing period {}, resetting quota usage", now);
+            for (used, exhausted) in self.used.iter().zip(&self.exhausted) {
+                used.store(0, Ordering::Relaxed);
+                exhausted.store(false, Ordering::Relaxed);
+            }
+            *period = now;
+        }
+    }
+
+    /// Account relayed bytes against a balancer's quota
+    pub fn record(&self, idx: usize, bytes: u64) {
+        let Some(used) = self.used.get(idx) else {
+            return;
+        };
+        let total = used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+
+        if let Some(limit) = self.limits[idx] {
+            if total >= limit && !self.exhausted[idx].swap(true, Ordering::Relaxed) {
+                warn!("Load balancer {} exceeded its quota ({} bytes), skipping it", idx + 1, limit);
+            }
+        }
+    }
+
+    /// Selection weight multiplier: 1 below the soft limit, shrinking linearly
+    /// to 0 at the quota
+    pub fn factor(&self, idx: usize) -> f64 {
+        let Some(Some(limit)) = self.limits.get(idx) else {
+            return 1.0;
+        };
+        let used = self.used[idx].load(Ordering::Relaxed) as f64 / *limit as f64;
+
+        if used >= 1.0 {
+            0.0
+        } else if used <= SOFT_LIMIT {
+            1.0
+        } else {
+            (1.0 - used) / (1.0 - SOFT_LIMIT)
+        }
+    }
+}
+
+/// Periodically persist usage and roll over billing periods
+pub fn spawn_persistence(tracker: Arc<QuotaTracker>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            tracker.roll_period();
+            if let Err(e) = tracker.save() {
+                warn!("{:#}", e);
+            }
+        }
+    });
+}
+
+/// Start date of the billing period containing today (UTC)
+fn current_period(reset_day: u32) -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400;
+    let (mut year, mut month, day) = civil_from_days(days as i64);
+
+    if day < reset_day {
+        if month == 1 {
+            month = 12;
+            year -= 1;
+        } else {
+            month -= 1;
+        }
+    }
+    format!("{:04}-{:02}-{:02}", year, month, reset_day)
+}
+
+/// Convert days since 1970-01-01 into a (year, month, day) civil date
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
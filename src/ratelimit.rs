@@ -0,0 +1,148 @@
+//! Bandwidth caps shared by relays, proxy-wide or per balancer
+//!
+//! Each cap is a token bucket holding the bytes that may still be relayed.
+//! Each read through a throttled stream is charged to it after the fact, and
+//! while the bucket is in debt all streams throttled by it wait for the same
+//! refill, so active relays take turns instead of one of them draining the
+//! allowance.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// Bytes that may be relayed in a burst after an idle period, as a fraction of the rate
+const BURST_FRACTION: f64 = 0.1;
+
+/// Token bucket refilled at a fixed number of bytes per second
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Bytes available; negative while the bucket is paying off an oversized read
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        let rate = bytes_per_second as f64;
+        let burst = (rate * BURST_FRACTION).max(1.0);
+        Self {
+            rate,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// How long to wait before reading again, or None when tokens are available
+    fn wait(&self) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last = now;
+
+        (bucket.tokens <= 0.0).then(|| Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+    }
+
+    fn consume(&self, bytes: usize) {
+        self.bucket.lock().unwrap().tokens -= bytes as f64;
+    }
+}
+
+/// Stream wrapper whose reads draw from a shared rate limiter
+pub struct Throttled<'a, S> {
+    inner: &'a mut S,
+    limiter: &'a RateLimiter,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<'a, S> Throttled<'a, S> {
+    pub fn new(inner: &'a mut S, limiter: &'a RateLimiter) -> Self {
+        Self {
+            inner,
+            limiter,
+            sleep: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<'_, S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if let Some(sleep) = &mut self.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            match self.limiter.wait() {
+                Some(wait) => self.sleep = Some(Box::pin(tokio::time::sleep(wait))),
+                None => break,
+            }
+        }
+
+        let before = buf.filled().len();
+        let result = ready!(Pin::new(&mut *self.inner).poll_read(cx, buf));
+        self.limiter.consume(buf.filled().len() - before);
+        Poll::Ready(result)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<'_, S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Read `bytes` through a stream throttled to `rate` bytes per second and
+    /// return how long it took
+    async fn transfer(bytes: usize, rate: u64) -> Duration {
+        let (mut sender, mut receiver) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            sender.write_all(&vec![0u8; bytes]).await.unwrap();
+        });
+        let limiter = RateLimiter::new(rate);
+        let start = Instant::now();
+        let mut received = Vec::new();
+        Throttled::new(&mut receiver, &limiter).read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), bytes);
+        start.elapsed()
+    }
+
+    #[tokio::test]
+    async fn transfers_take_at_least_the_rate_allows() {
+        // 50 kB at 100 kB/s, less the 10 kB burst and the last read of up to 4 kB
+        let elapsed = transfer(50_000, 100_000).await;
+        assert!(elapsed >= Duration::from_millis(350), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn transfers_within_the_burst_are_not_delayed() {
+        let elapsed = transfer(5_000, 100_000).await;
+        assert!(elapsed < Duration::from_millis(100), "took {:?}", elapsed);
+    }
+}
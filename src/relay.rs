@@ -0,0 +1,260 @@
+//! Bidirectional relay between a client and its upstream connection
+
+use crate::idle::{Activity, Watched};
+use crate::load_balancer::{LoadBalancer, LoadBalancerPool};
+use crate::platform;
+use crate::ratelimit::Throttled;
+use crate::settings::Settings;
+use crate::sni::SniLogger;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tracing::{debug, info};
+
+/// Copy buffer size used by tokio's copy_bidirectional
+const DEFAULT_BUFFER: usize = 8 * 1024;
+
+/// Full-size segments per copy buffer when sizing buffers from the MTU
+const SEGMENTS_PER_BUFFER: usize = 16;
+
+/// A client connection accepted by a listener (plain TCP or TLS)
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClientStream for T {}
+
+/// What a relay is carrying: used for logging and per-balancer accounting
+pub struct Flow<'a> {
+    pub client_addr: SocketAddr,
+    pub target: &'a str,
+    pub lb: &'a LoadBalancer,
+    pub lb_index: usize,
+    pub pool: &'a LoadBalancerPool,
+}
+
+/// Relay data in both directions until either side closes, then log how much
+/// moved each way and for how long.
+/// Returns the number of bytes sent to the remote and to the client.
+pub async fn relay<S: ClientStream>(
+    client: &mut S,
+    remote: &mut TcpStream,
+    flow: &Flow<'_>,
+    settings: &Settings,
+) -> std::io::Result<(u64, u64)> {
+    if let Some(keepalive) = &settings.keepalive {
+        platform::set_keepalive(remote, keepalive);
+    }
+    platform::set_nodelay(remote, settings.nodelay);
+
+    let opened = Instant::now();
+    let started = flow.pool.relay_started(flow.lb_index);
+    let result = if settings.log_sni {
+        let mut client = SniLogger::new(client, flow.client_addr, flow.target);
+        copy(&mut client, remote, flow, settings).await
+    } else {
+        copy(client, remote, flow, settings).await
+    };
+    flow.pool.relay_finished(flow.lb_index, started);
+
+    match result {
+        Ok((up, down)) => {
+            flow.pool.record_transfer(flow.lb_index, up, down);
+            flow.pool.record_relay_size(flow.lb_index, up + down);
+            check_skew(up, down, flow, settings);
+            info!(
+                "{} -> {} closed LB: {}, {} bytes out, {} bytes in, {:.1?}",
+                flow.target,
+                flow.lb.address,
+                flow.lb_index + 1,
+                up,
+                down,
+                opened.elapsed()
+            );
+        }
+        Err(ref e) => info!(
+            "{} -> {} closed LB: {} {{{}}} after {:.1?}",
+            flow.target,
+            flow.lb.address,
+            flow.lb_index + 1,
+            e,
+            opened.elapsed()
+        ),
+    }
+
+    result
+}
+
+async fn copy<S: ClientStream>(
+    client: &mut S,
+    remote: &mut TcpStream,
+    flow: &Flow<'_>,
+    settings: &Settings,
+) -> std::io::Result<(u64, u64)> {
+    let buffer = buffer_size(flow, settings);
+
+    // Hold the copy buffers' share of the memory cap for the life of the relay;
+    // when it is used up, new relays wait here without reading from either side
+    let _reserved = match &settings.buffer_memory {
+        Some(memory) => {
+            let kib = (2 * buffer).div_ceil(1024) as u32;
+            let kib = kib.min(memory.limit_kib);
+            if memory.semaphore.available_permits() < kib as usize {
+                debug!("Relay {} -> {} waiting for buffer memory", flow.client_addr, flow.target);
+            }
+            Some(memory.semaphore.acquire_many(kib).await.expect("buffer semaphore closed"))
+        }
+        None => None,
+    };
+
+    #[cfg(feature = "pcap")]
+    if let Some(tracer) = settings.tracer.as_ref().filter(|t| t.matches(flow.target)) {
+        let server_addr = remote.peer_addr()?;
+        let mut traced = tracer.trace(client, flow.client_addr, server_addr);
+        return copy_limited(&mut traced, remote, buffer, flow, settings).await;
+    }
+
+    copy_limited(client, remote, buffer, flow, settings).await
+}
+
+/// Copy in both directions until either side closes, with an idle timeout
+/// until neither side has sent anything for that long, and with
+/// `--drain-on-unhealthy` until the balancer fails a health check
+async fn copy_limited<A, B>(
+    a: &mut A,
+    b: &mut B,
+    buffer: usize,
+    flow: &Flow<'_>,
+    settings: &Settings,
+) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut health_drop = flow.pool.health_drop(flow.lb_index);
+    if settings.idle_timeout.is_none() && health_drop.is_none() {
+        return copy_throttled(a, b, buffer, flow, settings).await;
+    }
+
+    let activity = Activity::new();
+    let mut a = Watched::new(a, &activity, &activity.up);
+    let mut b = Watched::new(b, &activity, &activity.down);
+    let idle = async {
+        match settings.idle_timeout {
+            Some(timeout) => activity.idle(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    let unhealthy = async {
+        // The pool holding the sender outlives its relays, so changed() only
+        // fails once the relay is over anyway
+        let signalled = match health_drop.as_mut() {
+            Some(drop) => drop.changed().await.is_ok(),
+            None => false,
+        };
+        if !signalled {
+            std::future::pending::<()>().await;
+        }
+    };
+    // Both sides are closed when the caller drops the streams
+    tokio::select! {
+        result = copy_throttled(&mut a, &mut b, buffer, flow, settings) => result,
+        _ = idle => {
+            debug!("Relay {} -> {} idle for {:?}, closing", flow.client_addr, flow.target, settings.idle_timeout);
+            Ok(activity.bytes())
+        }
+        _ = unhealthy => {
+            debug!("Relay {} -> {} closed, load balancer {} is unhealthy", flow.client_addr, flow.target, flow.lb_index + 1);
+            Ok(activity.bytes())
+        }
+    }
+}
+
+/// Copy in both directions, drawing from the balancer's rate limit if it has one
+async fn copy_throttled<A, B>(
+    a: &mut A,
+    b: &mut B,
+    buffer: usize,
+    flow: &Flow<'_>,
+    settings: &Settings,
+) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    match flow.pool.rate_limit(flow.lb_index) {
+        Some(limiter) => {
+            let mut a = Throttled::new(a, limiter);
+            let mut b = Throttled::new(b, limiter);
+            copy_total_rate(&mut a, &mut b, buffer, settings).await
+        }
+        None => copy_total_rate(a, b, buffer, settings).await,
+    }
+}
+
+/// Copy in both directions, drawing from the proxy-wide rate limit if one is set
+async fn copy_total_rate<A, B>(a: &mut A, b: &mut B, buffer: usize, settings: &Settings) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    match &settings.total_rate {
+        Some(limiter) => {
+            let mut a = Throttled::new(a, limiter);
+            let mut b = Throttled::new(b, limiter);
+            tokio::io::copy_bidirectional_with_sizes(&mut a, &mut b, buffer, buffer).await
+        }
+        None => tokio::io::copy_bidirectional_with_sizes(a, b, buffer, buffer).await,
+    }
+}
+
+/// Cap on the copy buffer memory held by all relays together
+pub struct BufferMemory {
+    semaphore: Semaphore,
+    limit_kib: u32,
+}
+
+impl BufferMemory {
+    pub fn new(limit_bytes: u64) -> Self {
+        let limit_kib = (limit_bytes / 1024).clamp(1, Semaphore::MAX_PERMITS as u64) as u32;
+        Self {
+            semaphore: Semaphore::new(limit_kib as usize),
+            limit_kib,
+        }
+    }
+}
+
+/// Copy buffer size for the flow: a whole number of full-size segments on
+/// the balancer's interface with --mtu-buffers, otherwise tokio's default
+fn buffer_size(flow: &Flow<'_>, settings: &Settings) -> usize {
+    if !settings.mtu_buffers {
+        return DEFAULT_BUFFER;
+    }
+    let Some(mtu) = flow.lb.iface.as_deref().and_then(platform::interface_mtu) else {
+        return DEFAULT_BUFFER;
+    };
+
+    // IP and TCP headers without options
+    let headers = if flow.lb.is_ipv6 { 60 } else { 40 };
+    let mss = (mtu as usize).saturating_sub(headers).max(536);
+    mss * SEGMENTS_PER_BUFFER
+}
+
+/// Log relays whose up/down byte counts are lopsided beyond the configured
+/// ratio, which usually means the balancer is throttled in one direction
+fn check_skew(up: u64, down: u64, flow: &Flow<'_>, settings: &Settings) {
+    let Some(threshold) = settings.skew_ratio else {
+        return;
+    };
+    if up + down < settings.skew_min_bytes {
+        return;
+    }
+
+    let ratio = up.max(down) as f64 / up.min(down).max(1) as f64;
+    if ratio >= threshold {
+        debug!(
+            "Asymmetric relay {} -> {} LB: {} ({}): {} bytes up, {} bytes down",
+            flow.client_addr, flow.target, flow.lb_index + 1, flow.lb.address, up, down
+        );
+    }
+}
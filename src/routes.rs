@@ -0,0 +1,250 @@
+//! Routing rules that pin destinations, or authenticated users, to a balancer
+//!
+//! A rule is `PATTERN=N`, where N is a balancer's position in the startup
+//! listing. `example.com` matches only that name; `*.example.com` matches the
+//! name and every subdomain. The first matching rule wins. Only SOCKS domain
+//! targets are matched, since an IP target carries no name to match.
+//!
+//! User rules match the username a client logged in with, where `*` stands
+//! for any run of characters, so `team-*` matches every session of a team.
+//! A client can also name a balancer itself with a username such as
+//! `lb2-session`, after a configured prefix.
+
+use anyhow::{bail, Result};
+use tracing::info;
+
+/// Domain routing table in command-line order
+#[derive(Debug, Clone, Default)]
+pub struct RouteTable {
+    rules: Vec<Route>,
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    pattern: Pattern,
+    balancer: usize,
+}
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    Exact(String),
+    /// The domain and all its subdomains
+    Suffix(String),
+}
+
+impl RouteTable {
+    /// Parse `PATTERN=N` rules against a pool of `balancers` balancers
+    pub fn parse(specs: &[String], balancers: usize) -> Result<Self> {
+        let mut rules = Vec::with_capacity(specs.len());
+        for spec in specs {
+            let (domain, balancer) = split_rule(spec, balancers)?;
+
+            let normalized = normalize(domain);
+            let pattern = match normalized.strip_prefix("*.") {
+                Some(suffix) => Pattern::Suffix(suffix.to_string()),
+                None => Pattern::Exact(normalized),
+            };
+            if matches!(&pattern, Pattern::Exact(d) | Pattern::Suffix(d) if d.is_empty() || d.contains('*')) {
+                bail!("Invalid route {}, expected a domain or *.domain", spec);
+            }
+            info!("Routing {} through load balancer {}", domain, balancer + 1);
+            rules.push(Route { pattern, balancer });
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Balancer index of the first rule matching `domain`
+    pub fn lookup(&self, domain: &str) -> Option<usize> {
+        let domain = normalize(domain);
+        self.rules
+            .iter()
+            .find(|rule| match &rule.pattern {
+                Pattern::Exact(name) => domain == *name,
+                Pattern::Suffix(suffix) => {
+                    domain == *suffix
+                        || domain.strip_suffix(suffix.as_str()).is_some_and(|sub| sub.ends_with('.'))
+                }
+            })
+            .map(|rule| rule.balancer)
+    }
+}
+
+/// Username routing table in command-line order
+#[derive(Debug, Clone, Default)]
+pub struct UserRouteTable {
+    rules: Vec<(String, usize)>,
+}
+
+impl UserRouteTable {
+    /// Parse `PATTERN=N` rules against a pool of `balancers` balancers
+    pub fn parse(specs: &[String], balancers: usize) -> Result<Self> {
+        let mut rules = Vec::with_capacity(specs.len());
+        for spec in specs {
+            let (pattern, balancer) = split_rule(spec, balancers)?;
+            if pattern.is_empty() {
+                bail!("Invalid user route {}, expected a username pattern", spec);
+            }
+            info!("Routing user {} through load balancer {}", pattern, balancer + 1);
+            rules.push((pattern.to_string(), balancer));
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Balancer index of the first rule matching `username`
+    pub fn lookup(&self, username: &str) -> Option<usize> {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| glob_matches(pattern, username))
+            .map(|&(_, balancer)| balancer)
+    }
+}
+
+/// Balancer named by a username of the form `{prefix}N` or `{prefix}N-anything`,
+/// as a 0-based index into a pool of `balancers` balancers
+pub fn prefixed_balancer(username: &str, prefix: &str, balancers: usize) -> Option<usize> {
+    let rest = username.strip_prefix(prefix)?;
+    let digits = rest.split_once('-').map_or(rest, |(digits, _)| digits);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    match digits.parse::<usize>() {
+        Ok(n) if (1..=balancers).contains(&n) => Some(n - 1),
+        _ => None,
+    }
+}
+
+/// Split `PATTERN=N` into the pattern and the 0-based balancer index
+fn split_rule(spec: &str, balancers: usize) -> Result<(&str, usize)> {
+    let Some((pattern, index)) = spec.rsplit_once('=') else {
+        bail!("Invalid route {}, expected PATTERN=LB_INDEX", spec);
+    };
+    match index.parse::<usize>() {
+        Ok(n) if (1..=balancers).contains(&n) => Ok((pattern, n - 1)),
+        _ => bail!("Invalid route {}, the load balancer index must be between 1 and {}", spec, balancers),
+    }
+}
+
+/// Whether `text` matches `pattern`, in which `*` matches any run of characters
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // split always yields at least one part
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    // Without a `*` the pattern must match the whole text
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Domains compare case-insensitively and without a trailing dot
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes(specs: &[&str]) -> RouteTable {
+        let specs: Vec<String> = specs.iter().map(|s| s.to_string()).collect();
+        RouteTable::parse(&specs, 3).unwrap()
+    }
+
+    #[test]
+    fn exact_routes_match_only_the_name() {
+        let table = routes(&["example.com=2"]);
+        assert_eq!(table.lookup("example.com"), Some(1));
+        assert_eq!(table.lookup("Example.COM."), Some(1));
+        assert_eq!(table.lookup("www.example.com"), None);
+    }
+
+    #[test]
+    fn wildcard_routes_match_the_name_and_subdomains() {
+        let table = routes(&["*.netflix.com=3"]);
+        assert_eq!(table.lookup("netflix.com"), Some(2));
+        assert_eq!(table.lookup("www.netflix.com"), Some(2));
+        assert_eq!(table.lookup("a.b.netflix.com"), Some(2));
+        assert_eq!(table.lookup("notnetflix.com"), None);
+    }
+
+    #[test]
+    fn unmatched_domains_fall_through_to_the_pool() {
+        let table = routes(&["example.com=1", "*.example.com=2"]);
+        // The first matching rule wins
+        assert_eq!(table.lookup("example.com"), Some(0));
+        assert_eq!(table.lookup("api.example.com"), Some(1));
+        assert_eq!(table.lookup("example.org"), None);
+        assert_eq!(table.lookup("example.com.evil"), None);
+        assert!(routes(&[]).is_empty());
+        assert_eq!(routes(&[]).lookup("example.com"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_routes() {
+        for spec in ["example.com", "example.com=0", "example.com=4", "=1", "*.=1", "a*.com=1"] {
+            assert!(RouteTable::parse(&[spec.to_string()], 3).is_err(), "accepted {}", spec);
+        }
+    }
+
+    fn users(specs: &[&str]) -> UserRouteTable {
+        let specs: Vec<String> = specs.iter().map(|s| s.to_string()).collect();
+        UserRouteTable::parse(&specs, 3).unwrap()
+    }
+
+    #[test]
+    fn user_routes_match_globs_in_order() {
+        let table = users(&["alice=1", "team-*=2", "*-session-*=3", "*=1"]);
+        assert_eq!(table.lookup("alice"), Some(0));
+        assert_eq!(table.lookup("team-red"), Some(1));
+        assert_eq!(table.lookup("bob-session-xyz"), Some(2));
+        assert_eq!(table.lookup("carol"), Some(0));
+
+        let table = users(&["alice=1", "team-*=2"]);
+        assert_eq!(table.lookup("alice2"), None);
+        assert_eq!(table.lookup("my-team-red"), None);
+    }
+
+    #[test]
+    fn glob_needs_room_for_prefix_and_suffix() {
+        assert!(glob_matches("a*a", "aa"));
+        assert!(!glob_matches("a*a", "a"));
+        assert!(glob_matches("a*b*c", "axxbyyc"));
+        assert!(!glob_matches("a*b*c", "acb"));
+    }
+
+    #[test]
+    fn prefixed_username_names_a_balancer() {
+        assert_eq!(prefixed_balancer("lb2-session-xyz", "lb", 3), Some(1));
+        assert_eq!(prefixed_balancer("lb3", "lb", 3), Some(2));
+        assert_eq!(prefixed_balancer("lb4-x", "lb", 3), None);
+        assert_eq!(prefixed_balancer("lb0", "lb", 3), None);
+        assert_eq!(prefixed_balancer("lb2x", "lb", 3), None);
+        assert_eq!(prefixed_balancer("lb-2", "lb", 3), None);
+        assert_eq!(prefixed_balancer("alice", "lb", 3), None);
+    }
+
+    #[test]
+    fn rejects_invalid_user_routes() {
+        for spec in ["alice", "alice=0", "alice=4", "=1"] {
+            assert!(UserRouteTable::parse(&[spec.to_string()], 3).is_err(), "accepted {}", spec);
+        }
+    }
+}
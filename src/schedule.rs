@@ -0,0 +1,214 @@
+//! Contention ratios that change with the time of day
+//!
+//! A balancer's `schedule=` option lists windows of local time with the
+//! contention ratio to use during each, such as
+//! `schedule=22:00-06:00=5,06:00-09:00=2`. A window may wrap past midnight
+//! and the first one containing the current time wins; outside all of them
+//! the balancer's own ratio applies. The ratios in effect are set whenever a
+//! pool is built, and a background task swaps in a rebuilt pool through
+//! [`SharedPool::update`] when a window starts or ends.
+
+use crate::admin::{self, BuildPool};
+use crate::load_balancer::{LoadBalancer, LoadBalancerPool, SharedPool};
+use anyhow::{bail, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+/// How often the task checks whether a window started or ended
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// Time windows of a balancer and the contention ratio in each
+#[derive(Debug, Clone, PartialEq)]
+pub struct RatioSchedule {
+    windows: Vec<Window>,
+    /// Ratio outside all windows
+    base: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Window {
+    /// Minutes after midnight, the start inclusive and the end exclusive
+    start: u16,
+    end: u16,
+    ratio: u32,
+}
+
+impl RatioSchedule {
+    /// Parse comma-separated `HH:MM-HH:MM=RATIO` windows for a balancer whose
+    /// own contention ratio is `base`
+    pub fn parse(spec: &str, base: u32) -> Result<Self> {
+        let windows = spec
+            .split(',')
+            .map(|window| {
+                let parsed = window.trim().split_once('=').and_then(|(times, ratio)| {
+                    let (start, end) = times.split_once('-')?;
+                    Some(Window {
+                        start: parse_time(start)?,
+                        end: parse_time(end)?,
+                        ratio: ratio.parse().ok().filter(|&r| r > 0)?,
+                    })
+                });
+                match parsed {
+                    Some(w) if w.start != w.end => Ok(w),
+                    _ => bail!("Invalid schedule window {:?}, expected HH:MM-HH:MM=RATIO", window),
+                }
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { windows, base })
+    }
+
+    /// Change the ratio used outside the windows
+    pub fn set_base(&mut self, base: u32) {
+        self.base = base;
+    }
+
+    /// Contention ratio in effect `minute` minutes after midnight
+    pub fn ratio_at(&self, minute: u16) -> u32 {
+        self.windows
+            .iter()
+            .find(|w| {
+                if w.start < w.end {
+                    (w.start..w.end).contains(&minute)
+                } else {
+                    // Wraps past midnight
+                    minute >= w.start || minute < w.end
+                }
+            })
+            .map_or(self.base, |w| w.ratio)
+    }
+}
+
+impl std::fmt::Display for RatioSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, w) in self.windows.iter().enumerate() {
+            let time = |m: u16| format!("{:02}:{:02}", m / 60, m % 60);
+            write!(f, "{}{}-{}={}", if i == 0 { "" } else { "," }, time(w.start), time(w.end), w.ratio)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_time(time: &str) -> Option<u16> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    if hours.len() > 2 || minutes.len() != 2 {
+        return None;
+    }
+    let (hours, minutes): (u16, u16) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+/// Set the contention ratio of each scheduled balancer to the one in effect
+/// `minute` minutes after midnight
+pub fn apply(balancers: &mut [LoadBalancer], minute: u16) {
+    for (idx, lb) in balancers.iter_mut().enumerate() {
+        let Some(schedule) = &lb.options.schedule else {
+            continue;
+        };
+        let ratio = schedule.ratio_at(minute);
+        if ratio != lb.contention_ratio {
+            info!("Scheduled contention ratio of load balancer {}: {} -> {}", idx + 1, lb.contention_ratio, ratio);
+            lb.contention_ratio = ratio;
+        }
+    }
+}
+
+/// Whether a balancer of the pool has a ratio other than the one in effect at `minute`
+fn outdated(pool: &LoadBalancerPool, minute: u16) -> bool {
+    (0..pool.len())
+        .filter_map(|idx| pool.balancer(idx))
+        .any(|lb| lb.options.schedule.as_ref().is_some_and(|s| s.ratio_at(minute) != lb.contention_ratio))
+}
+
+/// Minutes since local midnight
+#[cfg(unix)]
+pub fn local_minute() -> u16 {
+    // SAFETY: localtime_r only writes the tm it is given
+    let local = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        (!libc::localtime_r(&now, &mut tm).is_null()).then_some(tm)
+    };
+    match local {
+        Some(tm) => (tm.tm_hour * 60 + tm.tm_min) as u16,
+        None => utc_minute(),
+    }
+}
+
+/// Minutes since midnight, in UTC where the local time zone is not known
+#[cfg(not(unix))]
+pub fn local_minute() -> u16 {
+    utc_minute()
+}
+
+fn utc_minute() -> u16 {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (secs / 60 % u64::from(MINUTES_PER_DAY)) as u16
+}
+
+/// Rebuild the pool with `build` whenever a balancer's schedule window starts
+/// or ends, carrying over counters as a reload does
+pub fn spawn(shared: Arc<SharedPool>, build: Box<BuildPool>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            if !outdated(&shared.current(), local_minute()) {
+                continue;
+            }
+            // Building the pool applies the schedule
+            let _ = shared.update(|current| Ok(build(admin::balancers(current), current.strategy()).with_counters_from(current)));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn at(hours: u16, minutes: u16) -> u16 {
+        hours * 60 + minutes
+    }
+
+    #[test]
+    fn picks_the_ratio_of_the_first_matching_window() {
+        let schedule = RatioSchedule::parse("22:00-06:00=5, 6:00-09:30=2,08:00-10:00=3", 1).unwrap();
+        assert_eq!(schedule.to_string(), "22:00-06:00=5,06:00-09:30=2,08:00-10:00=3");
+        assert_eq!(schedule.ratio_at(at(22, 0)), 5);
+        assert_eq!(schedule.ratio_at(at(0, 0)), 5);
+        assert_eq!(schedule.ratio_at(at(5, 59)), 5);
+        assert_eq!(schedule.ratio_at(at(6, 0)), 2);
+        assert_eq!(schedule.ratio_at(at(9, 0)), 2);
+        assert_eq!(schedule.ratio_at(at(9, 30)), 3);
+        assert_eq!(schedule.ratio_at(at(10, 0)), 1);
+        assert_eq!(schedule.ratio_at(at(21, 59)), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_windows() {
+        for spec in ["", "22:00-06:00", "22:00=5", "22:00-06:00=0", "24:00-06:00=2", "22:60-06:00=2", "10:00-10:00=2", "ten-six=2"] {
+            assert!(RatioSchedule::parse(spec, 1).is_err(), "accepted {:?}", spec);
+        }
+    }
+
+    #[test]
+    fn applies_the_ratio_in_effect() {
+        let mut lb = LoadBalancer::new("192.0.2.1:0".to_string(), None, 1, false);
+        lb.options.schedule = Some(RatioSchedule::parse("22:00-06:00=5", 1).unwrap());
+        let mut balancers = vec![lb, LoadBalancer::new("192.0.2.2:0".to_string(), None, 2, false)];
+
+        apply(&mut balancers, at(23, 0));
+        assert_eq!([balancers[0].contention_ratio, balancers[1].contention_ratio], [5, 2]);
+        let pool = LoadBalancerPool::new(balancers.clone());
+        assert!(!outdated(&pool, at(23, 30)));
+        assert!(outdated(&pool, at(12, 0)));
+
+        apply(&mut balancers, at(12, 0));
+        assert_eq!([balancers[0].contention_ratio, balancers[1].contention_ratio], [1, 2]);
+    }
+}
@@ -0,0 +1,112 @@
+//! `selftest` subcommand: the full proxy pipeline over loopback
+//!
+//! Starts an echo server and a proxy listener with a single loopback
+//! balancer, then drives a SOCKS5 CONNECT through it and checks that the
+//! payload comes back unchanged. Needs no network access or privileges.
+
+use crate::load_balancer::{LoadBalancer, LoadBalancerPool};
+use crate::settings::Settings;
+use anyhow::{bail, Context, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Time allowed for the whole exchange
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const PAYLOAD: &[u8] = b"dispatch-proxy selftest payload";
+
+/// Run the self-test, printing each step; returns an error if any step failed
+pub async fn run() -> Result<()> {
+    println!("--- Running selftest over loopback");
+
+    match tokio::time::timeout(TIMEOUT, exercise()).await {
+        Ok(Ok(())) => {
+            println!("PASS");
+            Ok(())
+        }
+        Ok(Err(e)) => {
+            println!("FAIL: {:#}", e);
+            bail!("Selftest failed")
+        }
+        Err(_) => {
+            println!("FAIL: timed out after {:?}", TIMEOUT);
+            bail!("Selftest failed")
+        }
+    }
+}
+
+async fn exercise() -> Result<()> {
+    let echo = spawn_echo().await.context("echo server")?;
+    println!("[+] Echo server on {}", echo);
+
+    let proxy = spawn_proxy().await.context("proxy listener")?;
+    println!("[+] Proxy on {} with load balancer 127.0.0.1", proxy);
+
+    let mut client = TcpStream::connect(proxy).await.context("connect to proxy")?;
+
+    // Greeting offering no authentication
+    client.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.context("greeting reply")?;
+    if choice != [0x05, 0x00] {
+        bail!("unexpected method selection {:02x?}", choice);
+    }
+    println!("[+] SOCKS5 handshake");
+
+    let SocketAddr::V4(target) = echo else {
+        bail!("echo server is not on IPv4");
+    };
+    let mut request = vec![0x05, 0x01, 0x00, 0x01];
+    request.extend_from_slice(&target.ip().octets());
+    request.extend_from_slice(&target.port().to_be_bytes());
+    client.write_all(&request).await?;
+
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.context("CONNECT reply")?;
+    if reply[1] != 0x00 {
+        bail!("CONNECT failed with reply code {}", reply[1]);
+    }
+    println!("[+] CONNECT {} through load balancer", echo);
+
+    client.write_all(PAYLOAD).await?;
+    let mut echoed = vec![0u8; PAYLOAD.len()];
+    client.read_exact(&mut echoed).await.context("echo")?;
+    if echoed != PAYLOAD {
+        bail!("relayed payload came back altered");
+    }
+    println!("[+] Relayed {} bytes in both directions", PAYLOAD.len());
+
+    Ok(())
+}
+
+/// Echo server accepting a single connection
+async fn spawn_echo() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        if let Ok((mut stream, _)) = listener.accept().await {
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        }
+    });
+    Ok(addr)
+}
+
+/// Proxy listener with default settings and a loopback balancer, accepting a single connection
+async fn spawn_proxy() -> Result<SocketAddr> {
+    let balancer = LoadBalancer::new("127.0.0.1:0".to_string(), None, 1, false);
+    let pool = Arc::new(LoadBalancerPool::new(vec![balancer]));
+    let settings = Arc::new(Settings::default());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        if let Ok((socket, client_addr)) = listener.accept().await {
+            crate::handle_connection(socket, client_addr, addr, crate::ListenMode::Socks, None, pool, settings).await;
+        }
+    });
+    Ok(addr)
+}
@@ -0,0 +1,108 @@
+//! Runtime settings shared by all connection handlers
+
+#[cfg(feature = "pcap")]
+use crate::pcap::PcapTracer;
+use crate::acl::AccessList;
+use crate::load_balancer::IpFamily;
+use crate::lockout::Lockout;
+use crate::platform::{PortRange, ReusePolicy, RetryPolicy};
+use crate::proxy_protocol::{HeaderVersion, ProxyProtocol};
+use crate::ratelimit::RateLimiter;
+use crate::relay::BufferMemory;
+use crate::routes::{RouteTable, UserRouteTable};
+use crate::socks::{AuthMethod, Credentials, ReplyCodes};
+use crate::tarpit::Tarpit;
+use socket2::TcpKeepalive;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::time::Duration;
+#[cfg(feature = "pcap")]
+use std::sync::Arc;
+
+/// Options that affect how each accepted connection is handled
+#[derive(Default)]
+pub struct Settings {
+    /// Use tunnelling mode (transparent load balancing proxy)
+    pub tunnel: bool,
+    /// Relay intercepted connections to their original destination without a handshake
+    pub transparent: bool,
+    /// Expect a PROXY protocol header on tunnel connections
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// PROXY protocol header sent to tunnel upstreams ahead of the relayed data
+    pub send_proxy_protocol: Option<HeaderVersion>,
+    /// Reject malformed SOCKS request headers
+    pub strict_socks: bool,
+    /// Drop SOCKS clients whose greeting does not offer this method
+    pub required_auth_method: Option<AuthMethod>,
+    /// Username/password pairs clients must authenticate with; empty for no authentication
+    pub credentials: Vec<Credentials>,
+    /// SOCKS reply code sent for each kind of failed request
+    pub reply_codes: ReplyCodes,
+    /// Delay before dropping connections denied by policy
+    pub tarpit: Option<Tarpit>,
+    /// Refuse clients after repeated failed logins
+    pub lockout: Option<Lockout>,
+    /// Close new connections while fewer balancers than this are healthy
+    pub refuse_below_healthy: Option<usize>,
+    /// Time allowed for the SOCKS greeting and request, or the PROXY protocol header
+    pub handshake_timeout: Option<Duration>,
+    /// Largest HTTP CONNECT request head accepted, in bytes
+    pub http_max_head: usize,
+    /// Connect timeout for balancers without their own
+    pub connect_timeout: Option<Duration>,
+    /// Total time allowed from request to established connection
+    pub connect_deadline: Option<Duration>,
+    /// Retries of a balancer after a transient connect error; None to move straight on
+    pub retry: Option<RetryPolicy>,
+    /// Close relays on which neither side has sent anything for this long
+    pub idle_timeout: Option<Duration>,
+    /// Most resolved addresses tried per connection
+    pub max_resolve_attempts: Option<usize>,
+    /// Nameserver domain targets are resolved with through the selected
+    /// balancer; None for the system resolver
+    pub dns_server: Option<SocketAddr>,
+    /// Restrict targets and name resolution to one address family
+    pub ip_family: Option<IpFamily>,
+    /// Refuse targets in private, loopback and link-local ranges
+    pub block_private: bool,
+    /// Prefix IPv4 targets are embedded in when connected from an IPv6 address
+    pub nat64_prefix: Option<Ipv6Addr>,
+    /// Allow and deny rules for target addresses
+    pub access: AccessList,
+    /// Use TCP Fast Open on outbound connections
+    pub fast_open: bool,
+    /// Forward client bytes sent ahead of the SOCKS reply together with the connect
+    pub early_data: bool,
+    /// Size relay buffers from the balancer interface's MTU
+    pub mtu_buffers: bool,
+    /// Cap on copy buffer memory across all relays
+    pub buffer_memory: Option<BufferMemory>,
+    /// Balancer index pairs: client bytes relayed via the first are copied to the second
+    pub mirrors: Vec<(usize, usize)>,
+    /// Domains pinned to a balancer
+    pub routes: RouteTable,
+    /// Authenticated users pinned to a balancer
+    pub user_routes: UserRouteTable,
+    /// Prefix of usernames that name a balancer, as in `lb2-session`
+    pub user_balancer_prefix: Option<String>,
+    /// Connect from the client's source port instead of an ephemeral one
+    pub preserve_source_port: bool,
+    /// Connect from a port of this range instead of an ephemeral one
+    pub source_ports: Option<PortRange>,
+    /// Address reuse options on outbound sockets
+    pub socket_reuse: ReusePolicy,
+    /// Cap on the combined throughput of all relays
+    pub total_rate: Option<RateLimiter>,
+    /// Log relays whose up/down byte ratio reaches this value
+    pub skew_ratio: Option<f64>,
+    /// Minimum total bytes before a relay is checked for skew
+    pub skew_min_bytes: u64,
+    /// Log the server name of TLS ClientHellos read from clients
+    pub log_sni: bool,
+    /// TCP keepalive applied to client and outbound sockets
+    pub keepalive: Option<TcpKeepalive>,
+    /// TCP_NODELAY applied to client and outbound sockets
+    pub nodelay: bool,
+    /// Write relayed traffic of matching connections to a pcap-ng file
+    #[cfg(feature = "pcap")]
+    pub tracer: Option<Arc<PcapTracer>>,
+}
@@ -0,0 +1,127 @@
+//! Load shedding when the process runs out of file descriptors, and the
+//! optional cap on concurrent connections
+//!
+//! `accept` fails with EMFILE/ENFILE while the connection stays queued, so
+//! retrying at once spins on the same error. Instead the listener stops
+//! accepting for a backoff that grows while the limit persists, giving active
+//! connections time to close and free their descriptors.
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{info, warn};
+
+/// First pause after hitting the descriptor limit
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Longest pause between accept attempts while the limit persists
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Number of client connections currently being handled
+#[derive(Clone, Default)]
+pub struct ActiveConnections(Arc<AtomicUsize>);
+
+impl ActiveConnections {
+    /// Count a connection until the returned guard is dropped
+    pub fn track(&self) -> ActiveGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        ActiveGuard(Arc::clone(&self.0))
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct ActiveGuard(Arc<AtomicUsize>);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether an error means the process or system is out of file descriptors
+pub fn is_fd_exhaustion(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+/// Accept backoff state of a listener
+pub struct Shedder {
+    active: ActiveConnections,
+    backoff: Option<Duration>,
+}
+
+impl Shedder {
+    pub fn new(active: ActiveConnections) -> Self {
+        Self { active, backoff: None }
+    }
+
+    /// Pause accepting after a descriptor exhaustion error
+    pub async fn shed(&mut self, e: &io::Error) {
+        let backoff = self.backoff.map_or(INITIAL_BACKOFF, |b| (b * 2).min(MAX_BACKOFF));
+        if self.backoff.is_none() {
+            warn!(
+                "File descriptor exhaustion ({}) with {} active connections, shedding new connections",
+                e,
+                self.active.count()
+            );
+        }
+        self.backoff = Some(backoff);
+        tokio::time::sleep(backoff).await;
+    }
+
+    /// Note a successful accept, ending the shedding state if it was active
+    pub fn accepted(&mut self) {
+        if self.backoff.take().is_some() {
+            info!(
+                "Recovered from file descriptor exhaustion with {} active connections",
+                self.active.count()
+            );
+        }
+    }
+}
+
+/// Cap on connections handled at once. At the cap the listeners stop
+/// accepting, so new connections wait in the kernel's listen backlog instead
+/// of each taking a task and a descriptor. Clones share the cap.
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    permits: Arc<Semaphore>,
+    max: usize,
+    waiting: bool,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max)),
+            max,
+            waiting: false,
+        }
+    }
+
+    /// Wait for a free slot, held until the returned permit is dropped
+    pub async fn acquire(&mut self) -> OwnedSemaphorePermit {
+        match Arc::clone(&self.permits).try_acquire_owned() {
+            Ok(permit) => {
+                if std::mem::take(&mut self.waiting) {
+                    info!("Below the limit of {} connections again", self.max);
+                }
+                permit
+            }
+            Err(_) => {
+                if !self.waiting {
+                    warn!("Limit of {} connections reached, new connections wait until one closes", self.max);
+                    self.waiting = true;
+                }
+                Arc::clone(&self.permits)
+                    .acquire_owned()
+                    .await
+                    .expect("connection semaphore closed")
+            }
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Graceful shutdown on SIGINT/SIGTERM
+//!
+//! The first signal stops the listener and lets active connections finish for
+//! up to the drain timeout; a second signal exits at once.
+
+use crate::shedding::ActiveConnections;
+use std::io;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// How often the drain checks whether connections have finished
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
+/// Shutdown signals of the process
+pub struct Signals {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            terminate: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?,
+        })
+    }
+
+    /// Wait for SIGINT (Ctrl-C) or, on Unix, SIGTERM
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = self.terminate.recv() => {}
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Wait up to `timeout` for active connections to finish, or until another signal
+pub async fn drain(active: &ActiveConnections, timeout: Duration, signals: &mut Signals) {
+    let remaining = active.count();
+    if remaining == 0 {
+        info!("Shutting down");
+        return;
+    }
+    info!(
+        "Shutting down, waiting up to {:?} for {} active connections (signal again to exit now)",
+        timeout, remaining
+    );
+
+    let finished = async {
+        while active.count() > 0 {
+            tokio::time::sleep(DRAIN_POLL).await;
+        }
+    };
+    tokio::select! {
+        _ = finished => info!("All connections finished"),
+        _ = tokio::time::sleep(timeout) => {
+            warn!("Drain timeout reached, closing {} active connections", active.count());
+        }
+        _ = signals.recv() => {
+            warn!("Exiting now, closing {} active connections", active.count());
+        }
+    }
+}
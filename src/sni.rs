@@ -0,0 +1,182 @@
+//! Logging of the TLS server name (SNI) requested on relayed connections
+//!
+//! The client's first bytes are inspected as they pass through the relay, so
+//! nothing is held back or replayed and connections that are not TLS, or
+//! where the server speaks first, are not delayed. TLS is never terminated;
+//! only the plaintext ClientHello is read.
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::{debug, info};
+
+/// TLS record header: content type, version, length
+const RECORD_HEADER: usize = 5;
+
+/// Largest TLS plaintext record; a ClientHello is read from the first record only
+const MAX_RECORD: usize = 16 * 1024;
+
+/// Outcome of inspecting the bytes seen so far
+enum Parse {
+    /// The ClientHello is not complete yet
+    Incomplete,
+    /// Not a TLS ClientHello, or one without a server name
+    NoName,
+    Name(String),
+}
+
+/// Client stream wrapper that logs the SNI of a TLS ClientHello read through it
+pub struct SniLogger<'a, S> {
+    inner: &'a mut S,
+    client_addr: SocketAddr,
+    target: &'a str,
+    /// Bytes collected until the ClientHello is parsed; None once done
+    buffer: Option<Vec<u8>>,
+}
+
+impl<'a, S> SniLogger<'a, S> {
+    pub fn new(inner: &'a mut S, client_addr: SocketAddr, target: &'a str) -> Self {
+        Self {
+            inner,
+            client_addr,
+            target,
+            buffer: Some(Vec::new()),
+        }
+    }
+
+    fn inspect(&mut self, read: &[u8]) {
+        let Some(buffer) = &mut self.buffer else {
+            return;
+        };
+        buffer.extend_from_slice(read);
+
+        match parse_client_hello(buffer) {
+            Parse::Incomplete => return,
+            Parse::NoName => debug!("No TLS server name from {} -> {}", self.client_addr, self.target),
+            Parse::Name(name) => info!("TLS SNI {} from {} -> {}", name, self.client_addr, self.target),
+        }
+        self.buffer = None;
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SniLogger<'_, S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut *self.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = result {
+            let read = &buf.filled()[before..];
+            if !read.is_empty() {
+                self.inspect(read);
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SniLogger<'_, S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Find the server name in a ClientHello at the start of `data`
+fn parse_client_hello(data: &[u8]) -> Parse {
+    // Handshake record with a TLS 1.x (or SSL 3) version
+    match data {
+        [] | [0x16] | [0x16, 0x03] => return Parse::Incomplete,
+        [0x16, 0x03, ..] => {}
+        _ => return Parse::NoName,
+    }
+    if data.len() < RECORD_HEADER {
+        return Parse::Incomplete;
+    }
+    let length = u16::from_be_bytes([data[3], data[4]]) as usize;
+    if length > MAX_RECORD {
+        return Parse::NoName;
+    }
+    let Some(record) = data.get(RECORD_HEADER..RECORD_HEADER + length) else {
+        return Parse::Incomplete;
+    };
+
+    server_name(record).map_or(Parse::NoName, Parse::Name)
+}
+
+/// Server name extension of the ClientHello handshake message in `record`
+fn server_name(record: &[u8]) -> Option<String> {
+    let mut r = Reader(record);
+    // Handshake type 1 is ClientHello
+    if r.u8()? != 1 {
+        return None;
+    }
+    // A ClientHello continued in a later record is cut short here; the
+    // extensions are then usually missing and nothing is logged
+    r.take(3)?;
+    // Version and random
+    r.take(2 + 32)?;
+    // Session ID, cipher suites, compression methods
+    let session = r.u8()? as usize;
+    r.take(session)?;
+    let suites = r.u16()? as usize;
+    r.take(suites)?;
+    let compression = r.u8()? as usize;
+    r.take(compression)?;
+
+    let extensions = r.u16()? as usize;
+    let mut extensions = Reader(r.take(extensions)?);
+    while let (Some(kind), Some(length)) = (extensions.u16(), extensions.u16()) {
+        let body = extensions.take(length as usize)?;
+        if kind != 0 {
+            continue;
+        }
+
+        let mut names = Reader(body);
+        let list = names.u16()? as usize;
+        let mut names = Reader(names.take(list)?);
+        while let Some(name_type) = names.u8() {
+            let length = names.u16()? as usize;
+            let name = names.take(length)?;
+            // Type 0 is a DNS host name
+            if name_type == 0 {
+                return std::str::from_utf8(name)
+                    .ok()
+                    .filter(|name| name.bytes().all(|b| b.is_ascii_graphic()))
+                    .map(str::to_string);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/// Cursor over big-endian TLS fields
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
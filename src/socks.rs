@@ -1,77 +1,203 @@
 use anyhow::{bail, Result};
+use clap::ValueEnum;
+use std::net::{IpAddr, SocketAddr};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+
+use crate::relay::ClientStream;
+use crate::lockout::Lockout;
+use crate::tarpit::Tarpit;
 
 pub use crate::load_balancer::TargetAddressType;
 
 // SOCKS5 Constants
 
 // Auth methods
-#[allow(dead_code)]
 pub const NOAUTH: u8 = 0x00;
-#[allow(dead_code)]
 pub const GSSAPI: u8 = 0x01;
-#[allow(dead_code)]
 pub const USERNAME_PASSWORD: u8 = 0x02;
-#[allow(dead_code)]
 pub const NO_ACCEPTABLE_METHOD: u8 = 0xFF;
 
+/// An authentication method a client can offer in its greeting
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum AuthMethod {
+    NoAuth,
+    Gssapi,
+    UsernamePassword,
+}
+
+impl AuthMethod {
+    fn code(self) -> u8 {
+        match self {
+            AuthMethod::NoAuth => NOAUTH,
+            AuthMethod::Gssapi => GSSAPI,
+            AuthMethod::UsernamePassword => USERNAME_PASSWORD,
+        }
+    }
+}
+
 // Commands
 pub const CONNECT: u8 = 0x01;
 #[allow(dead_code)]
 pub const BIND: u8 = 0x02;
-#[allow(dead_code)]
 pub const UDP_ASSOCIATE: u8 = 0x03;
 
+/// A request the proxy serves
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Connect,
+    UdpAssociate,
+    /// CONNECT from a SOCKS4 or SOCKS4a client, which expects SOCKS4 replies
+    Socks4Connect,
+}
+
 // Address types
 pub const IPV4: u8 = 0x01;
 pub const DOMAIN: u8 = 0x03;
-#[allow(dead_code)]
 pub const IPV6: u8 = 0x04;
 
 // Response status codes
 pub const SUCCESS: u8 = 0x00;
 pub const SERVER_FAILURE: u8 = 0x01;
-#[allow(dead_code)]
 pub const CONNECTION_NOT_ALLOWED: u8 = 0x02;
 pub const NETWORK_UNREACHABLE: u8 = 0x03;
-#[allow(dead_code)]
 pub const HOST_UNREACHABLE: u8 = 0x04;
-#[allow(dead_code)]
 pub const CONNECTION_REFUSED: u8 = 0x05;
-#[allow(dead_code)]
 pub const TTL_EXPIRED: u8 = 0x06;
 pub const COMMAND_NOT_SUPPORTED: u8 = 0x07;
 pub const ADDRTYPE_NOT_SUPPORTED: u8 = 0x08;
 
 /// Send a SOCKS5 error response and close the connection
-async fn send_error_response(conn: &mut TcpStream, status: u8) -> Result<()> {
+async fn send_error_response<S: ClientStream>(conn: &mut S, status: u8) -> Result<()> {
     let response = [5, status, 0, 1, 0, 0, 0, 0, 0, 0];
     conn.write_all(&response).await?;
     Ok(())
 }
 
-/// Send a SOCKS5 success response
-pub async fn send_success_response(conn: &mut TcpStream) -> Result<()> {
+/// Send a SOCKS5 success response with an all-zeros bound address
+pub async fn send_success_response<S: ClientStream>(conn: &mut S) -> Result<()> {
     let response = [5, SUCCESS, 0, 1, 0, 0, 0, 0, 0, 0];
     conn.write_all(&response).await?;
     Ok(())
 }
 
-/// Send a SOCKS5 network unreachable response
-pub async fn send_network_unreachable(conn: &mut TcpStream) -> Result<()> {
-    let response = [5, NETWORK_UNREACHABLE, 0, 1, 0, 0, 0, 0, 0, 0];
+/// Send a SOCKS5 success response carrying the address the proxy bound: the
+/// outbound socket's for CONNECT, the relay socket's for UDP ASSOCIATE
+pub async fn send_bound_response<S: ClientStream>(conn: &mut S, bound: SocketAddr) -> Result<()> {
+    let mut response = vec![5, SUCCESS, 0];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            response.push(IPV4);
+            response.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            response.push(IPV6);
+            response.extend_from_slice(&ip.octets());
+        }
+    }
+    response.extend_from_slice(&bound.port().to_be_bytes());
     conn.write_all(&response).await?;
     Ok(())
 }
 
-/// Parse SOCKS5 client greeting
-async fn client_greeting(conn: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
-    let mut header = [0u8; 2];
-    conn.read_exact(&mut header).await?;
+/// Why a connect request failed, which decides the reply code sent
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Failure {
+    /// No load balancer could be selected
+    NoBalancer,
+    /// The target refused the connection
+    Refused,
+    /// The target host could not be reached or its name resolved
+    HostUnreachable,
+    /// No route to the target's network
+    NetworkUnreachable,
+    /// The target did not answer within the connect timeout
+    TimedOut,
+    /// Any other connect error
+    ConnectFailed,
+    /// The connect deadline ran out
+    Deadline,
+    /// The target is blocked by policy
+    NotAllowed,
+    /// The target's address family is disabled
+    AddressType,
+}
 
-    let socks_version = header[0];
-    let num_auth_methods = header[1] as usize;
+impl Failure {
+    const COUNT: usize = 9;
+
+    /// Reply code given by RFC 1928 for this failure
+    fn default_code(self) -> u8 {
+        match self {
+            Failure::NoBalancer => SERVER_FAILURE,
+            Failure::Refused => CONNECTION_REFUSED,
+            Failure::HostUnreachable => HOST_UNREACHABLE,
+            Failure::NetworkUnreachable => NETWORK_UNREACHABLE,
+            Failure::TimedOut | Failure::Deadline => TTL_EXPIRED,
+            Failure::ConnectFailed => SERVER_FAILURE,
+            Failure::NotAllowed => CONNECTION_NOT_ALLOWED,
+            Failure::AddressType => ADDRTYPE_NOT_SUPPORTED,
+        }
+    }
+}
+
+/// Reply code sent for each failure, for clients that only handle some codes
+#[derive(Debug, Clone)]
+pub struct ReplyCodes([u8; Failure::COUNT]);
+
+impl Default for ReplyCodes {
+    fn default() -> Self {
+        let mut codes = [0; Failure::COUNT];
+        for failure in Failure::value_variants() {
+            codes[*failure as usize] = failure.default_code();
+        }
+        Self(codes)
+    }
+}
+
+impl ReplyCodes {
+    pub fn code(&self, failure: Failure) -> u8 {
+        self.0[failure as usize]
+    }
+
+    /// Apply a `failure=code` override, where the code is a number or a name
+    /// such as `host-unreachable`
+    pub fn set_from(&mut self, spec: &str) -> Result<()> {
+        let Some((failure, code)) = spec.split_once('=') else {
+            bail!("Invalid reply code mapping {}, expected FAILURE=CODE", spec);
+        };
+        let failure = Failure::from_str(failure, true)
+            .map_err(|_| anyhow::anyhow!("Unknown failure {} in reply code mapping {}", failure, spec))?;
+        let code = match code {
+            "server-failure" => SERVER_FAILURE,
+            "not-allowed" => CONNECTION_NOT_ALLOWED,
+            "network-unreachable" => NETWORK_UNREACHABLE,
+            "host-unreachable" => HOST_UNREACHABLE,
+            "connection-refused" => CONNECTION_REFUSED,
+            "ttl-expired" => TTL_EXPIRED,
+            "command-not-supported" => COMMAND_NOT_SUPPORTED,
+            "addrtype-not-supported" => ADDRTYPE_NOT_SUPPORTED,
+            _ => match code.parse::<u8>() {
+                Ok(code) if code != SUCCESS => code,
+                _ => bail!("Invalid reply code {} in mapping {}", code, spec),
+            },
+        };
+        self.0[failure as usize] = code;
+        Ok(())
+    }
+}
+
+/// Send the reply code configured for a failed request
+pub async fn send_failure<S: ClientStream>(conn: &mut S, codes: &ReplyCodes, failure: Failure) -> Result<()> {
+    send_error_response(conn, codes.code(failure)).await
+}
+
+/// Parse the rest of a SOCKS5 client greeting, after its version byte
+async fn client_greeting<S: ClientStream>(conn: &mut S, socks_version: u8) -> Result<(u8, Vec<u8>)> {
+    let num_auth_methods = conn.read_u8().await? as usize;
+    // A greeting offering no methods has none to read and none we can accept
+    if num_auth_methods == 0 {
+        return Ok((socks_version, Vec::new()));
+    }
 
     let mut auth_methods = vec![0u8; num_auth_methods];
     conn.read_exact(&mut auth_methods).await?;
@@ -79,14 +205,95 @@ async fn client_greeting(conn: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
     Ok((socks_version, auth_methods))
 }
 
-/// Send server's authentication choice (no auth required)
-async fn servers_choice(conn: &mut TcpStream) -> Result<()> {
-    conn.write_all(&[5, NOAUTH]).await?;
+/// Send server's authentication choice
+async fn servers_choice<S: ClientStream>(conn: &mut S, method: u8) -> Result<()> {
+    conn.write_all(&[5, method]).await?;
     Ok(())
 }
 
-/// Parse client connection request and return target address with its type
-async fn client_connection_request(conn: &mut TcpStream) -> Result<(String, TargetAddressType)> {
+/// Username/password sub-negotiation version (RFC 1929)
+const AUTH_VERSION: u8 = 0x01;
+
+/// A username and password clients may authenticate with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// Parse `user:pass`; the password may itself contain colons
+    pub fn parse(spec: &str) -> Result<Self> {
+        let Some((username, password)) = spec.split_once(':') else {
+            bail!("Invalid credentials {}, expected user:pass", spec);
+        };
+        // Both are sent with a one-byte length
+        if username.is_empty() || username.len() > 255 || password.is_empty() || password.len() > 255 {
+            bail!("Invalid credentials for {}, username and password must be 1-255 bytes", username);
+        }
+        Ok(Self {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    pub fn matches(&self, username: &[u8], password: &[u8]) -> bool {
+        // Compare every byte so the time taken doesn't reveal how much matched
+        let eq = |a: &[u8], b: &[u8]| a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0;
+        eq(self.username.as_bytes(), username) & eq(self.password.as_bytes(), password)
+    }
+}
+
+/// Read the client's username/password request and check it against the
+/// configured credentials, counting failures from `client_ip` towards its
+/// lockout, and return the username. The caller bounds the whole exchange by
+/// the handshake timeout.
+async fn authenticate<S: ClientStream>(
+    conn: &mut S,
+    credentials: &[Credentials],
+    tarpit: Option<&Tarpit>,
+    lockout: Option<&Lockout>,
+    client_ip: IpAddr,
+) -> Result<String> {
+    let version = conn.read_u8().await?;
+    if version != AUTH_VERSION {
+        // RFC 1929 has no reply for this, so fail it the way a bad password is
+        conn.write_all(&[AUTH_VERSION, 0x01]).await?;
+        bail!("Unsupported username/password auth version {}", version);
+    }
+    let username_len = conn.read_u8().await? as usize;
+    let mut username = vec![0u8; username_len];
+    conn.read_exact(&mut username).await?;
+    let password_len = conn.read_u8().await? as usize;
+    let mut password = vec![0u8; password_len];
+    conn.read_exact(&mut password).await?;
+
+    if !credentials.iter().any(|c| c.matches(&username, &password)) {
+        if let Some(lockout) = lockout {
+            lockout.record_failure(client_ip);
+        }
+        if let Some(tarpit) = tarpit {
+            tarpit.hold().await;
+        }
+        conn.write_all(&[AUTH_VERSION, 0x01]).await?;
+        bail!("Authentication failed for user {:?}", String::from_utf8_lossy(&username));
+    }
+
+    if let Some(lockout) = lockout {
+        lockout.record_success(client_ip);
+    }
+    conn.write_all(&[AUTH_VERSION, 0x00]).await?;
+    Ok(String::from_utf8_lossy(&username).into_owned())
+}
+
+/// Parse client connection request and return the command with its target
+/// address and type. The request version must match the version sent in the
+/// greeting, and in strict mode the reserved byte must be zero.
+async fn client_connection_request<S: ClientStream>(
+    conn: &mut S,
+    greeting_version: u8,
+    strict: bool,
+) -> Result<(Command, String, TargetAddressType)> {
     let mut header = [0u8; 4];
     conn.read_exact(&mut header).await.map_err(|_| {
         anyhow::anyhow!("Failed to read connection request header")
@@ -94,19 +301,32 @@ async fn client_connection_request(conn: &mut TcpStream) -> Result<(String, Targ
 
     let socks_version = header[0];
     let cmd_code = header[1];
-    // header[2] is reserved
+    let reserved = header[2];
     let address_type = header[3];
 
-    if socks_version != 5 {
+    if socks_version != greeting_version {
         send_error_response(conn, SERVER_FAILURE).await?;
-        bail!("Unsupported SOCKS version");
+        bail!(
+            "Request version {} does not match greeting version {}",
+            socks_version,
+            greeting_version
+        );
     }
 
-    if cmd_code != CONNECT {
-        send_error_response(conn, COMMAND_NOT_SUPPORTED).await?;
-        bail!("Unsupported command code");
+    if strict && reserved != 0 {
+        send_error_response(conn, SERVER_FAILURE).await?;
+        bail!("Non-zero reserved byte in request: {:#04x}", reserved);
     }
 
+    let command = match cmd_code {
+        CONNECT => Command::Connect,
+        UDP_ASSOCIATE => Command::UdpAssociate,
+        _ => {
+            send_error_response(conn, COMMAND_NOT_SUPPORTED).await?;
+            bail!("Unsupported command code");
+        }
+    };
+
     let (address, target_type) = match address_type {
         IPV4 => {
             let mut ipv4_addr = [0u8; 4];
@@ -143,8 +363,14 @@ async fn client_connection_request(conn: &mut TcpStream) -> Result<(String, Targ
             })?;
 
             let port = u16::from_be_bytes(port_bytes);
-            let domain_str = String::from_utf8_lossy(&domain);
-            (format!("{}:{}", domain_str, port), TargetAddressType::Domain)
+            let domain = match parse_domain(&domain) {
+                Ok(domain) => domain,
+                Err(reason) => {
+                    send_error_response(conn, HOST_UNREACHABLE).await?;
+                    bail!("Rejected domain name {:?} in connection request: {}", String::from_utf8_lossy(&domain), reason);
+                }
+            };
+            (format!("{}:{}", domain, port), TargetAddressType::Domain)
         }
         IPV6 => {
             let mut ipv6_addr = [0u8; 16];
@@ -160,7 +386,13 @@ async fn client_connection_request(conn: &mut TcpStream) -> Result<(String, Targ
 
             let port = u16::from_be_bytes(port_bytes);
             let addr = std::net::Ipv6Addr::from(ipv6_addr);
-            (format!("[{}]:{}", addr, port), TargetAddressType::IPv6)
+
+            // An IPv4-mapped address (::ffff:a.b.c.d) is really an IPv4 host,
+            // so route it over an IPv4 balancer
+            match addr.to_ipv4_mapped() {
+                Some(ipv4) => (format!("{}:{}", ipv4, port), TargetAddressType::IPv4),
+                None => (format!("[{}]:{}", addr, port), TargetAddressType::IPv6),
+            }
         }
         _ => {
             send_error_response(conn, ADDRTYPE_NOT_SUPPORTED).await?;
@@ -168,22 +400,409 @@ async fn client_connection_request(conn: &mut TcpStream) -> Result<(String, Targ
         }
     };
 
-    Ok((address, target_type))
+    Ok((command, address, target_type))
 }
 
-/// Handle complete SOCKS5 handshake and return target address with its type
-pub async fn handle_socks_handshake(conn: &mut TcpStream) -> Result<(String, TargetAddressType)> {
+/// Check the domain bytes of a request, which are used as the host name as
+/// they are, and return the name or why it was refused
+fn parse_domain(bytes: &[u8]) -> std::result::Result<&str, &'static str> {
+    let domain = std::str::from_utf8(bytes).map_err(|_| "not valid UTF-8")?;
+    if domain.is_empty() {
+        return Err("empty");
+    }
+    if domain.len() > 253 {
+        return Err("longer than 253 characters");
+    }
+    if domain.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        return Err("contains control or whitespace characters");
+    }
+    Ok(domain)
+}
+
+/// Handle complete SOCKS5 handshake and return the command with its target
+/// address and type, and the username the client logged in with
+pub async fn handle_socks_handshake<S: ClientStream>(
+    conn: &mut S,
+    strict: bool,
+    required_method: Option<AuthMethod>,
+    credentials: &[Credentials],
+    tarpit: Option<&Tarpit>,
+    lockout: Option<&Lockout>,
+    client_ip: IpAddr,
+) -> Result<(Command, String, TargetAddressType, Option<String>)> {
+    // Check the version before reading anything else so that non-SOCKS
+    // traffic (e.g. a browser speaking HTTP to this port) is rejected immediately
+    let version = conn.read_u8().await?;
+    match version {
+        5 => {}
+        SOCKS4_VERSION => {
+            if required_method.is_some() || !credentials.is_empty() {
+                if let Some(tarpit) = tarpit {
+                    tarpit.hold().await;
+                }
+                send_socks4_reply(conn, false, None).await?;
+                bail!("SOCKS4 clients cannot authenticate");
+            }
+            let (command, target, target_type) = read_socks4_request(conn).await?;
+            return Ok((command, target, target_type, None));
+        }
+        other => bail!("Not a SOCKS client (first byte {:#04x})", other),
+    }
+
     // Client greeting
-    let (version, _auth_methods) = client_greeting(conn).await?;
-    if version != 5 {
-        bail!("Unsupported SOCKS version: {}", version);
+    let (version, auth_methods) = client_greeting(conn, version).await?;
+
+    if let Some(method) = required_method {
+        if !auth_methods.contains(&method.code()) {
+            if let Some(tarpit) = tarpit {
+                tarpit.hold().await;
+            }
+            conn.write_all(&[5, NO_ACCEPTABLE_METHOD]).await?;
+            bail!("Client did not offer required auth method {:?} (offered {:?})", method, auth_methods);
+        }
     }
 
-    // Server's choice (no auth)
-    servers_choice(conn).await?;
+    let mut username = None;
+    if credentials.is_empty() {
+        if !auth_methods.contains(&NOAUTH) {
+            if let Some(tarpit) = tarpit {
+                tarpit.hold().await;
+            }
+            conn.write_all(&[5, NO_ACCEPTABLE_METHOD]).await?;
+            bail!("Client did not offer the no-authentication method (offered {:?})", auth_methods);
+        }
+        servers_choice(conn, NOAUTH).await?;
+    } else {
+        if !auth_methods.contains(&USERNAME_PASSWORD) {
+            if let Some(tarpit) = tarpit {
+                tarpit.hold().await;
+            }
+            conn.write_all(&[5, NO_ACCEPTABLE_METHOD]).await?;
+            bail!("Client did not offer username/password authentication (offered {:?})", auth_methods);
+        }
+        servers_choice(conn, USERNAME_PASSWORD).await?;
+        username = Some(authenticate(conn, credentials, tarpit, lockout, client_ip).await?);
+    }
 
     // Client connection request
-    let (address, target_type) = client_connection_request(conn).await?;
+    let (command, target, target_type) = client_connection_request(conn, version, strict).await?;
+    Ok((command, target, target_type, username))
+}
+
+/// SOCKS4 version byte, in requests only; replies carry 0
+const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS4_GRANTED: u8 = 0x5A;
+const SOCKS4_REJECTED: u8 = 0x5B;
+
+/// Longest user ID or SOCKS4a host name read
+const SOCKS4_MAX_FIELD: usize = 255;
+
+/// Parse a SOCKS4 or SOCKS4a request after its version byte. A destination
+/// of 0.0.0.x (x non-zero) is the SOCKS4a form, with the host name following
+/// the user ID, which is ignored.
+async fn read_socks4_request<S: ClientStream>(conn: &mut S) -> Result<(Command, String, TargetAddressType)> {
+    let mut header = [0u8; 7];
+    conn.read_exact(&mut header)
+        .await
+        .map_err(|_| anyhow::anyhow!("Failed to read SOCKS4 request"))?;
+    let [command, port_hi, port_lo, a, b, c, d] = header;
+    let port = u16::from_be_bytes([port_hi, port_lo]);
+    read_socks4_field(conn).await?;
+
+    if command != CONNECT {
+        send_socks4_reply(conn, false, None).await?;
+        bail!("Unsupported SOCKS4 command code {}", command);
+    }
+
+    if [a, b, c] == [0, 0, 0] && d != 0 {
+        let host = read_socks4_field(conn).await?;
+        let host = match parse_domain(&host) {
+            Ok(host) => host,
+            Err(reason) => {
+                send_socks4_reply(conn, false, None).await?;
+                bail!("Rejected domain name {:?} in SOCKS4a request: {}", String::from_utf8_lossy(&host), reason);
+            }
+        };
+        return Ok((Command::Socks4Connect, format!("{}:{}", host, port), TargetAddressType::Domain));
+    }
+    Ok((Command::Socks4Connect, format!("{}.{}.{}.{}:{}", a, b, c, d, port), TargetAddressType::IPv4))
+}
+
+/// Read a NUL-terminated field of a SOCKS4 request
+async fn read_socks4_field<S: ClientStream>(conn: &mut S) -> Result<Vec<u8>> {
+    let mut field = Vec::new();
+    loop {
+        match conn.read_u8().await? {
+            0 => return Ok(field),
+            _ if field.len() == SOCKS4_MAX_FIELD => bail!("SOCKS4 request field longer than {} bytes", SOCKS4_MAX_FIELD),
+            byte => field.push(byte),
+        }
+    }
+}
+
+/// Send a SOCKS4 reply: granted with the bound address when it is IPv4, or
+/// rejected. SOCKS4 has a single failure code, so every failure maps to it.
+pub async fn send_socks4_reply<S: ClientStream>(conn: &mut S, granted: bool, bound: Option<SocketAddr>) -> Result<()> {
+    let mut response = [0, if granted { SOCKS4_GRANTED } else { SOCKS4_REJECTED }, 0, 0, 0, 0, 0, 0];
+    if let Some(SocketAddr::V4(bound)) = bound {
+        response[2..4].copy_from_slice(&bound.port().to_be_bytes());
+        response[4..].copy_from_slice(&bound.ip().octets());
+    }
+    conn.write_all(&response).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::DuplexStream;
+
+    /// Feed `request` to the connection request parser as the client, with
+    /// the client side closed after it, and return the result with the reply
+    async fn parse_request(request: &[u8], strict: bool) -> (Result<(Command, String, TargetAddressType)>, Vec<u8>) {
+        let (mut client, mut server): (DuplexStream, DuplexStream) = tokio::io::duplex(1024);
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+        let result = client_connection_request(&mut server, 5, strict).await;
+        drop(server);
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        (result, reply)
+    }
+
+    /// A CONNECT request for `domain` port 80
+    fn domain_request(domain: &[u8]) -> Vec<u8> {
+        let mut request = vec![5, CONNECT, 0, DOMAIN, domain.len() as u8];
+        request.extend_from_slice(domain);
+        request.extend_from_slice(&80u16.to_be_bytes());
+        request
+    }
+
+    #[tokio::test]
+    async fn parses_ipv4_connect() {
+        let (result, reply) = parse_request(&[5, CONNECT, 0, IPV4, 192, 0, 2, 1, 0, 80], true).await;
+        let (command, address, target_type) = result.unwrap();
+        assert_eq!(command, Command::Connect);
+        assert_eq!(address, "192.0.2.1:80");
+        assert_eq!(target_type, TargetAddressType::IPv4);
+        assert!(reply.is_empty());
+    }
+
+    #[tokio::test]
+    async fn parses_domain() {
+        let (result, _) = parse_request(&domain_request(b"example.com"), true).await;
+        let (_, address, target_type) = result.unwrap();
+        assert_eq!((address.as_str(), target_type), ("example.com:80", TargetAddressType::Domain));
+    }
 
-    Ok((address, target_type))
+    #[tokio::test]
+    async fn normalizes_ipv4_mapped_targets() {
+        let ipv6_request = |addr: std::net::Ipv6Addr| {
+            let mut request = vec![5, CONNECT, 0, IPV6];
+            request.extend_from_slice(&addr.octets());
+            request.extend_from_slice(&22u16.to_be_bytes());
+            request
+        };
+
+        let (result, _) = parse_request(&ipv6_request("::ffff:1.2.3.4".parse().unwrap()), false).await;
+        let (_, address, target_type) = result.unwrap();
+        assert_eq!((address.as_str(), target_type), ("1.2.3.4:22", TargetAddressType::IPv4));
+
+        let (result, _) = parse_request(&ipv6_request("2001:db8::1".parse().unwrap()), false).await;
+        let (_, address, target_type) = result.unwrap();
+        assert_eq!((address.as_str(), target_type), ("[2001:db8::1]:22", TargetAddressType::IPv6));
+        // IPv4-compatible addresses (::a.b.c.d) are deprecated and stay IPv6
+        let (result, _) = parse_request(&ipv6_request("::1.2.3.4".parse().unwrap()), false).await;
+        assert_eq!(result.unwrap().2, TargetAddressType::IPv6);
+    }
+
+    #[tokio::test]
+    async fn strict_mode_rejects_non_zero_reserved_byte() {
+        let request = [5, CONNECT, 1, IPV4, 192, 0, 2, 1, 0, 80];
+        let (result, reply) = parse_request(&request, true).await;
+        assert!(result.unwrap_err().to_string().contains("reserved byte"));
+        assert_eq!(reply, [5, SERVER_FAILURE, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        let (result, _) = parse_request(&request, false).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_version_differing_from_greeting() {
+        for strict in [false, true] {
+            let (result, reply) = parse_request(&[4, CONNECT, 0, IPV4, 192, 0, 2, 1, 0, 80], strict).await;
+            assert!(result.unwrap_err().to_string().contains("does not match greeting version 5"));
+            assert_eq!(reply[..2], [5, SERVER_FAILURE]);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_address_type() {
+        let (result, reply) = parse_request(&[5, CONNECT, 0, 0x02, 0, 0], true).await;
+        assert!(result.unwrap_err().to_string().contains("Unsupported address type"));
+        assert_eq!(reply[..2], [5, ADDRTYPE_NOT_SUPPORTED]);
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_command() {
+        let (result, reply) = parse_request(&[5, BIND, 0, IPV4, 192, 0, 2, 1, 0, 80], true).await;
+        assert!(result.is_err());
+        assert_eq!(reply[..2], [5, COMMAND_NOT_SUPPORTED]);
+    }
+
+    #[tokio::test]
+    async fn fails_on_truncated_requests() {
+        let (result, reply) = parse_request(&[5, CONNECT], true).await;
+        assert!(result.unwrap_err().to_string().contains("request header"));
+        assert!(reply.is_empty());
+
+        let (result, _) = parse_request(&[5, CONNECT, 0, IPV4, 192, 0], true).await;
+        assert!(result.unwrap_err().to_string().contains("IPv4 address"));
+
+        let (result, _) = parse_request(&[5, CONNECT, 0, DOMAIN, 20, b'a', b'b'], true).await;
+        assert!(result.unwrap_err().to_string().contains("domain name"));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_domain_names() {
+        for domain in [b"bad\nhost\0".as_slice(), b"bad host", b"caf\xe9.example"] {
+            let (result, reply) = parse_request(&domain_request(domain), false).await;
+            assert!(result.unwrap_err().to_string().contains("Rejected domain name"));
+            assert_eq!(reply[..2], [5, HOST_UNREACHABLE]);
+        }
+    }
+
+    #[test]
+    fn validates_domain_names() {
+        assert_eq!(parse_domain(b"example.com"), Ok("example.com"));
+        assert_eq!(parse_domain("bücher.example".as_bytes()), Ok("bücher.example"));
+        assert_eq!(parse_domain(&[b'a'; 253]).map(str::len), Ok(253));
+
+        assert_eq!(parse_domain(b""), Err("empty"));
+        assert_eq!(parse_domain(&[b'a'; 254]), Err("longer than 253 characters"));
+        assert_eq!(parse_domain(b"\xff\xfe"), Err("not valid UTF-8"));
+        assert_eq!(parse_domain(b"caf\xe9.example"), Err("not valid UTF-8"));
+        for domain in [b"example.com\0".as_slice(), b"exa\tmple.com", b"example.com\r\n", b"\x1bexample.com", b"a b"] {
+            assert_eq!(parse_domain(domain), Err("contains control or whitespace characters"), "{:?}", domain);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_zero_length_domain() {
+        let (result, reply) = parse_request(&domain_request(b""), false).await;
+        assert!(result.unwrap_err().to_string().contains("empty"));
+        assert_eq!(reply[..2], [5, HOST_UNREACHABLE]);
+    }
+
+    #[tokio::test]
+    async fn leaves_pipelined_payload_for_the_relay() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        // Greeting, request and application data in one write, without
+        // waiting for either reply
+        let mut pipelined = vec![5, 1, NOAUTH];
+        pipelined.extend_from_slice(&domain_request(b"example.com"));
+        pipelined.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        client.write_all(&pipelined).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let (command, target, _, _) = handle_socks_handshake(&mut server, true, None, &[], None, None, CLIENT).await.unwrap();
+        assert_eq!((command, target.as_str()), (Command::Connect, "example.com:80"));
+        let mut early = Vec::new();
+        server.read_to_end(&mut early).await.unwrap();
+        assert_eq!(early, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn reads_handshake_sent_a_byte_at_a_time() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let drip = tokio::spawn(async move {
+            let mut handshake = vec![5, 1, NOAUTH];
+            handshake.extend_from_slice(&domain_request(b"example.com"));
+            for byte in handshake {
+                client.write_all(&[byte]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+            let mut choice = [0u8; 2];
+            client.read_exact(&mut choice).await.unwrap();
+            choice
+        });
+        let (command, target, target_type, _) =
+            handle_socks_handshake(&mut server, true, None, &[], None, None, CLIENT).await.unwrap();
+        assert_eq!((command, target.as_str(), target_type), (Command::Connect, "example.com:80", TargetAddressType::Domain));
+        assert_eq!(drip.await.unwrap(), [5, NOAUTH]);
+    }
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 9));
+
+    fn credentials() -> Vec<Credentials> {
+        vec![Credentials::parse("alice:secret").unwrap()]
+    }
+
+    /// Auth request for `username` and `password` in the RFC 1929 layout
+    fn auth_request(version: u8, username: &[u8], password: &[u8]) -> Vec<u8> {
+        let mut request = vec![version, username.len() as u8];
+        request.extend_from_slice(username);
+        request.push(password.len() as u8);
+        request.extend_from_slice(password);
+        request
+    }
+
+    /// Run the auth sub-negotiation over `request` and return the result with
+    /// the reply
+    async fn parse_auth(request: &[u8]) -> (Result<String>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+        let result = authenticate(&mut server, &credentials(), None, None, CLIENT).await;
+        drop(server);
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        (result, reply)
+    }
+
+    #[tokio::test]
+    async fn accepts_matching_credentials() {
+        let (result, reply) = parse_auth(&auth_request(AUTH_VERSION, b"alice", b"secret")).await;
+        assert_eq!(result.unwrap(), "alice");
+        assert_eq!(reply, [AUTH_VERSION, 0x00]);
+
+        let (result, reply) = parse_auth(&auth_request(AUTH_VERSION, b"alice", b"wrong")).await;
+        assert!(result.unwrap_err().to_string().contains("Authentication failed"));
+        assert_eq!(reply, [AUTH_VERSION, 0x01]);
+    }
+
+    #[tokio::test]
+    async fn replies_failure_to_unknown_auth_version() {
+        let (result, reply) = parse_auth(&auth_request(0x05, b"alice", b"secret")).await;
+        assert!(result.unwrap_err().to_string().contains("auth version 5"));
+        assert_eq!(reply, [AUTH_VERSION, 0x01]);
+    }
+
+    #[tokio::test]
+    async fn fails_on_truncated_auth_request() {
+        let request = auth_request(AUTH_VERSION, b"alice", b"secret");
+        for len in [1, 4, 7, request.len() - 1] {
+            let (result, reply) = parse_auth(&request[..len]).await;
+            assert!(result.is_err(), "accepted {} bytes", len);
+            assert!(reply.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_auth_request_sent_a_byte_at_a_time() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let request = auth_request(AUTH_VERSION, b"alice", b"secret");
+        let drip = tokio::spawn(async move {
+            for byte in request {
+                client.write_all(&[byte]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).await.unwrap();
+            reply
+        });
+        authenticate(&mut server, &credentials(), None, None, CLIENT).await.unwrap();
+        assert_eq!(drip.await.unwrap(), [AUTH_VERSION, 0x00]);
+    }
 }
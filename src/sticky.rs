@@ -0,0 +1,156 @@
+//! Remembered sticky-session assignments
+//!
+//! With only `--sticky`, a client's balancer follows from hashing its address,
+//! so it changes whenever the balancer set does. `--sticky-state` also
+//! remembers which balancer each client was given, by balancer identity, for
+//! a TTL after its latest connection. The assignments are written to a small
+//! state file periodically and at shutdown and read back at startup, so
+//! affinities survive restarts and reloads.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// How often assignments are written to the state file
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Balancer a client was assigned
+struct Assignment {
+    /// `LoadBalancer::identity` of the balancer
+    balancer: String,
+    /// Seconds since the Unix epoch after which the assignment is forgotten
+    expires: u64,
+}
+
+/// Client addresses and the balancer each was last given
+pub struct StickyMap {
+    ttl: Duration,
+    path: PathBuf,
+    clients: Mutex<HashMap<IpAddr, Assignment>>,
+}
+
+impl StickyMap {
+    /// Restore assignments from the state file at `path`, dropping those past
+    /// their TTL or naming a balancer not in `identities`
+    pub fn load(path: String, ttl: Duration, identities: &[String]) -> Result<Self> {
+        let map = Self {
+            ttl,
+            path: PathBuf::from(path),
+            clients: Mutex::default(),
+        };
+        let contents = match std::fs::read_to_string(&map.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(map),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Could not read sticky state {}", map.path.display()))
+            }
+        };
+
+        let now = unix_now();
+        let mut clients = map.clients.lock().unwrap();
+        for line in contents.lines() {
+            let mut fields = line.splitn(3, ' ');
+            let (Some(client), Some(expires), Some(balancer)) = (fields.next(), fields.next(), fields.next()) else {
+                continue;
+            };
+            let (Ok(client), Ok(expires)) = (client.parse::<IpAddr>(), expires.parse::<u64>()) else {
+                continue;
+            };
+            if expires > now && identities.iter().any(|id| id == balancer) {
+                clients.insert(client, Assignment { balancer: balancer.to_string(), expires });
+            }
+        }
+        info!("Restored {} sticky assignments from {}", clients.len(), map.path.display());
+        drop(clients);
+        Ok(map)
+    }
+
+    /// Identity of the balancer `client` is assigned, unless it has expired
+    pub fn get(&self, client: IpAddr) -> Option<String> {
+        let clients = self.clients.lock().unwrap();
+        clients
+            .get(&client)
+            .filter(|a| a.expires > unix_now())
+            .map(|a| a.balancer.clone())
+    }
+
+    /// Assign `client` to a balancer, or keep it there, for another TTL
+    pub fn assign(&self, client: IpAddr, balancer: String) {
+        let expires = unix_now() + self.ttl.as_secs();
+        self.clients.lock().unwrap().insert(client, Assignment { balancer, expires });
+    }
+
+    /// Write the unexpired assignments to the state file, forgetting the rest
+    pub fn save(&self) -> Result<()> {
+        let now = unix_now();
+        let mut contents = String::new();
+        {
+            let mut clients = self.clients.lock().unwrap();
+            clients.retain(|_, a| a.expires > now);
+            for (client, a) in clients.iter() {
+                contents.push_str(&format!("{} {} {}\n", client, a.expires, a.balancer));
+            }
+        }
+
+        // Write then rename so a crash never leaves a truncated state file
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, contents)
+            .and_then(|_| std::fs::rename(&tmp, &self.path))
+            .with_context(|| format!("Could not write sticky state {}", self.path.display()))
+    }
+}
+
+/// Periodically persist the assignments
+pub fn spawn_persistence(map: Arc<StickyMap>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = map.save() {
+                warn!("{:#}", e);
+            }
+        }
+    });
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restores_unexpired_assignments_of_present_balancers() {
+        let path = std::env::temp_dir().join(format!("dispatch-sticky-test-{}", std::process::id()));
+        let now = unix_now();
+        std::fs::write(
+            &path,
+            format!(
+                "192.0.2.1 {} 10.0.0.5%eth0\n192.0.2.2 {} 10.0.0.5%eth0\n192.0.2.3 {} 10.0.0.9%wlan0\nbad line\n",
+                now + 100,
+                now - 1,
+                now + 100
+            ),
+        )
+        .unwrap();
+
+        let map = StickyMap::load(path.display().to_string(), Duration::from_secs(60), &["10.0.0.5%eth0".to_string()]).unwrap();
+        assert_eq!(map.get("192.0.2.1".parse().unwrap()).as_deref(), Some("10.0.0.5%eth0"));
+        assert_eq!(map.get("192.0.2.2".parse().unwrap()), None);
+        assert_eq!(map.get("192.0.2.3".parse().unwrap()), None);
+
+        map.assign("2001:db8::1".parse().unwrap(), "10.0.0.5%eth0".to_string());
+        map.save().unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(contents.lines().count(), 2);
+        assert!(contents.contains("2001:db8::1 "));
+    }
+}
@@ -0,0 +1,634 @@
+//! Balancer selection algorithms
+//!
+//! The pool decides which balancers may take a connection (skip set, address
+//! family, metered tier) and hands that to a `LoadBalancerStrategy`, which
+//! only chooses among them. Strategies that cannot decide for a connection,
+//! such as target hashing without a target, defer to weighted round-robin.
+
+use crate::load_balancer::{LoadBalancer, Strategy};
+use crate::sticky::StickyMap;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// What a strategy gets to choose from for one connection
+pub struct Selection<'a> {
+    pub balancers: &'a [Arc<LoadBalancer>],
+    /// Whether a balancer may take this connection
+    pub eligible: &'a dyn Fn(usize) -> bool,
+    /// The caller's skip set merged with the pool's soft skips; used when no
+    /// balancer is eligible
+    pub skip: Option<&'a [bool]>,
+    /// `host:port` or host of the connection's target
+    pub target: Option<&'a str>,
+    /// Source address of the client
+    pub client: Option<IpAddr>,
+    /// Live connections per balancer
+    pub live: &'a [Arc<AtomicU32>],
+    /// Average round-trip time per balancer in milliseconds (f64 bits, 0 until measured)
+    pub latencies: &'a [AtomicU64],
+    /// Average deviation from `latencies` per balancer in milliseconds (f64 bits)
+    pub jitters: &'a [AtomicU64],
+}
+
+impl Selection<'_> {
+    fn eligible(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.balancers.len()).filter(|&i| (self.eligible)(i))
+    }
+
+    /// Weighted rendezvous hashing: every eligible balancer scores the key and
+    /// the highest score wins, so adding or removing a balancer only moves the
+    /// keys it wins or held, and a skipped balancer's keys go to their runner-up.
+    /// Scores are weighted by contention ratio.
+    fn rendezvous(&self, key: impl Hash) -> Option<usize> {
+        self.eligible()
+            .map(|i| {
+                let lb = &self.balancers[i];
+                let mut hasher = DefaultHasher::new();
+                (&key, &lb.address, &lb.iface).hash(&mut hasher);
+
+                // Map the hash into (0, 1) and apply the weight: -w / ln(u)
+                let unit = (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64;
+                let unit = unit.max(f64::MIN_POSITIVE);
+                (i, -f64::from(lb.contention_ratio) / unit.ln())
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i)
+    }
+}
+
+/// A balancer selection algorithm
+pub trait LoadBalancerStrategy {
+    /// Index of the balancer for the next connection; `selection.balancers` is never empty
+    fn select(&self, selection: &Selection<'_>) -> usize;
+}
+
+/// Selection algorithm for a strategy, with its round-robin cycle starting at
+/// `start_index`. Connections to a target port in `ports` use the strategy
+/// given for it instead. With `sticky`, clients are pinned by source address
+/// and the strategy only picks for connections without one; `remembered`
+/// keeps the assignments made.
+pub fn build(
+    strategy: Strategy,
+    ports: &[(u16, Strategy)],
+    jitter: bool,
+    start_index: usize,
+    sticky: bool,
+    remembered: Option<Arc<StickyMap>>,
+) -> Box<dyn LoadBalancerStrategy + Send + Sync> {
+    let single = |strategy: Strategy| -> Box<dyn LoadBalancerStrategy + Send + Sync> {
+        let round_robin = WeightedRoundRobin::new(jitter, start_index);
+        match strategy {
+            Strategy::RoundRobin | Strategy::ErrorAware => Box::new(round_robin),
+            Strategy::TargetHash => Box::new(TargetHash { fallback: round_robin }),
+            Strategy::LeastConn => Box::new(LeastConnections { fallback: round_robin }),
+            Strategy::Latency => Box::new(LatencyWeighted { fallback: round_robin }),
+            Strategy::LowestJitter => Box::new(LowestJitter { fallback: round_robin }),
+            Strategy::Random => Box::new(WeightedRandom::new(round_robin)),
+        }
+    };
+    let strategy = if ports.is_empty() {
+        single(strategy)
+    } else {
+        Box::new(ByPort {
+            ports: ports.iter().map(|&(port, strategy)| (port, single(strategy))).collect(),
+            default: single(strategy),
+        })
+    };
+    if sticky {
+        Box::new(StickyClients { fallback: strategy, remembered })
+    } else {
+        strategy
+    }
+}
+
+/// Each balancer takes `contention_ratio` consecutive connections in turn, or
+/// with jitter that many per cycle in random order.
+///
+/// Every balancer has its own credits for the current cycle, so a connection
+/// that has to skip a balancer (wrong family, failed connect) is served by the
+/// next one that still has credits without cutting short anyone's turn. The
+/// cycle starts over once no balancer the connection may use has credits left.
+pub struct WeightedRoundRobin {
+    jitter: bool,
+    start_index: usize,
+    /// Selections left per balancer in the current cycle, taken without a lock
+    credits: OnceLock<Vec<AtomicU32>>,
+    /// Held while starting a new cycle so that concurrent selections refill once
+    refill: Mutex<()>,
+}
+
+/// Take one credit, unless there is none left
+fn take(credit: &AtomicU32) -> bool {
+    credit.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| c.checked_sub(1)).is_ok()
+}
+
+impl WeightedRoundRobin {
+    pub fn new(jitter: bool, start_index: usize) -> Self {
+        Self {
+            jitter,
+            start_index,
+            credits: OnceLock::new(),
+            refill: Mutex::new(()),
+        }
+    }
+
+    fn credits(&self, balancers: &[Arc<LoadBalancer>]) -> &[AtomicU32] {
+        self.credits
+            .get_or_init(|| balancers.iter().map(|lb| AtomicU32::new(lb.contention_ratio)).collect())
+    }
+
+    /// The first eligible balancer in cycle order that still has credits left
+    fn pick_in_order(&self, credits: &[AtomicU32], selection: &Selection<'_>) -> Option<usize> {
+        let len = selection.balancers.len();
+        (0..len)
+            .map(|offset| (self.start_index + offset) % len)
+            .find(|&idx| (selection.eligible)(idx) && take(&credits[idx]))
+    }
+
+    /// Weighted random pick among the eligible balancers that still have credits
+    /// left in this cycle
+    fn pick_jittered(credits: &[AtomicU32], selection: &Selection<'_>) -> Option<usize> {
+        loop {
+            let total: u32 = selection.eligible().map(|i| credits[i].load(Ordering::Relaxed)).sum();
+            if total == 0 {
+                return None;
+            }
+
+            let mut pick = rand::thread_rng().gen_range(0..total);
+            let picked = selection.eligible().find(|&i| {
+                let left = credits[i].load(Ordering::Relaxed);
+                if pick < left {
+                    return true;
+                }
+                pick -= left;
+                false
+            });
+            // Another selection may have taken the credit meanwhile, so pick again
+            if let Some(idx) = picked.filter(|&idx| take(&credits[idx])) {
+                return Some(idx);
+            }
+        }
+    }
+
+    /// Start a new cycle once every eligible balancer has used its share.
+    /// Returns whether an eligible balancer has credits left to pick again,
+    /// either from the new cycle or one a concurrent selection started.
+    fn start_cycle(&self, credits: &[AtomicU32], selection: &Selection<'_>) -> bool {
+        let _refill = self.refill.lock().unwrap();
+        let mut eligible = selection.eligible().peekable();
+        if eligible.peek().is_none() {
+            return false;
+        }
+        if eligible.any(|i| credits[i].load(Ordering::Relaxed) > 0) {
+            return true;
+        }
+        for (credit, lb) in credits.iter().zip(selection.balancers) {
+            credit.store(lb.contention_ratio, Ordering::Relaxed);
+        }
+        selection.eligible().any(|i| selection.balancers[i].contention_ratio > 0)
+    }
+}
+
+impl LoadBalancerStrategy for WeightedRoundRobin {
+    fn select(&self, selection: &Selection<'_>) -> usize {
+        let balancers = selection.balancers;
+        let credits = self.credits(balancers);
+
+        // Concurrent selections may take the new cycle's credits before this
+        // one picks again, so keep trying while there are any to take
+        loop {
+            let picked = if self.jitter {
+                Self::pick_jittered(credits, selection)
+            } else {
+                self.pick_in_order(credits, selection)
+            };
+            if let Some(idx) = picked {
+                return idx;
+            }
+            if !self.start_cycle(credits, selection) {
+                break;
+            }
+        }
+
+        // Fall back to first non-skipped balancer, or the first in cycle order if all are skipped
+        let skip = selection.skip;
+        (0..balancers.len())
+            .find(|&i| !skip.is_some_and(|s| s.get(i).copied().unwrap_or(false)))
+            .unwrap_or(self.start_index % balancers.len())
+    }
+}
+
+/// Keep each target host on the same balancer (weighted rendezvous hashing)
+pub struct TargetHash {
+    fallback: WeightedRoundRobin,
+}
+
+impl LoadBalancerStrategy for TargetHash {
+    fn select(&self, selection: &Selection<'_>) -> usize {
+        selection
+            .target
+            .and_then(|target| selection.rendezvous(target_host(target)))
+            .unwrap_or_else(|| self.fallback.select(selection))
+    }
+}
+
+/// Keep each client source address on the same balancer (weighted rendezvous
+/// hashing). With a map of remembered assignments, a client stays on the
+/// balancer it was given while that one is eligible, even after the hash
+/// would move it.
+pub struct StickyClients {
+    fallback: Box<dyn LoadBalancerStrategy + Send + Sync>,
+    remembered: Option<Arc<StickyMap>>,
+}
+
+impl LoadBalancerStrategy for StickyClients {
+    fn select(&self, selection: &Selection<'_>) -> usize {
+        let Some(client) = selection.client else {
+            return self.fallback.select(selection);
+        };
+        let Some(remembered) = &self.remembered else {
+            return selection.rendezvous(client).unwrap_or_else(|| self.fallback.select(selection));
+        };
+
+        let identity = |i: usize| selection.balancers[i].identity();
+        if let Some(assigned) = remembered.get(client) {
+            if let Some(idx) = selection.eligible().find(|&i| identity(i).as_ref() == Some(&assigned)) {
+                remembered.assign(client, assigned);
+                return idx;
+            }
+        }
+        match selection.rendezvous(client) {
+            Some(idx) => {
+                if let Some(identity) = identity(idx) {
+                    remembered.assign(client, identity);
+                }
+                idx
+            }
+            None => self.fallback.select(selection),
+        }
+    }
+}
+
+/// The balancer with the fewest live connections, ties going to the higher contention ratio
+pub struct LeastConnections {
+    fallback: WeightedRoundRobin,
+}
+
+impl LoadBalancerStrategy for LeastConnections {
+    fn select(&self, selection: &Selection<'_>) -> usize {
+        selection
+            .eligible()
+            .min_by_key(|&i| {
+                let live = selection.live[i].load(Ordering::Relaxed);
+                (live, std::cmp::Reverse(selection.balancers[i].contention_ratio))
+            })
+            .unwrap_or_else(|| self.fallback.select(selection))
+    }
+}
+
+/// Weighted random pick with weights of contention ratio over average
+/// round-trip time. Balancers not yet measured count as the fastest measured
+/// one, so they are tried rather than starved.
+pub struct LatencyWeighted {
+    fallback: WeightedRoundRobin,
+}
+
+impl LoadBalancerStrategy for LatencyWeighted {
+    fn select(&self, selection: &Selection<'_>) -> usize {
+        let latency = |i: usize| f64::from_bits(selection.latencies[i].load(Ordering::Relaxed));
+        let fastest = (0..selection.balancers.len())
+            .map(latency)
+            .filter(|&l| l > 0.0)
+            .min_by(f64::total_cmp)
+            .unwrap_or(1.0);
+
+        let weights: Vec<(usize, f64)> = selection
+            .eligible()
+            .map(|i| {
+                let rtt = match latency(i) {
+                    l if l > 0.0 => l,
+                    _ => fastest,
+                };
+                (i, f64::from(selection.balancers[i].contention_ratio) / rtt.max(0.1))
+            })
+            .collect();
+
+        let total: f64 = weights.iter().map(|(_, w)| w).sum();
+        if total <= 0.0 {
+            return self.fallback.select(selection);
+        }
+        let mut pick = rand::thread_rng().gen_range(0.0..total);
+        for &(i, weight) in &weights {
+            if pick < weight {
+                return i;
+            }
+            pick -= weight;
+        }
+        weights.last().map_or_else(|| self.fallback.select(selection), |&(i, _)| i)
+    }
+}
+
+/// The balancer with the lowest jitter, ties going to the lower round-trip
+/// time and then the higher contention ratio. Balancers not yet measured
+/// count as the steadiest measured one, so they are tried rather than starved.
+pub struct LowestJitter {
+    fallback: WeightedRoundRobin,
+}
+
+impl LoadBalancerStrategy for LowestJitter {
+    fn select(&self, selection: &Selection<'_>) -> usize {
+        let load = |values: &[AtomicU64], i: usize| f64::from_bits(values[i].load(Ordering::Relaxed));
+        let measured = |i: usize| load(selection.latencies, i) > 0.0;
+        let steadiest = (0..selection.balancers.len())
+            .filter(|&i| measured(i))
+            .map(|i| (load(selection.jitters, i), load(selection.latencies, i)))
+            .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1)))
+            .unwrap_or((0.0, 0.0));
+        let key = |i: usize| if measured(i) { (load(selection.jitters, i), load(selection.latencies, i)) } else { steadiest };
+
+        selection
+            .eligible()
+            .min_by(|&a, &b| {
+                let (ka, kb) = (key(a), key(b));
+                ka.0.total_cmp(&kb.0)
+                    .then(ka.1.total_cmp(&kb.1))
+                    .then(selection.balancers[b].contention_ratio.cmp(&selection.balancers[a].contention_ratio))
+            })
+            .unwrap_or_else(|| self.fallback.select(selection))
+    }
+}
+
+/// Weighted random pick by contention ratio, so over many connections each
+/// balancer's share converges to its ratio without round-robin's regular
+/// pattern. The generator is a SplitMix64 sequence seeded once, advanced
+/// with an atomic add so concurrent selections never wait on each other.
+pub struct WeightedRandom {
+    state: AtomicU64,
+    fallback: WeightedRoundRobin,
+}
+
+impl WeightedRandom {
+    fn new(fallback: WeightedRoundRobin) -> Self {
+        Self {
+            state: AtomicU64::new(rand::random()),
+            fallback,
+        }
+    }
+
+    fn next(&self) -> u64 {
+        const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut z = self.state.fetch_add(GAMMA, Ordering::Relaxed).wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl LoadBalancerStrategy for WeightedRandom {
+    fn select(&self, selection: &Selection<'_>) -> usize {
+        let total: u64 = selection.eligible().map(|i| u64::from(selection.balancers[i].contention_ratio)).sum();
+        if total == 0 {
+            return self.fallback.select(selection);
+        }
+        // Scale into [0, total) by the high bits, without modulo bias
+        let mut pick = ((u128::from(self.next()) * u128::from(total)) >> 64) as u64;
+        for i in selection.eligible() {
+            let weight = u64::from(selection.balancers[i].contention_ratio);
+            if pick < weight {
+                return i;
+            }
+            pick -= weight;
+        }
+        self.fallback.select(selection)
+    }
+}
+
+/// Strategies for connections to particular target ports
+#[derive(Debug, Default, PartialEq)]
+pub struct PortStrategies {
+    pub ports: Vec<(u16, Strategy)>,
+    /// Strategy for the other ports, in place of `--strategy`
+    pub default: Option<Strategy>,
+}
+
+/// Parse a list such as `443=latency,6881=least-conn,default=round-robin`
+pub fn parse_port_strategies(spec: &str) -> Result<PortStrategies> {
+    let mut parsed = PortStrategies::default();
+    for entry in spec.split(',') {
+        let Some((port, name)) = entry.split_once('=') else {
+            bail!("Expected PORT=STRATEGY, got {:?}", entry);
+        };
+        let strategy = Strategy::from_str(name.trim(), false).map_err(|_| anyhow::anyhow!("Unknown strategy {:?}", name))?;
+        match port.trim() {
+            "default" if parsed.default.is_some() => bail!("default is given twice"),
+            "default" => parsed.default = Some(strategy),
+            port => {
+                let port: u16 = port.parse().with_context(|| format!("Invalid port {:?}", port))?;
+                if parsed.ports.iter().any(|&(p, _)| p == port) {
+                    bail!("Port {} is given twice", port);
+                }
+                parsed.ports.push((port, strategy));
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+/// Hands each connection to the strategy for its target port, or the default
+/// one for other ports and connections without a target
+pub struct ByPort {
+    ports: Vec<(u16, Box<dyn LoadBalancerStrategy + Send + Sync>)>,
+    default: Box<dyn LoadBalancerStrategy + Send + Sync>,
+}
+
+impl LoadBalancerStrategy for ByPort {
+    fn select(&self, selection: &Selection<'_>) -> usize {
+        let port = selection.target.and_then(target_port);
+        let strategy = self.ports.iter().find(|(p, _)| Some(*p) == port).map_or(&self.default, |(_, s)| s);
+        strategy.select(selection)
+    }
+}
+
+/// Port of a `host:port` or `[v6]:port` target
+pub fn target_port(target: &str) -> Option<u16> {
+    let (host, port) = target.rsplit_once(':')?;
+    if host.is_empty() || (host.contains(':') && !host.ends_with(']')) {
+        return None;
+    }
+    port.parse().ok()
+}
+
+/// Host part of a `host:port` or `[v6]:port` target
+pub fn target_host(target: &str) -> &str {
+    let host = match target.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => target,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Balancers with the given contention ratios and the per-balancer state
+    /// strategies read
+    struct Fixture {
+        balancers: Vec<Arc<LoadBalancer>>,
+        live: Vec<Arc<AtomicU32>>,
+        latencies: Vec<AtomicU64>,
+        jitters: Vec<AtomicU64>,
+    }
+
+    impl Fixture {
+        fn new(ratios: &[u32]) -> Self {
+            Self {
+                balancers: (1..)
+                    .zip(ratios)
+                    .map(|(i, &ratio)| Arc::new(LoadBalancer::new(format!("192.0.2.{}", i), None, ratio, false)))
+                    .collect(),
+                live: ratios.iter().map(|_| Arc::default()).collect(),
+                latencies: ratios.iter().map(|_| AtomicU64::new(0f64.to_bits())).collect(),
+                jitters: ratios.iter().map(|_| AtomicU64::new(0f64.to_bits())).collect(),
+            }
+        }
+
+        /// Set the round-trip time and jitter averages in milliseconds
+        fn measured(self, latencies: &[f64], jitters: &[f64]) -> Self {
+            for (slot, &value) in self.latencies.iter().zip(latencies).chain(self.jitters.iter().zip(jitters)) {
+                slot.store(value.to_bits(), Ordering::Relaxed);
+            }
+            self
+        }
+
+        fn select(&self, strategy: &dyn LoadBalancerStrategy, target: Option<&str>, eligible: &dyn Fn(usize) -> bool) -> usize {
+            strategy.select(&Selection {
+                balancers: &self.balancers,
+                eligible,
+                skip: None,
+                target,
+                client: None,
+                live: &self.live,
+                latencies: &self.latencies,
+                jitters: &self.jitters,
+            })
+        }
+    }
+
+    #[test]
+    fn round_robin_follows_the_ratios() {
+        let fixture = Fixture::new(&[3, 1, 2]);
+        let selector = WeightedRoundRobin::new(false, 0);
+        let picks: Vec<_> = (0..18).map(|_| fixture.select(&selector, None, &|_| true)).collect();
+        assert_eq!(picks, [0, 0, 0, 1, 2, 2].repeat(3));
+
+        // The cycle order begins at the start index
+        let selector = WeightedRoundRobin::new(false, 1);
+        let picks: Vec<_> = (0..6).map(|_| fixture.select(&selector, None, &|_| true)).collect();
+        assert_eq!(picks, [1, 2, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn round_robin_skips_without_cutting_turns_short() {
+        let fixture = Fixture::new(&[3, 1, 2]);
+        let selector = WeightedRoundRobin::new(false, 0);
+        let select = |eligible: &dyn Fn(usize) -> bool| fixture.select(&selector, None, eligible);
+
+        assert_eq!(select(&|_| true), 0);
+        // Skipping the first balancer takes the second's credit, not the first's
+        assert_eq!(select(&|i| i != 0), 1);
+        assert_eq!([select(&|_| true), select(&|_| true)], [0, 0]);
+        assert_eq!([select(&|_| true), select(&|_| true)], [2, 2]);
+        assert_eq!(select(&|_| true), 0);
+    }
+
+    #[test]
+    fn jittered_round_robin_keeps_the_ratios_per_cycle() {
+        let fixture = Fixture::new(&[3, 1, 2]);
+        let selector = WeightedRoundRobin::new(true, 0);
+        for _ in 0..20 {
+            let mut counts = [0; 3];
+            for _ in 0..6 {
+                counts[fixture.select(&selector, None, &|_| true)] += 1;
+            }
+            assert_eq!(counts, [3, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn random_split_converges_to_the_ratios() {
+        let fixture = Fixture::new(&[3, 1, 2, 0]);
+        let selector = WeightedRandom::new(WeightedRoundRobin::new(false, 0));
+        let mut counts = [0u32; 4];
+        for _ in 0..60_000 {
+            counts[fixture.select(&selector, None, &|_| true)] += 1;
+        }
+        // Each share is within 1.5 percentage points of its weight
+        for (count, expected) in counts.iter().zip([30_000, 10_000, 20_000, 0]) {
+            assert!(count.abs_diff(expected) < 900, "{:?}", counts);
+        }
+
+        // Balancers the connection may not use are never picked
+        for _ in 0..1000 {
+            assert_ne!(fixture.select(&selector, None, &|i| i != 0), 0);
+        }
+    }
+
+    #[test]
+    fn parses_port_strategies() {
+        let parsed = parse_port_strategies("443=lowest-latency, 6881=least-conn,default=round-robin").unwrap();
+        assert_eq!(parsed.ports, [(443, Strategy::Latency), (6881, Strategy::LeastConn)]);
+        assert_eq!(parsed.default, Some(Strategy::RoundRobin));
+        assert_eq!(parse_port_strategies("80=random").unwrap().default, None);
+
+        assert!(parse_port_strategies("443").is_err());
+        assert!(parse_port_strategies("443=fastest").is_err());
+        assert!(parse_port_strategies("http=random").is_err());
+        assert!(parse_port_strategies("443=random,443=latency").is_err());
+        assert!(parse_port_strategies("default=random,default=latency").is_err());
+    }
+
+    #[test]
+    fn finds_target_ports() {
+        assert_eq!(target_port("example.com:443"), Some(443));
+        assert_eq!(target_port("192.0.2.1:80"), Some(80));
+        assert_eq!(target_port("[2001:db8::1]:8443"), Some(8443));
+        assert_eq!(target_port("2001:db8::1"), None);
+        assert_eq!(target_port("example.com"), None);
+    }
+
+    #[test]
+    fn selects_by_target_port() {
+        let fixture = Fixture::new(&[1, 1]);
+        fixture.live[0].store(5, Ordering::Relaxed);
+        let selector = build(Strategy::RoundRobin, &[(6881, Strategy::LeastConn)], false, 0, false, None);
+        let select = |target| fixture.select(selector.as_ref(), Some(target), &|_| true);
+
+        // Least connections always picks the idle second balancer
+        assert_eq!([select("192.0.2.9:6881"), select("192.0.2.9:6881")], [1, 1]);
+        // Round-robin alternates
+        assert_eq!([select("192.0.2.9:80"), select("192.0.2.9:80")], [0, 1]);
+    }
+
+    #[test]
+    fn lowest_jitter_prefers_the_steadiest_link() {
+        let selector = build(Strategy::LowestJitter, &[], false, 0, false, None);
+
+        // The slower but steadier link wins
+        let fixture = Fixture::new(&[1, 1, 1]).measured(&[20.0, 80.0, 30.0], &[9.0, 1.0, 4.0]);
+        assert_eq!(fixture.select(selector.as_ref(), None, &|_| true), 1);
+        assert_eq!(fixture.select(selector.as_ref(), None, &|i| i != 1), 2);
+        // Equal jitter goes to the lower round-trip time
+        let fixture = Fixture::new(&[1, 1, 1]).measured(&[20.0, 10.0, 30.0], &[2.0, 2.0, 5.0]);
+        assert_eq!(fixture.select(selector.as_ref(), None, &|_| true), 1);
+        // An unmeasured link ties with the steadiest one
+        let fixture = Fixture::new(&[1, 1, 1]).measured(&[0.0, 10.0, 30.0], &[0.0, 2.0, 5.0]);
+        assert_eq!(fixture.select(selector.as_ref(), None, &|i| i != 1), 0);
+    }
+}
@@ -0,0 +1,30 @@
+//! Delayed refusal of denied connections to slow down abusive clients
+
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+/// Holds denied connections open for a while before they are refused.
+/// Beyond `max_held` concurrent connections, denials are refused immediately
+/// so a flood cannot exhaust file descriptors.
+pub struct Tarpit {
+    delay: Duration,
+    slots: Semaphore,
+}
+
+impl Tarpit {
+    pub fn new(delay: Duration, max_held: usize) -> Self {
+        Self {
+            delay,
+            slots: Semaphore::new(max_held),
+        }
+    }
+
+    /// Wait out the delay, or return at once if the tarpit is full
+    pub async fn hold(&self) {
+        match self.slots.try_acquire() {
+            Ok(_slot) => tokio::time::sleep(self.delay).await,
+            Err(_) => debug!("Tarpit full, refusing denied connection immediately"),
+        }
+    }
+}
@@ -0,0 +1,38 @@
+//! TLS termination for the local listener
+//!
+//! Clients connect over TLS and speak SOCKS5 (or are tunnelled) inside the
+//! encrypted stream.
+
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// Build a TLS acceptor from a PEM certificate chain and private key
+pub fn load_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor> {
+    let mut cert_reader = BufReader::new(
+        File::open(cert_path).with_context(|| format!("Could not open certificate {}", cert_path))?,
+    );
+    let certs = rustls_pemfile::certs(&mut cert_reader)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid certificate {}", cert_path))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {}", cert_path);
+    }
+
+    let mut key_reader = BufReader::new(
+        File::open(key_path).with_context(|| format!("Could not open private key {}", key_path))?,
+    );
+    let key = rustls_pemfile::private_key(&mut key_reader)
+        .with_context(|| format!("Invalid private key {}", key_path))?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", key_path))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate/key pair")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -0,0 +1,435 @@
+//! SOCKS5 UDP ASSOCIATE relay
+//!
+//! Each association gets a UDP socket facing the client. Datagrams from the
+//! client carry a SOCKS UDP header naming their destination; the header is
+//! stripped and the payload sent from a balancer's source address. Each
+//! destination keeps the balancer it was first sent through, so a QUIC or
+//! WireGuard flow stays on one uplink. Destinations are resolved like TCP
+//! targets, with `--dns` and the address family restriction, once per
+//! association. Lookups run off the relay loop, so a slow one only holds up
+//! the few datagrams to its destination queued meanwhile, and one that fails
+//! is not looked up again for a while. Replies are wrapped in a header naming
+//! their source and returned to the client. The association ends when the
+//! controlling TCP connection closes.
+
+use crate::load_balancer::{IpFamily, LoadBalancer, LoadBalancerPool};
+use crate::platform;
+use crate::relay::ClientStream;
+use crate::settings::Settings;
+use crate::socks::{self, Failure, TargetAddressType, DOMAIN, IPV4, IPV6};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+/// Largest UDP payload
+const MAX_DATAGRAM: usize = 65535;
+
+/// Destinations remembered per association; datagrams to further ones are dropped
+const MAX_ROUTES: usize = 4096;
+
+/// Time a destination that could not be routed is not tried again
+const UNROUTED_RETRY: Duration = Duration::from_secs(10);
+
+/// Replies queued for the client before further ones are dropped
+const REPLY_BACKLOG: usize = 256;
+
+/// Datagrams queued for a destination while it is resolved; further ones are dropped
+const MAX_PENDING: usize = 8;
+
+/// Serve a UDP ASSOCIATE request until the control connection closes.
+/// `local_ip` is the address the client reached the proxy on; the client-facing
+/// relay socket is bound there.
+pub async fn associate<S: ClientStream>(
+    mut control: S,
+    client_addr: SocketAddr,
+    local_ip: IpAddr,
+    pool: Arc<LoadBalancerPool>,
+    settings: &Settings,
+) -> Result<()> {
+    let relay = match UdpSocket::bind((local_ip, 0)).await {
+        Ok(relay) => relay,
+        Err(e) => {
+            socks::send_failure(&mut control, &settings.reply_codes, Failure::ConnectFailed).await?;
+            return Err(e.into());
+        }
+    };
+    let relay_addr = relay.local_addr()?;
+    socks::send_bound_response(&mut control, relay_addr).await?;
+    info!("UDP association for {} on {}", client_addr, relay_addr);
+
+    let (reply_tx, mut reply_rx) = mpsc::channel::<(usize, SocketAddr, Vec<u8>)>(REPLY_BACKLOG);
+    let mut association = Association {
+        client_ip: client_addr.ip(),
+        client: None,
+        routes: HashMap::new(),
+        unrouted: HashMap::new(),
+        pending: HashMap::new(),
+        resolving: JoinSet::new(),
+        uplinks: HashMap::new(),
+        warned_fragment: false,
+        receivers: JoinSet::new(),
+        reply_tx,
+        pool: &pool,
+        settings,
+    };
+
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let mut control_buf = [0u8; 64];
+    loop {
+        tokio::select! {
+            // The client sends nothing more on the control connection; it only
+            // closing it matters
+            read = control.read(&mut control_buf) => {
+                if matches!(read, Ok(0) | Err(_)) {
+                    break;
+                }
+            }
+            received = relay.recv_from(&mut buf) => {
+                let (n, from) = received?;
+                association.client_datagram(&buf[..n], from).await;
+            }
+            Some(Ok((target, idx, destination))) = association.resolving.join_next() => {
+                association.resolved(target, idx, destination).await;
+            }
+            Some((idx, source, payload)) = reply_rx.recv() => {
+                if let Some(client) = association.client {
+                    let mut datagram = encode_header(source);
+                    datagram.extend_from_slice(&payload);
+                    if let Err(e) = relay.send_to(&datagram, client).await {
+                        debug!("UDP reply to {} failed: {}", client, e);
+                    }
+                    pool.record_transfer(idx, 0, payload.len() as u64);
+                }
+            }
+        }
+    }
+
+    debug!("UDP association for {} closed", client_addr);
+    Ok(())
+}
+
+/// State of one UDP association
+struct Association<'a> {
+    client_ip: IpAddr,
+    /// Client address learned from its first datagram
+    client: Option<SocketAddr>,
+    /// Destination as requested by the client -> balancer and resolved address
+    routes: HashMap<String, (usize, SocketAddr)>,
+    /// Destinations that could not be resolved or were refused, and when
+    unrouted: HashMap<String, Instant>,
+    /// Destinations being resolved -> datagrams waiting for them
+    pending: HashMap<String, Vec<Vec<u8>>>,
+    /// Lookups of new destinations, each yielding the destination, its balancer and address
+    resolving: JoinSet<(String, usize, Option<SocketAddr>)>,
+    /// Outbound socket per balancer and family
+    uplinks: HashMap<(usize, bool), Arc<UdpSocket>>,
+    /// Set once a fragmented datagram was dropped, so the warning is logged once
+    warned_fragment: bool,
+    /// Tasks forwarding replies from the outbound sockets, aborted on drop
+    receivers: JoinSet<()>,
+    reply_tx: mpsc::Sender<(usize, SocketAddr, Vec<u8>)>,
+    pool: &'a LoadBalancerPool,
+    settings: &'a Settings,
+}
+
+impl Association<'_> {
+    async fn client_datagram(&mut self, datagram: &[u8], from: SocketAddr) {
+        // Only the client that opened the association may use it
+        if from.ip() != self.client_ip {
+            debug!("Dropping UDP datagram from {}, association belongs to {}", from, self.client_ip);
+            return;
+        }
+        self.client = Some(from);
+
+        if let Some(&frag) = datagram.get(2).filter(|&&frag| frag != 0) {
+            if !self.warned_fragment {
+                self.warned_fragment = true;
+                warn!("Dropping fragmented UDP datagrams from {} (FRAG {}), reassembly is not supported", from, frag);
+            }
+            return;
+        }
+        let Some((target, target_type, payload)) = parse_header(datagram) else {
+            debug!("Dropping malformed UDP datagram from {}", from);
+            return;
+        };
+        if self.settings.ip_family.is_some_and(|f| !f.allows(target_type)) {
+            return;
+        }
+
+        let Some(&(idx, destination)) = self.routes.get(&target) else {
+            if let Some(queued) = self.pending.get_mut(&target) {
+                if queued.len() < MAX_PENDING {
+                    queued.push(payload.to_vec());
+                }
+                return;
+            }
+            if self.unrouted.get(&target).is_some_and(|since| since.elapsed() < UNROUTED_RETRY) {
+                return;
+            }
+            self.unrouted.retain(|_, since| since.elapsed() < UNROUTED_RETRY);
+            if self.routes.len() + self.unrouted.len() + self.pending.len() >= MAX_ROUTES {
+                debug!("UDP association for {} has too many destinations, dropping {}", from, target);
+                return;
+            }
+            if self.route(&target, target_type) {
+                self.pending.insert(target, vec![payload.to_vec()]);
+            } else {
+                self.unrouted.insert(target, Instant::now());
+            }
+            return;
+        };
+        self.send(idx, &target, destination, payload).await;
+    }
+
+    /// Pick a balancer able to send UDP for a new destination and start
+    /// resolving it; false if there is none
+    fn route(&mut self, target: &str, target_type: TargetAddressType) -> bool {
+        // Upstream proxies and blackholes cannot carry datagrams
+        let skip: Vec<bool> = (0..self.pool.len())
+            .map(|i| self.pool.balancer(i).is_none_or(|lb| lb.upstream.is_some() || lb.blackhole.is_some()))
+            .collect();
+        if skip.iter().all(|&s| s) {
+            warn!("No load balancer can relay UDP to {}", target);
+            return false;
+        }
+
+        let Some((lb, idx)) = self.pool.get_load_balancer(Some(&skip), Some(target_type), Some(target), Some(self.client_ip)) else {
+            return false;
+        };
+        let (target, family, dns_server) = (target.to_string(), self.settings.ip_family, self.settings.dns_server);
+        self.resolving.spawn(async move {
+            let destination = resolve(&target, &lb, family, dns_server).await;
+            (target, idx, destination)
+        });
+        true
+    }
+
+    /// Finish routing a destination once its lookup is done, sending the
+    /// datagrams queued for it if it may be used
+    async fn resolved(&mut self, target: String, idx: usize, destination: Option<SocketAddr>) {
+        let queued = self.pending.remove(&target).unwrap_or_default();
+        let Some(destination) = destination else {
+            debug!("Could not resolve UDP destination {} for LB {}", target, idx + 1);
+            self.unrouted.insert(target, Instant::now());
+            return;
+        };
+        if self.settings.block_private && !platform::is_global(&destination.ip()) {
+            warn!("UDP datagram to {} blocked: not publicly routable", destination);
+            self.unrouted.insert(target, Instant::now());
+            return;
+        }
+        if !self.settings.access.permits(destination) {
+            warn!("UDP datagram to {} blocked by --allow/--deny rules", destination);
+            self.unrouted.insert(target, Instant::now());
+            return;
+        }
+
+        if let Some(lb) = self.pool.balancer(idx) {
+            info!("UDP {} -> {} LB: {}", target, lb.address, idx + 1);
+        }
+        self.routes.insert(target.clone(), (idx, destination));
+        for payload in queued {
+            self.send(idx, &target, destination, &payload).await;
+        }
+    }
+
+    /// Send a payload to a routed destination through its balancer
+    async fn send(&mut self, idx: usize, target: &str, destination: SocketAddr, payload: &[u8]) {
+        let Some(uplink) = self.uplink(idx, destination.is_ipv6()) else {
+            return;
+        };
+        match uplink.send_to(payload, destination).await {
+            Ok(_) => self.pool.record_transfer(idx, payload.len() as u64, 0),
+            Err(e) => debug!("UDP datagram to {} via LB {} failed: {}", target, idx + 1, e),
+        }
+    }
+
+    /// Outbound socket for a balancer and family, opened on first use
+    fn uplink(&mut self, idx: usize, ipv6: bool) -> Option<Arc<UdpSocket>> {
+        if let Some(uplink) = self.uplinks.get(&(idx, ipv6)) {
+            return Some(Arc::clone(uplink));
+        }
+
+        let lb = self.pool.balancer(idx)?;
+        let uplink = match platform::bind_udp(lb, ipv6) {
+            Ok(uplink) => Arc::new(uplink),
+            Err(e) => {
+                warn!("Could not open UDP socket on LB {} ({}): {}", idx + 1, lb.address, e);
+                return None;
+            }
+        };
+
+        let receiver = Arc::clone(&uplink);
+        let reply_tx = self.reply_tx.clone();
+        self.receivers.spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            while let Ok((n, source)) = receiver.recv_from(&mut buf).await {
+                // A client that cannot keep up loses replies rather than stalling the uplink
+                let _ = reply_tx.try_send((idx, source, buf[..n].to_vec()));
+            }
+        });
+
+        self.uplinks.insert((idx, ipv6), Arc::clone(&uplink));
+        Some(uplink)
+    }
+}
+
+/// Resolve a destination as TCP targets are, with `--dns` and the family
+/// restriction, to an address the balancer can send to: of its family, or of
+/// either for a dual-stack balancer
+async fn resolve(target: &str, lb: &LoadBalancer, family: Option<IpFamily>, dns_server: Option<SocketAddr>) -> Option<SocketAddr> {
+    platform::resolve_target(target, lb, family, dns_server)
+        .await
+        .ok()?
+        .into_iter()
+        .find(|a| lb.has_family(a.is_ipv6()))
+}
+
+/// Split a client datagram into its destination and payload. Fragmented
+/// datagrams (FRAG != 0) are not supported and yield None.
+fn parse_header(datagram: &[u8]) -> Option<(String, TargetAddressType, &[u8])> {
+    let (&[_, _, frag, address_type], rest) = datagram.split_first_chunk::<4>()?;
+    if frag != 0 {
+        return None;
+    }
+
+    let (target, target_type, rest) = match address_type {
+        IPV4 => {
+            let (ip, rest) = rest.split_first_chunk::<4>()?;
+            (Ipv4Addr::from(*ip).to_string(), TargetAddressType::IPv4, rest)
+        }
+        IPV6 => {
+            let (ip, rest) = rest.split_first_chunk::<16>()?;
+            let ip = Ipv6Addr::from(*ip);
+            match ip.to_ipv4_mapped() {
+                Some(ip) => (ip.to_string(), TargetAddressType::IPv4, rest),
+                None => (format!("[{}]", ip), TargetAddressType::IPv6, rest),
+            }
+        }
+        DOMAIN => {
+            let (&len, rest) = rest.split_first()?;
+            if len == 0 || rest.len() < len as usize {
+                return None;
+            }
+            let (name, rest) = rest.split_at(len as usize);
+            (String::from_utf8_lossy(name).into_owned(), TargetAddressType::Domain, rest)
+        }
+        _ => return None,
+    };
+
+    let (port, payload) = rest.split_first_chunk::<2>()?;
+    Some((format!("{}:{}", target, u16::from_be_bytes(*port)), target_type, payload))
+}
+
+/// SOCKS UDP header naming the source of a reply
+fn encode_header(source: SocketAddr) -> Vec<u8> {
+    let mut header = vec![0, 0, 0];
+    match source.ip() {
+        IpAddr::V4(ip) => {
+            header.push(IPV4);
+            header.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => {
+                header.push(IPV4);
+                header.extend_from_slice(&ip.octets());
+            }
+            None => {
+                header.push(IPV6);
+                header.extend_from_slice(&ip.octets());
+            }
+        },
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_headers() {
+        let datagram = [0, 0, 0, IPV4, 192, 0, 2, 1, 0, 53, b'h', b'i'];
+        let (target, target_type, payload) = parse_header(&datagram).unwrap();
+        assert_eq!((target.as_str(), target_type, payload), ("192.0.2.1:53", TargetAddressType::IPv4, &b"hi"[..]));
+
+        let mut datagram = vec![0, 0, 0, DOMAIN, 11];
+        datagram.extend_from_slice(b"example.com");
+        datagram.extend_from_slice(&[1, 187]);
+        let (target, target_type, payload) = parse_header(&datagram).unwrap();
+        assert_eq!((target.as_str(), target_type, payload), ("example.com:443", TargetAddressType::Domain, &b""[..]));
+
+        let mut datagram = vec![0, 0, 0, IPV6];
+        datagram.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        datagram.extend_from_slice(&[0, 80]);
+        assert_eq!(parse_header(&datagram).unwrap().0, "[2001:db8::1]:80");
+    }
+
+    #[test]
+    fn rejects_fragmented_and_truncated_headers() {
+        assert_eq!(parse_header(&[0, 0, 1, IPV4, 192, 0, 2, 1, 0, 53]), None);
+        assert_eq!(parse_header(&[0, 0, 0, IPV4, 192, 0, 2]), None);
+        assert_eq!(parse_header(&[0, 0, 0, DOMAIN, 0, 0, 53]), None);
+        assert_eq!(parse_header(&[0, 0, 0, 9, 0, 53]), None);
+    }
+
+    #[test]
+    fn encoded_headers_parse_back() {
+        for source in ["192.0.2.1:53", "[2001:db8::1]:443"] {
+            let source: SocketAddr = source.parse().unwrap();
+            let header = encode_header(source);
+            assert_eq!(parse_header(&header).unwrap().0, source.to_string());
+        }
+        let mapped = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 7).to_ipv6_mapped().into(), 9);
+        assert_eq!(encode_header(mapped)[3], IPV4);
+    }
+
+    #[tokio::test]
+    async fn relays_datagrams_until_the_control_connection_closes() {
+        use tokio::io::AsyncReadExt;
+
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..n], from).await;
+            }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let pool = Arc::new(LoadBalancerPool::new(vec![LoadBalancer::new("127.0.0.1:0".to_string(), None, 1, false)]));
+        let (mut control, server) = tokio::io::duplex(64);
+        let client_addr = client.local_addr().unwrap();
+        let association = tokio::spawn(async move {
+            associate(server, client_addr, IpAddr::V4(Ipv4Addr::LOCALHOST), pool, &Settings::default()).await
+        });
+
+        // The reply to UDP ASSOCIATE names the relay socket
+        let mut reply = [0u8; 10];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..8], [5, 0, 0, IPV4, 127, 0, 0, 1]);
+        let relay_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), u16::from_be_bytes([reply[8], reply[9]]));
+
+        let mut datagram = encode_header(echo_addr);
+        datagram.extend_from_slice(b"ping");
+        client.send_to(&datagram, relay_addr).await.unwrap();
+        let mut buf = [0u8; 512];
+        let (n, from) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(from, relay_addr);
+        let (source, _, payload) = parse_header(&buf[..n]).unwrap();
+        assert_eq!((source, payload), (echo_addr.to_string(), &b"ping"[..]));
+
+        drop(control);
+        let ended = tokio::time::timeout(Duration::from_secs(2), association).await;
+        assert!(ended.unwrap().unwrap().is_ok());
+    }
+}
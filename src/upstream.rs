@@ -0,0 +1,184 @@
+//! Chaining connections through upstream proxies
+
+use crate::load_balancer::{LoadBalancer, Upstream, UpstreamKind};
+use crate::platform::ConnectError;
+use crate::socks;
+use anyhow::{bail, Result};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Maximum size of an upstream HTTP CONNECT response header
+const MAX_RESPONSE_HEADER: usize = 8192;
+
+/// Connect to the target through the balancer's upstream proxy. The connect
+/// to the proxy and its handshake together are bounded by `timeout`.
+pub async fn connect_via_upstream(
+    target_addr: &str,
+    lb: &LoadBalancer,
+    upstream: &Upstream,
+    timeout: Option<Duration>,
+) -> Result<TcpStream> {
+    let connecting = async {
+        let mut stream = TcpStream::connect(&lb.address).await.map_err(ConnectError::Connect)?;
+        match upstream.kind {
+            UpstreamKind::Http => http_connect(&mut stream, target_addr, upstream).await?,
+            UpstreamKind::Socks5 => socks5_connect(&mut stream, target_addr, upstream).await?,
+        }
+        Ok(stream)
+    };
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, connecting).await.map_err(|_| {
+            ConnectError::Connect(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("upstream {} timed out", lb.address),
+            ))
+        })?,
+        None => connecting.await,
+    }
+}
+
+/// Perform an HTTP CONNECT exchange and require a 200 response
+async fn http_connect(stream: &mut TcpStream, target_addr: &str, upstream: &Upstream) -> Result<()> {
+    let mut request = format!(
+        "CONNECT {} HTTP/1.1\r\nHost: {}\r\n",
+        target_addr, target_addr
+    );
+    if let Some((user, pass)) = &upstream.credentials {
+        let token = base64_encode(format!("{}:{}", user, pass).as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte so nothing past the header is consumed from the tunnel
+    let mut response = Vec::with_capacity(256);
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE_HEADER {
+            bail!("Upstream proxy response header too large");
+        }
+        let byte = stream.read_u8().await.map_err(|_| {
+            anyhow::anyhow!("Upstream proxy closed the connection during CONNECT")
+        })?;
+        response.push(byte);
+    }
+
+    let status_line = String::from_utf8_lossy(&response);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    if !status_line.starts_with("HTTP/1.") || status != "200" {
+        bail!("Upstream proxy refused CONNECT: {}", status_line);
+    }
+
+    Ok(())
+}
+
+/// Perform a SOCKS5 handshake, with username/password authentication when the
+/// balancer has credentials, and CONNECT to the target. Domains are sent as
+/// they are so the upstream resolves them.
+async fn socks5_connect(stream: &mut TcpStream, target_addr: &str, upstream: &Upstream) -> Result<()> {
+    let greeting: &[u8] = match upstream.credentials {
+        Some(_) => &[5, 2, socks::NOAUTH, socks::USERNAME_PASSWORD],
+        None => &[5, 1, socks::NOAUTH],
+    };
+    stream.write_all(greeting).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await.map_err(|_| {
+        anyhow::anyhow!("Upstream proxy closed the connection during the SOCKS greeting")
+    })?;
+    match (choice, &upstream.credentials) {
+        ([5, socks::NOAUTH], _) => {}
+        ([5, socks::USERNAME_PASSWORD], Some((user, pass))) => {
+            if user.len() > 255 || pass.len() > 255 {
+                bail!("Upstream proxy credentials must be at most 255 bytes each");
+            }
+            let mut request = vec![1, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(pass.len() as u8);
+            request.extend_from_slice(pass.as_bytes());
+            stream.write_all(&request).await?;
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                bail!("Upstream proxy rejected the credentials for {}", user);
+            }
+        }
+        ([5, socks::NO_ACCEPTABLE_METHOD], _) => bail!("Upstream proxy accepted none of the offered authentication methods"),
+        _ => bail!("Upstream proxy is not a SOCKS5 server"),
+    }
+
+    let mut request = vec![5, socks::CONNECT, 0];
+    let port = match target_addr.parse::<SocketAddr>() {
+        Ok(SocketAddr::V4(target)) => {
+            request.push(socks::IPV4);
+            request.extend_from_slice(&target.ip().octets());
+            target.port()
+        }
+        Ok(SocketAddr::V6(target)) => {
+            request.push(socks::IPV6);
+            request.extend_from_slice(&target.ip().octets());
+            target.port()
+        }
+        Err(_) => {
+            let Some((host, port)) = target_addr.rsplit_once(':').and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?))) else {
+                bail!("Invalid target address {}", target_addr);
+            };
+            if host.len() > 255 {
+                bail!("Target host {} is too long for SOCKS5", host);
+            }
+            request.extend_from_slice(&[socks::DOMAIN, host.len() as u8]);
+            request.extend_from_slice(host.as_bytes());
+            port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await.map_err(|_| {
+        anyhow::anyhow!("Upstream proxy closed the connection during CONNECT")
+    })?;
+    match reply[1] {
+        socks::SUCCESS => {}
+        // Reported as I/O errors so the client gets the matching reply code
+        socks::CONNECTION_REFUSED => {
+            return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "upstream proxy: connection refused").into());
+        }
+        socks::HOST_UNREACHABLE => {
+            return Err(std::io::Error::new(std::io::ErrorKind::HostUnreachable, "upstream proxy: host unreachable").into());
+        }
+        code => bail!("Upstream proxy refused CONNECT with SOCKS reply code {:#04x}", code),
+    }
+
+    // Skip the bound address, whose length depends on its type
+    let address_len = match reply[3] {
+        socks::IPV4 => 4,
+        socks::IPV6 => 16,
+        socks::DOMAIN => stream.read_u8().await? as usize,
+        other => bail!("Upstream proxy sent an unknown address type {:#04x}", other),
+    };
+    let mut bound = vec![0u8; address_len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(())
+}
+
+/// Standard base64 encoding for Basic credentials
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}